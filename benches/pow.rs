@@ -0,0 +1,53 @@
+// benches/pow.rs
+//
+// Compares the single-threaded hash rates of the proof of work hashers and
+// what they mean for mining a block. Their test vectors are unit tests in
+// src/pow.rs. Run with `cargo bench --bench pow`.
+
+use std::time::{Duration, Instant};
+
+use rust_chain::consensus::block_work;
+use rust_chain::pow::{PowAlgorithm, PowHasher, ScryptHasher, HEADER_SIZE};
+
+// How long each hasher is timed for
+const RUN_TIME: Duration = Duration::from_secs(2);
+
+fn main() {
+    println!("expected hashes per block: {}", block_work());
+    report("sha256", 0, &*PowAlgorithm::Sha256.hasher());
+    let scrypt = ScryptHasher::default();
+    report("scrypt", scrypt.memory(), &scrypt);
+    // Costlier scrypt settings a network could pick
+    for log_n in [12, 14] {
+        let hasher = ScryptHasher::new(log_n, 1, 1);
+        report(&format!("scrypt N=2^{}", log_n), hasher.memory(), &hasher);
+    }
+}
+
+// Hashes for RUN_TIME and prints the rate and the expected time to mine a
+// block on one thread
+fn report(name: &str, memory: usize, hasher: &dyn PowHasher) {
+    let mut header = [0; HEADER_SIZE];
+    for (i, byte) in header.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let start = Instant::now();
+    let mut hashes: u64 = 0;
+    while start.elapsed() < RUN_TIME {
+        header[HEADER_SIZE - 8..].copy_from_slice(&hashes.to_be_bytes());
+        hasher.hash(&header);
+        hashes += 1;
+    }
+    let rate = hashes as f64 / start.elapsed().as_secs_f64();
+    let memory = match memory {
+        0 => String::new(),
+        bytes => format!(" {:>6} KiB/hash", bytes / 1024),
+    };
+    println!(
+        "{:<16} {:>12.0} H/s {:>10.3} s/block{}",
+        name,
+        rate,
+        block_work() as f64 / rate,
+        memory
+    );
+}
@@ -0,0 +1,60 @@
+// benches/store.rs
+//
+// Compares UTXO queries through one long lived SledStore handle with
+// opening the database again for every query, as UTXOSet used to do.
+// Run with `cargo bench --bench store`.
+
+use std::time::{Duration, Instant};
+
+use rust_chain::chain::Blockchain;
+use rust_chain::errors::Result;
+use rust_chain::store::SledStore;
+use rust_chain::utxo::UTXOSet;
+use rust_chain::wallet::{hash_pub_key, Wallets};
+
+const ROUNDS: u32 = 200;
+
+fn main() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("rust-chain-bench-{}", std::process::id()));
+
+    let store = SledStore::open(&dir)?;
+    let mut wallets = Wallets::open(store.clone())?;
+    let address = wallets.create_wallet();
+    wallets.save_all()?;
+    let mut pub_key_hash = wallets.get_wallet(&address).unwrap().public_key.clone();
+    hash_pub_key(&mut pub_key_hash);
+
+    let utxo_set = UTXOSet {
+        blockchain: Blockchain::create(store, address)?,
+    };
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        utxo_set.find_utxo_for_address(&pub_key_hash)?;
+    }
+    let shared = start.elapsed();
+    drop(utxo_set);
+    drop(wallets);
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let utxo_set = UTXOSet {
+            blockchain: Blockchain::open(SledStore::open(&dir)?)?,
+        };
+        utxo_set.find_utxo_for_address(&pub_key_hash)?;
+    }
+    let reopened = start.elapsed();
+
+    report("shared handle", shared);
+    report("reopen per call", reopened);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+fn report(name: &str, total: Duration) {
+    println!(
+        "{:<16} {:>10.3} ms total {:>10.1} us/query",
+        name,
+        total.as_secs_f64() * 1e3,
+        total.as_secs_f64() * 1e6 / ROUNDS as f64
+    );
+}
@@ -0,0 +1,305 @@
+// addrbook.rs
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Result;
+use crate::peers::unix_now;
+
+// File in the data directory the address book is kept in, next to the blocks
+pub const ADDRBOOK_FILE: &str = "peers.json";
+
+// Most addresses kept, the least useful ones are dropped past this
+pub const MAX_ADDRESSES: usize = 2000;
+
+// Most addresses sent in one Addr message
+pub const MAX_ADDR: usize = 1000;
+
+// Failed attempts after which an address that never answered is forgotten
+const MAX_FAILURES: u32 = 10;
+
+// Wait before trying an address again after its first failure, doubled
+// with every further failure
+const RETRY_DELAY: u64 = 60;
+
+// A node address with what is known of it, times are unix seconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KnownAddress {
+    pub addr: SocketAddr,
+    // When a node last said the address is up
+    pub last_seen: u64,
+    pub last_attempt: u64,
+    pub last_success: u64,
+    // Failed attempts since the last success
+    pub failures: u32,
+    pub seed: bool,
+}
+
+impl KnownAddress {
+    fn new(addr: SocketAddr, last_seen: u64) -> KnownAddress {
+        KnownAddress {
+            addr,
+            last_seen,
+            last_attempt: 0,
+            last_success: 0,
+            failures: 0,
+            seed: false,
+        }
+    }
+
+    // True while the address is waiting out its retry delay
+    fn backing_off(&self, now: u64) -> bool {
+        if self.failures == 0 {
+            return false;
+        }
+        let delay = RETRY_DELAY.saturating_mul(1 << (self.failures - 1).min(16));
+        now < self.last_attempt.saturating_add(delay)
+    }
+
+    // Addresses that answered recently go first
+    fn rank(&self) -> (u32, std::cmp::Reverse<u64>, std::cmp::Reverse<u64>) {
+        (
+            self.failures,
+            std::cmp::Reverse(self.last_success),
+            std::cmp::Reverse(self.last_seen),
+        )
+    }
+}
+
+// Group of addresses likely run by the same operator: the /16 of routable
+// IPv4 addresses and the /32 of IPv6 ones. Loopback and private addresses
+// are each their own group, so local test networks spread over all nodes.
+pub fn address_group(addr: &SocketAddr) -> String {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_loopback() || ip.is_private() || ip.is_link_local() => {
+            addr.to_string()
+        }
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            format!("{}.{}", octets[0], octets[1])
+        }
+        IpAddr::V6(ip) if ip.is_loopback() => addr.to_string(),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}", segments[0], segments[1])
+        }
+    }
+}
+
+// The node addresses learned from seeds, peers and Addr messages, with when
+// they were seen and how connecting to them went. Persisted when opened on a
+// data directory. Clones share the book.
+#[derive(Clone, Default)]
+pub struct AddressBook {
+    inner: Arc<Mutex<BookState>>,
+}
+
+#[derive(Default)]
+struct BookState {
+    addresses: HashMap<SocketAddr, KnownAddress>,
+    file: Option<PathBuf>,
+}
+
+impl AddressBook {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<AddressBook> {
+        fs::create_dir_all(&dir)?;
+        let path = dir.as_ref().join(ADDRBOOK_FILE);
+        let addresses: Vec<KnownAddress> = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let book = AddressBook::default();
+        {
+            let mut state = book.state();
+            state.addresses = addresses
+                .into_iter()
+                .map(|known| (known.addr, known))
+                .collect();
+            state.file = Some(path);
+        }
+        Ok(book)
+    }
+
+    fn state(&self) -> MutexGuard<'_, BookState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn len(&self) -> usize {
+        self.state().addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state().addresses.is_empty()
+    }
+
+    // Adds configured seed nodes, which are never forgotten
+    pub fn add_seeds(&self, seeds: &[SocketAddr]) {
+        let mut state = self.state();
+        for seed in seeds {
+            state
+                .addresses
+                .entry(*seed)
+                .or_insert_with(|| KnownAddress::new(*seed, 0))
+                .seed = true;
+        }
+        state.save();
+    }
+
+    // Records addresses a peer says are up, as of `last_seen`. Times in the
+    // future are taken as now.
+    pub fn add(&self, addresses: &[(SocketAddr, u64)]) {
+        let now = unix_now();
+        let mut state = self.state();
+        for (addr, last_seen) in addresses.iter().take(MAX_ADDR) {
+            if addr.port() == 0 || addr.ip().is_unspecified() {
+                continue;
+            }
+            let last_seen = (*last_seen).min(now);
+            let known = state
+                .addresses
+                .entry(*addr)
+                .or_insert_with(|| KnownAddress::new(*addr, last_seen));
+            known.last_seen = known.last_seen.max(last_seen);
+        }
+        state.evict();
+        state.save();
+    }
+
+    // Records that a handshake with the address worked
+    pub fn mark_good(&self, addr: &SocketAddr) {
+        let now = unix_now();
+        let mut state = self.state();
+        let known = state
+            .addresses
+            .entry(*addr)
+            .or_insert_with(|| KnownAddress::new(*addr, now));
+        known.last_seen = now;
+        known.last_attempt = now;
+        known.last_success = now;
+        known.failures = 0;
+        state.evict();
+        state.save();
+    }
+
+    // Records a failed attempt, forgetting addresses that never worked
+    // after MAX_FAILURES of them
+    pub fn mark_failed(&self, addr: &SocketAddr) {
+        let mut state = self.state();
+        let forget = match state.addresses.get_mut(addr) {
+            Some(known) => {
+                known.last_attempt = unix_now();
+                known.failures = known.failures.saturating_add(1);
+                !known.seed && known.last_success == 0 && known.failures >= MAX_FAILURES
+            }
+            None => return,
+        };
+        if forget {
+            state.addresses.remove(addr);
+        }
+        state.save();
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) {
+        let mut state = self.state();
+        if state.addresses.remove(addr).is_some() {
+            state.save();
+        }
+    }
+
+    // All addresses, the most useful first
+    pub fn addresses(&self) -> Vec<KnownAddress> {
+        let mut addresses: Vec<KnownAddress> = self.state().addresses.values().cloned().collect();
+        addresses.sort_by_key(|known| (known.rank(), known.addr));
+        addresses
+    }
+
+    // Addresses to hand out in an Addr message: the most recently seen ones
+    // that did not keep failing
+    pub fn to_share(&self) -> Vec<(SocketAddr, u64)> {
+        let mut addresses: Vec<KnownAddress> = self
+            .state()
+            .addresses
+            .values()
+            .filter(|known| known.failures < 3)
+            .cloned()
+            .collect();
+        addresses.sort_by_key(|known| (std::cmp::Reverse(known.last_seen), known.addr));
+        addresses
+            .into_iter()
+            .take(MAX_ADDR)
+            .map(|known| (known.addr, known.last_seen))
+            .collect()
+    }
+
+    // Picks up to `count` addresses to connect to, at most one per address
+    // group and none in a group of `exclude`, skipping the ones `skip`
+    // rejects and the ones waiting to be retried
+    pub fn select(
+        &self,
+        count: usize,
+        exclude: &[SocketAddr],
+        skip: impl Fn(&SocketAddr) -> bool,
+    ) -> Vec<SocketAddr> {
+        let now = unix_now();
+        let mut groups: HashSet<String> = exclude.iter().map(address_group).collect();
+        let mut selected = Vec::new();
+        for known in self.addresses() {
+            if selected.len() == count {
+                break;
+            }
+            if known.backing_off(now) || skip(&known.addr) {
+                continue;
+            }
+            if groups.insert(address_group(&known.addr)) {
+                selected.push(known.addr);
+            }
+        }
+        selected
+    }
+}
+
+impl BookState {
+    // Drops the least useful addresses past MAX_ADDRESSES, seeds are kept
+    fn evict(&mut self) {
+        if self.addresses.len() <= MAX_ADDRESSES {
+            return;
+        }
+        let mut candidates: Vec<&KnownAddress> = self
+            .addresses
+            .values()
+            .filter(|known| !known.seed)
+            .collect();
+        candidates.sort_by_key(|known| std::cmp::Reverse((known.rank(), known.addr)));
+        let excess = self.addresses.len() - MAX_ADDRESSES;
+        let dropped: Vec<SocketAddr> = candidates
+            .iter()
+            .take(excess)
+            .map(|known| known.addr)
+            .collect();
+        for addr in dropped {
+            self.addresses.remove(&addr);
+        }
+    }
+
+    fn save(&self) {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return,
+        };
+        let mut addresses: Vec<&KnownAddress> = self.addresses.values().collect();
+        addresses.sort_by_key(|known| known.addr);
+        let result = serde_json::to_string_pretty(&addresses)
+            .map_err(failure::Error::from)
+            .and_then(|data| Ok(fs::write(path, data)?));
+        if let Err(e) = result {
+            warn!("can not save the address book: {}", e);
+        }
+    }
+}
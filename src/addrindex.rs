@@ -0,0 +1,255 @@
+// addrindex.rs
+
+use std::collections::HashMap;
+
+use failure::format_err;
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::store::{ChainStore, StoreBatch};
+use crate::tx::{TXInput, TXOutput};
+use crate::wallet::hash_pub_key;
+
+// Keys are <kind><pub key hash><...> so all entries of one address are a
+// contiguous range and a lookup only touches that address's own entries:
+//   'u' pkh txid index(u32 BE) -> bincode TXOutput, an unspent output
+//   'h' pkh height(u64 BE) txid -> empty, a transaction paying or spending
+const UTXO_KIND: u8 = b'u';
+const HISTORY_KIND: u8 = b'h';
+const TXID_LEN: usize = 64;
+
+fn prefix(kind: u8, pub_key_hash: &[u8]) -> Vec<u8> {
+    let mut key = vec![kind];
+    key.extend_from_slice(pub_key_hash);
+    key
+}
+
+fn utxo_key(pub_key_hash: &[u8], txid: &str, index: i32) -> Vec<u8> {
+    let mut key = prefix(UTXO_KIND, pub_key_hash);
+    key.extend_from_slice(txid.as_bytes());
+    key.extend_from_slice(&(index as u32).to_be_bytes());
+    key
+}
+
+fn history_key(pub_key_hash: &[u8], height: usize, txid: &str) -> Vec<u8> {
+    let mut key = prefix(HISTORY_KIND, pub_key_hash);
+    key.extend_from_slice(&(height as u64).to_be_bytes());
+    key.extend_from_slice(txid.as_bytes());
+    key
+}
+
+// Adds the outputs created and spent by a block that is being connected,
+// the outputs it spends are looked up in the UTXO set it is applied to
+pub(crate) fn stage_connect<S: ChainStore>(
+    store: &S,
+    block: &Block,
+    batch: &mut StoreBatch,
+) -> Result<()> {
+    stage_entries(block, batch, |txid, index| {
+        let outputs = store.get_utxos(txid)?;
+        match outputs.and_then(|outputs| outputs.outputs.get(&index).cloned()) {
+            Some(output) => Ok(output.pub_key_hash),
+            None => Err(format_err!("UTXO {} of {} is not found", index, txid)),
+        }
+    })
+}
+
+// Adds the entries of a block. Spent outputs are unindexed under the hash
+// they are locked to, which `spent_pub_key_hash` returns for outputs from
+// before the block, the spending goes in the history of the spender. They
+// differ for HTLCs, which their sender can refund.
+fn stage_entries(
+    block: &Block,
+    batch: &mut StoreBatch,
+    spent_pub_key_hash: impl Fn(&str, i32) -> Result<Vec<u8>>,
+) -> Result<()> {
+    // Outputs created earlier in the block, later transactions may spend them
+    let mut created: HashMap<(&str, i32), &[u8]> = HashMap::new();
+    for tx in block.get_transactions() {
+        if !tx.is_coinbase() {
+            for tx_input in &tx.v_inputs {
+                let outpoint = (tx_input.txid.as_str(), tx_input.output_index);
+                let pub_key_hash = match created.get(&outpoint) {
+                    Some(pub_key_hash) => pub_key_hash.to_vec(),
+                    None => spent_pub_key_hash(&tx_input.txid, tx_input.output_index)?,
+                };
+                batch.unindex_address(utxo_key(
+                    &pub_key_hash,
+                    &tx_input.txid,
+                    tx_input.output_index,
+                ));
+                batch.index_address(
+                    history_key(&spender_of(tx_input), block.get_height(), &tx.id),
+                    vec![],
+                );
+            }
+        }
+        for (index, output) in tx.v_outputs.iter().enumerate() {
+            if output.is_data() {
+                continue;
+            }
+            batch.index_address(
+                utxo_key(&output.pub_key_hash, &tx.id, index as i32),
+                bincode::serialize(output)?,
+            );
+            batch.index_address(
+                history_key(&output.pub_key_hash, block.get_height(), &tx.id),
+                vec![],
+            );
+            created.insert((&tx.id, index as i32), &output.pub_key_hash);
+        }
+    }
+    Ok(())
+}
+
+// The pub key hash of the wallet that signed an input
+fn spender_of(tx_input: &TXInput) -> Vec<u8> {
+    let mut pub_key_hash = tx_input.pub_key.clone();
+    hash_pub_key(&mut pub_key_hash);
+    pub_key_hash
+}
+
+// Reverts stage_connect for a block that is being disconnected,
+// the outputs it spent are looked up again in the chain
+pub(crate) fn stage_disconnect<S: ChainStore>(
+    bc: &Blockchain<S>,
+    block: &Block,
+    batch: &mut StoreBatch,
+) -> Result<()> {
+    for tx in block.get_transactions().iter().rev() {
+        for (index, output) in tx.v_outputs.iter().enumerate() {
+            if output.is_data() {
+                continue;
+            }
+            batch.unindex_address(utxo_key(&output.pub_key_hash, &tx.id, index as i32));
+            batch.unindex_address(history_key(&output.pub_key_hash, block.get_height(), &tx.id));
+        }
+        if !tx.is_coinbase() {
+            for tx_input in &tx.v_inputs {
+                let prev_tx = bc.find_tranasaction(&tx_input.txid)?;
+                let output = match prev_tx.v_outputs.get(tx_input.output_index as usize) {
+                    Some(output) => output,
+                    None => return Err(format_err!("Spent output of {} is not found", tx.id)),
+                };
+                batch.index_address(
+                    utxo_key(&output.pub_key_hash, &tx_input.txid, tx_input.output_index),
+                    bincode::serialize(output)?,
+                );
+                batch.unindex_address(history_key(
+                    &spender_of(tx_input),
+                    block.get_height(),
+                    &tx.id,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Replaces the whole index with one built from the active chain
+pub(crate) fn stage_rebuild<S: ChainStore>(
+    bc: &Blockchain<S>,
+    batch: &mut StoreBatch,
+) -> Result<()> {
+    batch.clear_addrindex();
+    let mut blocks: Vec<Block> = bc.iter().collect();
+    blocks.reverse();
+    // Pub key hashes of the outputs unspent so far
+    let mut unspent: HashMap<(String, i32), Vec<u8>> = HashMap::new();
+    for block in &blocks {
+        stage_entries(block, batch, |txid, index| {
+            match unspent.get(&(txid.to_string(), index)) {
+                Some(pub_key_hash) => Ok(pub_key_hash.clone()),
+                None => Err(format_err!("Output {} of {} is not found", index, txid)),
+            }
+        })?;
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                for tx_input in &tx.v_inputs {
+                    unspent.remove(&(tx_input.txid.clone(), tx_input.output_index));
+                }
+            }
+            for (index, output) in tx.v_outputs.iter().enumerate() {
+                if !output.is_data() {
+                    unspent.insert((tx.id.clone(), index as i32), output.pub_key_hash.clone());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Returns (txid, output index, output) of the unspent outputs of an address
+pub(crate) fn address_utxos<S: ChainStore>(
+    store: &S,
+    pub_key_hash: &[u8],
+) -> Result<Vec<(String, i32, TXOutput)>> {
+    let start = 1 + pub_key_hash.len();
+    let mut utxos = Vec::new();
+    for (key, value) in store.scan_addrindex(&prefix(UTXO_KIND, pub_key_hash))? {
+        let txid = String::from_utf8(key[start..start + TXID_LEN].to_vec())?;
+        let mut index = [0; 4];
+        index.copy_from_slice(&key[start + TXID_LEN..]);
+        utxos.push((txid, u32::from_be_bytes(index) as i32, bincode::deserialize(&value)?));
+    }
+    Ok(utxos)
+}
+
+// Returns (block height, txid) of every transaction touching an address, oldest first
+pub(crate) fn address_history<S: ChainStore>(
+    store: &S,
+    pub_key_hash: &[u8],
+) -> Result<Vec<(usize, String)>> {
+    let start = 1 + pub_key_hash.len();
+    let mut history = Vec::new();
+    for (key, _) in store.scan_addrindex(&prefix(HISTORY_KIND, pub_key_hash))? {
+        let mut height = [0; 8];
+        height.copy_from_slice(&key[start..start + 8]);
+        let txid = String::from_utf8(key[start + 8..].to_vec())?;
+        history.push((u64::from_be_bytes(height) as usize, txid));
+    }
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::htlc::{self, hash_secret, new_secret};
+    use crate::params::ChainParams;
+    use crate::testutil::{mine, new_chain, pub_key_hash};
+    use crate::transaction::TransactionBuilder;
+
+    #[test]
+    fn refunded_htlcs_leave_the_receivers_utxos() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        utxo.blockchain.set_addrindex(true).unwrap();
+        let sender_hash = pub_key_hash(&utxo, &miner);
+        let receiver_hash = pub_key_hash(&utxo, &other);
+
+        let hash = hash_secret(&new_secret());
+        let offer = TransactionBuilder::new(&utxo)
+            .sender(&miner)
+            .pay_to_htlc(&other, 30, &hash, 1)
+            .build()
+            .unwrap();
+        mine(&mut utxo, &miner, vec![offer.clone()]).unwrap();
+        assert_eq!(utxo.blockchain.get_address_utxos(&receiver_hash).unwrap().len(), 1);
+
+        let refund = htlc::refund(&utxo, &offer.id, 0).unwrap();
+        mine(&mut utxo, &miner, vec![refund.clone()]).unwrap();
+        let refunded = (2, refund.id.clone());
+        assert!(utxo.blockchain.get_address_utxos(&receiver_hash).unwrap().is_empty());
+        assert!(utxo.blockchain.get_address_history(&sender_hash).unwrap().contains(&refunded));
+
+        // A rebuilt index agrees with the one kept up to date
+        let history = utxo.blockchain.get_address_history(&sender_hash).unwrap();
+        utxo.blockchain.set_addrindex(true).unwrap();
+        assert!(utxo.blockchain.get_address_utxos(&receiver_hash).unwrap().is_empty());
+        assert_eq!(utxo.blockchain.get_address_history(&sender_hash).unwrap(), history);
+
+        utxo.blockchain.disconnect_tip().unwrap();
+        let utxos = utxo.blockchain.get_address_utxos(&receiver_hash).unwrap();
+        assert_eq!((utxos[0].0.as_str(), utxos[0].1), (offer.id.as_str(), 0));
+        assert!(!utxo.blockchain.get_address_history(&sender_hash).unwrap().contains(&refunded));
+    }
+}
@@ -0,0 +1,156 @@
+// anchor.rs
+
+use crypto::{digest::Digest, sha2::Sha256};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::block::{BlockHeader, MerkleBranch};
+use crate::blockchain::Blockchain;
+use crate::engine::ConsensusEngine;
+use crate::errors::Result;
+use crate::store::ChainStore;
+use crate::transaction::Transaction;
+
+// Shows that a block holds a transaction anchoring some data, to anyone who
+// has the block's header: the transaction with the data output and the merkle
+// branch from it to the header's merkle root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnchorProof {
+    pub header: BlockHeader,
+    pub tx: Transaction,
+    // Index of the data output in the transaction
+    pub output_index: i32,
+    pub branch: MerkleBranch,
+}
+
+impl AnchorProof {
+    pub fn block_hash(&self) -> Result<String> {
+        self.header.hash()
+    }
+
+    // Checks that the transaction anchors `data`, that the branch leads from
+    // it to the header's merkle root and that `engine` accepts the header's seal.
+    // Whether the header is in the best chain is up to the caller.
+    pub fn verify(&self, data: &[u8], engine: &dyn ConsensusEngine) -> Result<bool> {
+        let anchored = usize::try_from(self.output_index)
+            .ok()
+            .and_then(|index| self.tx.v_outputs.get(index))
+            .and_then(|output| output.data.as_deref());
+        if anchored != Some(data) {
+            return Ok(false);
+        }
+        // Merkle leaves hash the whole transaction, signatures included
+        let leaf = self.tx.clone().hash()?;
+        Ok(self.branch.verify(&self.header.merkle_root, &leaf)?
+            && engine.verify_seal(&self.header)?)
+    }
+}
+
+// The sha256 of a document, which is what gets anchored for it
+pub fn hash_document(document: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(document);
+    let mut hash = vec![0; 32];
+    hasher.result(&mut hash);
+    hash
+}
+
+// Proves the first block of the chain anchoring `data`, None if no block does
+pub fn find_anchor<S: ChainStore>(bc: &Blockchain<S>, data: &[u8]) -> Result<Option<AnchorProof>> {
+    // Blocks come from the tip backwards, the last match is the earliest
+    let mut found = None;
+    for block in bc.iter() {
+        let anchoring = block.get_transactions().iter().find_map(|tx| {
+            tx.v_outputs
+                .iter()
+                .position(|output| output.data.as_deref() == Some(data))
+                .map(|position| (tx.clone(), position as i32))
+        });
+        if let Some((tx, output_index)) = anchoring {
+            found = Some((block, tx, output_index));
+        }
+    }
+    let (block, tx, output_index) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let branch = match block.merkle_branch(&tx.id)? {
+        Some(branch) => branch,
+        None => return Err(format_err!("No merkle branch for {}", tx.id)),
+    };
+    Ok(Some(AnchorProof {
+        header: block.get_header()?,
+        tx,
+        output_index,
+        branch,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::testutil::{mine, new_chain, pay, resign, seal_block};
+    use crate::transaction::TransactionBuilder;
+
+    #[test]
+    fn proves_anchored_data_to_a_header() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let data = hash_document(b"document");
+        let anchoring = TransactionBuilder::new(&utxo)
+            .sender(&miner)
+            .pay_to(&other, 5)
+            .data(&data)
+            .build()
+            .unwrap();
+        mine(&mut utxo, &miner, vec![anchoring.clone()]).unwrap();
+        assert!(find_anchor(&utxo.blockchain, &hash_document(b"other")).unwrap().is_none());
+
+        let proof = find_anchor(&utxo.blockchain, &data).unwrap().unwrap();
+        assert_eq!(proof.block_hash().unwrap(), utxo.blockchain.get_tip_hash());
+        assert_eq!(proof.tx.id, anchoring.id);
+        let engine = utxo.blockchain.engine();
+        assert!(proof.verify(&data, engine.as_ref()).unwrap());
+        assert!(!proof.verify(&hash_document(b"other"), engine.as_ref()).unwrap());
+
+        // The proof does not hold for another header or a changed transaction
+        let mut moved = proof.clone();
+        moved.header.height += 1;
+        assert!(!moved.verify(&data, engine.as_ref()).unwrap());
+        let mut changed = proof;
+        changed.tx.v_outputs[0].value += 1;
+        assert!(!changed.verify(&data, engine.as_ref()).unwrap());
+    }
+
+    #[test]
+    fn data_outputs_can_not_be_spent() {
+        let (mut utxo, miner, _) = new_chain(ChainParams::regtest());
+        let anchoring = TransactionBuilder::new(&utxo)
+            .sender(&miner)
+            .data(&hash_document(b"document"))
+            .build()
+            .unwrap();
+        mine(&mut utxo, &miner, vec![anchoring.clone()]).unwrap();
+        let index = anchoring
+            .v_outputs
+            .iter()
+            .position(|output| output.is_data())
+            .unwrap() as i32;
+
+        // They stay out of the UTXO set, rebuilt or kept up to date
+        let utxos = utxo.blockchain.find_utxo();
+        assert!(!utxos[&anchoring.id].outputs.contains_key(&index));
+        let stored = utxo.blockchain.store().get_utxos(&anchoring.id).unwrap().unwrap();
+        assert!(!stored.outputs.contains_key(&index));
+
+        // and no key meets their conditions
+        let mut spending = pay(&utxo, &miner, &miner, 1).unwrap();
+        spending.v_inputs[0].txid = anchoring.id.clone();
+        spending.v_inputs[0].output_index = index;
+        assert!(!anchoring.v_outputs[index as usize].can_be_spent_by(&spending.v_inputs[0], 0));
+        resign(&utxo, &mut spending, &miner).unwrap();
+        let block = seal_block(&utxo.blockchain, &miner, vec![spending]).unwrap();
+        assert!(utxo.blockchain.submit_block(&block).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+// assembler.rs
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::consensus;
+use crate::errors::Result;
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::store::ChainStore;
+use crate::transaction::Transaction;
+
+// Totals of a mempool transaction and the ancestors not in the block yet,
+// which have to go in with it
+#[derive(Clone, Copy)]
+struct Package {
+    fee: i64,
+    size: usize,
+    sigops: usize,
+}
+
+// A package waiting in the queue, scored as it was when queued. It is queued
+// again whenever ancestors of it go into the block.
+struct Candidate<'a> {
+    txid: &'a str,
+    // Mempool order, the earlier wins among equal feerates
+    position: usize,
+    fee: i64,
+    size: usize,
+}
+
+impl Ord for Candidate<'_> {
+    // Orders by package feerate, compared without rounding
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        (self.fee as i128 * other.size as i128)
+            .cmp(&(other.fee as i128 * self.size as i128))
+            .then(other.position.cmp(&self.position))
+    }
+}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Candidate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate<'_> {}
+
+// Fills block templates from the mempool. A transaction goes in with its
+// unconfirmed ancestors, ranked by the feerate of the whole package, so a
+// child paying a high fee pulls in a parent paying little (child pays for
+// parent). Parents always come before their children.
+pub struct BlockAssembler {
+    max_size: usize,
+}
+
+impl Default for BlockAssembler {
+    fn default() -> BlockAssembler {
+        BlockAssembler::new(usize::MAX)
+    }
+}
+
+impl BlockAssembler {
+    // Blocks of at most `max_size` bincode bytes, and never over the
+    // limits of the chain's network
+    pub fn new(max_size: usize) -> BlockAssembler {
+        BlockAssembler { max_size }
+    }
+
+    // Picks the mempool transactions fitting in `space` bytes and `sigops`
+    // signature operations, best package feerate first, and returns them in
+    // block order with their total fee
+    pub fn select(
+        &self,
+        mempool: &Mempool,
+        space: usize,
+        sigops: usize,
+    ) -> (Vec<Transaction>, i64) {
+        let entries = mempool.entries();
+        let order: HashMap<&str, usize> = entries
+            .iter()
+            .enumerate()
+            .map(|(position, entry)| (entry.tx.id.as_str(), position))
+            .collect();
+        let tx_sigops: HashMap<&str, usize> = entries
+            .iter()
+            .map(|entry| (entry.tx.id.as_str(), consensus::sigops(&entry.tx)))
+            .collect();
+
+        // Packages are scored once here, then only updated when ancestors
+        // of them go into the block
+        let mut packages: HashMap<&str, Package> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        for entry in &entries {
+            let package = mempool
+                .ancestors(&entry.tx.id)
+                .iter()
+                .filter_map(|ancestor| mempool.get(ancestor))
+                .fold(
+                    Package {
+                        fee: 0,
+                        size: 0,
+                        sigops: 0,
+                    },
+                    |package, ancestor| Package {
+                        fee: package.fee + ancestor.fee,
+                        size: package.size + ancestor.size,
+                        sigops: package.sigops + tx_sigops[ancestor.tx.id.as_str()],
+                    },
+                );
+            let txid = entry.tx.id.as_str();
+            packages.insert(txid, package);
+            queue.push(candidate(txid, &order, &package));
+        }
+
+        let mut selected: Vec<Transaction> = Vec::new();
+        let mut in_block: HashSet<&str> = HashSet::new();
+        // Transactions whose package did not fit, their descendants do not either
+        let mut skipped: HashSet<String> = HashSet::new();
+        let mut used = 0;
+        let mut used_sigops = 0;
+        let mut fees = 0;
+        while let Some(best) = queue.pop() {
+            if in_block.contains(best.txid) || skipped.contains(best.txid) {
+                continue;
+            }
+            let package = packages[best.txid];
+            // Ancestors went in since it was queued, it is queued again
+            if package.fee != best.fee || package.size != best.size {
+                continue;
+            }
+            if used + package.size > space || used_sigops + package.sigops > sigops {
+                skipped.extend(mempool.descendants(best.txid));
+                continue;
+            }
+            used += package.size;
+            used_sigops += package.sigops;
+            fees += package.fee;
+
+            // Mempool order puts parents first
+            let mut added: Vec<&MempoolEntry> = mempool
+                .ancestors(best.txid)
+                .iter()
+                .filter(|ancestor| !in_block.contains(ancestor.as_str()))
+                .filter_map(|ancestor| mempool.get(ancestor))
+                .collect();
+            added.sort_by_key(|entry| order[entry.tx.id.as_str()]);
+            for entry in &added {
+                in_block.insert(entry.tx.id.as_str());
+                selected.push(entry.tx.clone());
+            }
+
+            // What went in no longer counts in the packages of its descendants
+            let mut changed: HashSet<&str> = HashSet::new();
+            for entry in &added {
+                for descendant in mempool.descendants(&entry.tx.id) {
+                    let txid = match mempool.get(&descendant) {
+                        Some(descendant) => descendant.tx.id.as_str(),
+                        None => continue,
+                    };
+                    if in_block.contains(txid) {
+                        continue;
+                    }
+                    if let Some(package) = packages.get_mut(txid) {
+                        package.fee -= entry.fee;
+                        package.size -= entry.size;
+                        package.sigops -= tx_sigops[entry.tx.id.as_str()];
+                        changed.insert(txid);
+                    }
+                }
+            }
+            for txid in changed {
+                if !skipped.contains(txid) {
+                    queue.push(candidate(txid, &order, &packages[txid]));
+                }
+            }
+        }
+        (selected, fees)
+    }
+
+    // A template on top of the tip paying the reward and the fees of the
+    // selected mempool transactions to `address`
+    pub fn assemble<S: ChainStore>(
+        &self,
+        bc: &Blockchain<S>,
+        mempool: &Mempool,
+        address: &str,
+        data: String,
+    ) -> Result<(Block, i64)> {
+        // The coinbase has the same size whatever fees it collects, room is
+        // kept for an extra nonce
+        let coinbase = Transaction::new_coinbase(address.to_string(), data.clone())?;
+        let mut empty = bc.new_block_template(vec![coinbase])?;
+        empty.set_extra_nonce(u64::MAX)?;
+        let params = bc.params();
+        let space = self
+            .max_size
+            .min(params.max_block_size)
+            .saturating_sub(bincode::serialized_size(&empty)? as usize);
+        let (transactions, fees) = self.select(mempool, space, params.max_block_sigops);
+
+        let reward = i32::try_from(fees).unwrap_or(i32::MAX);
+        let mut block_transactions = vec![Transaction::new_coinbase_with_fees(
+            address.to_string(),
+            data,
+            reward,
+        )?];
+        block_transactions.extend(transactions);
+        Ok((bc.new_block_template(block_transactions)?, fees))
+    }
+}
+
+// The queue entry of a package as it is now
+fn candidate<'a>(txid: &'a str, order: &HashMap<&str, usize>, package: &Package) -> Candidate<'a> {
+    Candidate {
+        txid,
+        position: order[txid],
+        fee: package.fee,
+        size: package.size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::store::MemoryStore;
+    use crate::testutil::{mine, new_chain};
+    use crate::transaction::TransactionBuilder;
+    use crate::utxoset::UTXOSet;
+
+    // A mempool holding a parent paying a fee of 1, its child paying 60 and
+    // an unrelated transaction paying 10, returned in that order
+    fn cpfp_mempool(
+        utxo: &mut UTXOSet<MemoryStore>,
+        miner: &str,
+        other: &str,
+    ) -> (Mempool, Vec<Transaction>) {
+        mine(utxo, other, Vec::new()).unwrap();
+        let mut mempool = Mempool::default();
+        let add = |mempool: &mut Mempool, from: &str, to: &str, amount, fee| {
+            let tx = TransactionBuilder::new(utxo)
+                .mempool(mempool)
+                .sender(from)
+                .pay_to(to, amount)
+                .fee(fee)
+                .build()
+                .unwrap();
+            mempool.accept(&utxo.blockchain, tx.clone()).unwrap();
+            tx
+        };
+        let parent = add(&mut mempool, other, miner, 30, 1);
+        let child = add(&mut mempool, other, miner, 5, 60);
+        let unrelated = add(&mut mempool, miner, other, 30, 10);
+        assert_eq!(child.v_inputs[0].txid, parent.id);
+        (mempool, vec![parent, child, unrelated])
+    }
+
+    fn size(mempool: &Mempool, tx: &Transaction) -> usize {
+        mempool.get(&tx.id).unwrap().size
+    }
+
+    fn ids(transactions: &[Transaction]) -> Vec<&str> {
+        transactions.iter().map(|tx| tx.id.as_str()).collect()
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let (mempool, txs) = cpfp_mempool(&mut utxo, &miner, &other);
+        let (selected, fees) = BlockAssembler::default().select(&mempool, usize::MAX, usize::MAX);
+        // The parent pays least but goes first, pulled in by its child
+        assert_eq!(ids(&selected), ids(&txs));
+        assert_eq!(fees, 71);
+    }
+
+    #[test]
+    fn puts_parents_before_children() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let (mempool, txs) = cpfp_mempool(&mut utxo, &miner, &other);
+        let (selected, _) = BlockAssembler::default().select(&mempool, usize::MAX, usize::MAX);
+        let position = |tx: &Transaction| selected.iter().position(|s| s.id == tx.id).unwrap();
+        assert!(position(&txs[0]) < position(&txs[1]));
+    }
+
+    #[test]
+    fn leaves_out_packages_over_the_size_limit() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let (mempool, txs) = cpfp_mempool(&mut utxo, &miner, &other);
+        // Just too little room for the parent with its child
+        let space = size(&mempool, &txs[0]) + size(&mempool, &txs[1]) - 1;
+        let (selected, fees) = BlockAssembler::default().select(&mempool, space, usize::MAX);
+        assert!(!ids(&selected).contains(&txs[1].id.as_str()));
+        assert!(ids(&selected).contains(&txs[2].id.as_str()));
+        assert!(selected.iter().map(|tx| size(&mempool, tx)).sum::<usize>() <= space);
+        assert_eq!(
+            fees,
+            selected
+                .iter()
+                .map(|tx| mempool.get(&tx.id).unwrap().fee)
+                .sum::<i64>()
+        );
+    }
+
+    #[test]
+    fn leaves_out_packages_over_the_sigop_limit() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let (mempool, txs) = cpfp_mempool(&mut utxo, &miner, &other);
+        let limit = consensus::sigops(&txs[2]);
+        let (selected, fees) = BlockAssembler::default().select(&mempool, usize::MAX, limit);
+        assert_eq!(ids(&selected), vec![txs[2].id.as_str()]);
+        assert_eq!(fees, 10);
+    }
+}
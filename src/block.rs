@@ -1,17 +1,18 @@
 // block.rs
 
+use crate::consensus::TARGET_HEXT;
+use crate::pow::PowHasher;
+use crate::tx::Confirmation;
 use crate::{errors::Result, transaction::Transaction};
 
-use bincode;
-use crypto::{digest::Digest, sha2::Sha256};
-use log::info;
+use crypto::{digest::Digest, ed25519, sha2::Sha256};
+use failure::format_err;
 use merkle_cbt::merkle_tree::Merge;
-use merkle_cbt::merkle_tree::CBMT;
+use merkle_cbt::merkle_tree::{MerkleProof, CBMT};
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
-
-pub const TARGET_HEXT: usize = 4;
 
+// Blocks are stored in the blocks DB as bincode and their fields feed the
+// header hash, so the field list is frozen for a major version
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     timestamp: u128,
@@ -19,17 +20,94 @@ pub struct Block {
     prev_block_hash: String,
     hash: String,
     height: usize,
-    nonce: i32,
+    nonce: u64,
+    // Validator signature of the hash on proof of authority networks,
+    // empty with proof of work
+    signature: Vec<u8>,
+}
+
+// The proof of work only hashes this fixed-size header, the transactions are
+// committed to by the merkle root:
+//   prev hash (32) | merkle root (32) | timestamp (16) | height (8) | target (4) | nonce (8)
+pub const HEADER_SIZE: usize = 100;
+pub(crate) const NONCE_OFFSET: usize = HEADER_SIZE - 8;
+
+// The part of a block the proof of work commits to, which is all that
+// headers-first sync and light clients need to follow the chain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub prev_block_hash: String,
+    pub merkle_root: Vec<u8>,
+    pub timestamp: u128,
+    pub height: usize,
+    pub nonce: u64,
+    // Not hashed, it signs the hash (see Block)
+    pub signature: Vec<u8>,
+}
+
+// Path from a transaction id up to the merkle root of its block, enough to
+// show the block holds the transaction to someone who only has the header
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleBranch {
+    // Position of the transaction among the tree's nodes, root first
+    pub index: u32,
+    // Sibling hashes from the transaction up to the root
+    pub lemmas: Vec<Vec<u8>>,
 }
 
 struct MergeTX {}
 
+impl MerkleBranch {
+    // Checks that the branch leads from the leaf of a transaction to the
+    // merkle root. The leaf is the hash of the signed transaction, as
+    // Transaction::hash gives it, not its id.
+    pub fn verify(&self, merkle_root: &[u8], signed_tx_hash: &str) -> Result<bool> {
+        let leaf = hex::decode(signed_tx_hash)?;
+        let proof = MerkleProof::<Vec<u8>, MergeTX>::new(vec![self.index], self.lemmas.clone());
+        Ok(proof.verify(&merkle_root.to_vec(), &[leaf]))
+    }
+}
+
+impl BlockHeader {
+    // Serializes the header into the fixed-size form that is hashed
+    pub fn to_bytes(&self) -> Result<[u8; HEADER_SIZE]> {
+        let mut header = [0; HEADER_SIZE];
+        // The genesis block has no parent and keeps a zero prev hash
+        if self.prev_block_hash != "nil" {
+            let prev_hash = hex::decode(&self.prev_block_hash)?;
+            if prev_hash.len() != 32 {
+                return Err(format_err!("Prev block hash is not a sha256 hash"));
+            }
+            header[0..32].copy_from_slice(&prev_hash);
+        }
+        if self.merkle_root.len() != 32 {
+            return Err(format_err!("Merkle root is not a sha256 hash"));
+        }
+        header[32..64].copy_from_slice(&self.merkle_root);
+        header[64..80].copy_from_slice(&self.timestamp.to_be_bytes());
+        header[80..88].copy_from_slice(&(self.height as u64).to_be_bytes());
+        header[88..92].copy_from_slice(&(TARGET_HEXT as u32).to_be_bytes());
+        header[NONCE_OFFSET..].copy_from_slice(&self.nonce.to_be_bytes());
+        Ok(header)
+    }
+
+    // Returns the hash of the block this header belongs to
+    pub fn hash(&self) -> Result<String> {
+        Ok(hex::encode(hash_header(&self.to_bytes()?)))
+    }
+
+    // Checks that the proof of work hash of the header meets the target
+    pub fn verify_proof_of_work(&self, hasher: &dyn PowHasher) -> Result<bool> {
+        Ok(meets_target(&hasher.hash(&self.to_bytes()?)))
+    }
+}
+
 impl Block {
     pub fn get_transactions(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
-    pub(crate) fn get_prev_hash(&self) -> String {
+    pub fn get_prev_hash(&self) -> String {
         self.prev_block_hash.clone()
     }
 
@@ -37,77 +115,154 @@ impl Block {
         self.hash.clone()
     }
 
-    ///new Genesis Block
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::from("nil"), 0).unwrap()
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    // Where the block's transactions are confirmed, for relative locks
+    pub fn confirmation(&self) -> Confirmation {
+        Confirmation {
+            height: self.height,
+            timestamp: self.timestamp,
+        }
+    }
+
+    pub fn get_nonce(&self) -> u64 {
+        self.nonce
     }
 
-    pub fn new_block(
+    pub fn get_signature(&self) -> &[u8] {
+        &self.signature
+    }
+
+    // Returns an unmined block for a miner to search a nonce for
+    pub fn new_template(
         data: Vec<Transaction>,
         prev_block_hash: String,
         height: usize,
-    ) -> Result<Block> {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_millis();
-
-        let mut block = Block {
-            timestamp: timestamp,
+        timestamp: u128,
+    ) -> Block {
+        Block {
+            timestamp,
             transactions: data,
             prev_block_hash,
             hash: String::new(),
-            height: height,
+            height,
             nonce: 0,
-        };
-        block.run_proof_of_work()?;
-        Ok(block)
+            signature: Vec::new(),
+        }
     }
 
-    fn run_proof_of_work(&mut self) -> Result<()> {
-        info!("Mining the block");
-        while !self.validate()? {
-            self.nonce += 1;
-        }
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
+    // Checks that the stored hash belongs to the block and that the proof of
+    // work hash of its header meets the target
+    pub fn verify_proof_of_work(&self, hasher: &dyn PowHasher) -> Result<bool> {
+        let header = self.header()?;
+        Ok(hex::encode(hash_header(&header)) == self.hash && meets_target(&hasher.hash(&header)))
+    }
+
+    // Checks that the stored hash belongs to the block, whatever sealed it
+    pub fn verify_hash(&self) -> Result<bool> {
+        Ok(hex::encode(hash_header(&self.header()?)) == self.hash)
+    }
+
+    // Returns the header of the block with its current nonce
+    pub fn get_header(&self) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            prev_block_hash: self.prev_block_hash.clone(),
+            merkle_root: self.hash_transactions()?,
+            timestamp: self.timestamp,
+            height: self.height,
+            nonce: self.nonce,
+            signature: self.signature.clone(),
+        })
+    }
+
+    // Serializes the header with the block's current nonce
+    pub(crate) fn header(&self) -> Result<[u8; HEADER_SIZE]> {
+        self.get_header()?.to_bytes()
+    }
+
+    // Sets a nonce found by a miner and the header hash it gives
+    pub fn set_nonce(&mut self, nonce: u64) -> Result<()> {
+        self.nonce = nonce;
+        self.hash = hex::encode(hash_header(&self.header()?));
         Ok(())
     }
 
-    fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let mut vec1: Vec<u8> = vec![];
-        vec1.resize(TARGET_HEXT, '0' as u8);
-        Ok(&hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(vec1)?)
-    }
-
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.transactions.clone(),
-            self.timestamp,
-            TARGET_HEXT,
-            self.nonce,
-        );
-        let bytes = bincode::serialize(&content)?;
-        Ok(bytes)
-    }
-
-    fn hash_transactions(&self) -> Result<Vec<u8>> {
-        let mut transactions = Vec::new();
+    // Stores the header hash and a validator's signature of it
+    pub(crate) fn sign(&mut self, private_key: &[u8]) -> Result<()> {
+        let hash = hash_header(&self.header()?);
+        self.hash = hex::encode(hash);
+        self.signature = ed25519::signature(&hash, private_key).to_vec();
+        Ok(())
+    }
+
+    // Changes the coinbase, and so the merkle root, to get a fresh nonce space.
+    // The extra nonce is kept in the coinbase input's otherwise unused signature.
+    pub(crate) fn set_extra_nonce(&mut self, extra_nonce: u64) -> Result<()> {
+        let coinbase = match self.transactions.first_mut() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => return Err(format_err!("First transaction of a block must be coinbase")),
+        };
+        coinbase.v_inputs[0].signature = extra_nonce.to_be_bytes().to_vec();
+        coinbase.id = coinbase.hash()?;
+        Ok(())
+    }
+
+    // Returns the merkle root of the block's transaction ids
+    pub fn hash_transactions(&self) -> Result<Vec<u8>> {
+        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&self.merkle_leaves()?);
+        Ok(tree.root())
+    }
+
+    // Returns the branch proving the transaction is in this block,
+    // None if the block does not hold it
+    pub fn merkle_branch(&self, txid: &str) -> Result<Option<MerkleBranch>> {
+        let position = match self.transactions.iter().position(|tx| tx.id == txid) {
+            Some(position) => position as u32,
+            None => return Ok(None),
+        };
+        let leaves = self.merkle_leaves()?;
+        Ok(
+            CBMT::<Vec<u8>, MergeTX>::build_merkle_proof(&leaves, &[position]).map(|proof| {
+                MerkleBranch {
+                    index: proof.indices()[0],
+                    lemmas: proof.lemmas().to_vec(),
+                }
+            }),
+        )
+    }
+
+    fn merkle_leaves(&self) -> Result<Vec<Vec<u8>>> {
+        let mut leaves = Vec::new();
         for tx in &self.transactions {
             let mut copy = tx.clone();
-            transactions.push(copy.hash()?.as_bytes().to_owned());
+            leaves.push(hex::decode(copy.hash()?)?);
         }
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&*transactions);
-        Ok(tree.root())
+        Ok(leaves)
     }
- 
 }
 
+// SHA-256 of a serialized header
+pub(crate) fn hash_header(header: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(header);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    hash
+}
+
+// Checks that the hash starts with TARGET_HEXT zero hex digits
+pub(crate) fn meets_target(hash: &[u8; 32]) -> bool {
+    (0..TARGET_HEXT).all(|i| {
+        let nibble = if i % 2 == 0 { hash[i / 2] >> 4 } else { hash[i / 2] & 0x0f };
+        nibble == 0
+    })
+}
 
 impl Merge for MergeTX {
     type Item = Vec<u8>;
@@ -1,97 +1,564 @@
 // blockchain.rs
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 use failure::format_err;
-use log::info;
+use log::{info, warn};
 
-use crate::block::{Block, TARGET_HEXT};
+use crate::block::Block;
+use crate::consensus::{
+    check_block, check_block_limits, check_genesis_block, check_lock_time, check_sequence_locks,
+    check_timestamp, check_transaction, median_time_past, Clock, SystemClock, MEDIAN_TIME_SPAN,
+};
 use crate::errors::Result;
-use crate::transaction::Transaction;
-use crate::tx::{TXOutput, TXOutputs};
+use crate::filter::{self, FilterEntry};
+use crate::engine::{ConsensusEngine, ProofOfAuthority};
+use crate::miner::CancelToken;
+use crate::params::{ChainParams, Checkpoints, Consensus};
+use crate::store::{ChainStore, SledStore, StoreBatch, DATA_DIR};
+use crate::transaction::{Transaction, SUBSIDY};
+use crate::addrindex;
+use crate::tx::{Confirmation, TXOutput, TXOutputs};
+use crate::txindex::{self, TxLocation};
+use crate::utxoset::{self, stage_block, stage_reindex};
+use crate::wallet::Wallets;
 
 const GENSIS_COINBASE_DATA: &str = "SATOSHI NAKAMOTO";
 
 #[derive(Debug, Clone)]
-pub struct Blockchain {
+pub struct Blockchain<S: ChainStore = SledStore> {
     current_hash: String,
-    db: sled::Db,
+    store: S,
+    // Local time for block timestamps and the future drift rule
+    clock: Arc<dyn Clock>,
+    // Consensus limits of the network the chain was created on
+    params: ChainParams,
+    // Seals the blocks this node makes, with the network's consensus
+    engine: Arc<dyn ConsensusEngine>,
 }
 
-pub struct BlockchainIter<'a> {
+pub struct BlockchainIter<'a, S: ChainStore = SledStore> {
     current_hash: String,
-    bc: &'a Blockchain,
+    bc: &'a Blockchain<S>,
 }
 
 impl Blockchain {
     // Opens the blockchain DB
     pub fn open_blockchain() -> Result<Blockchain> {
         info!("open blockchain");
+        Blockchain::open(SledStore::open(DATA_DIR)?)
+    }
 
-        let db = sled::open("data/blocks")?;
-        let hash = db
-            .get("LAST")?
-            .expect("Must create a new block database first");
+    // CreateBlockchain creates a new blockchain DB
+    pub fn create_blockchain(address: String, params: ChainParams) -> Result<Blockchain> {
+        info!("Creating new {} blockchain", params.network);
+        Blockchain::create_with_params(SledStore::open(DATA_DIR)?, address, params)
+    }
+}
+
+impl<S: ChainStore> Blockchain<S> {
+    // Opens the blockchain kept in the given store
+    pub fn open(store: S) -> Result<Blockchain<S>> {
+        let lasthast = match store.get_tip()? {
+            Some(hash) => hash,
+            None => return Err(format_err!("Must create a new block database first")),
+        };
         info!("Found block database");
-        let lasthast = String::from_utf8(hash.to_vec())?;
+        let mut params = match store.get_network()? {
+            Some(name) => ChainParams::for_network(name.parse()?),
+            None => ChainParams::default(),
+        };
+        if let Some(consensus) = store.get_consensus()? {
+            params.consensus = consensus;
+        }
+
+        let mut bc = Blockchain {
+            current_hash: lasthast,
+            engine: sealing_engine(&store, &params)?,
+            store,
+            clock: Arc::new(SystemClock),
+            params,
+        };
+        bc.check_consistency()?;
+        bc.check_height_index()?;
+        filter::build_missing(&bc)?;
+        Ok(bc)
+    }
+
+    // Replaces whatever chain the store holds with a new one on the main network
+    pub fn create(store: S, address: String) -> Result<Blockchain<S>> {
+        Blockchain::create_with_params(store, address, ChainParams::default())
+    }
+
+    // Replaces whatever chain the store holds with a new one on the network
+    // of `params`. On a proof of authority network the first validator must
+    // be a local wallet, to sign the genesis block.
+    pub fn create_with_params(
+        store: S,
+        address: String,
+        params: ChainParams,
+    ) -> Result<Blockchain<S>> {
+        let cbtx = Transaction::new_coinbase(address, String::from(GENSIS_COINBASE_DATA))?;
+        let template = Block::new_template(vec![cbtx], String::from("nil"), 0, SystemClock.now());
+        let genesis = match sealing_engine(&store, &params)?.seal(template, &CancelToken::new())? {
+            Some(genesis) => genesis,
+            None => return Err(format_err!("Sealing was cancelled")),
+        };
+        Blockchain::init(store, genesis, params)
+    }
+
+    // Replaces whatever chain the store holds with the one starting at a
+    // genesis block received from a peer
+    pub fn create_from_genesis(
+        store: S,
+        genesis: Block,
+        params: ChainParams,
+    ) -> Result<Blockchain<S>> {
+        check_genesis_block(&genesis, &params)?;
+        Blockchain::init(store, genesis, params)
+    }
+
+    fn init(store: S, genesis: Block, params: ChainParams) -> Result<Blockchain<S>> {
+        store.clear_blocks()?;
+        info!("Creating new block database");
+
+        let mut batch = StoreBatch::default();
+        batch.put_block(&genesis);
+        batch.set_tip(&genesis.get_hash());
+        batch.index_height(0, &genesis.get_hash());
+        batch.set_network(params.network.name());
+        batch.set_consensus(&params.consensus);
+        stage_block(&store, &genesis, &mut batch)?;
+        filter::stage_connect(&store, &genesis, &mut batch)?;
+        if store.txindex_enabled()? {
+            txindex::stage_connect(&genesis, &mut batch);
+        }
+        if store.addrindex_enabled()? {
+            addrindex::stage_connect(&store, &genesis, &mut batch)?;
+        }
+        store.write_batch(batch)?;
 
         Ok(Blockchain {
-            current_hash: lasthast.clone(),
-            db,
+            current_hash: genesis.get_hash(),
+            engine: sealing_engine(&store, &params)?,
+            store,
+            clock: Arc::new(SystemClock),
+            params,
         })
     }
 
-    // CreateBlockchain creates a new blockchain DB
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
-        info!("Creating new blockchain");
-        if let Err(e) = std::fs::remove_dir_all("data/blocks") {
-            info!("blocks do not exist to delete")
+    // Replaces the system clock, e.g. with a MockClock in tests
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Blockchain<S> {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // Also requires configured checkpoints, on top of the network's
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> Blockchain<S> {
+        self.params = self.params.with_checkpoints(checkpoints);
+        self
+    }
+
+    // Skips the signature checks of this block and its ancestors when syncing them
+    pub fn with_assume_valid(mut self, hash: String) -> Blockchain<S> {
+        self.params = self.params.with_assume_valid(hash);
+        self
+    }
+
+    // The consensus limits of the chain's network
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // The engine sealing this node's blocks, which signs with the local
+    // validator wallets on a proof of authority network
+    pub fn engine(&self) -> Arc<dyn ConsensusEngine> {
+        self.engine.clone()
+    }
+
+    // Returns the local time in milliseconds from the chain's clock
+    pub fn now(&self) -> u128 {
+        self.clock.now()
+    }
+
+    // The tip and the UTXO set are written together, so they only disagree
+    // if the DB was written by an older version or a connect was cut short.
+    // A connect missing only its UTXO changes is finished, a tip ahead of an
+    // older UTXO set is rolled back, anything else rebuilds the UTXO set.
+    fn check_consistency(&mut self) -> Result<()> {
+        let utxo_tip = self.store.get_utxo_tip()?;
+        if utxo_tip.as_deref() == Some(self.current_hash.as_str()) {
+            return Ok(());
         }
 
-        let db = sled::open("data/blocks")?;
-        info!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from(GENSIS_COINBASE_DATA))?;
-        let genesis: Block = Block::new_genesis_block(cbtx);
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        let bc = Blockchain {
-            current_hash: genesis.get_hash(),
-            db,
+        let tip = match self.get_block(&self.current_hash)? {
+            Some(block) => block,
+            None => return Err(format_err!("Tip block is not found")),
         };
-        bc.db.flush()?;
-        Ok(bc)
+
+        let mut batch = StoreBatch::default();
+        match utxo_tip {
+            Some(hash) if hash == tip.get_prev_hash() => {
+                warn!("finishing interrupted connect of block {}", tip.get_hash());
+                stage_block(&self.store, &tip, &mut batch)?;
+            }
+            Some(hash) if self.iter().any(|block| block.get_hash() == hash) => {
+                warn!("rolling the tip back to {}", hash);
+                let txindex_enabled = self.store.txindex_enabled()?;
+                let addrindex_enabled = self.store.addrindex_enabled()?;
+                for block in self.iter().take_while(|block| block.get_hash() != hash) {
+                    batch.unindex_height(block.get_height());
+                    if txindex_enabled {
+                        txindex::stage_disconnect(&block, &mut batch);
+                    }
+                    if addrindex_enabled {
+                        addrindex::stage_disconnect(self, &block, &mut batch)?;
+                    }
+                }
+                batch.set_tip(&hash);
+                self.current_hash = hash;
+            }
+            _ => {
+                warn!("UTXO set does not match the chain, rebuilding it");
+                stage_reindex(self, &mut batch);
+                if self.store.txindex_enabled()? {
+                    txindex::stage_rebuild(self, &mut batch);
+                }
+                if self.store.addrindex_enabled()? {
+                    addrindex::stage_rebuild(self, &mut batch)?;
+                }
+            }
+        }
+        self.store.write_batch(batch)
+    }
+
+    // Chains written before the height index have none, it is built once
+    fn check_height_index(&self) -> Result<()> {
+        let height = self.get_best_height()?;
+        if self.store.get_block_hash(height)?.as_deref() == Some(self.current_hash.as_str()) {
+            return Ok(());
+        }
+        warn!("building the height index");
+        let mut batch = StoreBatch::default();
+        batch.clear_heights();
+        for block in self.iter() {
+            batch.index_height(block.get_height(), &block.get_hash());
+        }
+        self.store.write_batch(batch)
+    }
+
+    // Returns the store the chain is kept in
+    pub fn store(&self) -> &S {
+        &self.store
     }
 
     // Adds block to to the blockchain and blockchain database
     pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
-        let lasthash = self.db.get("LAST")?.unwrap();
+        let template = self.new_block_template(transactions)?;
+        let new_block = match self.engine.seal(template, &CancelToken::new())? {
+            Some(block) => block,
+            None => return Err(format_err!("Sealing was cancelled")),
+        };
+        self.connect_block(&new_block)?;
+        Ok(new_block)
+    }
 
-        let new_block = Block::new_block(
+    // Returns an unmined block on top of the tip, to be mined and passed to submit_block,
+    // or an error if it breaks the limits of the network.
+    // It is stamped with the local time, or just past the median time past if the clock is behind.
+    pub fn new_block_template(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        let timestamp = self.now().max(self.median_time_past()? + 1);
+        let template = Block::new_template(
             transactions,
-            String::from_utf8(lasthash.to_vec())?,
-            TARGET_HEXT,
-        )?;
-        self.db
-            .insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
-        self.current_hash = new_block.get_hash();
-        Ok(new_block)
-    } 
+            self.current_hash.clone(),
+            self.get_best_height()? + 1,
+            timestamp,
+        );
+        check_block_limits(&template, &self.params)?;
+        Ok(template)
+    }
+
+    // Returns the median timestamp of the last MEDIAN_TIME_SPAN blocks of the chain,
+    // the next block's timestamp must be above it
+    pub fn median_time_past(&self) -> Result<u128> {
+        let timestamps: Vec<u128> = self
+            .iter()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|block| block.get_timestamp())
+            .collect();
+        Ok(median_time_past(&timestamps))
+    }
+
+    // Removes the tip block from the active chain, undoing its UTXO and index
+    // changes in one batch. The block itself stays in the store.
+    pub fn disconnect_tip(&mut self) -> Result<Block> {
+        let tip = match self.get_block(&self.current_hash)? {
+            Some(block) => block,
+            None => return Err(format_err!("Tip block is not found")),
+        };
+        if tip.get_height() == 0 {
+            return Err(format_err!("Can not disconnect the genesis block"));
+        }
+        // Blocks up to the last checkpoint the chain has are final, a chain
+        // that does not match a checkpoint may still be rolled back past it
+        if self.params.checkpoints.get(&tip.get_height()) == Some(&tip.get_hash()) {
+            return Err(format_err!(
+                "Can not disconnect block {} at the checkpoint at height {}",
+                tip.get_hash(),
+                tip.get_height()
+            ));
+        }
+
+        let mut batch = StoreBatch::default();
+        batch.set_tip(&tip.get_prev_hash());
+        batch.unindex_height(tip.get_height());
+        utxoset::stage_disconnect(self, &tip, &mut batch)?;
+        if self.store.txindex_enabled()? {
+            txindex::stage_disconnect(&tip, &mut batch);
+        }
+        if self.store.addrindex_enabled()? {
+            addrindex::stage_disconnect(self, &tip, &mut batch)?;
+        }
+        self.store.write_batch(batch)?;
+        self.current_hash = tip.get_prev_hash();
+        Ok(tip)
+    }
+
+    // Returns the hash of the block at the given height on the current chain
+    pub fn get_block_hash(&self, height: usize) -> Result<Option<String>> {
+        self.store.get_block_hash(height)
+    }
+
+    // Writes the block, the new tip and the block's UTXO changes in one batch
+    fn connect_block(&mut self, block: &Block) -> Result<()> {
+        let mut batch = StoreBatch::default();
+        batch.put_block(block);
+        batch.set_tip(&block.get_hash());
+        batch.index_height(block.get_height(), &block.get_hash());
+        stage_block(&self.store, block, &mut batch)?;
+        filter::stage_connect(&self.store, block, &mut batch)?;
+        if self.store.txindex_enabled()? {
+            txindex::stage_connect(block, &mut batch);
+        }
+        if self.store.addrindex_enabled()? {
+            addrindex::stage_connect(&self.store, block, &mut batch)?;
+        }
+        self.store.write_batch(batch)?;
+        self.current_hash = block.get_hash();
+        Ok(())
+    }
+
+    // Builds the transaction index and keeps it up to date from now on,
+    // or drops it when `enabled` is false
+    pub fn set_txindex(&self, enabled: bool) -> Result<()> {
+        let mut batch = StoreBatch::default();
+        batch.set_txindex_enabled(enabled);
+        if enabled {
+            txindex::stage_rebuild(self, &mut batch);
+        } else {
+            batch.clear_txindex();
+        }
+        self.store.write_batch(batch)
+    }
+
+    // Builds the address index and keeps it up to date from now on,
+    // or drops it when `enabled` is false
+    pub fn set_addrindex(&self, enabled: bool) -> Result<()> {
+        let mut batch = StoreBatch::default();
+        batch.set_addrindex_enabled(enabled);
+        if enabled {
+            addrindex::stage_rebuild(self, &mut batch)?;
+        } else {
+            batch.clear_addrindex();
+        }
+        self.store.write_batch(batch)
+    }
+
+    // Returns (txid, output index, output) of the unspent outputs of a pub key hash
+    pub fn get_address_utxos(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, i32, TXOutput)>> {
+        if !self.store.addrindex_enabled()? {
+            return Err(format_err!("Address index is disabled, run reindex --addrindex"));
+        }
+        addrindex::address_utxos(&self.store, pub_key_hash)
+    }
+
+    // Returns (block height, txid) of every transaction paying to or spending
+    // from a pub key hash, oldest first
+    pub fn get_address_history(&self, pub_key_hash: &[u8]) -> Result<Vec<(usize, String)>> {
+        if !self.store.addrindex_enabled()? {
+            return Err(format_err!("Address index is disabled, run reindex --addrindex"));
+        }
+        addrindex::address_history(&self.store, pub_key_hash)
+    }
+
+    // Returns the compact filter of a block with its filter header
+    pub fn get_block_filter(&self, block_hash: &str) -> Result<Option<FilterEntry>> {
+        self.store.get_cfilter(block_hash)
+    }
+
+    // Returns where the transaction is stored, None if it is not in the
+    // active chain or the transaction index is disabled
+    pub fn get_tx_location(&self, txid: &str) -> Result<Option<TxLocation>> {
+        if !self.store.txindex_enabled()? {
+            return Ok(None);
+        }
+        self.store.get_tx_location(txid)
+    }
+
+    // Validates a block mined elsewhere and connects it on top of the tip
+    pub fn submit_block(&mut self, block: &Block) -> Result<()> {
+        self.check_and_connect(block, true)
+    }
+
+    // Like submit_block without checking the signatures of the transactions,
+    // for blocks under the assumed-valid block. Their seal, linkage, limits,
+    // locks, spent outputs and values are still checked.
+    pub(crate) fn submit_assumed_valid_block(&mut self, block: &Block) -> Result<()> {
+        self.check_and_connect(block, false)
+    }
+
+    fn check_and_connect(&mut self, block: &Block, check_signatures: bool) -> Result<()> {
+        check_block(block, &self.current_hash, self.get_best_height()?, &self.params)?;
+        let median_time_past = self.median_time_past()?;
+        check_timestamp(block.get_timestamp(), median_time_past, self.now())?;
+        let height = block.get_height();
+        let coinbase = &block.get_transactions()[0];
+        // UTXOs are stored under the id, a made up one could overwrite others
+        if coinbase.id != coinbase.derived_id()? {
+            return Err(format_err!("Coinbase {} does not hash to its id", coinbase.id));
+        }
+        // A negative output would let the others pay more than the reward
+        if coinbase.v_outputs.iter().any(|output| !output.is_data() && output.value <= 0) {
+            return Err(format_err!(
+                "Coinbase {} has an output that is not positive",
+                coinbase.id
+            ));
+        }
+        check_lock_time(coinbase, height, median_time_past)?;
+        // Transactions may spend the outputs of earlier ones of the same block
+        let mut in_block: HashMap<String, Transaction> = HashMap::new();
+        let mut fees: i64 = 0;
+        for tx in &block.get_transactions()[1..] {
+            check_transaction(tx)?;
+            let mut prev_txs = HashMap::new();
+            let mut confirmations = Vec::new();
+            let mut input_value: i64 = 0;
+            for input in &tx.v_inputs {
+                let (prev_tx, confirmation) = match in_block.get(&input.txid) {
+                    Some(prev_tx) => (prev_tx.clone(), None),
+                    None => {
+                        let (prev_tx, confirmation) = self.find_confirmed_transaction(&input.txid)?;
+                        (prev_tx, Some(confirmation))
+                    }
+                };
+                // Whether the output is still unspent is checked when the block is staged
+                let spent = usize::try_from(input.output_index)
+                    .ok()
+                    .and_then(|index| prev_tx.v_outputs.get(index));
+                match spent {
+                    Some(output) => input_value += output.value as i64,
+                    None => {
+                        return Err(format_err!(
+                            "Output {} of {} does not exist",
+                            input.output_index,
+                            input.txid
+                        ))
+                    }
+                }
+                prev_txs.insert(prev_tx.id.clone(), prev_tx);
+                confirmations.push(confirmation);
+            }
+            let output_value: i64 = tx.v_outputs.iter().map(|output| output.value as i64).sum();
+            if output_value > input_value {
+                return Err(format_err!(
+                    "Transaction {} spends {} but only has {}",
+                    tx.id,
+                    output_value,
+                    input_value
+                ));
+            }
+            fees += input_value - output_value;
+            check_lock_time(tx, height, median_time_past)?;
+            check_sequence_locks(tx, &confirmations, height, median_time_past)?;
+            if check_signatures && !tx.clone().verify(prev_txs)? {
+                return Err(format_err!("Transaction {} is not valid", tx.id));
+            }
+            in_block.insert(tx.id.clone(), tx.clone());
+        }
+        let reward: i64 = coinbase.v_outputs.iter().map(|output| output.value as i64).sum();
+        if reward > SUBSIDY as i64 + fees {
+            return Err(format_err!(
+                "Coinbase {} pays {} but the block only allows {}",
+                coinbase.id,
+                reward,
+                SUBSIDY as i64 + fees
+            ));
+        }
+
+        self.connect_block(block)
+    }
+
+    // Returns the hash of the tip of the blockchain
+    pub fn get_tip_hash(&self) -> String {
+        self.current_hash.clone()
+    }
+
+    // Returns the height of the tip of the blockchain
+    pub fn get_best_height(&self) -> Result<usize> {
+        match self.get_block(&self.current_hash)? {
+            Some(block) => Ok(block.get_height()),
+            None => Err(format_err!("Tip block is not found")),
+        }
+    }
+
+    // Returns the block with the given hash if it is stored in the DB
+    pub fn get_block(&self, hash: &str) -> Result<Option<Block>> {
+        self.store.get_block(hash)
+    }
+
+    // Returns the block at the given height on the current chain
+    pub fn get_block_by_height(&self, height: usize) -> Result<Option<Block>> {
+        match self.store.get_block_hash(height)? {
+            Some(hash) => self.get_block(&hash),
+            None => Ok(None),
+        }
+    }
 
     // Iterates over the blockchain
-    pub fn iter(&self) -> BlockchainIter {
+    pub fn iter(&self) -> BlockchainIter<'_, S> {
         BlockchainIter {
             current_hash: self.current_hash.clone(),
-            bc: &self,
+            bc: self,
         }
     }
 
     // Returns Transaction with a given transaction id from the whole Blockchain
     pub fn find_tranasaction(&self, id: &str) -> Result<Transaction> {
+        Ok(self.find_confirmed_transaction(id)?.0)
+    }
+
+    // Like find_tranasaction, with the block it was confirmed in
+    pub fn find_confirmed_transaction(&self, id: &str) -> Result<(Transaction, Confirmation)> {
+        if self.store.txindex_enabled()? {
+            let location = match self.store.get_tx_location(id)? {
+                Some(location) => location,
+                None => return Err(format_err!("Transaction is not found")),
+            };
+            let block = match self.get_block(&location.block_hash)? {
+                Some(block) => block,
+                None => return Err(format_err!("Indexed block is not found")),
+            };
+            return match block.get_transactions().get(location.position as usize) {
+                Some(tx) if tx.id == id => Ok((tx.clone(), block.confirmation())),
+                _ => Err(format_err!("Transaction index is corrupted, run reindex")),
+            };
+        }
+
         for block in self.iter() {
             for tx in block.get_transactions() {
                 if tx.id == id {
-                    return Ok(tx.clone());
+                    return Ok((tx.clone(), block.confirmation()));
                 }
             }
         }
@@ -122,122 +589,250 @@ impl Blockchain {
         tx.verify(prev_txs)
     }
 
-    // Returns a list of all transactions containing UTXOs for a particular address
-    fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
-        // String is the txID
-        // Value is the vector of integers containing the index of outputs in the tx that have been spent
-        let mut spent_txos: HashMap<String, Vec<i32>> = HashMap::new();
-
-        // Vector to collect transactions that contains unspent outputs for a given address
-        let mut unspent_txs: Vec<Transaction> = Vec::new();
-
-        for block in self.iter() {
-            for tx in block.get_transactions() {
-                for index in 0..tx.v_outputs.len() {
-                    // Checks which outputs are spent
-                    // by checking id the output index is in the spent_txos hash-map
-                    if let Some(indices) = spent_txos.get(&tx.id) {
-                        if indices.contains(&(index as i32)) {
-                            continue;
-                        }
-                    }
-
-                    // if output index is not in the the spent_txos hash-map
-                    // Push the output to the the unspent_txs if it is to the given address
-                    if tx.v_outputs[index].can_be_unlocked_with(address) {
-                        unspent_txs.push(tx.to_owned()) // Creates owned data from borrowed data
-                    }
-                }
-                if !tx.is_coinbase() {
-                    for tx_input in &tx.v_inputs {
-                        if tx_input.can_unlock_output_with(address) {
-                            match spent_txos.get_mut(&tx_input.txid) {
-                                Some(v) => {
-                                    v.push(tx_input.output_index);
-                                }
-                                None => {
-                                    spent_txos
-                                        .insert(tx_input.txid.clone(), vec![tx_input.output_index]);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        unspent_txs
-    }
-
     pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
         // String is transaction id which contains utxos
-        // TXOutputs contains a vector of tx output
+        // TXOutputs maps the index of each unspent output to the output
         let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
 
         // Contains tx ids and index of spent outputs
         let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
 
+        // Blocks come from the tip backwards, so every spend is seen
+        // before the output it spends
         for block in self.iter() {
             for tx in block.get_transactions() {
                 for index in 0..tx.v_outputs.len() {
+                    if tx.v_outputs[index].is_data() {
+                        continue;
+                    }
                     if let Some(ids) = spend_txos.get(&tx.id) {
                         if ids.contains(&(index as i32)) {
                             continue;
                         }
                     }
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v_tx_outputs) => {
-                            v_tx_outputs.outputs.push(tx.v_outputs[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.v_outputs[index].clone()],
-                                },
-                            );
-                        }
-                    }
+                    utxos
+                        .entry(tx.id.clone())
+                        .or_insert_with(|| TXOutputs {
+                            outputs: BTreeMap::new(),
+                            confirmation: block.confirmation(),
+                        })
+                        .outputs
+                        .insert(index as i32, tx.v_outputs[index].clone());
+                }
 
-                    if !tx.is_coinbase() {
-                        for tx_input in &tx.v_inputs {
-                            match spend_txos.get_mut(&tx_input.txid) {
-                                Some(v_indices) => {
-                                    v_indices.push(tx_input.output_index);
-                                }
-                                None => {
-                                    spend_txos.insert(
-                                        tx_input.txid.clone(),
-                                        vec![tx_input.output_index.clone()],
-                                    );
-                                }
-                            }
-                        }
+                if !tx.is_coinbase() {
+                    for tx_input in &tx.v_inputs {
+                        spend_txos
+                            .entry(tx_input.txid.clone())
+                            .or_default()
+                            .push(tx_input.output_index);
                     }
                 }
             }
         }
         utxos
     }
-
 }
-impl<'a> Iterator for BlockchainIter<'a> {
+
+impl<S: ChainStore> Iterator for BlockchainIter<'_, S> {
     type Item = Block;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(encode_block) = self.bc.db.get(&self.current_hash) {
-            return match encode_block {
-                Some(b) => {
-                    if let Ok(block) = bincode::deserialize::<Block>(&b) {
-                        self.current_hash = block.get_prev_hash();
-                        Some(block)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            };
+        if let Ok(Some(block)) = self.bc.store.get_block(&self.current_hash) {
+            self.current_hash = block.get_prev_hash();
+            return Some(block);
         }
         None
     }
 }
+
+// The engine of the network, given the keys of the local validator wallets
+fn sealing_engine<S: ChainStore>(
+    store: &S,
+    params: &ChainParams,
+) -> Result<Arc<dyn ConsensusEngine>> {
+    match &params.consensus {
+        Consensus::ProofOfAuthority(validators) => {
+            let wallets = Wallets::open(store.clone())?;
+            Ok(Arc::new(
+                ProofOfAuthority::new(validators.clone()).with_wallets(&wallets),
+            ))
+        }
+        Consensus::ProofOfWork(_) => Ok(Arc::from(params.engine())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::testutil::{
+        mine, new_chain, pay, pay_with_fee, resign, seal_block, seal_block_with_coinbase,
+    };
+
+    #[test]
+    fn connects_a_block_paying_between_wallets() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let tx = pay(&utxo, &miner, &other, 30).unwrap();
+        mine(&mut utxo, &miner, vec![tx]).unwrap();
+        assert_eq!(utxo.blockchain.get_best_height().unwrap(), 1);
+    }
+
+    #[test]
+    fn rejects_a_transaction_spending_more_than_its_inputs() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut tx = pay(&utxo, &miner, &other, 30).unwrap();
+        tx.v_outputs[0].value += 1000;
+        resign(&utxo, &mut tx, &miner).unwrap();
+        let block = seal_block(&utxo.blockchain, &miner, vec![tx]).unwrap();
+        let err = utxo.blockchain.submit_block(&block).unwrap_err();
+        assert!(err.to_string().contains("but only has"), "{}", err);
+        assert_eq!(utxo.blockchain.get_best_height().unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_a_transaction_that_does_not_hash_to_its_id() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut tx = pay(&utxo, &miner, &other, 30).unwrap();
+        // Claims the id of the genesis coinbase, to overwrite its outputs
+        tx.id = tx.v_inputs[0].txid.clone();
+        let private_key = Wallets::open(utxo.blockchain.store().clone())
+            .unwrap()
+            .get_wallet(&miner)
+            .unwrap()
+            .private_key
+            .clone();
+        utxo.blockchain.sign_transaction(&mut tx, &private_key).unwrap();
+        let block = seal_block(&utxo.blockchain, &miner, vec![tx]).unwrap();
+        let err = utxo.blockchain.submit_block(&block).unwrap_err();
+        assert!(err.to_string().contains("does not hash to its id"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_a_transaction_spending_an_output_twice() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut tx = pay(&utxo, &miner, &other, 30).unwrap();
+        tx.v_inputs.push(tx.v_inputs[0].clone());
+        resign(&utxo, &mut tx, &miner).unwrap();
+        let block = seal_block(&utxo.blockchain, &miner, vec![tx]).unwrap();
+        let err = utxo.blockchain.submit_block(&block).unwrap_err();
+        assert!(err.to_string().contains("spends an output twice"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_an_output_that_is_not_positive() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut tx = pay(&utxo, &miner, &other, 30).unwrap();
+        tx.v_outputs[0].value = -30;
+        resign(&utxo, &mut tx, &miner).unwrap();
+        let block = seal_block(&utxo.blockchain, &miner, vec![tx]).unwrap();
+        let err = utxo.blockchain.submit_block(&block).unwrap_err();
+        assert!(err.to_string().contains("not positive"), "{}", err);
+    }
+
+    #[test]
+    fn limits_the_coinbase_to_the_subsidy_and_fees() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let tx = pay_with_fee(&utxo, &miner, &other, 30, 5).unwrap();
+        let greedy = Transaction::new_coinbase_with_fees(miner.clone(), String::new(), 6).unwrap();
+        let block = seal_block_with_coinbase(&utxo.blockchain, greedy, vec![tx.clone()]).unwrap();
+        let err = utxo.blockchain.submit_block(&block).unwrap_err();
+        assert!(err.to_string().contains("only allows 105"), "{}", err);
+
+        let coinbase = Transaction::new_coinbase_with_fees(miner, String::new(), 5).unwrap();
+        let block = seal_block_with_coinbase(&utxo.blockchain, coinbase, vec![tx]).unwrap();
+        utxo.blockchain.submit_block(&block).unwrap();
+        assert_eq!(utxo.blockchain.get_best_height().unwrap(), 1);
+    }
+
+    #[test]
+    fn rejects_a_coinbase_without_fees_paying_more_than_the_subsidy() {
+        let (mut utxo, miner, _) = new_chain(ChainParams::regtest());
+        let greedy = Transaction::new_coinbase_with_fees(miner, String::new(), 1).unwrap();
+        let block = seal_block_with_coinbase(&utxo.blockchain, greedy, Vec::new()).unwrap();
+        assert!(utxo.blockchain.submit_block(&block).is_err());
+    }
+
+    #[test]
+    fn keeps_blocks_up_to_the_last_checkpoint() {
+        let (mut utxo, miner, _) = new_chain(ChainParams::regtest());
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let checkpoint = mine(&mut utxo, &miner, Vec::new()).unwrap();
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let mut bc = utxo
+            .blockchain
+            .with_checkpoints(Checkpoints::from([(2, checkpoint.get_hash())]));
+
+        bc.disconnect_tip().unwrap();
+        let err = bc.disconnect_tip().unwrap_err();
+        assert!(err.to_string().contains("checkpoint at height 2"), "{}", err);
+        assert_eq!(bc.get_tip_hash(), checkpoint.get_hash());
+    }
+
+    #[test]
+    fn rolls_back_a_fork_that_misses_the_checkpoint() {
+        let (mut utxo, miner, _) = new_chain(ChainParams::regtest());
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let fork = mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let mut bc = utxo
+            .blockchain
+            .with_checkpoints(Checkpoints::from([(2, "another".to_string())]));
+
+        assert_eq!(bc.disconnect_tip().unwrap().get_hash(), fork.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+    }
+
+    #[test]
+    fn skips_only_signatures_under_the_assumed_valid_block() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut forged = pay(&utxo, &miner, &other, 30).unwrap();
+        forged.v_inputs[0].signature[0] ^= 1;
+        let block = seal_block(&utxo.blockchain, &miner, vec![forged.clone()]).unwrap();
+        assert!(utxo.blockchain.submit_block(&block).is_err());
+
+        // Spending more than the inputs is still caught
+        let mut overspent = forged.clone();
+        overspent.v_outputs[0].value += 1000;
+        overspent.id = overspent.derived_id().unwrap();
+        let bad = seal_block(&utxo.blockchain, &miner, vec![overspent]).unwrap();
+        let err = utxo.blockchain.submit_assumed_valid_block(&bad).unwrap_err();
+        assert!(err.to_string().contains("spends"), "{}", err);
+
+        utxo.blockchain.submit_assumed_valid_block(&block).unwrap();
+        assert_eq!(utxo.blockchain.get_tip_hash(), block.get_hash());
+        // So are the linkage and the spent outputs
+        assert!(utxo.blockchain.submit_assumed_valid_block(&block).is_err());
+        let again = seal_block(&utxo.blockchain, &miner, vec![forged]).unwrap();
+        assert!(utxo.blockchain.submit_assumed_valid_block(&again).is_err());
+        // And the seal
+        let coinbase = Transaction::new_coinbase(miner.clone(), "unsealed".to_string()).unwrap();
+        let unsealed = utxo.blockchain.new_block_template(vec![coinbase]).unwrap();
+        assert!(utxo.blockchain.submit_assumed_valid_block(&unsealed).is_err());
+    }
+
+    #[test]
+    fn open_builds_a_missing_height_index() {
+        let (mut utxo, miner, _) = new_chain(ChainParams::regtest());
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let tip = mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let store = utxo.blockchain.store().clone();
+        // As written by a version without the index
+        let mut batch = StoreBatch::default();
+        batch.clear_heights();
+        store.write_batch(batch).unwrap();
+        assert!(store.get_block_hash(2).unwrap().is_none());
+
+        let bc = Blockchain::open(store).unwrap();
+        assert_eq!(bc.get_block_hash(2).unwrap(), Some(tip.get_hash()));
+        assert_eq!(bc.get_block_by_height(2).unwrap().unwrap().get_hash(), tip.get_hash());
+        assert_eq!(bc.get_block_by_height(0).unwrap().unwrap().get_height(), 0);
+        assert!(bc.get_block_by_height(3).unwrap().is_none());
+    }
+
+    #[test]
+    fn open_fails_on_a_store_without_a_chain() {
+        let err = Blockchain::open(MemoryStore::new()).err().unwrap();
+        assert!(err.to_string().contains("new block database"), "{}", err);
+    }
+}
@@ -1,16 +1,34 @@
 // cli.rs
 
+use std::fs;
+use std::net::ToSocketAddrs;
 use std::process::exit;
+use std::thread;
+use std::time::Duration;
 
 use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use clap::{arg, ArgMatches, Command};
+use failure::format_err;
 
-use crate::block::Block;
-use crate::blockchain::Blockchain;
-use crate::errors::Result;
-use crate::transaction::Transaction;
-use crate::utxoset::UTXOSet;
-use crate::wallet::Wallets;
+use rust_chain::anchor::{find_anchor, hash_document};
+use rust_chain::chain::{Blockchain, Transaction, TransactionBuilder};
+use rust_chain::consensus::check_lock_time;
+use rust_chain::engine::VALIDATOR_KEY_SIZE;
+use rust_chain::errors::Result;
+use rust_chain::htlc::{self, find_secret, hash_secret, new_secret};
+use rust_chain::mempool::{Mempool, DEFAULT_MAX_MEMPOOL_SIZE, DEFAULT_MEMPOOL_EXPIRY};
+use rust_chain::miner::{CancelToken, Miner};
+use rust_chain::net::DEFAULT_P2P_PORT;
+use rust_chain::node::Node;
+use rust_chain::params::{ChainParams, Checkpoints, Consensus, Network};
+use rust_chain::peers::{PeerManager, MAX_OUTBOUND};
+use rust_chain::pow::PowAlgorithm;
+use rust_chain::store::{ChainStore, SledStore, DATA_DIR};
+use rust_chain::sync::{sync, sync_new, SyncProgress};
+use rust_chain::rpc::{load_or_generate_token, RpcServer, DEFAULT_RPC_PORT};
+use rust_chain::spv::LightClient;
+use rust_chain::utxo::UTXOSet;
+use rust_chain::wallet::{address_of, Wallets};
 
 pub struct Cli {}
 
@@ -21,12 +39,39 @@ impl Cli {
 
     pub fn run(&mut self) -> Result<()> {
         let matches = Command::new("Rust-Chain")
-            .version("0.1")
+            .version(env!("CARGO_PKG_VERSION"))
             .author("github.com/lla-dane/Rust-Chain")
             .subcommand(Command::new("printchain").about("print all the chain blocks"))
             .subcommand(Command::new("createwallet").about("create a wallet"))
             .subcommand(Command::new("listaddresses").about("list all addresses"))
-            .subcommand(Command::new("reindex").about("reindex UTXOs in the DB"))
+            .subcommand(
+                Command::new("getpublickey")
+                    .about("print the public key of a wallet, which identifies it as a validator")
+                    .arg(arg!(<ADDRESS>"'The wallet address'")),
+            )
+            .subcommand(
+                Command::new("reindex")
+                    .about("reindex UTXOs in the DB")
+                    .arg(arg!(--txindex "'Build and maintain the transaction index'"))
+                    .arg(arg!(--"no-txindex" "'Drop the transaction index'"))
+                    .arg(arg!(--addrindex "'Build and maintain the address index'"))
+                    .arg(arg!(--"no-addrindex" "'Drop the address index'")),
+            )
+            .subcommand(
+                Command::new("getaddressutxos")
+                    .about("list unspent outputs of any address (needs the address index)")
+                    .arg(arg!(<ADDRESS>"'The address to list outputs for'")),
+            )
+            .subcommand(
+                Command::new("getaddresshistory")
+                    .about("list transactions of any address (needs the address index)")
+                    .arg(arg!(<ADDRESS>"'The address to list transactions for'")),
+            )
+            .subcommand(
+                Command::new("gettransaction")
+                    .about("print a transaction of the chain")
+                    .arg(arg!(<TXID>"'The transaction id'")),
+            )
             .subcommand(
                 Command::new("getbalance")
                     .about("get balance in the blockchain")
@@ -35,32 +80,129 @@ impl Cli {
             .subcommand(
                 Command::new("create")
                     .about("Create new blockchain")
-                    .arg(arg!(<ADDRESS>"'The address to send genesis block reward to'")),
+                    .arg(arg!(<ADDRESS>"'The address to send genesis block reward to'"))
+                    .arg(arg!(--network <NETWORK> "'main, test, regtest or poa, main by default'"))
+                    .arg(arg!(--validators <KEYS> "'Comma separated hex public keys of the validators of a poa network, see getpublickey'"))
+                    .arg(arg!(--pow <ALGORITHM> "'Proof of work hash, sha256 or the memory-hard scrypt, sha256 by default'")),
             )
             .subcommand(
                 Command::new("send")
                     .about("send in the blockchain")
                     .arg(arg!(<SENDER>"'Source wallet address'"))
                     .arg(arg!(<RECEIVER>"'Destination wallet address'"))
-                    .arg(arg!(<AMOUNT>"'Destination wallet address'")),
+                    .arg(arg!(<AMOUNT>"'Destination wallet address'"))
+                    .arg(arg!(--locktime <LOCKTIME> "'Block height, or unix time in seconds from 500000000 on, the transaction is locked until'")),
+            )
+            .subcommand(
+                Command::new("htlccreate")
+                    .about("lock coins in a hash time-locked output, to start or answer an atomic swap")
+                    .arg(arg!(<SENDER>"'Source wallet address, which can take the coins back after the lock time'"))
+                    .arg(arg!(<RECEIVER>"'Address that can take the coins with the secret'"))
+                    .arg(arg!(<AMOUNT>"'Amount to lock'"))
+                    .arg(arg!(--locktime <LOCKTIME> "'Block height, or unix time in seconds from 500000000 on, the refund is locked until'").required(true))
+                    .arg(arg!(--hash <HASH> "'Hex sha256 of the secret, a new secret is made if not given'")),
+            )
+            .subcommand(
+                Command::new("htlcredeem")
+                    .about("take the coins of a hash time-locked output with its secret")
+                    .arg(arg!(<TXID>"'Transaction holding the output'"))
+                    .arg(arg!(<INDEX>"'Index of the output'"))
+                    .arg(arg!(<SECRET>"'Hex secret'")),
+            )
+            .subcommand(
+                Command::new("htlcrefund")
+                    .about("take back the coins of a hash time-locked output after its lock time")
+                    .arg(arg!(<TXID>"'Transaction holding the output'"))
+                    .arg(arg!(<INDEX>"'Index of the output'")),
+            )
+            .subcommand(
+                Command::new("htlcsecret")
+                    .about("find the secret revealed by redeeming a hash time-locked output")
+                    .arg(arg!(<HASH>"'Hex sha256 of the secret'")),
+            )
+            .subcommand(
+                Command::new("anchor")
+                    .about("timestamp data in the chain with an unspendable data output")
+                    .arg(arg!(<DATA>"'Hex data, or a file whose sha256 is anchored'"))
+                    .arg(arg!(--from <ADDRESS> "'Wallet address paying for the transaction'").required(true)),
+            )
+            .subcommand(
+                Command::new("getanchor")
+                    .about("prove which block anchors data")
+                    .arg(arg!(<DATA>"'Hex data, or a file whose sha256 was anchored'")),
+            )
+            .subcommand(
+                Command::new("mine")
+                    .about("mine a block with only the reward transaction")
+                    .arg(arg!(<ADDRESS>"'The address to send the block reward to'"))
+                    .arg(arg!(--threads <N> "'Number of mining threads, all cores by default'")),
+            )
+            .subcommand(
+                Command::new("sync")
+                    .about("download the most-work chain from peers, headers first")
+                    .arg(arg!(--connect <PEERS> "'Comma separated host:port of the peers, instead of picking them from the address book'"))
+                    .arg(arg!(--seeds <PEERS> "'Comma separated host:port of seed nodes to add to the address book'"))
+                    .arg(arg!(--network <NETWORK> "'main, test, regtest or poa, for a chain that is not in data yet (main by default)'"))
+                    .arg(arg!(--validators <KEYS> "'Comma separated hex public keys of the validators, for a poa chain that is not in data yet'"))
+                    .arg(arg!(--pow <ALGORITHM> "'sha256 or scrypt, for a proof of work chain that is not in data yet (sha256 by default)'"))
+                    .arg(arg!(--checkpoints <CHECKPOINTS> "'Comma separated height:hash of blocks the chain must have'"))
+                    .arg(arg!(--assumevalid <HASH> "'Block whose signatures and those of its ancestors are not checked while syncing'")),
+            )
+            .subcommand(
+                Command::new("lightsync")
+                    .about("light mode: sync headers only and get proofs of the wallets' transactions")
+                    .arg(arg!(--connect <PEERS> "'Comma separated host:port of the peers, instead of picking them from the address book'"))
+                    .arg(arg!(--seeds <PEERS> "'Comma separated host:port of seed nodes to add to the address book'"))
+                    .arg(arg!(--filters "'Test compact block filters locally instead of sending the addresses to the peers'"))
+                    .arg(arg!(--network <NETWORK> "'main, test, regtest or poa, main by default'"))
+                    .arg(arg!(--validators <KEYS> "'Comma separated hex public keys of the validators of a poa network'"))
+                    .arg(arg!(--pow <ALGORITHM> "'Proof of work hash of the network, sha256 or scrypt, sha256 by default'"))
+                    .arg(arg!(--checkpoints <CHECKPOINTS> "'Comma separated height:hash of blocks the header chain must have'")),
+            )
+            .subcommand(
+                Command::new("lightbalance")
+                    .about("light mode: balance from the proven transactions")
+                    .arg(arg!(<ADDRESS>"'The Address it get balance for'")),
+            )
+            .subcommand(
+                Command::new("startnode")
+                    .about("serve the chain to other nodes and discover more, after syncing from peers")
+                    .arg(arg!(--port <PORT> "'Port to listen on'"))
+                    .arg(arg!(--connect <PEERS> "'Comma separated host:port of the peers, instead of picking them from the address book'"))
+                    .arg(arg!(--seeds <PEERS> "'Comma separated host:port of seed nodes to add to the address book'"))
+                    .arg(arg!(--rpcport <PORT> "'Also serve JSON-RPC on localhost, with getpeerinfo and setban'"))
+                    .arg(arg!(--token <TOKEN> "'Bearer token RPC clients must send'"))
+                    .arg(arg!(--maxmempool <MB> "'Most megabytes of transactions kept in the mempool, 50 by default'"))
+                    .arg(arg!(--mempoolexpiry <HOURS> "'Hours after which unmined transactions are dropped, 336 by default'"))
+                    .arg(arg!(--network <NETWORK> "'main, test, regtest or poa, for a chain that is not in data yet (main by default)'"))
+                    .arg(arg!(--validators <KEYS> "'Comma separated hex public keys of the validators, for a poa chain that is not in data yet'"))
+                    .arg(arg!(--pow <ALGORITHM> "'sha256 or scrypt, for a proof of work chain that is not in data yet (sha256 by default)'"))
+                    .arg(arg!(--checkpoints <CHECKPOINTS> "'Comma separated height:hash of blocks the chain must have'"))
+                    .arg(arg!(--assumevalid <HASH> "'Block whose signatures and those of its ancestors are not checked while syncing'")),
+            )
+            .subcommand(
+                Command::new("startrpc")
+                    .about("start the JSON-RPC server on localhost")
+                    .arg(arg!(--port <PORT> "'Port to listen on'"))
+                    .arg(arg!(--token <TOKEN> "'Bearer token clients must send'")),
             )
             .get_matches();
 
-        if let Some(_) = matches.subcommand_matches("printchain") {
+        if matches.subcommand_matches("printchain").is_some() {
             let bc = Blockchain::open_blockchain()?;
             for block in bc.iter() {
                 println!("ITEM {:#?} \n\n", block);
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
+        if matches.subcommand_matches("createwallet").is_some() {
             let mut ws = Wallets::new()?;
             let address = ws.create_wallet();
             ws.save_all()?;
             println!("success: address {}", address);
         }
 
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
+        if matches.subcommand_matches("listaddresses").is_some() {
             let ws = Wallets::new()?;
             let addresses = ws.get_all_address();
             println!("addresses: ");
@@ -69,27 +211,82 @@ impl Cli {
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("reindex") {
+        if let Some(matches) = matches.subcommand_matches("getpublickey") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let wallets = Wallets::new()?;
+            match wallets.get_wallet(address) {
+                Some(wallet) => println!("{}", hex::encode(&wallet.public_key)),
+                None => return Err(format_err!("Wallet {} not found", address)),
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("reindex") {
             let bc = Blockchain::open_blockchain()?;
+            if matches.get_flag("txindex") {
+                bc.set_txindex(true)?;
+                println!("Transaction index enabled");
+            } else if matches.get_flag("no-txindex") {
+                bc.set_txindex(false)?;
+                println!("Transaction index dropped");
+            }
+            if matches.get_flag("addrindex") {
+                bc.set_addrindex(true)?;
+                println!("Address index enabled");
+            } else if matches.get_flag("no-addrindex") {
+                bc.set_addrindex(false)?;
+                println!("Address index dropped");
+            }
             let utxo_set = UTXOSet { blockchain: bc };
             utxo_set.reindex()?;
             let count = utxo_set.count_transactions()?;
             println!("Done! There are {} transactions in the UTXO set.", count);
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("create") {
+        if let Some(matches) = matches.subcommand_matches("create") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
                 let address = String::from(address);
-                let bc = Blockchain::create_blockchain(address.clone())?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                utxo_set.reindex()?;
+                Blockchain::create_blockchain(address, chain_params(matches)?)?;
                 println!("SUCCESS..! BLOCKCHAIN CREATED");
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+        if let Some(matches) = matches.subcommand_matches("gettransaction") {
+            if let Some(txid) = matches.get_one::<String>("TXID") {
+                let bc = Blockchain::open_blockchain()?;
+                let tx = bc.find_tranasaction(txid)?;
+                if let Some(location) = bc.get_tx_location(txid)? {
+                    println!(
+                        "BLOCK {} POSITION {}",
+                        location.block_hash, location.position
+                    );
+                }
+                println!("{:#?}", tx);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getaddressutxos") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let pub_key_hash = address_hash(address)?;
+                let bc = Blockchain::open_blockchain()?;
+                for (txid, output_index, utxo) in bc.get_address_utxos(&pub_key_hash)? {
+                    println!("{}:{} {}", txid, output_index, utxo.value);
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getaddresshistory") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let pub_key_hash = address_hash(address)?;
+                let bc = Blockchain::open_blockchain()?;
+                for (height, txid) in bc.get_address_history(&pub_key_hash)? {
+                    println!("{} {}", height, txid);
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let pub_key_hash = Address::decode(&address).unwrap().body;
+                let pub_key_hash = address_hash(address)?;
                 let bc = Blockchain::open_blockchain()?;
                 // let utxos = bc.find_utxo(&pub_key_hash);
 
@@ -97,7 +294,7 @@ impl Cli {
                 let utxos = utxo_set.find_utxo_for_address(&pub_key_hash)?;
 
                 let mut balance = 0;
-                for utxo in utxos.outputs {
+                for utxo in utxos {
                     balance += utxo.value;
                 }
 
@@ -105,7 +302,7 @@ impl Cli {
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
+        if let Some(matches) = matches.subcommand_matches("send") {
             let sender_addr = if let Some(sender) = matches.get_one::<String>("SENDER") {
                 sender
             } else {
@@ -129,15 +326,460 @@ impl Cli {
 
             let bc = Blockchain::open_blockchain()?;
             let mut utxo_set = UTXOSet { blockchain: bc };
-            let tx = Transaction::new_transaction(sender_addr, receiver_addr, amount, &utxo_set)?;
+            let tx = match matches.get_one::<String>("locktime") {
+                Some(lock_time) => TransactionBuilder::new(&utxo_set)
+                    .sender(sender_addr)
+                    .pay_to(receiver_addr, amount)
+                    .lock_time(lock_time.parse()?)
+                    .build()?,
+                None => Transaction::new_transaction(sender_addr, receiver_addr, amount, &utxo_set)?,
+            };
 
-            let cbtx = Transaction::new_coinbase(sender_addr.to_string(), String::from("reward"))?;
-            let new_block = utxo_set.blockchain.add_block(vec![cbtx, tx])?;
+            mine_or_print(&mut utxo_set, tx, sender_addr)?;
+        }
 
-            utxo_set.update(&new_block)?;
-            println!("BLOCK CREATED");
+        if let Some(matches) = matches.subcommand_matches("htlccreate") {
+            let sender_addr = matches.get_one::<String>("SENDER").unwrap();
+            let receiver_addr = matches.get_one::<String>("RECEIVER").unwrap();
+            let amount: i32 = matches.get_one::<String>("AMOUNT").unwrap().parse()?;
+            let lock_time: u32 = matches.get_one::<String>("locktime").unwrap().parse()?;
+            let hash = match matches.get_one::<String>("hash") {
+                Some(hash) => hex::decode(hash)?,
+                None => {
+                    // Only the side starting the swap knows the secret, until
+                    // it redeems the other side's HTLC
+                    let secret = new_secret();
+                    println!("SECRET {}", hex::encode(&secret));
+                    hash_secret(&secret)
+                }
+            };
+
+            let bc = Blockchain::open_blockchain()?;
+            let mut utxo_set = UTXOSet { blockchain: bc };
+            let tx = TransactionBuilder::new(&utxo_set)
+                .sender(sender_addr)
+                .pay_to_htlc(receiver_addr, amount, &hash, lock_time)
+                .build()?;
+            println!("HTLC {} OUTPUT 0 HASH {}", tx.id, hex::encode(&hash));
+            mine_or_print(&mut utxo_set, tx, sender_addr)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("htlcredeem") {
+            let txid = matches.get_one::<String>("TXID").unwrap();
+            let index: i32 = matches.get_one::<String>("INDEX").unwrap().parse()?;
+            let secret = hex::decode(matches.get_one::<String>("SECRET").unwrap())?;
+
+            let bc = Blockchain::open_blockchain()?;
+            let mut utxo_set = UTXOSet { blockchain: bc };
+            let tx = htlc::redeem(&utxo_set, txid, index, &secret)?;
+            let receiver_addr = address_of(&tx.v_outputs[0].pub_key_hash);
+            mine_or_print(&mut utxo_set, tx, &receiver_addr)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("htlcrefund") {
+            let txid = matches.get_one::<String>("TXID").unwrap();
+            let index: i32 = matches.get_one::<String>("INDEX").unwrap().parse()?;
+
+            let bc = Blockchain::open_blockchain()?;
+            let mut utxo_set = UTXOSet { blockchain: bc };
+            let tx = htlc::refund(&utxo_set, txid, index)?;
+            let sender_addr = address_of(&tx.v_outputs[0].pub_key_hash);
+            mine_or_print(&mut utxo_set, tx, &sender_addr)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("htlcsecret") {
+            let hash = hex::decode(matches.get_one::<String>("HASH").unwrap())?;
+            let bc = Blockchain::open_blockchain()?;
+            match find_secret(&bc, &hash) {
+                Some(secret) => println!("SECRET {}", hex::encode(secret)),
+                None => println!("SECRET NOT REVEALED YET"),
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("anchor") {
+            let data = anchor_data(matches.get_one::<String>("DATA").unwrap())?;
+            let from = matches.get_one::<String>("from").unwrap();
+
+            let bc = Blockchain::open_blockchain()?;
+            let mut utxo_set = UTXOSet { blockchain: bc };
+            let tx = TransactionBuilder::new(&utxo_set)
+                .sender(from)
+                .data(&data)
+                .build()?;
+            println!("ANCHORING {} IN TRANSACTION {}", hex::encode(&data), tx.id);
+            mine_or_print(&mut utxo_set, tx, from)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getanchor") {
+            let data = anchor_data(matches.get_one::<String>("DATA").unwrap())?;
+            let bc = Blockchain::open_blockchain()?;
+            match find_anchor(&bc, &data)? {
+                Some(proof) => {
+                    println!(
+                        "ANCHORED IN BLOCK {} AT HEIGHT {} (TIMESTAMP {})",
+                        proof.block_hash()?,
+                        proof.header.height,
+                        proof.header.timestamp
+                    );
+                    println!("TRANSACTION {} OUTPUT {}", proof.tx.id, proof.output_index);
+                    println!("MERKLE ROOT {}", hex::encode(&proof.header.merkle_root));
+                    println!(
+                        "BRANCH {} {}",
+                        proof.branch.index,
+                        proof.branch.lemmas.iter().map(hex::encode).collect::<Vec<_>>().join(",")
+                    );
+                    println!("PROOF VALID: {}", proof.verify(&data, &*bc.params().engine())?);
+                }
+                None => println!("NOT ANCHORED"),
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("mine") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let miner = match matches.get_one::<String>("threads") {
+                    Some(threads) => Miner::new(threads.parse()?),
+                    None => Miner::default(),
+                };
+                let mut bc = Blockchain::open_blockchain()?;
+                // The height keeps coinbases paying the same address apart
+                let height = bc.get_best_height()? + 1;
+                let cbtx = Transaction::new_coinbase(
+                    address.to_string(),
+                    format!("Reward to '{}' at height {}", address, height),
+                )?;
+                let template = bc.new_block_template(vec![cbtx])?;
+                match bc.params().consensus {
+                    // Validators sign instead of searching a nonce
+                    Consensus::ProofOfAuthority(_) => {
+                        if let Some(block) = bc.engine().seal(template, &CancelToken::new())? {
+                            bc.submit_block(&block)?;
+                            println!("BLOCK {} SIGNED AT HEIGHT {}", block.get_hash(), block.get_height());
+                        }
+                    }
+                    Consensus::ProofOfWork(algorithm) => {
+                        let miner = miner.with_hasher(algorithm.hasher());
+                        if let Some((block, stats)) = miner.mine(template, &CancelToken::new())? {
+                            bc.submit_block(&block)?;
+                            println!("BLOCK {} MINED AT HEIGHT {}", block.get_hash(), block.get_height());
+                            println!(
+                                "{} {} hashes in {:.2}s on {} threads: {:.0} H/s",
+                                stats.hashes,
+                                algorithm,
+                                stats.elapsed.as_secs_f64(),
+                                miner.threads(),
+                                stats.hashrate()
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sync") {
+            let manager = PeerManager::open(DATA_DIR)?;
+            let peers = peer_addrs(&manager, matches)?;
+            let bc = sync_chain(&manager, &peers, matches)?;
+            println!("SYNCED TO HEIGHT {} ({})", bc.get_best_height()?, bc.get_tip_hash());
+        }
+
+        if let Some(matches) = matches.subcommand_matches("lightsync") {
+            let manager = PeerManager::open(DATA_DIR)?;
+            let peers = peer_addrs(&manager, matches)?;
+            let client = LightClient::open_light_client()?
+                .with_peer_manager(manager)
+                .with_engine(chain_params(matches)?.engine())
+                .with_checkpoints(checkpoints_param(matches)?);
+            let found = if matches.get_flag("filters") {
+                client.sync_filtered(&peers, &print_progress)?
+            } else {
+                client.sync(&peers, &print_progress)?
+            };
+            println!(
+                "HEADERS SYNCED TO HEIGHT {}, {} NEW TRANSACTIONS",
+                client.get_best_height()?.unwrap_or(0),
+                found
+            );
+        }
+
+        if let Some(matches) = matches.subcommand_matches("lightbalance") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let pub_key_hash = address_hash(address)?;
+                let client = LightClient::open_light_client()?;
+                let balance = client.get_balance(&pub_key_hash)?;
+                println!("Balance of '{}': {} ", address, balance);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("startnode") {
+            let port: u16 = match matches.get_one::<String>("port") {
+                Some(port) => port.parse()?,
+                None => DEFAULT_P2P_PORT,
+            };
+            let peers = PeerManager::open(DATA_DIR)?;
+            let network = network_param(matches)?;
+            let bc = if matches.contains_id("connect") {
+                sync_chain(&peers, &peer_addrs(&peers, matches)?, matches)?
+            } else if peers.address_book().is_empty() && !matches.contains_id("seeds") {
+                check_consensus(open_chain(network)?, matches)?
+            } else {
+                // Peers from the address book may all be gone, the node
+                // then starts on the chain it has
+                match peer_addrs(&peers, matches)
+                    .and_then(|addrs| sync_chain(&peers, &addrs, matches))
+                {
+                    Ok(bc) => bc,
+                    Err(e) => {
+                        println!("NOT SYNCED: {}", e);
+                        check_consensus(open_chain(network)?, matches)?
+                    }
+                }
+            };
+            let max_size = match matches.get_one::<String>("maxmempool") {
+                Some(mb) => mb.parse::<usize>()? * 1024 * 1024,
+                None => DEFAULT_MAX_MEMPOOL_SIZE,
+            };
+            let expiry = match matches.get_one::<String>("mempoolexpiry") {
+                Some(hours) => Duration::from_secs(hours.parse::<u64>()? * 60 * 60),
+                None => DEFAULT_MEMPOOL_EXPIRY,
+            };
+            let node = Node::new(port, UTXOSet { blockchain: bc })
+                .with_peer_manager(peers)
+                .with_mempool(Mempool::with_limits(max_size, expiry));
+            if let Some(rpc_port) = matches.get_one::<String>("rpcport") {
+                let rpc_port: u16 = rpc_port.parse()?;
+                let token = load_or_generate_token(matches.get_one::<String>("token"))?;
+                let server = RpcServer::for_node(rpc_port, token, &node);
+                println!("RPC SERVER LISTENING ON 127.0.0.1:{}", rpc_port);
+                thread::spawn(move || {
+                    if let Err(e) = server.run() {
+                        eprintln!("RPC server stopped: {}", e);
+                    }
+                });
+            }
+            println!("NODE LISTENING ON PORT {}", port);
+            node.run()?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("startrpc") {
+            let port: u16 = match matches.get_one::<String>("port") {
+                Some(port) => port.parse()?,
+                None => DEFAULT_RPC_PORT,
+            };
+            let token = load_or_generate_token(matches.get_one::<String>("token"))?;
+            let server = RpcServer::new(port, token)?;
+            println!("RPC SERVER LISTENING ON 127.0.0.1:{}", port);
+            server.run()?;
         }
 
         Ok(())
     }
 }
+
+// Mines the transaction with a coinbase paying `reward_addr`, or prints it
+// for sendrawtransaction if its lock time keeps it out of the next block
+fn mine_or_print(utxo_set: &mut UTXOSet, tx: Transaction, reward_addr: &str) -> Result<()> {
+    let height = utxo_set.blockchain.get_best_height()? + 1;
+    let median_time_past = utxo_set.blockchain.median_time_past()?;
+    if check_lock_time(&tx, height, median_time_past).is_err() {
+        // Signed now, it can be sent to a node once the lock passed
+        println!("TRANSACTION {} LOCKED UNTIL {}", tx.id, tx.lock_time);
+        println!("{}", hex::encode(bincode::serialize(&tx)?));
+    } else {
+        let cbtx = Transaction::new_coinbase(
+            reward_addr.to_string(),
+            format!("Reward to '{}' at height {}", reward_addr, height),
+        )?;
+        utxo_set.blockchain.add_block(vec![cbtx, tx])?;
+        println!("BLOCK CREATED");
+    }
+    Ok(())
+}
+
+// The pub key hash an address pays to
+fn address_hash(address: &str) -> Result<Vec<u8>> {
+    match Address::decode(address) {
+        Ok(address) => Ok(address.body),
+        Err(_) => Err(format_err!("Invalid address {}", address)),
+    }
+}
+
+// The DATA of anchor commands: the sha256 of the file if there is one at
+// that path, the hex data otherwise
+fn anchor_data(data: &str) -> Result<Vec<u8>> {
+    match fs::read(data) {
+        Ok(document) => Ok(hash_document(&document)),
+        Err(_) => {
+            hex::decode(data).map_err(|_| format_err!("{} is neither a file nor hex data", data))
+        }
+    }
+}
+
+// Syncs the local chain from the comma separated peers, downloading
+// the whole chain of the --network if there is none yet
+fn sync_chain(manager: &PeerManager, peers: &[String], matches: &ArgMatches) -> Result<Blockchain> {
+    let store = SledStore::open(DATA_DIR)?;
+    let checkpoints = checkpoints_param(matches)?;
+    let assume_valid = matches.get_one::<String>("assumevalid");
+    if store.get_tip()?.is_none() {
+        let mut params = chain_params(matches)?.with_checkpoints(checkpoints);
+        if let Some(hash) = assume_valid {
+            params = params.with_assume_valid(hash.clone());
+        }
+        return sync_new(store, params, manager, peers, &print_progress);
+    }
+    let bc = Blockchain::open(store)?;
+    let mut bc = check_consensus(check_network(bc, network_param(matches)?)?, matches)?
+        .with_checkpoints(checkpoints);
+    if let Some(hash) = assume_valid {
+        bc = bc.with_assume_valid(hash.clone());
+    }
+    // Nodes start with an empty mempool, so there is none to follow the chain
+    sync(&mut bc, &mut Mempool::default(), manager, peers, &print_progress)?;
+    Ok(bc)
+}
+
+// The --network option
+fn network_param(matches: &ArgMatches) -> Result<Option<Network>> {
+    match matches.get_one::<String>("network") {
+        Some(name) => Ok(Some(name.parse()?)),
+        None => Ok(None),
+    }
+}
+
+// The params of the --network, main by default, with the --validators of
+// a poa network or the --pow hash of the others
+fn chain_params(matches: &ArgMatches) -> Result<ChainParams> {
+    let network = network_param(matches)?.unwrap_or(Network::Main);
+    let validators = match (network, matches.get_one::<String>("validators")) {
+        (Network::Poa, Some(validators)) => validators,
+        (Network::Poa, None) => return Err(format_err!("A poa network needs --validators")),
+        (_, Some(_)) => return Err(format_err!("--validators is only for the poa network")),
+        (_, None) => {
+            return Ok(ChainParams {
+                consensus: Consensus::ProofOfWork(pow_param(matches)?.unwrap_or_default()),
+                ..ChainParams::for_network(network)
+            })
+        }
+    };
+    if matches.contains_id("pow") {
+        return Err(format_err!("--pow is only for proof of work networks"));
+    }
+    Ok(ChainParams::poa(validator_keys(validators)?))
+}
+
+// The --checkpoints option, comma separated height:hash pairs
+fn checkpoints_param(matches: &ArgMatches) -> Result<Checkpoints> {
+    let mut checkpoints = Checkpoints::new();
+    if let Some(list) = matches.get_one::<String>("checkpoints") {
+        for checkpoint in list.split(',') {
+            match checkpoint.trim().split_once(':') {
+                Some((height, hash)) => checkpoints.insert(height.parse()?, hash.to_string()),
+                None => return Err(format_err!("Checkpoint {} is not height:hash", checkpoint)),
+            };
+        }
+    }
+    Ok(checkpoints)
+}
+
+// The --pow option
+fn pow_param(matches: &ArgMatches) -> Result<Option<PowAlgorithm>> {
+    match matches.get_one::<String>("pow") {
+        Some(name) => Ok(Some(name.parse()?)),
+        None => Ok(None),
+    }
+}
+
+// Comma separated hex validator keys
+fn validator_keys(validators: &str) -> Result<Vec<Vec<u8>>> {
+    let mut keys = Vec::new();
+    for key in validators.split(',') {
+        let key = hex::decode(key.trim())?;
+        if key.len() != VALIDATOR_KEY_SIZE {
+            return Err(format_err!("Validator keys are {} bytes", VALIDATOR_KEY_SIZE));
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+// Opens the chain in data, which must be on `network` if one is given
+fn open_chain(network: Option<Network>) -> Result<Blockchain> {
+    check_network(Blockchain::open_blockchain()?, network)
+}
+
+fn check_network(bc: Blockchain, network: Option<Network>) -> Result<Blockchain> {
+    match network {
+        Some(network) if bc.params().network != network => Err(format_err!(
+            "The chain in {} is on the {} network, not {}",
+            DATA_DIR,
+            bc.params().network,
+            network
+        )),
+        _ => Ok(bc),
+    }
+}
+
+// The chain in data keeps the validators or proof of work hash it was
+// created with, --validators and --pow must match them if given
+fn check_consensus(bc: Blockchain, matches: &ArgMatches) -> Result<Blockchain> {
+    let consensus = match (matches.get_one::<String>("validators"), pow_param(matches)?) {
+        (Some(validators), None) => Consensus::ProofOfAuthority(validator_keys(validators)?),
+        (None, Some(algorithm)) => Consensus::ProofOfWork(algorithm),
+        (Some(_), Some(_)) => return Err(format_err!("--pow is only for proof of work networks")),
+        (None, None) => return Ok(bc),
+    };
+    if bc.params().consensus != consensus {
+        return Err(format_err!(
+            "The chain in {} has other validators or proof of work than given",
+            DATA_DIR
+        ));
+    }
+    Ok(bc)
+}
+
+// The --connect peers, or else peers picked from the address book after
+// adding the --seeds to it
+fn peer_addrs(manager: &PeerManager, matches: &ArgMatches) -> Result<Vec<String>> {
+    if let Some(peers) = matches.get_one::<String>("connect") {
+        return Ok(split_peers(peers));
+    }
+    if let Some(seeds) = matches.get_one::<String>("seeds") {
+        let mut addrs = Vec::new();
+        for seed in split_peers(seeds) {
+            match seed.to_socket_addrs()?.next() {
+                Some(addr) => addrs.push(addr),
+                None => return Err(format_err!("Can not resolve {}", seed)),
+            }
+        }
+        manager.address_book().add_seeds(&addrs);
+    }
+    let peers: Vec<String> = manager
+        .select_outbound(MAX_OUTBOUND)
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect();
+    if peers.is_empty() {
+        return Err(format_err!(
+            "The address book has no peers, give --connect or --seeds"
+        ));
+    }
+    Ok(peers)
+}
+
+fn split_peers(peers: &str) -> Vec<String> {
+    peers.split(',').map(|peer| peer.trim().to_string()).collect()
+}
+
+fn print_progress(progress: &SyncProgress) {
+    if progress.total_blocks == 0 {
+        println!("headers: {}", progress.headers);
+    } else {
+        println!(
+            "blocks: {}/{} ({:.1}%), height {}",
+            progress.blocks,
+            progress.total_blocks,
+            100.0 * progress.blocks as f64 / progress.total_blocks as f64,
+            progress.height
+        );
+    }
+}
@@ -0,0 +1,539 @@
+// consensus.rs
+
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use failure::format_err;
+
+use crate::block::{Block, BlockHeader};
+use crate::engine::ConsensusEngine;
+use crate::errors::Result;
+use crate::params::{ChainParams, Checkpoints};
+use crate::transaction::Transaction;
+use crate::tx::{Confirmation, RelativeLock, SEQUENCE_LOCK_GRANULARITY};
+
+// Number of leading zero hex digits a block hash must have
+pub const TARGET_HEXT: usize = 4;
+
+// A block's timestamp must be above the median of this many previous blocks
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+// and at most this far ahead of the local clock, in milliseconds
+pub const MAX_FUTURE_DRIFT: u128 = 2 * 60 * 60 * 1000;
+
+// Lock times below this are block heights, from it on unix times in seconds
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+// Source of the local time the timestamp rules compare against
+pub trait Clock: Debug + Send + Sync {
+    // Milliseconds since the unix epoch
+    fn now(&self) -> u128;
+}
+
+// The system wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u128 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis())
+    }
+}
+
+// A clock that only moves when told to, for deterministic tests and simulations.
+// Clones share the time.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new(millis: u64) -> MockClock {
+        MockClock {
+            millis: Arc::new(AtomicU64::new(millis)),
+        }
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.millis.store(millis, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.millis.fetch_add(millis, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u128 {
+        self.millis.load(Ordering::Relaxed) as u128
+    }
+}
+
+// Expected number of hashes to find a block at the target, every block
+// adds this much work to its chain
+pub fn block_work() -> u128 {
+    16u128.pow(TARGET_HEXT as u32)
+}
+
+// Total work of a chain whose tip is at `height`
+pub fn chain_work(height: usize) -> u128 {
+    (height as u128 + 1) * block_work()
+}
+
+// Returns the median of the timestamps of the last MEDIAN_TIME_SPAN blocks
+pub fn median_time_past(timestamps: &[u128]) -> u128 {
+    let mut timestamps = timestamps.to_vec();
+    timestamps.sort_unstable();
+    match timestamps.len() {
+        0 => 0,
+        n => timestamps[n / 2],
+    }
+}
+
+// Checks a block timestamp against the median time past of its parent and the local time
+pub fn check_timestamp(timestamp: u128, median_time_past: u128, now: u128) -> Result<()> {
+    if timestamp <= median_time_past {
+        return Err(format_err!(
+            "Block timestamp {} is not above the median time past {}",
+            timestamp,
+            median_time_past
+        ));
+    }
+    if timestamp > now + MAX_FUTURE_DRIFT {
+        return Err(format_err!("Block timestamp {} is too far in the future", timestamp));
+    }
+    Ok(())
+}
+
+// Checks the linkage and seal of a header that is about
+// to follow the block `prev_hash` at `prev_height`
+pub fn check_header(
+    header: &BlockHeader,
+    prev_hash: &str,
+    prev_height: usize,
+    engine: &dyn ConsensusEngine,
+) -> Result<()> {
+    if header.prev_block_hash != prev_hash {
+        return Err(format_err!("Block does not extend the current tip"));
+    }
+    if header.height != prev_height + 1 {
+        return Err(format_err!("Block height is not correct"));
+    }
+    if !engine.verify_seal(header)? {
+        return Err(format_err!("Block seal is not valid"));
+    }
+    Ok(())
+}
+
+// Checks the header of a genesis block, which has no parent
+pub fn check_genesis_header(header: &BlockHeader, engine: &dyn ConsensusEngine) -> Result<()> {
+    if header.prev_block_hash != "nil" || header.height != 0 {
+        return Err(format_err!("Genesis block must have no parent and height 0"));
+    }
+    if !engine.verify_seal(header)? {
+        return Err(format_err!("Block seal is not valid"));
+    }
+    Ok(())
+}
+
+// Checks the context free rules and the linkage of a block that is about
+// to be connected on top of the block `prev_hash` at `prev_height`
+pub fn check_block(
+    block: &Block,
+    prev_hash: &str,
+    prev_height: usize,
+    params: &ChainParams,
+) -> Result<()> {
+    check_transactions(block, params)?;
+    check_header(&block.get_header()?, prev_hash, prev_height, &*params.engine())?;
+    if !block.verify_hash()? {
+        return Err(format_err!("Block hash does not match its header"));
+    }
+    check_checkpoint(block.get_height(), &block.get_hash(), &params.checkpoints)
+}
+
+// Checks the rules of a transaction other than a coinbase that need no chain:
+// it has inputs and outputs, pays positive values, spends no output twice and
+// hashes to its id, which its outputs are stored under
+pub fn check_transaction(tx: &Transaction) -> Result<()> {
+    if tx.v_inputs.is_empty() || tx.v_outputs.is_empty() {
+        return Err(format_err!(
+            "Transaction {} has no inputs or no outputs",
+            tx.id
+        ));
+    }
+    if tx
+        .v_outputs
+        .iter()
+        .any(|output| !output.is_data() && output.value <= 0)
+    {
+        return Err(format_err!(
+            "Transaction {} has an output that is not positive",
+            tx.id
+        ));
+    }
+    let mut outpoints = HashSet::new();
+    for input in &tx.v_inputs {
+        if !outpoints.insert((&input.txid, input.output_index)) {
+            return Err(format_err!("Transaction {} spends an output twice", tx.id));
+        }
+    }
+    if tx.id != tx.derived_id()? {
+        return Err(format_err!("Transaction {} does not hash to its id", tx.id));
+    }
+    Ok(())
+}
+
+// Checks a genesis block received from a peer
+pub fn check_genesis_block(block: &Block, params: &ChainParams) -> Result<()> {
+    check_transactions(block, params)?;
+    check_genesis_header(&block.get_header()?, &*params.engine())?;
+    if !block.verify_hash()? {
+        return Err(format_err!("Block hash does not match its header"));
+    }
+    check_checkpoint(0, &block.get_hash(), &params.checkpoints)
+}
+
+// Checks that a block at a checkpoint height is the checkpointed one
+pub fn check_checkpoint(height: usize, hash: &str, checkpoints: &Checkpoints) -> Result<()> {
+    match checkpoints.get(&height) {
+        Some(checkpoint) if checkpoint != hash => Err(format_err!(
+            "Block {} at height {} is not the checkpoint {}",
+            hash,
+            height,
+            checkpoint
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Checks the size and count limits of a transaction, and that its data
+// outputs hold no value and no more data than the network allows
+pub fn check_transaction_limits(tx: &Transaction, params: &ChainParams) -> Result<()> {
+    if tx.v_inputs.len() > params.max_tx_inputs {
+        return Err(format_err!(
+            "Transaction {} has more than {} inputs",
+            tx.id,
+            params.max_tx_inputs
+        ));
+    }
+    if tx.v_outputs.len() > params.max_tx_outputs {
+        return Err(format_err!(
+            "Transaction {} has more than {} outputs",
+            tx.id,
+            params.max_tx_outputs
+        ));
+    }
+    if bincode::serialized_size(tx)? as usize > params.max_tx_size {
+        return Err(format_err!(
+            "Transaction {} is larger than {} bytes",
+            tx.id,
+            params.max_tx_size
+        ));
+    }
+    for output in &tx.v_outputs {
+        let data = match &output.data {
+            Some(data) => data,
+            None => continue,
+        };
+        if data.len() > params.max_data_size {
+            return Err(format_err!(
+                "Transaction {} has a data output over {} bytes",
+                tx.id,
+                params.max_data_size
+            ));
+        }
+        if output.value != 0 || !output.pub_key_hash.is_empty() || output.htlc.is_some() {
+            return Err(format_err!(
+                "Transaction {} has a data output with a value or a receiver",
+                tx.id
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Checks that the lock time of a transaction has passed for a block at
+// `height` whose previous blocks have `median_time_past`. Lock times by
+// time are compared with the median time past, not the block's timestamp.
+pub fn check_lock_time(tx: &Transaction, height: usize, median_time_past: u128) -> Result<()> {
+    let passed = if tx.lock_time == 0 {
+        true
+    } else if tx.lock_time < LOCKTIME_THRESHOLD {
+        (tx.lock_time as usize) < height
+    } else {
+        (tx.lock_time as u128) < median_time_past / 1000
+    };
+    if !passed {
+        return Err(format_err!(
+            "Transaction {} is locked until {}",
+            tx.id,
+            tx.lock_time
+        ));
+    }
+    Ok(())
+}
+
+// Checks the relative locks of the inputs against where the outputs they spend
+// were confirmed, None for outputs of the same block or of the mempool
+pub fn check_sequence_locks(
+    tx: &Transaction,
+    confirmations: &[Option<Confirmation>],
+    height: usize,
+    median_time_past: u128,
+) -> Result<()> {
+    for (input, confirmation) in tx.v_inputs.iter().zip(confirmations) {
+        let passed = match (input.relative_lock(), confirmation) {
+            (None, _) | (Some(RelativeLock::Blocks(0)), _) | (Some(RelativeLock::Time(0)), _) => {
+                true
+            }
+            (Some(_), None) => false,
+            (Some(RelativeLock::Blocks(blocks)), Some(confirmation)) => {
+                height >= confirmation.height + blocks as usize
+            }
+            (Some(RelativeLock::Time(units)), Some(confirmation)) => {
+                let wait = units as u128 * SEQUENCE_LOCK_GRANULARITY as u128 * 1000;
+                median_time_past >= confirmation.timestamp + wait
+            }
+        };
+        if !passed {
+            return Err(format_err!(
+                "Transaction {} spends output {} of {} before its relative lock passed",
+                tx.id,
+                input.output_index,
+                input.txid
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Signature checks needed to validate the transaction, one per spent output
+pub fn sigops(tx: &Transaction) -> usize {
+    if tx.is_coinbase() {
+        0
+    } else {
+        tx.v_inputs.len()
+    }
+}
+
+// Checks the size, transaction and signature operation limits of a block
+pub fn check_block_limits(block: &Block, params: &ChainParams) -> Result<()> {
+    if bincode::serialized_size(block)? as usize > params.max_block_size {
+        return Err(format_err!(
+            "Block is larger than {} bytes",
+            params.max_block_size
+        ));
+    }
+    let transactions = block.get_transactions();
+    for tx in transactions {
+        check_transaction_limits(tx, params)?;
+    }
+    if transactions.iter().map(sigops).sum::<usize>() > params.max_block_sigops {
+        return Err(format_err!(
+            "Block has more than {} signature operations",
+            params.max_block_sigops
+        ));
+    }
+    Ok(())
+}
+
+// Checks the limits and coinbase rules of a block
+fn check_transactions(block: &Block, params: &ChainParams) -> Result<()> {
+    let transactions = block.get_transactions();
+    if transactions.is_empty() || !transactions[0].is_coinbase() {
+        return Err(format_err!("First transaction of a block must be coinbase"));
+    }
+    if transactions[1..].iter().any(|tx| tx.is_coinbase()) {
+        return Err(format_err!("Block contains more than one coinbase"));
+    }
+    check_block_limits(block, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::testutil::{mine, new_chain, seal_block, seal_block_at};
+    use crate::transaction::TransactionBuilder;
+    use crate::tx::{TXInput, SEQUENCE_FINAL};
+    use crate::utxoset::UTXOSet;
+
+    // A chain on a mock clock, with MEDIAN_TIME_SPAN blocks a second apart
+    // after the genesis block
+    fn chain_on_mock_clock() -> (UTXOSet<MemoryStore>, String, MockClock) {
+        let (utxo, miner, _) = new_chain(ChainParams::regtest());
+        let genesis = utxo
+            .blockchain
+            .get_block(&utxo.blockchain.get_tip_hash())
+            .unwrap()
+            .unwrap();
+        let clock = MockClock::new(genesis.get_timestamp() as u64);
+        let mut utxo = UTXOSet {
+            blockchain: utxo.blockchain.with_clock(clock.clone()),
+        };
+        for _ in 0..MEDIAN_TIME_SPAN {
+            clock.advance(1000);
+            mine(&mut utxo, &miner, Vec::new()).unwrap();
+        }
+        (utxo, miner, clock)
+    }
+
+    #[test]
+    fn median_time_past_is_the_middle_timestamp() {
+        assert_eq!(median_time_past(&[]), 0);
+        assert_eq!(median_time_past(&[5, 1, 3]), 3);
+        assert_eq!(median_time_past(&[4, 1, 3, 2]), 3);
+    }
+
+    #[test]
+    fn rejects_blocks_not_above_the_median_time_past() {
+        let (mut utxo, miner, clock) = chain_on_mock_clock();
+        let median = utxo.blockchain.median_time_past().unwrap();
+        // The middle one of the last MEDIAN_TIME_SPAN blocks
+        assert_eq!(median, clock.now() - 5000);
+
+        let block = seal_block_at(&utxo.blockchain, &miner, median).unwrap();
+        let err = utxo.blockchain.submit_block(&block).unwrap_err();
+        assert!(err.to_string().contains("median time past"), "{}", err);
+
+        // Older than the tip is fine as long as it is above the median
+        let block = seal_block_at(&utxo.blockchain, &miner, median + 1).unwrap();
+        utxo.blockchain.submit_block(&block).unwrap();
+    }
+
+    #[test]
+    fn rejects_blocks_too_far_in_the_future() {
+        let (mut utxo, miner, clock) = chain_on_mock_clock();
+        let block = seal_block_at(&utxo.blockchain, &miner, clock.now() + MAX_FUTURE_DRIFT + 1)
+            .unwrap();
+        let err = utxo.blockchain.submit_block(&block).unwrap_err();
+        assert!(err.to_string().contains("too far in the future"), "{}", err);
+
+        // The same block is fine once the local time catches up
+        clock.advance(1);
+        utxo.blockchain.submit_block(&block).unwrap();
+    }
+
+    #[test]
+    fn templates_are_stamped_with_the_clock_unless_it_is_behind() {
+        let (utxo, _, clock) = chain_on_mock_clock();
+        let template = utxo.blockchain.new_block_template(Vec::new()).unwrap();
+        assert_eq!(template.get_timestamp(), clock.now());
+
+        clock.set(0);
+        let median = utxo.blockchain.median_time_past().unwrap();
+        let template = utxo.blockchain.new_block_template(Vec::new()).unwrap();
+        assert_eq!(template.get_timestamp(), median + 1);
+    }
+
+    // A transaction with an input of each sequence and the lock time
+    fn locked_tx(lock_time: u32, sequences: &[u32]) -> Transaction {
+        let v_inputs = sequences
+            .iter()
+            .map(|&sequence| TXInput {
+                txid: "parent".to_string(),
+                output_index: 0,
+                signature: Vec::new(),
+                pub_key: Vec::new(),
+                sequence,
+                preimage: Vec::new(),
+            })
+            .collect();
+        Transaction {
+            id: "locked".to_string(),
+            v_inputs,
+            v_outputs: Vec::new(),
+            lock_time,
+        }
+    }
+
+    #[test]
+    fn lock_times_below_the_threshold_are_heights() {
+        assert!(check_lock_time(&locked_tx(0, &[]), 0, 0).is_ok());
+        let tx = locked_tx(10, &[]);
+        assert!(check_lock_time(&tx, 10, u128::MAX).is_err());
+        assert!(check_lock_time(&tx, 11, 0).is_ok());
+
+        let tx = locked_tx(LOCKTIME_THRESHOLD - 1, &[]);
+        assert!(check_lock_time(&tx, LOCKTIME_THRESHOLD as usize - 1, u128::MAX).is_err());
+        assert!(check_lock_time(&tx, LOCKTIME_THRESHOLD as usize, 0).is_ok());
+    }
+
+    #[test]
+    fn lock_times_from_the_threshold_on_are_compared_to_the_median_time_past() {
+        let lock_time = LOCKTIME_THRESHOLD + 100;
+        let tx = locked_tx(lock_time, &[]);
+        // The median time past is in milliseconds, the lock time in seconds
+        let median = lock_time as u128 * 1000;
+        assert!(check_lock_time(&tx, usize::MAX, median + 999).is_err());
+        assert!(check_lock_time(&tx, 0, median + 1000).is_ok());
+    }
+
+    #[test]
+    fn relative_locks_count_from_the_parents_confirmation() {
+        let confirmed = Some(Confirmation {
+            height: 10,
+            timestamp: 50_000,
+        });
+        let tx = locked_tx(0, &[RelativeLock::Blocks(5).to_sequence()]);
+        assert!(check_sequence_locks(&tx, &[confirmed], 14, u128::MAX).is_err());
+        assert!(check_sequence_locks(&tx, &[confirmed], 15, 0).is_ok());
+
+        let tx = locked_tx(0, &[RelativeLock::Time(2).to_sequence()]);
+        let passed = 50_000 + 2 * SEQUENCE_LOCK_GRANULARITY as u128 * 1000;
+        assert!(check_sequence_locks(&tx, &[confirmed], usize::MAX, passed - 1).is_err());
+        assert!(check_sequence_locks(&tx, &[confirmed], 0, passed).is_ok());
+
+        // Each input waits for its own parent
+        let tx = locked_tx(0, &[SEQUENCE_FINAL, RelativeLock::Blocks(1).to_sequence()]);
+        let earlier = Some(Confirmation {
+            height: 2,
+            timestamp: 0,
+        });
+        assert!(check_sequence_locks(&tx, &[earlier, confirmed], 10, 0).is_err());
+        assert!(check_sequence_locks(&tx, &[confirmed, earlier], 10, 0).is_ok());
+    }
+
+    #[test]
+    fn relative_locks_on_unconfirmed_parents_wait() {
+        let tx = locked_tx(0, &[RelativeLock::Blocks(1).to_sequence()]);
+        assert!(check_sequence_locks(&tx, &[None], usize::MAX, u128::MAX).is_err());
+        let tx = locked_tx(0, &[RelativeLock::Time(1).to_sequence()]);
+        assert!(check_sequence_locks(&tx, &[None], usize::MAX, u128::MAX).is_err());
+
+        // Locks of zero and inputs without a lock do not need the parent
+        // confirmed
+        let tx = locked_tx(0, &[RelativeLock::Blocks(0).to_sequence(), SEQUENCE_FINAL]);
+        assert!(check_sequence_locks(&tx, &[None, None], 0, 0).is_ok());
+    }
+
+    #[test]
+    fn blocks_check_the_relative_locks_of_their_transactions() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let payment = TransactionBuilder::new(&utxo)
+            .sender(&miner)
+            .pay_to(&other, 10)
+            .build()
+            .unwrap();
+        mine(&mut utxo, &miner, vec![payment]).unwrap();
+
+        let locked = TransactionBuilder::new(&utxo)
+            .sender(&other)
+            .pay_to(&miner, 10)
+            .relative_lock(RelativeLock::Blocks(2))
+            .build()
+            .unwrap();
+        let block = seal_block(&utxo.blockchain, &miner, vec![locked.clone()]).unwrap();
+        let err = utxo.blockchain.submit_block(&block).unwrap_err();
+        assert!(err.to_string().contains("relative lock"), "{}", err);
+
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        mine(&mut utxo, &miner, vec![locked]).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+// engine.rs
+
+use std::fmt::Debug;
+
+use crypto::ed25519;
+use failure::format_err;
+
+use crate::block::{hash_header, Block, BlockHeader};
+use crate::errors::Result;
+use crate::miner::{CancelToken, Miner};
+use crate::store::ChainStore;
+use crate::wallet::{Wallet, Wallets};
+
+// Size of an ed25519 public key, which identifies a validator
+pub const VALIDATOR_KEY_SIZE: usize = 32;
+
+// How the blocks of a network are sealed and their seal checked. The seal
+// covers the fixed-size header, and so the transactions through the merkle root.
+pub trait ConsensusEngine: Debug + Send + Sync {
+    // Turns a block template into a block that can be connected.
+    // Returns None if the token was cancelled first.
+    fn seal(&self, block: Block, cancel: &CancelToken) -> Result<Option<Block>>;
+
+    // Checks the seal of a header, the linkage and timestamp rules are
+    // checked by consensus
+    fn verify_seal(&self, header: &BlockHeader) -> Result<bool>;
+}
+
+// Proof of work: the proof of work hash of the header, with the miner's
+// hasher, must meet the target. The default mines SHA-256.
+#[derive(Debug, Default)]
+pub struct ProofOfWork {
+    miner: Miner,
+}
+
+impl ProofOfWork {
+    pub fn new(miner: Miner) -> ProofOfWork {
+        ProofOfWork { miner }
+    }
+}
+
+impl ConsensusEngine for ProofOfWork {
+    fn seal(&self, block: Block, cancel: &CancelToken) -> Result<Option<Block>> {
+        Ok(self.miner.mine(block, cancel)?.map(|(block, _)| block))
+    }
+
+    fn verify_seal(&self, header: &BlockHeader) -> Result<bool> {
+        header.verify_proof_of_work(self.miner.hasher())
+    }
+}
+
+// Proof of authority: a set list of validators sign the blocks in turn, the
+// block at height h by validator h % n with its ed25519 key. Validators are
+// wallets, their public key is the one of the wallet.
+#[derive(Debug, Clone)]
+pub struct ProofOfAuthority {
+    validators: Vec<Vec<u8>>,
+    // Wallets of the validators this node signs for
+    signers: Vec<Wallet>,
+}
+
+impl ProofOfAuthority {
+    // An engine that checks seals, and signs none until given keys
+    pub fn new(validators: Vec<Vec<u8>>) -> ProofOfAuthority {
+        ProofOfAuthority {
+            validators,
+            signers: Vec::new(),
+        }
+    }
+
+    // Signs the blocks of the validators among the wallets
+    pub fn with_wallets<S: ChainStore>(mut self, wallets: &Wallets<S>) -> ProofOfAuthority {
+        self.signers = wallets
+            .get_all_address()
+            .iter()
+            .filter_map(|address| wallets.get_wallet(address))
+            .filter(|wallet| self.validators.contains(&wallet.public_key))
+            .cloned()
+            .collect();
+        self
+    }
+
+    // The validator whose turn it is to sign the block at `height`
+    pub fn validator_at(&self, height: usize) -> Result<&[u8]> {
+        match self.validators.len() {
+            0 => Err(format_err!("The chain has no validators")),
+            n => Ok(&self.validators[height % n]),
+        }
+    }
+}
+
+impl ConsensusEngine for ProofOfAuthority {
+    fn seal(&self, mut block: Block, _cancel: &CancelToken) -> Result<Option<Block>> {
+        let validator = self.validator_at(block.get_height())?;
+        let signer = match self
+            .signers
+            .iter()
+            .find(|wallet| wallet.public_key == validator)
+        {
+            Some(signer) => signer,
+            None => {
+                return Err(format_err!(
+                    "Block {} is for validator {} to sign, which is not a local wallet",
+                    block.get_height(),
+                    hex::encode(validator)
+                ))
+            }
+        };
+        block.sign(&signer.private_key)?;
+        Ok(Some(block))
+    }
+
+    fn verify_seal(&self, header: &BlockHeader) -> Result<bool> {
+        let validator = self.validator_at(header.height)?;
+        if validator.len() != VALIDATOR_KEY_SIZE || header.signature.len() != 64 {
+            return Ok(false);
+        }
+        let hash = hash_header(&header.to_bytes()?);
+        Ok(ed25519::verify(&hash, validator, &header.signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::transaction::Transaction;
+
+    // An engine with two validators, both local wallets
+    fn two_validators() -> (ProofOfAuthority, Wallet, Wallet) {
+        let mut wallets = Wallets::open(MemoryStore::new()).unwrap();
+        let first = wallets.create_wallet();
+        let second = wallets.create_wallet();
+        let first = wallets.get_wallet(&first).unwrap().clone();
+        let second = wallets.get_wallet(&second).unwrap().clone();
+        let validators = vec![first.public_key.clone(), second.public_key.clone()];
+        let engine = ProofOfAuthority::new(validators).with_wallets(&wallets);
+        (engine, first, second)
+    }
+
+    // A block of a coinbase paying `wallet`, left unsealed
+    fn template(wallet: &Wallet, height: usize) -> Block {
+        let coinbase = Transaction::new_coinbase(wallet.get_address(), height.to_string()).unwrap();
+        Block::new_template(vec![coinbase], "nil".to_string(), height, 1)
+    }
+
+    #[test]
+    fn validators_sign_in_turn() {
+        let (engine, first, second) = two_validators();
+        for height in 0..4 {
+            let block = engine.seal(template(&first, height), &CancelToken::new()).unwrap();
+            let block = block.unwrap();
+            assert!(engine.verify_seal(&block.get_header().unwrap()).unwrap());
+            let validator = if height % 2 == 0 { &first } else { &second };
+            assert_eq!(engine.validator_at(height).unwrap(), &validator.public_key[..]);
+        }
+    }
+
+    #[test]
+    fn rejects_a_block_signed_by_the_wrong_validator() {
+        let (engine, first, second) = two_validators();
+        let mut block = template(&first, 1);
+        block.sign(&first.private_key).unwrap();
+        assert!(!engine.verify_seal(&block.get_header().unwrap()).unwrap());
+        block.sign(&second.private_key).unwrap();
+        assert!(engine.verify_seal(&block.get_header().unwrap()).unwrap());
+
+        // A signature does not carry over to another height
+        let mut header = block.get_header().unwrap();
+        header.height = 3;
+        assert!(!engine.verify_seal(&header).unwrap());
+        header.signature.clear();
+        header.height = 1;
+        assert!(!engine.verify_seal(&header).unwrap());
+
+        // Without the key of the validator whose turn it is, nothing is signed
+        let engine = ProofOfAuthority {
+            signers: vec![first.clone()],
+            ..engine
+        };
+        assert!(engine.seal(template(&first, 1), &CancelToken::new()).is_err());
+    }
+
+    #[test]
+    fn an_empty_validator_set_is_an_error() {
+        let (_, wallet, _) = two_validators();
+        let engine = ProofOfAuthority::new(Vec::new());
+        assert!(engine.validator_at(0).is_err());
+        assert!(engine.seal(template(&wallet, 0), &CancelToken::new()).is_err());
+        let mut block = template(&wallet, 0);
+        block.sign(&wallet.private_key).unwrap();
+        let header = block.get_header().unwrap();
+        assert!(engine.verify_seal(&header).is_err());
+    }
+}
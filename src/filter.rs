@@ -0,0 +1,365 @@
+// filter.rs
+
+use std::collections::BTreeSet;
+
+use crypto::{digest::Digest, sha2::Sha256};
+use failure::format_err;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::store::{ChainStore, StoreBatch};
+
+// Golomb-Rice parameters: remainders take P bits and about 1 in M queries
+// for an element that is not in the set match anyway
+const FILTER_P: u8 = 19;
+const FILTER_M: u64 = 784931;
+
+// Compact filter of a block: a Golomb-coded set of the hashes of every output
+// pub key hash and every spent outpoint, so a light client can test its own
+// elements locally instead of telling a node which addresses it owns.
+// Values are hashed with sha256 keyed by the block hash, mapped to [0, N * M),
+// sorted and their differences Golomb-Rice coded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockFilter {
+    n: u32,
+    data: Vec<u8>,
+}
+
+// A stored filter with its header, which chains the filter to all filters before it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FilterEntry {
+    pub filter: BlockFilter,
+    pub header: Vec<u8>,
+}
+
+// Element for the outputs paying a pub key hash
+pub fn output_element(pub_key_hash: &[u8]) -> Vec<u8> {
+    pub_key_hash.to_vec()
+}
+
+// Element for the spending of an output
+pub fn outpoint_element(txid: &str, output_index: i32) -> Vec<u8> {
+    let mut element = txid.as_bytes().to_vec();
+    element.extend_from_slice(&output_index.to_be_bytes());
+    element
+}
+
+// Header of a filter: sha256(filter hash | previous header), all zeros before genesis
+pub fn filter_header(filter_hash: &[u8], prev_header: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(filter_hash);
+    hasher.input(prev_header);
+    let mut header = vec![0; 32];
+    hasher.result(&mut header);
+    header
+}
+
+impl BlockFilter {
+    // Builds the filter over the block's output pub key hashes and spent
+    // outpoints, data outputs pay nobody and are left out
+    pub fn build(block: &Block) -> Result<BlockFilter> {
+        let mut elements = Vec::new();
+        for tx in block.get_transactions() {
+            for output in tx.v_outputs.iter().filter(|output| !output.is_data()) {
+                elements.push(output_element(&output.pub_key_hash));
+            }
+            if !tx.is_coinbase() {
+                for input in &tx.v_inputs {
+                    elements.push(outpoint_element(&input.txid, input.output_index));
+                }
+            }
+        }
+        BlockFilter::new(&block.get_hash(), &elements)
+    }
+
+    pub fn new(block_hash: &str, elements: &[Vec<u8>]) -> Result<BlockFilter> {
+        let elements: BTreeSet<&Vec<u8>> = elements.iter().collect();
+        let n = elements.len() as u32;
+        let key = hex::decode(block_hash)?;
+        let mut values = BTreeSet::new();
+        for element in elements {
+            values.insert(hash_to_range(&key, element, n));
+        }
+
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in values {
+            let delta = value - last;
+            for _ in 0..delta >> FILTER_P {
+                writer.write_bit(true);
+            }
+            writer.write_bit(false);
+            writer.write_bits(delta, FILTER_P);
+            last = value;
+        }
+        Ok(BlockFilter {
+            n,
+            data: writer.bytes,
+        })
+    }
+
+    // Number of distinct elements in the filter
+    pub fn len(&self) -> usize {
+        self.n as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    // Hash of the filter, chained into the filter headers
+    pub fn hash(&self) -> Result<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        hasher.input(&bincode::serialize(self)?);
+        let mut hash = vec![0; 32];
+        hasher.result(&mut hash);
+        Ok(hash)
+    }
+
+    // True if any of the elements is probably in the set, false if none is
+    pub fn matches_any(&self, block_hash: &str, elements: &[Vec<u8>]) -> Result<bool> {
+        if self.n == 0 || elements.is_empty() {
+            return Ok(false);
+        }
+        let key = hex::decode(block_hash)?;
+        let queries: BTreeSet<u64> = elements
+            .iter()
+            .map(|element| hash_to_range(&key, element, self.n))
+            .collect();
+
+        let mut reader = BitReader::new(&self.data);
+        let mut queries = queries.into_iter().peekable();
+        let mut value = 0;
+        for _ in 0..self.n {
+            let mut quotient = 0;
+            loop {
+                match reader.read_bit() {
+                    Some(true) => quotient += 1,
+                    Some(false) => break,
+                    None => return Err(format_err!("Filter data is truncated")),
+                }
+            }
+            let remainder = match reader.read_bits(FILTER_P) {
+                Some(remainder) => remainder,
+                None => return Err(format_err!("Filter data is truncated")),
+            };
+            value += (quotient << FILTER_P) | remainder;
+
+            while let Some(query) = queries.peek() {
+                if *query == value {
+                    return Ok(true);
+                }
+                if *query > value {
+                    break;
+                }
+                queries.next();
+            }
+            if queries.peek().is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// Maps the keyed hash of the element uniformly to [0, n * FILTER_M)
+fn hash_to_range(key: &[u8], element: &[u8], n: u32) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.input(key);
+    hasher.input(element);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    let mut value = [0; 8];
+    value.copy_from_slice(&hash[..8]);
+    let range = n as u128 * FILTER_M as u128;
+    ((u64::from_be_bytes(value) as u128 * range) >> 64) as u64
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.bits.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> (self.bits % 8);
+        }
+        self.bits += 1;
+    }
+
+    // Writes the low `count` bits of the value, most significant first
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    bits: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, bits: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.bits / 8)?;
+        let bit = byte & (0x80 >> (self.bits % 8)) != 0;
+        self.bits += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+// Stores the filter of a block that is being connected, chained to the
+// filter header of its parent
+pub(crate) fn stage_connect<S: ChainStore>(
+    store: &S,
+    block: &Block,
+    batch: &mut StoreBatch,
+) -> Result<()> {
+    let prev_header = if block.get_height() == 0 {
+        vec![0; 32]
+    } else {
+        match store.get_cfilter(&block.get_prev_hash())? {
+            Some(entry) => entry.header,
+            None => {
+                return Err(format_err!(
+                    "Filter of block {} is not found",
+                    block.get_prev_hash()
+                ))
+            }
+        }
+    };
+    let filter = BlockFilter::build(block)?;
+    let entry = FilterEntry {
+        header: filter_header(&filter.hash()?, &prev_header),
+        filter,
+    };
+    batch.put_cfilter(&block.get_hash(), &entry);
+    Ok(())
+}
+
+// Builds the filters the active chain is missing, e.g. in a store written
+// before filters existed. Filters only depend on their block and its
+// ancestors, so the ones of disconnected blocks stay valid and are kept.
+pub(crate) fn build_missing<S: ChainStore>(bc: &Blockchain<S>) -> Result<()> {
+    if bc.store().get_cfilter(&bc.get_tip_hash())?.is_some() {
+        return Ok(());
+    }
+    let mut missing = Vec::new();
+    for block in bc.iter() {
+        if bc.store().get_cfilter(&block.get_hash())?.is_some() {
+            break;
+        }
+        missing.push(block);
+    }
+    info!("building {} block filters", missing.len());
+
+    // Each filter header needs the previous one, which is only in the store
+    // once written, so the batch is applied block by block
+    for block in missing.iter().rev() {
+        let mut batch = StoreBatch::default();
+        stage_connect(bc.store(), block, &mut batch)?;
+        bc.store().write_batch(batch)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::testutil::{mine, new_chain, pay, pub_key_hash};
+
+    const BLOCK_HASH: &str = "00000a1b2c3d4e5f00000a1b2c3d4e5f00000a1b2c3d4e5f00000a1b2c3d4e5f";
+
+    fn elements(prefix: &str, count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| format!("{}{}", prefix, i).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn matches_every_element_in_the_set() {
+        let members = elements("member", 500);
+        let filter = BlockFilter::new(BLOCK_HASH, &members).unwrap();
+        assert_eq!(filter.len(), 500);
+        for member in &members {
+            assert!(filter.matches_any(BLOCK_HASH, slice::from_ref(member)).unwrap());
+        }
+        let mut queries = elements("stranger", 10);
+        queries.push(members[250].clone());
+        assert!(filter.matches_any(BLOCK_HASH, &queries).unwrap());
+        assert!(!filter.matches_any(BLOCK_HASH, &[]).unwrap());
+
+        let empty = BlockFilter::new(BLOCK_HASH, &[]).unwrap();
+        assert!(empty.is_empty());
+        assert!(!empty.matches_any(BLOCK_HASH, &members).unwrap());
+    }
+
+    #[test]
+    fn false_positives_stay_around_one_in_m() {
+        let filter = BlockFilter::new(BLOCK_HASH, &elements("member", 500)).unwrap();
+        let false_positives = elements("stranger", 10_000)
+            .iter()
+            .filter(|stranger| filter.matches_any(BLOCK_HASH, slice::from_ref(stranger)).unwrap())
+            .count();
+        // 10_000 / FILTER_M is about 0.01 expected
+        assert!(false_positives <= 1, "{} false positives", false_positives);
+
+        // The set is keyed by the block hash, so the same elements under
+        // another block do not line up
+        let other_block = BLOCK_HASH.replace('a', "b");
+        let members = elements("member", 500);
+        let matching = members
+            .iter()
+            .filter(|member| filter.matches_any(&other_block, slice::from_ref(member)).unwrap())
+            .count();
+        assert!(matching <= 1, "{} members match under another block", matching);
+    }
+
+    #[test]
+    fn block_filters_match_the_outputs_and_spends_of_their_block() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let payment = pay(&utxo, &miner, &other, 10).unwrap();
+        let block = mine(&mut utxo, &miner, vec![payment.clone()]).unwrap();
+        let entry = utxo.blockchain.store().get_cfilter(&block.get_hash()).unwrap().unwrap();
+        assert_eq!(entry.filter, BlockFilter::build(&block).unwrap());
+
+        let hash = block.get_hash();
+        let receiver = output_element(&pub_key_hash(&utxo, &other));
+        assert!(entry.filter.matches_any(&hash, &[receiver]).unwrap());
+        let input = &payment.v_inputs[0];
+        let spent = outpoint_element(&input.txid, input.output_index);
+        assert!(entry.filter.matches_any(&hash, &[spent]).unwrap());
+        let unspent = outpoint_element(&payment.id, 0);
+        assert!(!entry.filter.matches_any(&hash, &[unspent]).unwrap());
+
+        // Its header chains the filter to the one of the parent block
+        let parent = utxo.blockchain.store().get_cfilter(&block.get_prev_hash()).unwrap().unwrap();
+        let header = filter_header(&entry.filter.hash().unwrap(), &parent.header);
+        assert_eq!(entry.header, header);
+    }
+}
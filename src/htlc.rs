@@ -0,0 +1,260 @@
+// htlc.rs
+
+use std::collections::HashMap;
+
+use crypto::{digest::Digest, sha2::Sha256};
+use failure::format_err;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Blockchain;
+use crate::consensus::LOCKTIME_THRESHOLD;
+use crate::errors::Result;
+use crate::store::ChainStore;
+use crate::transaction::Transaction;
+use crate::tx::{TXInput, TXOutput, SEQUENCE_FINAL};
+use crate::utxoset::UTXOSet;
+use crate::wallet::{address_of, Wallets};
+
+// Size of the secrets new_secret makes
+pub const SECRET_SIZE: usize = 32;
+
+// The conditions of a hash time-locked output, on top of the receiver's
+// pub_key_hash of the TXOutput it is attached to. The receiver spends it
+// with the preimage of `hash`, the sender takes it back once the lock time
+// passed. Part of TXOutput, so its encoding is part of the chain format.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Htlc {
+    // sha256 of the secret
+    pub hash: Vec<u8>,
+    pub refund_pub_key_hash: Vec<u8>,
+    // Block height, or unix time in seconds from LOCKTIME_THRESHOLD on,
+    // the refund is locked until
+    pub lock_time: u32,
+}
+
+impl Htlc {
+    pub fn is_preimage(&self, preimage: &[u8]) -> bool {
+        hash_secret(preimage) == self.hash
+    }
+
+    // True if a refund with this transaction lock time waits at least as
+    // long as the HTLC asks, counting in the same unit
+    pub fn is_refundable_at(&self, lock_time: u32) -> bool {
+        lock_time != 0
+            && (lock_time < LOCKTIME_THRESHOLD) == (self.lock_time < LOCKTIME_THRESHOLD)
+            && lock_time >= self.lock_time
+    }
+}
+
+pub fn hash_secret(secret: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(secret);
+    let mut hash = vec![0; 32];
+    hasher.result(&mut hash);
+    hash
+}
+
+// A random secret for the side starting a swap
+pub fn new_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_SIZE];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+// Spends the HTLC output to its receiver with the secret, signed by the
+// receiver's wallet from the local wallet DB
+pub fn redeem<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    txid: &str,
+    output_index: i32,
+    secret: &[u8],
+) -> Result<Transaction> {
+    let (output, htlc) = htlc_output(utxo, txid, output_index)?;
+    if !htlc.is_preimage(secret) {
+        return Err(format_err!("The secret does not match the HTLC hash"));
+    }
+    spend(
+        utxo,
+        txid,
+        output_index,
+        &output,
+        &output.pub_key_hash,
+        secret.to_vec(),
+        0,
+    )
+}
+
+// Spends the HTLC output back to its sender, signed by the sender's wallet.
+// The transaction is only valid in blocks past the HTLC's lock time.
+pub fn refund<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    txid: &str,
+    output_index: i32,
+) -> Result<Transaction> {
+    let (output, htlc) = htlc_output(utxo, txid, output_index)?;
+    spend(
+        utxo,
+        txid,
+        output_index,
+        &output,
+        &htlc.refund_pub_key_hash,
+        Vec::new(),
+        htlc.lock_time,
+    )
+}
+
+// Looks through the chain for a redeemed HTLC revealing the secret of `hash`,
+// which the other side of a swap needs to redeem its own HTLC
+pub fn find_secret<S: ChainStore>(bc: &Blockchain<S>, hash: &[u8]) -> Option<Vec<u8>> {
+    bc.iter()
+        .flat_map(|block| block.get_transactions().clone())
+        .flat_map(|tx| tx.v_inputs)
+        .map(|input| input.preimage)
+        .find(|preimage| !preimage.is_empty() && hash_secret(preimage) == hash)
+}
+
+// The unspent HTLC output with its conditions
+fn htlc_output<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    txid: &str,
+    output_index: i32,
+) -> Result<(TXOutput, Htlc)> {
+    let output = utxo
+        .blockchain
+        .store()
+        .get_utxos(txid)?
+        .and_then(|outputs| outputs.outputs.get(&output_index).cloned())
+        .ok_or_else(|| format_err!("Output {} of {} is not unspent", output_index, txid))?;
+    match output.htlc.clone() {
+        Some(htlc) => Ok((output, htlc)),
+        None => Err(format_err!(
+            "Output {} of {} is not an HTLC",
+            output_index,
+            txid
+        )),
+    }
+}
+
+// A transaction paying the whole output to the owner of `pub_key_hash`
+fn spend<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    txid: &str,
+    output_index: i32,
+    output: &TXOutput,
+    pub_key_hash: &[u8],
+    preimage: Vec<u8>,
+    lock_time: u32,
+) -> Result<Transaction> {
+    let address = address_of(pub_key_hash);
+    let wallets = Wallets::open(utxo.blockchain.store().clone())?;
+    let wallet = match wallets.get_wallet(&address) {
+        Some(wallet) => wallet,
+        None => return Err(format_err!("Wallet {} not found", address)),
+    };
+
+    let mut tx = Transaction {
+        id: String::new(),
+        v_inputs: vec![TXInput {
+            txid: txid.to_string(),
+            output_index,
+            signature: Vec::new(),
+            pub_key: wallet.public_key.clone(),
+            sequence: SEQUENCE_FINAL,
+            preimage,
+        }],
+        v_outputs: vec![TXOutput::new(output.value, address)?],
+        lock_time,
+    };
+    tx.id = tx.hash()?;
+    let prev_tx = utxo.blockchain.find_tranasaction(txid)?;
+    tx.sign(
+        &wallet.private_key,
+        HashMap::from([(prev_tx.id.clone(), prev_tx)]),
+    )?;
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::testutil::{mine, new_chain, resign, seal_block};
+    use crate::transaction::TransactionBuilder;
+
+    #[test]
+    fn redeems_only_with_the_preimage() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let secret = new_secret();
+        let offer = TransactionBuilder::new(&utxo)
+            .sender(&miner)
+            .pay_to_htlc(&other, 30, &hash_secret(&secret), 10)
+            .build()
+            .unwrap();
+        mine(&mut utxo, &miner, vec![offer.clone()]).unwrap();
+        assert!(redeem(&utxo, &offer.id, 0, &new_secret()).is_err());
+        assert!(find_secret(&utxo.blockchain, &offer.v_outputs[0].htlc.as_ref().unwrap().hash)
+            .is_none());
+
+        // A redeem signed over a wrong preimage does not get into a block
+        let mut forged = redeem(&utxo, &offer.id, 0, &secret).unwrap();
+        forged.v_inputs[0].preimage = new_secret();
+        resign(&utxo, &mut forged, &other).unwrap();
+        let block = seal_block(&utxo.blockchain, &miner, vec![forged]).unwrap();
+        assert!(utxo.blockchain.submit_block(&block).is_err());
+
+        let redeemed = redeem(&utxo, &offer.id, 0, &secret).unwrap();
+        mine(&mut utxo, &miner, vec![redeemed]).unwrap();
+        assert_eq!(find_secret(&utxo.blockchain, &hash_secret(&secret)), Some(secret));
+    }
+
+    #[test]
+    fn refunds_only_past_the_lock_time() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let offer = TransactionBuilder::new(&utxo)
+            .sender(&miner)
+            .pay_to_htlc(&other, 30, &hash_secret(&new_secret()), 3)
+            .build()
+            .unwrap();
+        mine(&mut utxo, &miner, vec![offer.clone()]).unwrap();
+
+        // Locking the refund for less than the HTLC asks fails the output's
+        // conditions whatever the height
+        let mut early = refund(&utxo, &offer.id, 0).unwrap();
+        early.lock_time = 1;
+        resign(&utxo, &mut early, &miner).unwrap();
+        mine(&mut utxo, &miner, vec![]).unwrap();
+        let block = seal_block(&utxo.blockchain, &miner, vec![early]).unwrap();
+        assert!(utxo.blockchain.submit_block(&block).is_err());
+
+        // The refund is final only in blocks above its lock time
+        let refunded = refund(&utxo, &offer.id, 0).unwrap();
+        let block = seal_block(&utxo.blockchain, &miner, vec![refunded.clone()]).unwrap();
+        assert_eq!(block.get_height(), 3);
+        assert!(utxo.blockchain.submit_block(&block).is_err());
+        mine(&mut utxo, &miner, vec![]).unwrap();
+        mine(&mut utxo, &miner, vec![refunded]).unwrap();
+    }
+
+    #[test]
+    fn refunds_count_the_lock_time_in_the_htlcs_unit() {
+        let htlc = Htlc {
+            hash: Vec::new(),
+            refund_pub_key_hash: Vec::new(),
+            lock_time: 10,
+        };
+        assert!(!htlc.is_refundable_at(0));
+        assert!(!htlc.is_refundable_at(9));
+        assert!(htlc.is_refundable_at(10));
+        assert!(htlc.is_refundable_at(LOCKTIME_THRESHOLD - 1));
+        assert!(!htlc.is_refundable_at(LOCKTIME_THRESHOLD));
+
+        let htlc = Htlc {
+            lock_time: LOCKTIME_THRESHOLD + 100,
+            ..htlc
+        };
+        assert!(!htlc.is_refundable_at(LOCKTIME_THRESHOLD - 1));
+        assert!(!htlc.is_refundable_at(LOCKTIME_THRESHOLD + 99));
+        assert!(htlc.is_refundable_at(LOCKTIME_THRESHOLD + 100));
+    }
+}
@@ -0,0 +1,75 @@
+//! A simplified implementation of a bitcoin based blockchain.
+//!
+//! The public API is what is re-exported from this file:
+//!
+//! * [`chain`]: blocks, transactions and the block database
+//! * [`consensus`]: proof of work target and block validation rules
+//! * [`engine`]: the `ConsensusEngine`s sealing blocks, proof of work or of authority
+//! * [`params`]: the networks and the consensus limits of each
+//! * [`miner`]: the multi-threaded proof of work miner
+//! * [`pow`]: the proof of work hash functions, SHA-256 or memory-hard scrypt
+//! * [`utxo`]: the unspent output set built from the chain
+//! * [`wallet`]: key pairs, addresses and the wallet database
+//! * [`filter`]: compact block filters for private light client scanning
+//! * [`htlc`]: hash time-locked outputs for atomic swaps
+//! * [`anchor`]: proofs of the block anchoring data in a data output
+//! * [`store`]: the `ChainStore` persistence trait with sled and in-memory backends
+//! * [`mempool`]: unconfirmed transactions, chains of them and the orphan pool
+//! * [`assembler`]: block templates filled from the mempool by ancestor package feerate
+//! * [`net`]: the peer to peer wire protocol and outbound peers
+//! * [`node`]: the peer to peer server
+//! * [`peers`]: connection limits, misbehavior scores and the ban list
+//! * [`addrbook`]: the persisted book of node addresses used for peer discovery
+//! * [`sync`]: headers-first download of the most-work chain from peers
+//! * [`spv`]: a light client keeping only headers and proven wallet transactions
+//! * [`rpc`]: the JSON-RPC server
+//!
+//! See "Semver policy" in the README for which changes are breaking.
+
+mod addrindex;
+mod block;
+mod blockchain;
+mod transaction;
+mod tx;
+mod txindex;
+mod utxoset;
+
+#[cfg(test)]
+mod testutil;
+
+pub mod addrbook;
+pub mod anchor;
+pub mod assembler;
+pub mod consensus;
+pub mod engine;
+pub mod errors;
+pub mod filter;
+pub mod htlc;
+pub mod mempool;
+pub mod miner;
+pub mod net;
+pub mod node;
+pub mod params;
+pub mod peers;
+pub mod pow;
+pub mod rpc;
+pub mod spv;
+pub mod store;
+pub mod sync;
+pub mod wallet;
+
+pub mod chain {
+    //! Blocks, transactions and the block database.
+
+    pub use crate::block::{Block, BlockHeader, MerkleBranch};
+    pub use crate::blockchain::{Blockchain, BlockchainIter};
+    pub use crate::transaction::{Transaction, TransactionBuilder};
+    pub use crate::tx::{Confirmation, RelativeLock, TXInput, TXOutput, TXOutputs};
+    pub use crate::txindex::TxLocation;
+}
+
+pub mod utxo {
+    //! The set of unspent transaction outputs.
+
+    pub use crate::utxoset::UTXOSet;
+}
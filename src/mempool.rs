@@ -0,0 +1,1000 @@
+// mempool.rs
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use failure::format_err;
+use log::info;
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::consensus::{
+    check_lock_time, check_sequence_locks, check_transaction, check_transaction_limits,
+};
+use crate::errors::Result;
+use crate::params::ChainParams;
+use crate::peers::unix_now;
+use crate::store::ChainStore;
+use crate::transaction::Transaction;
+use crate::tx::{Confirmation, TXOutput};
+
+// Most transactions waiting for their parents, the oldest is dropped past this
+pub const MAX_ORPHANS: usize = 100;
+
+// Largest transaction kept in the orphan pool, in bincode bytes
+pub const MAX_ORPHAN_TX_SIZE: usize = 100_000;
+
+// Orphans whose parents did not show up in this time are dropped
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+// Bincode bytes of transactions kept, the lowest feerates are evicted past this
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 50 * 1024 * 1024;
+
+// Transactions not mined in this time are dropped with their descendants
+pub const DEFAULT_MEMPOOL_EXPIRY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+// Most transactions one replacement may evict, descendants included
+pub const MAX_REPLACEMENTS: usize = 100;
+
+// What became of a transaction handed to the mempool
+#[derive(Debug, Clone, PartialEq)]
+pub enum Acceptance {
+    // The transaction and the orphans it completed, in the order they were added
+    Accepted(Vec<String>),
+    // Kept in the orphan pool until these parents show up
+    Orphan(Vec<String>),
+    // Already in the mempool or the orphan pool
+    Known,
+    // Spends an output the chain or a mempool transaction that can not be
+    // replaced already spent
+    Conflict(String),
+    // Valid but not kept: the mempool is full of transactions paying more,
+    // or a replacement does not pay more than what it replaces
+    Rejected(String),
+    // Breaks a rule no block could include it under, whoever sent it is to blame
+    Invalid(String),
+}
+
+// A transaction of the mempool with what it pays in fees
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    // Inputs minus outputs
+    pub fee: i64,
+    // Bincode bytes
+    pub size: usize,
+    // Unix time in seconds the transaction entered the mempool
+    pub time: u64,
+    // Order of arrival, parents always arrive before their children
+    seq: u64,
+}
+
+impl MempoolEntry {
+    // Orders entries by fee per byte without rounding
+    fn cmp_feerate(&self, other: &MempoolEntry) -> Ordering {
+        (self.fee as i128 * other.size as i128).cmp(&(other.fee as i128 * self.size as i128))
+    }
+}
+
+struct Orphan {
+    tx: Transaction,
+    missing: HashSet<String>,
+    time: u64,
+}
+
+// Valid transactions not in a block yet, spending confirmed outputs or the
+// outputs of other mempool transactions, and an orphan pool of the ones
+// whose parents are not known yet. Transactions that opt in may be replaced
+// by ones paying a higher fee, the lowest feerates are evicted when the
+// mempool is full and old transactions expire.
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+    // Sum of the entries' sizes
+    total_size: usize,
+    max_size: usize,
+    expiry: Duration,
+    // (txid, output index) -> mempool transaction spending it
+    spent: HashMap<(String, i32), String>,
+    next_seq: u64,
+    orphans: HashMap<String, Orphan>,
+    // Parent txid -> orphans waiting for it
+    waiting: HashMap<String, HashSet<String>>,
+    // Orphan txids, oldest first
+    orphan_order: VecDeque<String>,
+}
+
+// Where the output an input spends comes from
+enum Resolved {
+    // The output's value and transaction, with the block it was confirmed
+    // in, None for mempool outputs
+    Output(i32, Transaction, Option<Confirmation>),
+    Missing,
+    Spent(String),
+}
+
+impl Default for Mempool {
+    fn default() -> Mempool {
+        Mempool::with_limits(DEFAULT_MAX_MEMPOOL_SIZE, DEFAULT_MEMPOOL_EXPIRY)
+    }
+}
+
+impl Mempool {
+    // An empty mempool keeping at most `max_size` bytes of transactions for
+    // at most `expiry` each
+    pub fn with_limits(max_size: usize, expiry: Duration) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            total_size: 0,
+            max_size,
+            expiry,
+            spent: HashMap::new(),
+            next_seq: 0,
+            orphans: HashMap::new(),
+            waiting: HashMap::new(),
+            orphan_order: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Bincode bytes of the transactions in the mempool
+    pub fn size(&self) -> usize {
+        self.total_size
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    // True if the transaction is in the mempool or the orphan pool
+    pub fn contains(&self, txid: &str) -> bool {
+        self.entries.contains_key(txid) || self.orphans.contains_key(txid)
+    }
+
+    // The mempool transaction spending the output, if any
+    pub fn spender(&self, txid: &str, output_index: i32) -> Option<&str> {
+        self.spent
+            .get(&(txid.to_string(), output_index))
+            .map(String::as_str)
+    }
+
+    // The transaction and every mempool transaction spending its outputs,
+    // directly or not
+    pub fn descendants(&self, txid: &str) -> HashSet<String> {
+        let mut found = HashSet::new();
+        let mut queue = VecDeque::from([txid.to_string()]);
+        while let Some(txid) = queue.pop_front() {
+            let entry = match self.entries.get(&txid) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for index in 0..entry.tx.v_outputs.len() as i32 {
+                if let Some(child) = self.spender(&txid, index) {
+                    queue.push_back(child.to_string());
+                }
+            }
+            found.insert(txid);
+        }
+        found
+    }
+
+    // The transaction and the mempool transactions it spends, recursively
+    pub fn ancestors(&self, txid: &str) -> HashSet<String> {
+        let mut found = HashSet::new();
+        let mut queue = VecDeque::from([txid.to_string()]);
+        while let Some(txid) = queue.pop_front() {
+            if found.contains(&txid) {
+                continue;
+            }
+            let entry = match self.entries.get(&txid) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for input in &entry.tx.v_inputs {
+                if self.entries.contains_key(&input.txid) {
+                    queue.push_back(input.txid.clone());
+                }
+            }
+            found.insert(txid);
+        }
+        found
+    }
+
+    // Mempool transactions in arrival order, so parents come before children
+    pub fn entries(&self) -> Vec<&MempoolEntry> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.seq);
+        entries
+    }
+
+    pub fn transactions(&self) -> Vec<Transaction> {
+        self.entries()
+            .into_iter()
+            .map(|entry| entry.tx.clone())
+            .collect()
+    }
+
+    // Validates the transaction against the chain and the mempool and adds
+    // it, then retries the orphans that were waiting for it. Missing parents
+    // put it in the orphan pool. Errors are failures of the node, e.g. of
+    // its store, not of the transaction.
+    pub fn accept<S: ChainStore>(
+        &mut self,
+        bc: &Blockchain<S>,
+        tx: Transaction,
+    ) -> Result<Acceptance> {
+        self.expire(unix_now());
+        if self.contains(&tx.id) {
+            return Ok(Acceptance::Known);
+        }
+        let txid = tx.id.clone();
+        let acceptance = self.try_add(bc, tx)?;
+        if acceptance != Acceptance::Accepted(vec![txid.clone()]) {
+            return Ok(acceptance);
+        }
+        let mut accepted = vec![txid.clone()];
+        self.retry_orphans(bc, vec![txid], &mut accepted);
+        Ok(Acceptance::Accepted(accepted))
+    }
+
+    // Checks and adds one transaction, or puts it in the orphan pool
+    fn try_add<S: ChainStore>(
+        &mut self,
+        bc: &Blockchain<S>,
+        mut tx: Transaction,
+    ) -> Result<Acceptance> {
+        if let Err(e) = check_context_free(&tx, bc.params()) {
+            return Ok(Acceptance::Invalid(e.to_string()));
+        }
+        if bc.store().get_utxos(&tx.id)?.is_some() {
+            return Ok(Acceptance::Known);
+        }
+
+        // Mempool transactions spending the same outputs are replaced with
+        // their descendants if they all opted in
+        let mut conflicts = BTreeSet::new();
+        for input in &tx.v_inputs {
+            if let Some(spender) = self.spender(&input.txid, input.output_index) {
+                if !self.entries[spender].tx.is_replaceable() {
+                    return Ok(Acceptance::Conflict(format!(
+                        "Output {} of {} is spent by {}",
+                        input.output_index, input.txid, spender
+                    )));
+                }
+                conflicts.insert(spender.to_string());
+            }
+        }
+        let mut replaced = HashSet::new();
+        for conflict in &conflicts {
+            replaced.extend(self.descendants(conflict));
+        }
+        if replaced.len() > MAX_REPLACEMENTS {
+            return Ok(Acceptance::Rejected(format!(
+                "Replacing {} would evict {} transactions",
+                tx.id,
+                replaced.len()
+            )));
+        }
+
+        let mut prev_txs = HashMap::new();
+        let mut confirmations = Vec::new();
+        let mut missing = HashSet::new();
+        let mut input_value: i64 = 0;
+        for input in &tx.v_inputs {
+            if replaced.contains(&input.txid) {
+                return Ok(Acceptance::Invalid(format!(
+                    "Transaction {} spends an output of {} which it replaces",
+                    tx.id, input.txid
+                )));
+            }
+            match self.resolve(bc, &input.txid, input.output_index)? {
+                Resolved::Output(value, prev_tx, confirmation) => {
+                    input_value += value as i64;
+                    prev_txs.insert(prev_tx.id.clone(), prev_tx);
+                    confirmations.push(confirmation);
+                }
+                Resolved::Missing => {
+                    missing.insert(input.txid.clone());
+                }
+                Resolved::Spent(reason) => return Ok(Acceptance::Conflict(reason)),
+            }
+        }
+        if !missing.is_empty() {
+            let mut missing: Vec<String> = missing.into_iter().collect();
+            missing.sort();
+            let size = bincode::serialized_size(&tx)? as usize;
+            if size > MAX_ORPHAN_TX_SIZE {
+                return Ok(Acceptance::Rejected(format!(
+                    "Orphan transaction {} of {} bytes is too large",
+                    tx.id, size
+                )));
+            }
+            self.add_orphan(tx, &missing);
+            return Ok(Acceptance::Orphan(missing));
+        }
+        // Only transactions that can go in the next block are kept
+        let height = bc.get_best_height()? + 1;
+        let median_time_past = bc.median_time_past()?;
+        if let Err(e) = check_lock_time(&tx, height, median_time_past)
+            .and_then(|_| check_sequence_locks(&tx, &confirmations, height, median_time_past))
+        {
+            return Ok(Acceptance::Rejected(e.to_string()));
+        }
+
+        let output_value: i64 = tx.v_outputs.iter().map(|output| output.value as i64).sum();
+        if output_value > input_value {
+            return Ok(Acceptance::Invalid(format!(
+                "Transaction {} spends {} but only has {}",
+                tx.id, output_value, input_value
+            )));
+        }
+        if !tx.verify(prev_txs)? {
+            return Ok(Acceptance::Invalid(format!(
+                "Transaction {} is not validly signed",
+                tx.id
+            )));
+        }
+
+        let fee = input_value - output_value;
+        let replaced_fee: i64 = replaced.iter().map(|txid| self.entries[txid].fee).sum();
+        if !replaced.is_empty() && fee <= replaced_fee {
+            return Ok(Acceptance::Rejected(format!(
+                "Fee {} of {} does not exceed the {} of the transactions it replaces",
+                fee, tx.id, replaced_fee
+            )));
+        }
+        let mut removed = Vec::new();
+        for conflict in &conflicts {
+            removed.extend(self.remove_with_descendants(conflict));
+        }
+
+        let txid = tx.id.clone();
+        let entry = MempoolEntry {
+            fee,
+            size: bincode::serialized_size(&tx)? as usize,
+            time: unix_now(),
+            tx,
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        self.insert_entry(entry);
+        let evicted = self.trim();
+        if !self.entries.contains_key(&txid) {
+            // What it replaced or pushed out goes back, the mempool fitted before
+            for entry in removed.into_iter().chain(evicted) {
+                if entry.tx.id != txid {
+                    self.insert_entry(entry);
+                }
+            }
+            return Ok(Acceptance::Rejected(format!(
+                "Mempool is full of transactions paying more than {}",
+                txid
+            )));
+        }
+        for conflict in &conflicts {
+            info!("{} replaces {}", txid, conflict);
+        }
+        info!("mempool accepted {}", txid);
+        Ok(Acceptance::Accepted(vec![txid]))
+    }
+
+    // Evicts the lowest feerate transactions, with their descendants, until
+    // the mempool fits its size limit. The newest go first among equal feerates.
+    // Returns the evicted entries.
+    fn trim(&mut self) -> Vec<MempoolEntry> {
+        let mut evicted = Vec::new();
+        while self.total_size > self.max_size {
+            let lowest = self
+                .entries
+                .values()
+                .min_by(|a, b| a.cmp_feerate(b).then(b.seq.cmp(&a.seq)))
+                .map(|entry| entry.tx.id.clone());
+            match lowest {
+                Some(txid) => {
+                    info!("mempool full, evicting {}", txid);
+                    evicted.extend(self.remove_with_descendants(&txid));
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    // Drops the transactions that stayed past the expiry with their
+    // descendants, and the orphans that waited past ORPHAN_EXPIRY
+    pub fn expire(&mut self, now: u64) {
+        let expired: Vec<String> = self
+            .entries
+            .values()
+            .filter(|entry| entry.time.saturating_add(self.expiry.as_secs()) <= now)
+            .map(|entry| entry.tx.id.clone())
+            .collect();
+        for txid in expired {
+            info!("{} expired from the mempool", txid);
+            self.remove_with_descendants(&txid);
+        }
+        let expired: Vec<String> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.time.saturating_add(ORPHAN_EXPIRY.as_secs()) <= now)
+            .map(|(txid, _)| txid.clone())
+            .collect();
+        for txid in expired {
+            self.remove_orphan(&txid);
+        }
+    }
+
+    // Finds the output in the mempool or the chain's UTXO set, with the
+    // transaction holding it for the signature check
+    fn resolve<S: ChainStore>(
+        &self,
+        bc: &Blockchain<S>,
+        txid: &str,
+        output_index: i32,
+    ) -> Result<Resolved> {
+        if let Some(entry) = self.entries.get(txid) {
+            return Ok(
+                match usize::try_from(output_index)
+                    .ok()
+                    .and_then(|index| entry.tx.v_outputs.get(index))
+                {
+                    Some(output) => Resolved::Output(output.value, entry.tx.clone(), None),
+                    None => Resolved::Spent(format!("{} has no output {}", txid, output_index)),
+                },
+            );
+        }
+        match bc.store().get_utxos(txid)? {
+            Some(outputs) => match outputs.outputs.get(&output_index) {
+                Some(output) => Ok(Resolved::Output(
+                    output.value,
+                    bc.find_tranasaction(txid)?,
+                    Some(outputs.confirmation),
+                )),
+                None => Ok(Resolved::Spent(format!(
+                    "Output {} of {} is already spent",
+                    output_index, txid
+                ))),
+            },
+            // Confirmed with all its outputs spent, if the index can tell
+            None if bc.get_tx_location(txid)?.is_some() => Ok(Resolved::Spent(format!(
+                "Outputs of {} are already spent",
+                txid
+            ))),
+            None => Ok(Resolved::Missing),
+        }
+    }
+
+    // The unspent output of the mempool or the chain's UTXO set, if any
+    pub fn output<S: ChainStore>(
+        &self,
+        bc: &Blockchain<S>,
+        txid: &str,
+        output_index: i32,
+    ) -> Result<Option<TXOutput>> {
+        if let Some(entry) = self.entries.get(txid) {
+            return Ok(usize::try_from(output_index)
+                .ok()
+                .and_then(|index| entry.tx.v_outputs.get(index))
+                .cloned());
+        }
+        Ok(bc
+            .store()
+            .get_utxos(txid)?
+            .and_then(|outputs| outputs.outputs.get(&output_index).cloned()))
+    }
+
+    // Transactions of the mempool or the chain the inputs spend, for signing
+    pub fn prev_txs<S: ChainStore>(
+        &self,
+        bc: &Blockchain<S>,
+        tx: &Transaction,
+    ) -> Result<HashMap<String, Transaction>> {
+        let mut prev_txs = HashMap::new();
+        for input in &tx.v_inputs {
+            let prev_tx = match self.entries.get(&input.txid) {
+                Some(entry) => entry.tx.clone(),
+                None => bc.find_tranasaction(&input.txid)?,
+            };
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
+        Ok(prev_txs)
+    }
+
+    // Unspent outputs of mempool transactions locked to the pub key hash
+    pub fn unspent_outputs(&self, pub_key_hash: &[u8]) -> Vec<(String, i32, TXOutput)> {
+        let mut unspent = Vec::new();
+        for entry in self.entries() {
+            for (index, output) in (0..).zip(&entry.tx.v_outputs) {
+                if output.is_locked_with_key(pub_key_hash)
+                    && self.spender(&entry.tx.id, index).is_none()
+                {
+                    unspent.push((entry.tx.id.clone(), index, output.clone()));
+                }
+            }
+        }
+        unspent
+    }
+
+    fn add_orphan(&mut self, tx: Transaction, missing: &[String]) {
+        while self.orphans.len() >= MAX_ORPHANS {
+            match self.orphan_order.pop_front() {
+                Some(oldest) => {
+                    self.remove_orphan(&oldest);
+                }
+                None => break,
+            }
+        }
+        for parent in missing {
+            self.waiting
+                .entry(parent.clone())
+                .or_default()
+                .insert(tx.id.clone());
+        }
+        self.orphan_order.push_back(tx.id.clone());
+        self.orphans.insert(
+            tx.id.clone(),
+            Orphan {
+                tx,
+                missing: missing.iter().cloned().collect(),
+                time: unix_now(),
+            },
+        );
+    }
+
+    fn remove_orphan(&mut self, txid: &str) -> Option<Transaction> {
+        let orphan = self.orphans.remove(txid)?;
+        for parent in &orphan.missing {
+            if let Some(children) = self.waiting.get_mut(parent) {
+                children.remove(txid);
+                if children.is_empty() {
+                    self.waiting.remove(parent);
+                }
+            }
+        }
+        self.orphan_order.retain(|id| id != txid);
+        Some(orphan.tx)
+    }
+
+    // Tries the orphans waiting for the new parents again, and the orphans
+    // of those that get in, adding the accepted ones to `accepted`
+    fn retry_orphans<S: ChainStore>(
+        &mut self,
+        bc: &Blockchain<S>,
+        parents: Vec<String>,
+        accepted: &mut Vec<String>,
+    ) {
+        let mut queue: VecDeque<String> = parents.into();
+        while let Some(parent) = queue.pop_front() {
+            let children: Vec<String> = match self.waiting.get(&parent) {
+                Some(children) => children.iter().cloned().collect(),
+                None => continue,
+            };
+            for child in children {
+                let tx = match self.remove_orphan(&child) {
+                    Some(tx) => tx,
+                    None => continue,
+                };
+                // Invalid orphans and conflicting ones are dropped, the ones
+                // still missing a parent go back to the pool
+                match self.try_add(bc, tx) {
+                    Ok(Acceptance::Accepted(ids)) => {
+                        queue.extend(ids.iter().cloned());
+                        accepted.extend(ids);
+                    }
+                    Ok(_) => {}
+                    Err(e) => info!("dropping orphan {}: {}", child, e),
+                }
+            }
+        }
+    }
+
+    fn insert_entry(&mut self, entry: MempoolEntry) {
+        for input in &entry.tx.v_inputs {
+            self.spent
+                .insert((input.txid.clone(), input.output_index), entry.tx.id.clone());
+        }
+        self.total_size += entry.size;
+        self.entries.insert(entry.tx.id.clone(), entry);
+    }
+
+    fn remove_entry(&mut self, txid: &str) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        for input in &entry.tx.v_inputs {
+            self.spent.remove(&(input.txid.clone(), input.output_index));
+        }
+        self.total_size -= entry.size;
+        Some(entry)
+    }
+
+    // Removes the transaction, what spends its outputs and so on, and returns
+    // the removed entries in arrival order
+    fn remove_with_descendants(&mut self, txid: &str) -> Vec<MempoolEntry> {
+        let mut removed: Vec<MempoolEntry> = self
+            .descendants(txid)
+            .iter()
+            .filter_map(|txid| self.remove_entry(txid))
+            .collect();
+        removed.sort_by_key(|entry| entry.seq);
+        removed
+    }
+
+    // Drops the block's transactions and the ones conflicting with them, and
+    // retries the orphans that were waiting for the block's transactions.
+    // Returns the ids of the orphans that got in.
+    pub fn block_connected<S: ChainStore>(
+        &mut self,
+        bc: &Blockchain<S>,
+        block: &Block,
+    ) -> Vec<String> {
+        let mut confirmed = Vec::new();
+        for tx in block.get_transactions() {
+            confirmed.push(tx.id.clone());
+            if tx.is_coinbase() {
+                continue;
+            }
+            self.remove_entry(&tx.id);
+            for input in &tx.v_inputs {
+                let conflicting = self
+                    .spent
+                    .get(&(input.txid.clone(), input.output_index))
+                    .cloned();
+                if let Some(conflicting) = conflicting {
+                    info!(
+                        "dropping {}, it conflicts with block {}",
+                        conflicting,
+                        block.get_hash()
+                    );
+                    self.remove_with_descendants(&conflicting);
+                }
+            }
+        }
+        let mut accepted = Vec::new();
+        self.retry_orphans(bc, confirmed, &mut accepted);
+        accepted
+    }
+
+    // Puts the transactions of a block the chain no longer has back in the
+    // mempool, `bc` being at its parent. The mempool transactions spending
+    // their outputs are checked again after them, keeping parents before
+    // children, and dropped if they no longer get in, e.g. when they spend
+    // the block's coinbase.
+    pub fn block_disconnected<S: ChainStore>(&mut self, bc: &Blockchain<S>, block: &Block) {
+        let mut children = Vec::new();
+        for tx in block.get_transactions() {
+            for index in 0..tx.v_outputs.len() as i32 {
+                if let Some(child) = self.spender(&tx.id, index).map(str::to_string) {
+                    children.extend(self.remove_with_descendants(&child));
+                }
+            }
+        }
+        children.sort_by_key(|entry| entry.seq);
+        let transactions = block.get_transactions().iter().filter(|tx| !tx.is_coinbase());
+        for tx in transactions.cloned().chain(children.into_iter().map(|entry| entry.tx)) {
+            let txid = tx.id.clone();
+            match self.try_add(bc, tx) {
+                Ok(Acceptance::Accepted(_)) => {}
+                Ok(acceptance) => {
+                    info!("dropping {} after the reorg: {:?}", txid, acceptance);
+                    self.remove_orphan(&txid);
+                }
+                Err(e) => info!("dropping {} after the reorg: {}", txid, e),
+            }
+        }
+    }
+}
+
+// Checks that do not need the chain
+fn check_context_free(tx: &Transaction, params: &ChainParams) -> Result<()> {
+    if tx.is_coinbase() {
+        return Err(format_err!("Coinbase {} is only valid in a block", tx.id));
+    }
+    check_transaction_limits(tx, params)?;
+    check_transaction(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::testutil::{mine, new_chain};
+    use crate::transaction::TransactionBuilder;
+    use crate::utxoset::UTXOSet;
+    use crate::wallet::Wallets;
+
+    // Pays `amount` and `fee` from the wallet, spending the mempool's
+    // outputs too
+    fn spend(
+        utxo: &UTXOSet<MemoryStore>,
+        mempool: &Mempool,
+        from: &str,
+        to: &str,
+        amount: i32,
+        fee: i32,
+    ) -> Transaction {
+        TransactionBuilder::new(utxo)
+            .mempool(mempool)
+            .sender(from)
+            .pay_to(to, amount)
+            .fee(fee)
+            .replaceable()
+            .build()
+            .unwrap()
+    }
+
+    fn accepted(mempool: &mut Mempool, utxo: &UTXOSet<MemoryStore>, tx: &Transaction) {
+        let acceptance = mempool.accept(&utxo.blockchain, tx.clone()).unwrap();
+        assert_eq!(acceptance, Acceptance::Accepted(vec![tx.id.clone()]));
+    }
+
+    #[test]
+    fn keeps_an_orphan_until_its_parent_arrives() {
+        let (utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut relay = Mempool::default();
+        let parent = spend(&utxo, &relay, &miner, &other, 30, 1);
+        accepted(&mut relay, &utxo, &parent);
+        let child = spend(&utxo, &relay, &other, &miner, 10, 1);
+
+        let mut mempool = Mempool::default();
+        let acceptance = mempool.accept(&utxo.blockchain, child.clone()).unwrap();
+        assert_eq!(acceptance, Acceptance::Orphan(vec![parent.id.clone()]));
+        assert_eq!(mempool.orphan_count(), 1);
+        assert!(mempool.is_empty());
+        assert_eq!(
+            mempool.accept(&utxo.blockchain, child.clone()).unwrap(),
+            Acceptance::Known
+        );
+
+        let acceptance = mempool.accept(&utxo.blockchain, parent.clone()).unwrap();
+        assert_eq!(acceptance, Acceptance::Accepted(vec![parent.id, child.id]));
+        assert_eq!(mempool.orphan_count(), 0);
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn reports_invalid_transactions_apart_from_errors() {
+        let (utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut mempool = Mempool::default();
+        let mut forged = spend(&utxo, &mempool, &miner, &other, 30, 1);
+        forged.v_inputs[0].signature[0] ^= 1;
+        let acceptance = mempool.accept(&utxo.blockchain, forged).unwrap();
+        assert!(matches!(acceptance, Acceptance::Invalid(_)), "{:?}", acceptance);
+
+        let mut overspent = spend(&utxo, &mempool, &miner, &other, 30, 1);
+        overspent.v_outputs[0].value += 1000;
+        overspent.id = overspent.derived_id().unwrap();
+        let acceptance = mempool.accept(&utxo.blockchain, overspent).unwrap();
+        assert!(matches!(acceptance, Acceptance::Invalid(_)), "{:?}", acceptance);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn chains_unconfirmed_parents_until_they_are_mined() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut mempool = Mempool::default();
+        let parent = spend(&utxo, &mempool, &miner, &other, 30, 1);
+        accepted(&mut mempool, &utxo, &parent);
+        let child = spend(&utxo, &mempool, &other, &miner, 20, 1);
+        accepted(&mut mempool, &utxo, &child);
+        let grandchild = spend(&utxo, &mempool, &other, &miner, 5, 1);
+        accepted(&mut mempool, &utxo, &grandchild);
+        assert_eq!(child.v_inputs[0].txid, parent.id);
+        assert_eq!(grandchild.v_inputs[0].txid, child.id);
+        assert_eq!(mempool.descendants(&parent.id).len(), 3);
+        assert_eq!(mempool.ancestors(&grandchild.id).len(), 3);
+        assert_eq!(mempool.transactions().len(), 3);
+        assert_eq!(mempool.transactions()[0].id, parent.id);
+
+        let block = mine(&mut utxo, &miner, vec![parent.clone()]).unwrap();
+        mempool.block_connected(&utxo.blockchain, &block);
+        assert!(mempool.get(&parent.id).is_none());
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.ancestors(&grandchild.id).len(), 2);
+    }
+
+    #[test]
+    fn takes_back_the_transactions_of_a_disconnected_block() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut wallets = Wallets::open(utxo.blockchain.store().clone()).unwrap();
+        let third = wallets.create_wallet();
+        wallets.save_all().unwrap();
+        let mut mempool = Mempool::default();
+        let payment = spend(&utxo, &mempool, &miner, &other, 30, 0);
+        let block = mine(&mut utxo, &third, vec![payment.clone()]).unwrap();
+        // One spends the payment, the other the coinbase of the same block
+        let child = spend(&utxo, &mempool, &other, &miner, 20, 1);
+        accepted(&mut mempool, &utxo, &child);
+        let coinbase_spend = spend(&utxo, &mempool, &third, &miner, 50, 1);
+        accepted(&mut mempool, &utxo, &coinbase_spend);
+
+        utxo.blockchain.disconnect_tip().unwrap();
+        mempool.block_disconnected(&utxo.blockchain, &block);
+        let ids: Vec<String> = mempool.transactions().into_iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, vec![payment.id, child.id]);
+        assert_eq!(mempool.orphan_count(), 0);
+        assert_eq!(mempool.spender(&block.get_transactions()[0].id, 0), None);
+    }
+
+    #[test]
+    fn replaces_an_opted_in_transaction_paying_more() {
+        let (utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut mempool = Mempool::default();
+        let original = spend(&utxo, &mempool, &miner, &other, 30, 1);
+        accepted(&mut mempool, &utxo, &original);
+
+        let same_fee = TransactionBuilder::new(&utxo)
+            .mempool(&mempool)
+            .replacing(&original)
+            .sender(&miner)
+            .pay_to(&other, 30)
+            .fee(1)
+            .build()
+            .unwrap();
+        let acceptance = mempool.accept(&utxo.blockchain, same_fee).unwrap();
+        assert!(matches!(acceptance, Acceptance::Rejected(_)), "{:?}", acceptance);
+        assert!(mempool.get(&original.id).is_some());
+
+        let replacement = TransactionBuilder::new(&utxo)
+            .mempool(&mempool)
+            .replacing(&original)
+            .sender(&miner)
+            .pay_to(&other, 30)
+            .fee(2)
+            .build()
+            .unwrap();
+        accepted(&mut mempool, &utxo, &replacement);
+        assert!(mempool.get(&original.id).is_none());
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn does_not_replace_a_transaction_that_did_not_opt_in() {
+        let (utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut mempool = Mempool::default();
+        let original = TransactionBuilder::new(&utxo)
+            .sender(&miner)
+            .pay_to(&other, 30)
+            .build()
+            .unwrap();
+        accepted(&mut mempool, &utxo, &original);
+
+        let replacement = TransactionBuilder::new(&utxo)
+            .mempool(&mempool)
+            .replacing(&original)
+            .sender(&miner)
+            .pay_to(&other, 30)
+            .fee(10)
+            .build()
+            .unwrap();
+        let acceptance = mempool.accept(&utxo.blockchain, replacement).unwrap();
+        assert!(matches!(acceptance, Acceptance::Conflict(_)), "{:?}", acceptance);
+        assert!(mempool.get(&original.id).is_some());
+    }
+
+    // As bumpfee does: the new fee has to exceed what the transaction and
+    // its descendants pay together
+    #[test]
+    fn bumps_the_fee_over_the_transaction_and_its_descendants() {
+        let (utxo, miner, other) = new_chain(ChainParams::regtest());
+        let mut mempool = Mempool::default();
+        let original = spend(&utxo, &mempool, &miner, &other, 30, 1);
+        accepted(&mut mempool, &utxo, &original);
+        let child = spend(&utxo, &mempool, &miner, &other, 10, 2);
+        accepted(&mut mempool, &utxo, &child);
+        assert_eq!(child.v_inputs[0].txid, original.id);
+
+        let bump = |fee| {
+            TransactionBuilder::new(&utxo)
+                .mempool(&mempool)
+                .replacing(&original)
+                .sender(&miner)
+                .pay_to(&other, 30)
+                .fee(fee)
+                .replaceable()
+                .build()
+                .unwrap()
+        };
+        let (low, high) = (bump(3), bump(4));
+        let acceptance = mempool.accept(&utxo.blockchain, low).unwrap();
+        assert!(matches!(acceptance, Acceptance::Rejected(_)), "{:?}", acceptance);
+        assert_eq!(mempool.len(), 2);
+
+        accepted(&mut mempool, &utxo, &high);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.get(&high.id).unwrap().fee, 4);
+        assert!(mempool.get(&high.id).unwrap().tx.is_replaceable());
+    }
+
+    #[test]
+    fn evicts_the_lowest_feerate_when_full() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        for _ in 0..3 {
+            mine(&mut utxo, &miner, Vec::new()).unwrap();
+        }
+        let mut mempool = Mempool::default();
+        let mut txs = Vec::new();
+        for fee in [1, 3, 2, 1] {
+            let tx = spend(&utxo, &mempool, &miner, &other, 50, fee);
+            accepted(&mut mempool, &utxo, &tx);
+            txs.push(tx);
+        }
+        let size = mempool.get(&txs[0].id).unwrap().size;
+        assert!(txs.iter().all(|tx| mempool.get(&tx.id).unwrap().size == size));
+
+        let mut full = Mempool::with_limits(2 * size, DEFAULT_MEMPOOL_EXPIRY);
+        accepted(&mut full, &utxo, &txs[0]);
+        accepted(&mut full, &utxo, &txs[1]);
+        // Pays more than the first, which makes room
+        accepted(&mut full, &utxo, &txs[2]);
+        assert!(full.get(&txs[0].id).is_none());
+        assert_eq!(full.size(), 2 * size);
+        // Pays less than both
+        let acceptance = full.accept(&utxo.blockchain, txs[3].clone()).unwrap();
+        assert!(matches!(acceptance, Acceptance::Rejected(_)), "{:?}", acceptance);
+        assert!(full.get(&txs[1].id).is_some() && full.get(&txs[2].id).is_some());
+    }
+
+    #[test]
+    fn keeps_the_replaced_transaction_when_the_replacement_is_evicted() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let mut mempool = Mempool::default();
+        let original = spend(&utxo, &mempool, &miner, &other, 30, 1);
+        accepted(&mut mempool, &utxo, &original);
+        let unrelated = spend(&utxo, &mempool, &miner, &other, 50, 50);
+        accepted(&mut mempool, &utxo, &unrelated);
+        assert_ne!(unrelated.v_inputs[0].txid, original.id);
+
+        // Pays a bit more than the original, for many more bytes
+        let mut builder = TransactionBuilder::new(&utxo)
+            .mempool(&mempool)
+            .replacing(&original)
+            .sender(&miner)
+            .fee(2);
+        for _ in 0..8 {
+            builder = builder.pay_to(&other, 3);
+        }
+        let replacement = builder.build().unwrap();
+
+        let mut full = Mempool::with_limits(mempool.size(), DEFAULT_MEMPOOL_EXPIRY);
+        accepted(&mut full, &utxo, &original);
+        accepted(&mut full, &utxo, &unrelated);
+        let acceptance = full.accept(&utxo.blockchain, replacement).unwrap();
+        assert!(matches!(acceptance, Acceptance::Rejected(_)), "{:?}", acceptance);
+        assert_eq!(full.len(), 2);
+        assert!(full.get(&original.id).is_some());
+        assert_eq!(full.spender(&original.v_inputs[0].txid, 0), Some(original.id.as_str()));
+        assert_eq!(full.size(), mempool.size());
+    }
+
+    #[test]
+    fn expires_old_transactions_with_their_descendants() {
+        let (utxo, miner, other) = new_chain(ChainParams::regtest());
+        let expiry = Duration::from_secs(60);
+        let mut mempool = Mempool::with_limits(DEFAULT_MAX_MEMPOOL_SIZE, expiry);
+        let parent = spend(&utxo, &mempool, &miner, &other, 30, 1);
+        accepted(&mut mempool, &utxo, &parent);
+        let child = spend(&utxo, &mempool, &miner, &other, 10, 1);
+        accepted(&mut mempool, &utxo, &child);
+
+        mempool.expire(unix_now() + 30);
+        assert_eq!(mempool.len(), 2);
+        mempool.expire(mempool.get(&parent.id).unwrap().time + expiry.as_secs());
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.size(), 0);
+    }
+}
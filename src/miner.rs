@@ -0,0 +1,184 @@
+// miner.rs
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::block::{meets_target, Block, HEADER_SIZE, NONCE_OFFSET};
+use crate::errors::Result;
+use crate::pow::{PowHasher, Sha256Hasher};
+
+// Number of hashes a worker does between two looks at the stop flags, few
+// enough that a slow memory-hard hash still stops quickly
+const CHECK_INTERVAL: u64 = 64;
+
+// Stops a running search, e.g. when a competing block for the same height arrives.
+// Clones share the flag.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// Tokens are equal when they share the flag
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &CancelToken) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+}
+
+// What a search cost
+#[derive(Debug, Clone)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    // Hashes per second
+    pub fn hashrate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.hashes as f64 / secs
+    }
+}
+
+// Proof of work miner splitting the nonce space across worker threads.
+// Worker i tries the nonces i, i + threads, i + 2 * threads, ...
+#[derive(Debug)]
+pub struct Miner {
+    threads: usize,
+    hasher: Box<dyn PowHasher>,
+}
+
+impl Default for Miner {
+    // One worker per available core
+    fn default() -> Miner {
+        Miner::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+impl Miner {
+    // A SHA-256 miner, see with_hasher for other networks
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads: threads.max(1),
+            hasher: Box::new(Sha256Hasher),
+        }
+    }
+
+    // Searches with the proof of work hash of the chain's network
+    pub fn with_hasher(mut self, hasher: Box<dyn PowHasher>) -> Miner {
+        self.hasher = hasher;
+        self
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn hasher(&self) -> &dyn PowHasher {
+        &*self.hasher
+    }
+
+    // Searches a nonce for the block template. When the whole nonce space is
+    // exhausted the coinbase extra nonce is bumped and the search starts over.
+    // Returns None if the token was cancelled first.
+    pub fn mine(
+        &self,
+        mut block: Block,
+        cancel: &CancelToken,
+    ) -> Result<Option<(Block, MiningStats)>> {
+        info!("Mining the block with {} threads", self.threads);
+        let start = Instant::now();
+        let hashes = AtomicU64::new(0);
+        let mut extra_nonce = 0;
+
+        loop {
+            let header = block.header()?;
+            if let Some(nonce) = self.search(&header, cancel, &hashes) {
+                block.set_nonce(nonce)?;
+                let stats = MiningStats {
+                    hashes: hashes.load(Ordering::Relaxed),
+                    elapsed: start.elapsed(),
+                };
+                info!(
+                    "Mined block {} with {} hashes ({:.0} H/s)",
+                    block.get_hash(),
+                    stats.hashes,
+                    stats.hashrate()
+                );
+                return Ok(Some((block, stats)));
+            }
+            if cancel.is_cancelled() {
+                info!("Mining cancelled");
+                return Ok(None);
+            }
+            extra_nonce += 1;
+            info!("Nonce space exhausted, extra nonce {}", extra_nonce);
+            block.set_extra_nonce(extra_nonce)?;
+        }
+    }
+
+    // Runs the workers over the header until one finds a nonce, the token
+    // is cancelled or every nonce was tried
+    fn search(
+        &self,
+        header: &[u8; HEADER_SIZE],
+        cancel: &CancelToken,
+        hashes: &AtomicU64,
+    ) -> Option<u64> {
+        let found = AtomicBool::new(false);
+        let result = Mutex::new(None);
+
+        thread::scope(|scope| {
+            for worker in 0..self.threads {
+                let (found, result) = (&found, &result);
+                scope.spawn(move || {
+                    let mut header = *header;
+                    let mut nonce = worker as u64;
+                    let mut done = 0;
+                    loop {
+                        header[NONCE_OFFSET..].copy_from_slice(&nonce.to_be_bytes());
+                        done += 1;
+                        if meets_target(&self.hasher.hash(&header)) {
+                            found.store(true, Ordering::Relaxed);
+                            *result.lock().unwrap() = Some(nonce);
+                            break;
+                        }
+                        if done % CHECK_INTERVAL == 0
+                            && (found.load(Ordering::Relaxed) || cancel.is_cancelled())
+                        {
+                            break;
+                        }
+                        nonce = match nonce.checked_add(self.threads as u64) {
+                            Some(nonce) => nonce,
+                            None => break,
+                        };
+                    }
+                    hashes.fetch_add(done, Ordering::Relaxed);
+                });
+            }
+        });
+
+        result.into_inner().unwrap()
+    }
+}
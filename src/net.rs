@@ -0,0 +1,502 @@
+// net.rs
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use bincode::Options;
+use failure::format_err;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::addrbook::MAX_ADDR;
+use crate::block::{Block, BlockHeader, MerkleBranch};
+use crate::errors::Result;
+use crate::filter::BlockFilter;
+use crate::peers::{PeerManager, MALFORMED_MESSAGE_SCORE};
+use crate::transaction::Transaction;
+
+pub const DEFAULT_P2P_PORT: u16 = 9333;
+
+// Version of the message set below, peers with another version are dropped
+pub const PROTOCOL_VERSION: u32 = 9;
+
+// Most headers sent in one Headers message
+pub const MAX_HEADERS: usize = 2000;
+
+// Most locator hashes a node looks up, more than any chain's locator has
+pub const MAX_LOCATOR: usize = 101;
+
+// Most blocks asked for or sent in one message
+pub const MAX_BLOCKS: usize = 16;
+
+// Most blocks a node searches for one GetProofs message
+pub const MAX_PROOF_BLOCKS: usize = 2000;
+
+// Most filters and filter hashes sent in one CFilters or CFHeaders message
+pub const MAX_CFILTERS: usize = 1000;
+pub const MAX_CFHEADERS: usize = 2000;
+
+// Most transaction ids announced or asked for in one Inv or GetData message
+pub const MAX_INV: usize = 1000;
+
+// Most transactions sent in one Txs message
+pub const MAX_TXS: usize = 100;
+
+// Largest message a peer may send
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+// How long a peer may take to answer or accept a message
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Messages exchanged between nodes. Every message is sent as a 4 byte big
+// endian length followed by the bincode encoding of the message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    // First message both sides send on a new connection. `listen_port` is
+    // the port the sender accepts peers on, 0 if none, and `nonce` is random
+    // per node so a node notices when it connected to itself.
+    Version {
+        version: u32,
+        best_height: usize,
+        tip: String,
+        listen_port: u16,
+        nonce: u64,
+    },
+    // Asks for the headers following the first locator hash that is on the
+    // peer's active chain, or from genesis if none is
+    GetHeaders {
+        locator: Vec<String>,
+    },
+    Headers(Vec<BlockHeader>),
+    // Asks for full blocks by hash, the answer leaves out unknown blocks
+    GetBlocks(Vec<String>),
+    Blocks(Vec<Block>),
+    // Asks for the transactions paying or spending any of the pub key hashes
+    // in the active chain blocks from `from_height` up to, not including, `to_height`
+    GetProofs {
+        pub_key_hashes: Vec<Vec<u8>>,
+        from_height: usize,
+        to_height: usize,
+    },
+    // The matching transactions of the blocks below `next_height`, and the hash
+    // of the last block searched so the asker can tell it is on the same chain
+    Proofs {
+        proofs: Vec<TxProof>,
+        next_height: usize,
+        last_hash: String,
+    },
+    // Asks for the compact filters of the active chain blocks from
+    // `start_height` up to the block `stop_hash`, the answer may stop early
+    GetCFilters {
+        start_height: usize,
+        stop_hash: String,
+    },
+    // Block hashes with their filters, in height order
+    CFilters(Vec<(String, BlockFilter)>),
+    // Asks for the filter hashes of the same range as GetCFilters
+    GetCFHeaders {
+        start_height: usize,
+        stop_hash: String,
+    },
+    // The filter header before the range and the filter hashes of the range,
+    // from which the filter headers are recomputed
+    CFHeaders {
+        prev_header: Vec<u8>,
+        filter_hashes: Vec<Vec<u8>>,
+    },
+    // Asks for node addresses to connect to
+    GetAddr,
+    // Node addresses with the unix time they were last seen up, at most MAX_ADDR
+    Addr(Vec<(SocketAddr, u64)>),
+    // Announces new mempool transactions by id, answered with a GetData of
+    // the ones the peer does not have
+    Inv(Vec<String>),
+    // Asks for mempool transactions by id, the answer leaves out unknown ones.
+    // Also the answer to Inv and Txs, naming the transactions wanted next.
+    GetData(Vec<String>),
+    // Unconfirmed transactions, parents before children, answered with a
+    // GetData of the parents still missing
+    Txs(Vec<Transaction>),
+    // The peer could not handle the last message
+    Reject(String),
+}
+
+// A transaction with the branch linking it to the merkle root of its block
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxProof {
+    pub height: usize,
+    pub block_hash: String,
+    pub tx: Transaction,
+    pub branch: MerkleBranch,
+}
+
+// Error of a message that is too large or does not decode, which is the
+// sender's fault rather than the connection's. Callers find it with
+// `failure::Error::downcast_ref`.
+#[derive(Debug)]
+pub struct MalformedMessage {
+    pub reason: String,
+    // True if the whole message was read, so the next one can be
+    pub skipped: bool,
+}
+
+impl fmt::Display for MalformedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Malformed message: {}", self.reason)
+    }
+}
+
+impl std::error::Error for MalformedMessage {}
+
+// The MalformedMessage the error is, if it is one
+pub fn as_malformed(error: &failure::Error) -> Option<&MalformedMessage> {
+    error.downcast_ref::<MalformedMessage>()
+}
+
+// Writes one framed message
+pub fn write_message(stream: &mut TcpStream, message: &Message) -> Result<()> {
+    let data = bincode::serialize(message)?;
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(&data)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Reads one framed message
+pub fn read_message(stream: &mut TcpStream) -> Result<Message> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(MalformedMessage {
+            reason: format!("{} bytes is too large", len),
+            skipped: false,
+        }
+        .into());
+    }
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data)?;
+    // Same encoding as bincode::serialize, but a length prefix inside the
+    // message can not make the decoder allocate more than the message size
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(len as u64)
+        .deserialize(&data)
+        .map_err(|e| {
+            MalformedMessage {
+                reason: e.to_string(),
+                skipped: true,
+            }
+            .into()
+        })
+}
+
+// Error of a connection that reached our own node
+#[derive(Debug)]
+struct SelfConnection;
+
+impl fmt::Display for SelfConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connected to ourselves")
+    }
+}
+
+impl std::error::Error for SelfConnection {}
+
+// Outbound connection to another node, each request waits for its answer
+pub struct Peer {
+    addr: String,
+    stream: TcpStream,
+    best_height: usize,
+    tip: String,
+    // The manager the connection is registered with, and its id there
+    manager: Option<(PeerManager, u64)>,
+}
+
+impl Peer {
+    // Connects and exchanges Version messages
+    pub fn connect(addr: &str, best_height: usize, tip: &str) -> Result<Peer> {
+        Peer::open(None, addr, best_height, tip)
+    }
+
+    // Connects like `connect`, but as one of the manager's outbound
+    // connections: banned addresses and connections over the limit are
+    // refused, and misbehavior is scored
+    pub fn connect_with(
+        manager: &PeerManager,
+        addr: &str,
+        best_height: usize,
+        tip: &str,
+    ) -> Result<Peer> {
+        Peer::open(Some(manager), addr, best_height, tip)
+    }
+
+    fn open(
+        manager: Option<&PeerManager>,
+        addr: &str,
+        best_height: usize,
+        tip: &str,
+    ) -> Result<Peer> {
+        let socket_addr = match addr.to_socket_addrs()?.next() {
+            Some(socket_addr) => socket_addr,
+            None => return Err(format_err!("Can not resolve {}", addr)),
+        };
+        let manager = match manager {
+            Some(manager) => manager,
+            None => return Peer::handshake(None, addr, &socket_addr, best_height, tip),
+        };
+        if manager.is_banned(&socket_addr.ip()) {
+            return Err(format_err!("{} is banned", addr));
+        }
+
+        // How the attempt went is kept in the address book for the peer selection
+        let book = manager.address_book();
+        match Peer::handshake(Some(manager), addr, &socket_addr, best_height, tip) {
+            Ok(peer) => {
+                book.mark_good(&socket_addr);
+                Ok(peer)
+            }
+            Err(e) => {
+                if e.downcast_ref::<SelfConnection>().is_some() {
+                    book.remove(&socket_addr);
+                } else {
+                    book.mark_failed(&socket_addr);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn handshake(
+        manager: Option<&PeerManager>,
+        addr: &str,
+        socket_addr: &SocketAddr,
+        best_height: usize,
+        tip: &str,
+    ) -> Result<Peer> {
+        let stream = TcpStream::connect_timeout(socket_addr, PEER_TIMEOUT)?;
+        stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+        stream.set_write_timeout(Some(PEER_TIMEOUT))?;
+        let manager = match manager {
+            Some(manager) => Some((manager.clone(), manager.register(&stream, false)?)),
+            None => None,
+        };
+        // Unmanaged peers do not listen and pick a nonce of their own
+        let (listen_port, nonce) = match &manager {
+            Some((manager, _)) => (manager.listen_port(), manager.nonce()),
+            None => (0, OsRng.next_u64()),
+        };
+        let mut peer = Peer {
+            addr: addr.to_string(),
+            stream,
+            best_height: 0,
+            tip: String::new(),
+            manager,
+        };
+
+        write_message(
+            &mut peer.stream,
+            &Message::Version {
+                version: PROTOCOL_VERSION,
+                best_height,
+                tip: tip.to_string(),
+                listen_port,
+                nonce,
+            },
+        )?;
+        match peer.read()? {
+            Message::Version {
+                nonce: their_nonce, ..
+            } if their_nonce == nonce => Err(SelfConnection.into()),
+            Message::Version {
+                version,
+                best_height,
+                tip,
+                ..
+            } if version == PROTOCOL_VERSION => {
+                if let Some((manager, id)) = &peer.manager {
+                    manager.set_best_height(*id, best_height);
+                }
+                peer.best_height = best_height;
+                peer.tip = tip;
+                Ok(peer)
+            }
+            Message::Version { version, .. } => {
+                Err(format_err!("{} speaks protocol version {}", addr, version))
+            }
+            _ => Err(format_err!("{} did not send its version", addr)),
+        }
+    }
+
+    // The manager the peer is registered with, and its id there
+    pub fn manager(&self) -> Option<&(PeerManager, u64)> {
+        self.manager.as_ref()
+    }
+
+    // Adds to the peer's misbehavior score, returns true if it got banned.
+    // Unmanaged peers are never banned.
+    pub fn misbehaving(&self, score: u32, reason: &str) -> bool {
+        match &self.manager {
+            Some((manager, id)) => manager.misbehaving(*id, score, reason),
+            None => false,
+        }
+    }
+
+    // Scores the peer if the result of checking what it sent is an error
+    pub fn blame<T>(&self, score: u32, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            self.misbehaving(score, &e.to_string());
+        }
+        result
+    }
+
+    fn read(&mut self) -> Result<Message> {
+        let message = read_message(&mut self.stream);
+        if let Err(e) = &message {
+            if as_malformed(e).is_some() {
+                self.misbehaving(MALFORMED_MESSAGE_SCORE, &e.to_string());
+            }
+        }
+        message
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    // Height of the peer's tip when it connected
+    pub fn best_height(&self) -> usize {
+        self.best_height
+    }
+
+    pub fn tip(&self) -> &str {
+        &self.tip
+    }
+
+    fn request(&mut self, message: &Message) -> Result<Message> {
+        write_message(&mut self.stream, message)?;
+        match self.read()? {
+            Message::Reject(reason) => Err(format_err!("{} rejected: {}", self.addr, reason)),
+            answer => Ok(answer),
+        }
+    }
+
+    // Returns the headers following the fork point with the locator
+    pub fn get_headers(&mut self, locator: Vec<String>) -> Result<Vec<BlockHeader>> {
+        match self.request(&Message::GetHeaders { locator })? {
+            Message::Headers(headers) if headers.len() <= MAX_HEADERS => Ok(headers),
+            _ => Err(format_err!("{} did not answer with headers", self.addr)),
+        }
+    }
+
+    // Returns the blocks the peer has among the hashes
+    pub fn get_blocks(&mut self, hashes: Vec<String>) -> Result<Vec<Block>> {
+        match self.request(&Message::GetBlocks(hashes))? {
+            Message::Blocks(blocks) if blocks.len() <= MAX_BLOCKS => Ok(blocks),
+            _ => Err(format_err!("{} did not answer with blocks", self.addr)),
+        }
+    }
+
+    // Returns node addresses the peer knows, with when they were last seen
+    pub fn get_addr(&mut self) -> Result<Vec<(SocketAddr, u64)>> {
+        match self.request(&Message::GetAddr)? {
+            Message::Addr(addresses) if addresses.len() <= MAX_ADDR => Ok(addresses),
+            _ => Err(format_err!("{} did not answer with addresses", self.addr)),
+        }
+    }
+
+    // Announces transactions, returns the ids the peer wants
+    pub fn send_inv(&mut self, txids: Vec<String>) -> Result<Vec<String>> {
+        match self.request(&Message::Inv(txids))? {
+            Message::GetData(wanted) if wanted.len() <= MAX_INV => Ok(wanted),
+            _ => Err(format_err!("{} did not answer the inventory", self.addr)),
+        }
+    }
+
+    // Sends transactions, returns the ids of the parents the peer misses
+    pub fn send_txs(&mut self, txs: Vec<Transaction>) -> Result<Vec<String>> {
+        match self.request(&Message::Txs(txs))? {
+            Message::GetData(missing) if missing.len() <= MAX_INV => Ok(missing),
+            _ => Err(format_err!("{} did not answer the transactions", self.addr)),
+        }
+    }
+
+    // Returns the mempool transactions the peer has among the ids
+    pub fn get_txs(&mut self, txids: Vec<String>) -> Result<Vec<Transaction>> {
+        match self.request(&Message::GetData(txids))? {
+            Message::Txs(txs) if txs.len() <= MAX_TXS => Ok(txs),
+            _ => Err(format_err!("{} did not answer with transactions", self.addr)),
+        }
+    }
+
+    // Returns the block hashes and filters from the start height on
+    pub fn get_cfilters(
+        &mut self,
+        start_height: usize,
+        stop_hash: &str,
+    ) -> Result<Vec<(String, BlockFilter)>> {
+        let request = Message::GetCFilters {
+            start_height,
+            stop_hash: stop_hash.to_string(),
+        };
+        match self.request(&request)? {
+            Message::CFilters(filters) if filters.len() <= MAX_CFILTERS => Ok(filters),
+            _ => Err(format_err!("{} did not answer with filters", self.addr)),
+        }
+    }
+
+    // Returns the filter header before the start height and the filter hashes from it on
+    pub fn get_cfheaders(
+        &mut self,
+        start_height: usize,
+        stop_hash: &str,
+    ) -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+        let request = Message::GetCFHeaders {
+            start_height,
+            stop_hash: stop_hash.to_string(),
+        };
+        match self.request(&request)? {
+            Message::CFHeaders {
+                prev_header,
+                filter_hashes,
+            } if filter_hashes.len() <= MAX_CFHEADERS => Ok((prev_header, filter_hashes)),
+            _ => Err(format_err!("{} did not answer with filter headers", self.addr)),
+        }
+    }
+
+    // Returns the proven transactions of the searched blocks, the height to
+    // continue from and the hash of the last searched block
+    pub fn get_proofs(
+        &mut self,
+        pub_key_hashes: Vec<Vec<u8>>,
+        from_height: usize,
+        to_height: usize,
+    ) -> Result<(Vec<TxProof>, usize, String)> {
+        let request = Message::GetProofs {
+            pub_key_hashes,
+            from_height,
+            to_height,
+        };
+        match self.request(&request)? {
+            Message::Proofs {
+                proofs,
+                next_height,
+                last_hash,
+            } if next_height <= to_height => {
+                Ok((proofs, next_height, last_hash))
+            }
+            _ => Err(format_err!("{} did not answer with proofs", self.addr)),
+        }
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        if let Some((manager, id)) = &self.manager {
+            manager.unregister(*id);
+        }
+    }
+}
@@ -0,0 +1,599 @@
+// node.rs
+
+use std::collections::{BTreeSet, HashSet};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::Duration;
+
+use failure::format_err;
+use log::{error, info, warn};
+
+use crate::block::BlockHeader;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::filter::FilterEntry;
+use crate::mempool::{Acceptance, Mempool};
+use crate::net::{
+    as_malformed, read_message, write_message, Message, Peer, TxProof, MAX_BLOCKS,
+    MAX_CFHEADERS, MAX_CFILTERS, MAX_HEADERS, MAX_INV, MAX_LOCATOR, MAX_PROOF_BLOCKS, MAX_TXS,
+    PROTOCOL_VERSION,
+};
+use crate::peers::{
+    unix_now, PeerManager, INVALID_TRANSACTION_SCORE, MALFORMED_MESSAGE_SCORE, MAX_OUTBOUND,
+    UNEXPECTED_MESSAGE_SCORE,
+};
+use crate::store::{ChainStore, SledStore};
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+use crate::wallet::hash_pub_key;
+
+// Pause between two rounds of asking other nodes for addresses
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long an inbound peer may stay silent before it is dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// Most Txs messages sent to one peer when relaying, parents it misses included
+const MAX_RELAY_ROUNDS: usize = 25;
+
+// Peer to peer server answering other nodes' header and block requests and
+// relaying mempool transactions. Clones share the node, each peer is served
+// on its own thread. Whoever needs both takes the UTXO set lock before the
+// mempool lock.
+pub struct Node<S: ChainStore = SledStore> {
+    port: u16,
+    utxo_set: Arc<RwLock<UTXOSet<S>>>,
+    mempool: Arc<Mutex<Mempool>>,
+    peers: PeerManager,
+}
+
+impl<S: ChainStore> Clone for Node<S> {
+    fn clone(&self) -> Self {
+        Node {
+            port: self.port,
+            utxo_set: self.utxo_set.clone(),
+            mempool: self.mempool.clone(),
+            peers: self.peers.clone(),
+        }
+    }
+}
+
+impl<S: ChainStore + 'static> Node<S> {
+    pub fn new(port: u16, utxo_set: UTXOSet<S>) -> Node<S> {
+        Node {
+            port,
+            utxo_set: Arc::new(RwLock::new(utxo_set)),
+            mempool: Arc::new(Mutex::new(Mempool::default())),
+            peers: PeerManager::default(),
+        }
+    }
+
+    // Uses the manager's ban list and limits instead of an empty in-memory one
+    pub fn with_peer_manager(mut self, peers: PeerManager) -> Node<S> {
+        self.peers = peers;
+        self
+    }
+
+    // Uses the mempool, e.g. one with other limits, instead of an empty default one
+    pub fn with_mempool(mut self, mempool: Mempool) -> Node<S> {
+        self.mempool = Arc::new(Mutex::new(mempool));
+        self
+    }
+
+    pub fn peer_manager(&self) -> &PeerManager {
+        &self.peers
+    }
+
+    // The UTXO set the node serves, to share it with e.g. the RPC server
+    pub fn utxo_set(&self) -> Arc<RwLock<UTXOSet<S>>> {
+        self.utxo_set.clone()
+    }
+
+    // The node's mempool, to share it with e.g. the RPC server
+    pub fn mempool(&self) -> Arc<Mutex<Mempool>> {
+        self.mempool.clone()
+    }
+
+    // Adds a transaction made on this node to the mempool and relays it
+    pub fn submit_transaction(&self, tx: Transaction) -> Result<Acceptance> {
+        let acceptance = {
+            let utxo_set = self.read();
+            self.lock_mempool().accept(&utxo_set.blockchain, tx)?
+        };
+        if let Acceptance::Accepted(txids) = &acceptance {
+            self.relay(txids.clone(), None);
+        }
+        Ok(acceptance)
+    }
+
+    // Announces the mempool transactions to the nodes picked from the
+    // address book, except the one they came from, on a thread of their own
+    pub fn relay(&self, txids: Vec<String>, except: Option<SocketAddr>) {
+        if txids.is_empty() {
+            return;
+        }
+        let node = self.clone();
+        thread::spawn(move || {
+            let (height, tip) = {
+                let bc = &node.read().blockchain;
+                match bc.get_best_height() {
+                    Ok(height) => (height, bc.get_tip_hash()),
+                    Err(e) => {
+                        warn!("can not relay: {}", e);
+                        return;
+                    }
+                }
+            };
+            // Relays run side by side, so the addresses other ones are
+            // connected to are not left out as select_outbound would
+            let targets = node.peers.address_book().select(MAX_OUTBOUND, &[], |addr| {
+                Some(*addr) == except || node.peers.is_banned(&addr.ip())
+            });
+            for addr in targets {
+                match node.relay_to(&addr, &txids, height, &tip) {
+                    Ok(sent) => info!("relayed {} transactions to {}", sent, addr),
+                    Err(e) => info!("can not relay to {}: {}", addr, e),
+                }
+            }
+        });
+    }
+
+    // Sends the transactions the peer wants among the announced ones, then
+    // the parents it misses. Returns how many were sent.
+    fn relay_to(
+        &self,
+        addr: &SocketAddr,
+        txids: &[String],
+        height: usize,
+        tip: &str,
+    ) -> Result<usize> {
+        let mut peer = Peer::connect_with(&self.peers, &addr.to_string(), height, tip)?;
+        let mut sent = 0;
+        let mut wanted: Vec<String> = Vec::new();
+        for chunk in txids.chunks(MAX_INV) {
+            wanted.extend(peer.send_inv(chunk.to_vec())?);
+        }
+        for _ in 0..MAX_RELAY_ROUNDS {
+            // Parents go before their children
+            let txs = self.mempool_txs(&wanted);
+            if txs.is_empty() {
+                break;
+            }
+            let (batch, rest) = txs.split_at(txs.len().min(MAX_TXS));
+            let missing = peer.send_txs(batch.to_vec())?;
+            sent += batch.len();
+            wanted = missing;
+            wanted.extend(rest.iter().map(|tx| tx.id.clone()));
+        }
+        Ok(sent)
+    }
+
+    fn lock_mempool(&self) -> MutexGuard<'_, Mempool> {
+        self.mempool.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The mempool transactions among the ids, in mempool order
+    fn mempool_txs(&self, txids: &[String]) -> Vec<Transaction> {
+        let txids: HashSet<&String> = txids.iter().collect();
+        self.lock_mempool()
+            .entries()
+            .into_iter()
+            .filter(|entry| txids.contains(&entry.tx.id))
+            .map(|entry| entry.tx.clone())
+            .collect()
+    }
+
+    // Adds the peer's transactions to the mempool, scoring the invalid ones,
+    // relays the accepted ones and answers with the parents still missing
+    fn receive_txs(
+        &self,
+        id: u64,
+        txs: Vec<Transaction>,
+        from: Option<SocketAddr>,
+    ) -> Result<Message> {
+        if txs.len() > MAX_TXS {
+            self.peers.misbehaving(id, UNEXPECTED_MESSAGE_SCORE, "too many transactions");
+            return Err(format_err!("More than {} transactions", MAX_TXS));
+        }
+        let mut accepted = Vec::new();
+        let mut missing = BTreeSet::new();
+        {
+            let utxo_set = self.read();
+            let mut mempool = self.lock_mempool();
+            for tx in txs {
+                match mempool.accept(&utxo_set.blockchain, tx) {
+                    Ok(Acceptance::Accepted(txids)) => accepted.extend(txids),
+                    Ok(Acceptance::Orphan(parents)) => missing.extend(parents),
+                    Ok(Acceptance::Known) => {}
+                    Ok(Acceptance::Conflict(reason) | Acceptance::Rejected(reason)) => {
+                        info!("transaction not accepted: {}", reason)
+                    }
+                    Ok(Acceptance::Invalid(reason)) => {
+                        self.peers
+                            .misbehaving(id, INVALID_TRANSACTION_SCORE, &reason);
+                    }
+                    // The node failed, not the peer
+                    Err(e) => error!("can not check a transaction: {}", e),
+                }
+            }
+            // Parents that came later in the same message are not missing
+            missing.retain(|txid| !mempool.contains(txid));
+        }
+        self.relay(accepted, from);
+        Ok(Message::GetData(missing.into_iter().take(MAX_INV).collect()))
+    }
+
+    // Accepts peers until the listener fails, while discovering other nodes
+    // in the background
+    pub fn run(&self) -> Result<()> {
+        self.run_with(TcpListener::bind(("0.0.0.0", self.port))?)
+    }
+
+    // Like run, on a listener already bound to the node's port, e.g. one
+    // whose port the OS picked
+    pub fn run_with(&self, listener: TcpListener) -> Result<()> {
+        info!("P2P node listening on port {}", self.port);
+        self.peers.set_listen_port(self.port);
+
+        let node = self.clone();
+        thread::spawn(move || loop {
+            match node.discover() {
+                Ok(learned) => info!("discovery learned {} addresses", learned),
+                Err(e) => warn!("discovery failed: {}", e),
+            }
+            thread::sleep(DISCOVERY_INTERVAL);
+        });
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            // Banned addresses and peers over the limit are dropped right away
+            let id = match self.peers.register(&stream, true) {
+                Ok(id) => id,
+                Err(e) => {
+                    info!("refused {:?}: {}", stream.peer_addr(), e);
+                    continue;
+                }
+            };
+            let node = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = node.handle_peer(id, stream) {
+                    error!("peer error: {}", e);
+                }
+                node.peers.unregister(id);
+            });
+        }
+        Ok(())
+    }
+
+    // Connects to addresses from the book, spread over address groups, and
+    // asks them for more. Returns how many addresses the book gained.
+    pub fn discover(&self) -> Result<usize> {
+        let book = self.peers.address_book();
+        let before = book.len();
+        let (height, tip) = {
+            let bc = &self.read().blockchain;
+            (bc.get_best_height()?, bc.get_tip_hash())
+        };
+        for addr in self.peers.select_outbound(MAX_OUTBOUND) {
+            match Peer::connect_with(&self.peers, &addr.to_string(), height, &tip) {
+                Ok(mut peer) => match peer.get_addr() {
+                    Ok(addresses) => book.add(&addresses),
+                    Err(e) => warn!("no addresses from {}: {}", addr, e),
+                },
+                Err(e) => info!("can not connect to {}: {}", addr, e),
+            }
+        }
+        Ok(book.len().saturating_sub(before))
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, UTXOSet<S>> {
+        self.utxo_set.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Answers the peer's requests until it disconnects
+    fn handle_peer(&self, id: u64, mut stream: TcpStream) -> Result<()> {
+        let peer = stream.peer_addr()?;
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+        let (listen_port, nonce) = match self.read_from(id, &mut stream)? {
+            Message::Version {
+                version,
+                best_height,
+                listen_port,
+                nonce,
+                ..
+            } if version == PROTOCOL_VERSION => {
+                self.peers.set_best_height(id, best_height);
+                (listen_port, nonce)
+            }
+            _ => return Err(format_err!("{} did not send a matching version", peer)),
+        };
+        let version = {
+            let utxo_set = self.read();
+            Message::Version {
+                version: PROTOCOL_VERSION,
+                best_height: utxo_set.blockchain.get_best_height()?,
+                tip: utxo_set.blockchain.get_tip_hash(),
+                listen_port: self.port,
+                nonce: self.peers.nonce(),
+            }
+        };
+        write_message(&mut stream, &version)?;
+        // Our own nonce means we connected to ourselves, which the other side
+        // notices from our answer
+        if nonce == self.peers.nonce() {
+            return Ok(());
+        }
+        // The peer's listening address is worth handing out to other nodes
+        let listen_addr = match listen_port {
+            0 => None,
+            port => Some(SocketAddr::new(peer.ip(), port)),
+        };
+        if let Some(addr) = listen_addr {
+            self.peers.address_book().add(&[(addr, unix_now())]);
+        }
+        info!("peer {} connected", peer);
+
+        // The peer closing the connection, timing out, getting banned or
+        // sending a message that can not be skipped ends the loop
+        loop {
+            let message = match self.read_from(id, &mut stream) {
+                Ok(message) => message,
+                Err(e) => match as_malformed(&e) {
+                    Some(malformed) if malformed.skipped && !self.is_banned(&peer) => {
+                        write_message(&mut stream, &Message::Reject(e.to_string()))?;
+                        continue;
+                    }
+                    _ => break,
+                },
+            };
+            if !is_request(&message) {
+                if self.peers.misbehaving(id, UNEXPECTED_MESSAGE_SCORE, "unexpected message") {
+                    break;
+                }
+                write_message(&mut stream, &Message::Reject("Unexpected message".to_string()))?;
+                continue;
+            }
+            let answer = match message {
+                // The asker's own address is of no use to it
+                Message::GetAddr => Ok(Message::Addr(
+                    self.peers
+                        .address_book()
+                        .to_share()
+                        .into_iter()
+                        .filter(|(addr, _)| Some(*addr) != listen_addr)
+                        .collect(),
+                )),
+                Message::Txs(txs) => self.receive_txs(id, txs, listen_addr),
+                message => self.answer(message),
+            };
+            let answer = match answer {
+                Ok(answer) => answer,
+                Err(e) => Message::Reject(e.to_string()),
+            };
+            write_message(&mut stream, &answer)?;
+        }
+        info!("peer {} disconnected", peer);
+        Ok(())
+    }
+
+    fn is_banned(&self, peer: &SocketAddr) -> bool {
+        self.peers.is_banned(&peer.ip())
+    }
+
+    // Reads a message, scoring the peer if it is malformed
+    fn read_from(&self, id: u64, stream: &mut TcpStream) -> Result<Message> {
+        let message = read_message(stream);
+        if let Err(e) = &message {
+            if as_malformed(e).is_some() {
+                warn!("peer {:?}: {}", stream.peer_addr(), e);
+                self.peers.misbehaving(id, MALFORMED_MESSAGE_SCORE, &e.to_string());
+            }
+        }
+        message
+    }
+
+    fn answer(&self, message: Message) -> Result<Message> {
+        match message {
+            Message::GetHeaders { locator } => Ok(Message::Headers(self.headers_after(&locator)?)),
+            Message::GetBlocks(hashes) => {
+                let bc = &self.read().blockchain;
+                let mut blocks = Vec::new();
+                for hash in hashes.iter().take(MAX_BLOCKS) {
+                    if let Some(block) = bc.get_block(hash)? {
+                        blocks.push(block);
+                    }
+                }
+                Ok(Message::Blocks(blocks))
+            }
+            Message::GetProofs {
+                pub_key_hashes,
+                from_height,
+                to_height,
+            } => self.proofs_in(&pub_key_hashes, from_height, to_height),
+            Message::GetCFilters {
+                start_height,
+                stop_hash,
+            } => {
+                let bc = &self.read().blockchain;
+                let mut filters = Vec::new();
+                for hash in filter_range(bc, start_height, &stop_hash, MAX_CFILTERS)? {
+                    filters.push((hash.clone(), filter_of(bc, &hash)?.filter));
+                }
+                Ok(Message::CFilters(filters))
+            }
+            Message::GetCFHeaders {
+                start_height,
+                stop_hash,
+            } => {
+                let bc = &self.read().blockchain;
+                let prev_header = match start_height.checked_sub(1) {
+                    Some(height) => match bc.get_block_hash(height)? {
+                        Some(hash) => filter_of(bc, &hash)?.header,
+                        None => return Err(format_err!("No block at height {}", height)),
+                    },
+                    None => vec![0; 32],
+                };
+                let mut filter_hashes = Vec::new();
+                for hash in filter_range(bc, start_height, &stop_hash, MAX_CFHEADERS)? {
+                    filter_hashes.push(filter_of(bc, &hash)?.filter.hash()?);
+                }
+                Ok(Message::CFHeaders {
+                    prev_header,
+                    filter_hashes,
+                })
+            }
+            // Answered with the ids the node has neither in the mempool nor confirmed
+            Message::Inv(txids) => {
+                let utxo_set = self.read();
+                let mempool = self.lock_mempool();
+                let mut wanted = Vec::new();
+                for txid in txids.into_iter().take(MAX_INV) {
+                    if !mempool.contains(&txid) && utxo_set.blockchain.store().get_utxos(&txid)?.is_none() {
+                        wanted.push(txid);
+                    }
+                }
+                Ok(Message::GetData(wanted))
+            }
+            Message::GetData(txids) => {
+                let txids: Vec<String> = txids.into_iter().take(MAX_TXS).collect();
+                Ok(Message::Txs(self.mempool_txs(&txids)))
+            }
+            _ => Err(format_err!("Unexpected message")),
+        }
+    }
+
+    // Returns the headers of the active chain following the first locator
+    // hash that is on it, or following genesis if none is
+    fn headers_after(&self, locator: &[String]) -> Result<Vec<BlockHeader>> {
+        let bc = &self.read().blockchain;
+        let mut start = 0;
+        for hash in locator.iter().take(MAX_LOCATOR) {
+            if let Some(block) = bc.get_block(hash)? {
+                if bc.get_block_hash(block.get_height())?.as_ref() == Some(hash) {
+                    start = block.get_height() + 1;
+                    break;
+                }
+            }
+        }
+
+        let mut headers = Vec::new();
+        for height in start..start.saturating_add(MAX_HEADERS) {
+            let hash = match bc.get_block_hash(height)? {
+                Some(hash) => hash,
+                None => break,
+            };
+            match bc.get_block(&hash)? {
+                Some(block) => headers.push(block.get_header()?),
+                None => return Err(format_err!("Block {} is not found", hash)),
+            }
+        }
+        Ok(headers)
+    }
+
+    // Searches up to MAX_PROOF_BLOCKS active chain blocks of the height range
+    // for transactions paying or spending one of the pub key hashes
+    fn proofs_in(
+        &self,
+        pub_key_hashes: &[Vec<u8>],
+        from_height: usize,
+        to_height: usize,
+    ) -> Result<Message> {
+        let wanted: HashSet<&[u8]> = pub_key_hashes.iter().map(|pkh| pkh.as_slice()).collect();
+        let bc = &self.read().blockchain;
+        let next_height = (bc.get_best_height()? + 1)
+            .min(to_height)
+            .min(from_height.saturating_add(MAX_PROOF_BLOCKS));
+
+        let mut proofs = Vec::new();
+        for height in from_height..next_height {
+            let block = match bc.get_block_by_height(height)? {
+                Some(block) => block,
+                None => return Err(format_err!("Block at height {} is not found", height)),
+            };
+            for tx in block.get_transactions() {
+                let pays = tx
+                    .v_outputs
+                    .iter()
+                    .any(|output| wanted.contains(output.pub_key_hash.as_slice()));
+                let spends = !tx.is_coinbase()
+                    && tx.v_inputs.iter().any(|input| {
+                        let mut pub_key_hash = input.pub_key.clone();
+                        hash_pub_key(&mut pub_key_hash);
+                        wanted.contains(pub_key_hash.as_slice())
+                    });
+                if !pays && !spends {
+                    continue;
+                }
+                if let Some(branch) = block.merkle_branch(&tx.id)? {
+                    proofs.push(TxProof {
+                        height: block.get_height(),
+                        block_hash: block.get_hash(),
+                        tx: tx.clone(),
+                        branch,
+                    });
+                }
+            }
+        }
+        let last_hash = match next_height.checked_sub(1) {
+            Some(height) => bc.get_block_hash(height)?.unwrap_or_default(),
+            None => String::new(),
+        };
+        Ok(Message::Proofs {
+            proofs,
+            next_height,
+            last_hash,
+        })
+    }
+}
+
+// True for the messages a node answers, the others are only sent as answers
+fn is_request(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::GetHeaders { .. }
+            | Message::GetAddr
+            | Message::GetBlocks(_)
+            | Message::GetProofs { .. }
+            | Message::GetCFilters { .. }
+            | Message::GetCFHeaders { .. }
+            | Message::Inv(_)
+            | Message::GetData(_)
+            | Message::Txs(_)
+    )
+}
+
+// Hashes of the active chain from the start height up to the stop block,
+// at most `max` of them
+fn filter_range<S: ChainStore>(
+    bc: &Blockchain<S>,
+    start_height: usize,
+    stop_hash: &str,
+    max: usize,
+) -> Result<Vec<String>> {
+    let stop_height = match bc.get_block(stop_hash)? {
+        Some(block)
+            if block.get_height() >= start_height
+                && bc.get_block_hash(block.get_height())?.as_deref() == Some(stop_hash) =>
+        {
+            block.get_height()
+        }
+        _ => return Err(format_err!("Block {} is not on the chain past the start", stop_hash)),
+    };
+    let mut hashes = Vec::new();
+    for height in (start_height..=stop_height).take(max) {
+        match bc.get_block_hash(height)? {
+            Some(hash) => hashes.push(hash),
+            None => return Err(format_err!("No block at height {}", height)),
+        }
+    }
+    Ok(hashes)
+}
+
+fn filter_of<S: ChainStore>(bc: &Blockchain<S>, hash: &str) -> Result<FilterEntry> {
+    match bc.get_block_filter(hash)? {
+        Some(entry) => Ok(entry),
+        None => Err(format_err!("Filter of block {} is not found", hash)),
+    }
+}
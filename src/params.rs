@@ -0,0 +1,182 @@
+// params.rs
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::engine::{ConsensusEngine, ProofOfAuthority, ProofOfWork};
+use crate::errors::Result;
+use crate::miner::Miner;
+use crate::pow::PowAlgorithm;
+
+// The networks a chain can be created for. Each has its own consensus
+// parameters, a chain records the one it was created on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Main,
+    Test,
+    // Local testing with small limits that are easy to hit
+    Regtest,
+    // Private networks whose blocks are signed by a set list of validators
+    Poa,
+}
+
+impl Network {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Main => "main",
+            Network::Test => "test",
+            Network::Regtest => "regtest",
+            Network::Poa => "poa",
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = failure::Error;
+
+    fn from_str(name: &str) -> Result<Network> {
+        match name {
+            "main" => Ok(Network::Main),
+            "test" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            "poa" => Ok(Network::Poa),
+            _ => Err(format_err!(
+                "Unknown network '{}', expected main, test, regtest or poa",
+                name
+            )),
+        }
+    }
+}
+
+// How the blocks of a network are sealed. Chains record theirs, so it is
+// part of the chain format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Consensus {
+    ProofOfWork(PowAlgorithm),
+    // The ed25519 public keys of the validators, which sign blocks in turn
+    ProofOfAuthority(Vec<Vec<u8>>),
+}
+
+// Block hashes a chain must have at some heights, by height
+pub type Checkpoints = BTreeMap<usize, String>;
+
+// Consensus rules and limits of a network. Sizes are in bincode bytes, and every
+// input of a transaction other than a coinbase is one signature operation.
+// `max_data_size` limits the data of each data output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    pub network: Network,
+    pub consensus: Consensus,
+    pub max_block_size: usize,
+    pub max_block_sigops: usize,
+    pub max_tx_size: usize,
+    pub max_tx_inputs: usize,
+    pub max_tx_outputs: usize,
+    pub max_data_size: usize,
+    // Chains that differ from these are rejected, and so are forks and
+    // disconnects at or below the last checkpoint the local chain has
+    pub checkpoints: Checkpoints,
+    // The signatures of this block and its ancestors are not checked when
+    // syncing them. Everything else about them still is.
+    pub assume_valid: Option<String>,
+}
+
+impl ChainParams {
+    pub fn main() -> ChainParams {
+        ChainParams {
+            network: Network::Main,
+            consensus: Consensus::ProofOfWork(PowAlgorithm::Sha256),
+            max_block_size: 1_000_000,
+            max_block_sigops: 20_000,
+            max_tx_size: 100_000,
+            max_tx_inputs: 1_000,
+            max_tx_outputs: 1_000,
+            max_data_size: 80,
+            // Every chain is created with its own genesis block, so networks
+            // have no built-in checkpoints or assumed-valid block yet
+            checkpoints: Checkpoints::new(),
+            assume_valid: None,
+        }
+    }
+
+    pub fn test() -> ChainParams {
+        ChainParams {
+            network: Network::Test,
+            ..ChainParams::main()
+        }
+    }
+
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            network: Network::Regtest,
+            consensus: Consensus::ProofOfWork(PowAlgorithm::Sha256),
+            max_block_size: 20_000,
+            max_block_sigops: 50,
+            max_tx_size: 5_000,
+            max_tx_inputs: 10,
+            max_tx_outputs: 10,
+            max_data_size: 80,
+            checkpoints: Checkpoints::new(),
+            assume_valid: None,
+        }
+    }
+
+    // A proof of authority network with the limits of main
+    pub fn poa(validators: Vec<Vec<u8>>) -> ChainParams {
+        ChainParams {
+            network: Network::Poa,
+            consensus: Consensus::ProofOfAuthority(validators),
+            ..ChainParams::main()
+        }
+    }
+
+    // The params of a network, a poa network starts without validators
+    pub fn for_network(network: Network) -> ChainParams {
+        match network {
+            Network::Main => ChainParams::main(),
+            Network::Test => ChainParams::test(),
+            Network::Regtest => ChainParams::regtest(),
+            Network::Poa => ChainParams::poa(Vec::new()),
+        }
+    }
+
+    // Adds configured checkpoints to the network's, replacing any at the same height
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> ChainParams {
+        self.checkpoints.extend(checkpoints);
+        self
+    }
+
+    pub fn with_assume_valid(mut self, hash: String) -> ChainParams {
+        self.assume_valid = Some(hash);
+        self
+    }
+
+    // The engine checking block seals of the network. It can seal blocks
+    // with proof of work, proof of authority needs the validator keys.
+    pub fn engine(&self) -> Box<dyn ConsensusEngine> {
+        match &self.consensus {
+            Consensus::ProofOfWork(algorithm) => Box::new(ProofOfWork::new(
+                Miner::default().with_hasher(algorithm.hasher()),
+            )),
+            Consensus::ProofOfAuthority(validators) => {
+                Box::new(ProofOfAuthority::new(validators.clone()))
+            }
+        }
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> ChainParams {
+        ChainParams::main()
+    }
+}
@@ -0,0 +1,403 @@
+// peers.rs
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::format_err;
+use log::{info, warn};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::addrbook::AddressBook;
+use crate::errors::Result;
+
+// File in the data directory the bans are kept in
+pub const BANLIST_FILE: &str = "banlist.json";
+
+// Most connections accepted from other nodes, and made to them
+pub const MAX_INBOUND: usize = 32;
+pub const MAX_OUTBOUND: usize = 8;
+
+// A connection whose misbehavior score reaches this gets its address banned
+pub const BAN_THRESHOLD: u32 = 100;
+pub const DEFAULT_BAN_TIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+// What each kind of misbehavior adds to the score
+pub const INVALID_BLOCK_SCORE: u32 = 100;
+pub const INVALID_HEADERS_SCORE: u32 = 100;
+pub const INVALID_TRANSACTION_SCORE: u32 = 10;
+// A merkle proof or compact filter that does not match the headers
+pub const INVALID_PROOF_SCORE: u32 = 100;
+pub const MALFORMED_MESSAGE_SCORE: u32 = 20;
+pub const UNEXPECTED_MESSAGE_SCORE: u32 = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ban {
+    // Unix time in seconds the ban ends at
+    pub until: u64,
+    pub reason: String,
+}
+
+// A live connection as getpeerinfo reports it
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub inbound: bool,
+    // Unix time in seconds
+    pub connected_at: u64,
+    pub best_height: usize,
+    pub score: u32,
+}
+
+// Tracks the node's connections, enforces the connection limits, scores
+// misbehavior and keeps the list of banned addresses and the address book,
+// persisted when the manager was opened on a data directory. Clones share the state.
+#[derive(Clone)]
+pub struct PeerManager {
+    inner: Arc<Mutex<PeerState>>,
+    book: AddressBook,
+    // Sent in our Version messages to notice connections to ourselves
+    nonce: u64,
+}
+
+#[derive(Default)]
+struct PeerState {
+    next_id: u64,
+    connections: HashMap<u64, Connection>,
+    bans: BTreeMap<IpAddr, Ban>,
+    ban_file: Option<PathBuf>,
+    // Port our node accepts peers on, 0 if it does not
+    listen_port: u16,
+}
+
+struct Connection {
+    info: PeerInfo,
+    // Shut down to drop the peer when it gets banned
+    stream: TcpStream,
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+impl Default for PeerManager {
+    fn default() -> PeerManager {
+        PeerManager::with_address_book(AddressBook::default())
+    }
+}
+
+impl PeerManager {
+    // Loads the bans and the address book kept in the directory, later
+    // changes are saved there too
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<PeerManager> {
+        fs::create_dir_all(&dir)?;
+        let path = dir.as_ref().join(BANLIST_FILE);
+        let bans = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let manager = PeerManager::with_address_book(AddressBook::open(&dir)?);
+        {
+            let mut state = manager.state();
+            state.bans = bans;
+            state.ban_file = Some(path);
+        }
+        Ok(manager)
+    }
+
+    pub fn with_address_book(book: AddressBook) -> PeerManager {
+        PeerManager {
+            inner: Arc::new(Mutex::new(PeerState::default())),
+            book,
+            nonce: OsRng.next_u64(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, PeerState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn address_book(&self) -> &AddressBook {
+        &self.book
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    // Port announced in our Version messages so peers can connect back
+    pub fn listen_port(&self) -> u16 {
+        self.state().listen_port
+    }
+
+    pub fn set_listen_port(&self, port: u16) {
+        self.state().listen_port = port;
+    }
+
+    // Admits a new connection unless its address is banned or the limit
+    // for its direction is reached, and returns its id
+    pub fn register(&self, stream: &TcpStream, inbound: bool) -> Result<u64> {
+        let addr = stream.peer_addr()?;
+        let mut state = self.state();
+        if state.is_banned(&addr.ip()) {
+            return Err(format_err!("{} is banned", addr.ip()));
+        }
+        let limit = if inbound { MAX_INBOUND } else { MAX_OUTBOUND };
+        let open = state
+            .connections
+            .values()
+            .filter(|connection| connection.info.inbound == inbound)
+            .count();
+        if open >= limit {
+            return Err(format_err!("Too many connections"));
+        }
+
+        state.next_id += 1;
+        let id = state.next_id;
+        let connection = Connection {
+            info: PeerInfo {
+                id,
+                addr,
+                inbound,
+                connected_at: unix_now(),
+                best_height: 0,
+                score: 0,
+            },
+            stream: stream.try_clone()?,
+        };
+        state.connections.insert(id, connection);
+        Ok(id)
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.state().connections.remove(&id);
+    }
+
+    pub fn set_best_height(&self, id: u64, best_height: usize) {
+        if let Some(connection) = self.state().connections.get_mut(&id) {
+            connection.info.best_height = best_height;
+        }
+    }
+
+    // Adds to the connection's score and bans its address once the score
+    // reaches BAN_THRESHOLD. Returns true if the peer is banned.
+    pub fn misbehaving(&self, id: u64, score: u32, reason: &str) -> bool {
+        let (ip, total) = {
+            let mut state = self.state();
+            let connection = match state.connections.get_mut(&id) {
+                Some(connection) => connection,
+                None => return false,
+            };
+            connection.info.score = connection.info.score.saturating_add(score);
+            (connection.info.addr.ip(), connection.info.score)
+        };
+        warn!(
+            "peer {} misbehaving (+{} = {}): {}",
+            ip, score, total, reason
+        );
+        if total < BAN_THRESHOLD {
+            return false;
+        }
+        if let Err(e) = self.ban(ip, DEFAULT_BAN_TIME, reason) {
+            warn!("can not save the ban of {}: {}", ip, e);
+        }
+        true
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.state().is_banned(ip)
+    }
+
+    // Bans the address, dropping its connections
+    pub fn ban(&self, ip: IpAddr, duration: Duration, reason: &str) -> Result<()> {
+        let mut state = self.state();
+        info!("banning {} for {}s: {}", ip, duration.as_secs(), reason);
+        state.bans.insert(
+            ip,
+            Ban {
+                until: unix_now().saturating_add(duration.as_secs()),
+                reason: reason.to_string(),
+            },
+        );
+        for connection in state.connections.values() {
+            if connection.info.addr.ip() == ip {
+                let _ = connection.stream.shutdown(Shutdown::Both);
+            }
+        }
+        state.save()
+    }
+
+    // Returns false if the address was not banned
+    pub fn unban(&self, ip: &IpAddr) -> Result<bool> {
+        let mut state = self.state();
+        let removed = state.bans.remove(ip).is_some();
+        state.save()?;
+        Ok(removed)
+    }
+
+    pub fn clear_bans(&self) -> Result<()> {
+        let mut state = self.state();
+        state.bans.clear();
+        state.save()
+    }
+
+    // Bans still in effect, by address
+    pub fn list_bans(&self) -> Vec<(IpAddr, Ban)> {
+        let now = unix_now();
+        self.state()
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.until > now)
+            .map(|(ip, ban)| (*ip, ban.clone()))
+            .collect()
+    }
+
+    // Addresses from the book to open up to `count` outbound connections to,
+    // at most one per address group counting the outbound peers already
+    // connected, leaving out banned addresses
+    pub fn select_outbound(&self, count: usize) -> Vec<SocketAddr> {
+        let connected: Vec<SocketAddr> = self
+            .peer_info()
+            .iter()
+            .filter(|info| !info.inbound)
+            .map(|info| info.addr)
+            .collect();
+        let count = count.min(MAX_OUTBOUND.saturating_sub(connected.len()));
+        self.book
+            .select(count, &connected, |addr| self.is_banned(&addr.ip()))
+    }
+
+    // Live connections in the order they were made
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self
+            .state()
+            .connections
+            .values()
+            .map(|connection| connection.info.clone())
+            .collect();
+        peers.sort_by_key(|info| info.id);
+        peers
+    }
+}
+
+impl PeerState {
+    fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans.get(ip).is_some_and(|ban| ban.until > unix_now())
+    }
+
+    // Writes the bans still in effect, if the manager has a file
+    fn save(&mut self) -> Result<()> {
+        let now = unix_now();
+        self.bans.retain(|_, ban| ban.until > now);
+        match &self.ban_file {
+            Some(path) => Ok(fs::write(path, serde_json::to_string_pretty(&self.bans)?)?),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use super::*;
+
+    // Both ends of loopback connections: ours to register and the peer's
+    fn connections(count: usize) -> Vec<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (0..count)
+            .map(|_| {
+                let peer = TcpStream::connect(addr).unwrap();
+                let (ours, _) = listener.accept().unwrap();
+                (ours, peer)
+            })
+            .collect()
+    }
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn bans_a_peer_whose_score_reaches_the_threshold() {
+        let manager = PeerManager::default();
+        let mut streams = connections(2);
+        let id = manager.register(&streams[0].0, true).unwrap();
+
+        assert!(!manager.misbehaving(id, 60, "first"));
+        assert!(!manager.misbehaving(id, BAN_THRESHOLD - 61, "second"));
+        assert_eq!(manager.peer_info()[0].score, BAN_THRESHOLD - 1);
+        assert!(!manager.is_banned(&LOCALHOST));
+
+        assert!(manager.misbehaving(id, 1, "third"));
+        assert!(manager.is_banned(&LOCALHOST));
+        assert_eq!(manager.list_bans()[0].1.reason, "third");
+        // The connection is dropped and the address can not come back
+        let mut buf = [0; 1];
+        assert_eq!(streams[0].1.read(&mut buf).unwrap(), 0);
+        assert!(manager.register(&streams[1].0, true).is_err());
+        streams.clear();
+
+        // Scores of connections that are gone do not count
+        assert!(!manager.misbehaving(id + 1, BAN_THRESHOLD, "unknown"));
+    }
+
+    #[test]
+    fn limits_connections_in_each_direction() {
+        let manager = PeerManager::default();
+        let streams = connections(MAX_INBOUND + MAX_OUTBOUND + 2);
+        let mut streams = streams.iter().map(|(ours, _)| ours);
+
+        let inbound: Vec<u64> = (0..MAX_INBOUND)
+            .map(|_| manager.register(streams.next().unwrap(), true).unwrap())
+            .collect();
+        let extra = streams.next().unwrap();
+        assert!(manager.register(extra, true).is_err());
+        for _ in 0..MAX_OUTBOUND {
+            manager.register(streams.next().unwrap(), false).unwrap();
+        }
+        assert!(manager.register(streams.next().unwrap(), false).is_err());
+        assert_eq!(manager.peer_info().len(), MAX_INBOUND + MAX_OUTBOUND);
+
+        manager.unregister(inbound[0]);
+        manager.register(extra, true).unwrap();
+    }
+
+    #[test]
+    fn keeps_bans_in_the_data_directory() {
+        let dir = std::env::temp_dir().join(format!("rust-chain-bans-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        {
+            let manager = PeerManager::open(&dir).unwrap();
+            manager.ban(LOCALHOST, DEFAULT_BAN_TIME, "spam").unwrap();
+            manager.ban(other, DEFAULT_BAN_TIME, "more spam").unwrap();
+            assert!(dir.join(BANLIST_FILE).exists());
+        }
+
+        let manager = PeerManager::open(&dir).unwrap();
+        assert!(manager.is_banned(&LOCALHOST) && manager.is_banned(&other));
+        assert!(manager.unban(&other).unwrap());
+        assert!(!manager.unban(&other).unwrap());
+        // A ban that already ended is not kept
+        manager.ban(other, Duration::ZERO, "over").unwrap();
+        assert!(!manager.is_banned(&other));
+
+        let manager = PeerManager::open(&dir).unwrap();
+        let bans = manager.list_bans();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].0, LOCALHOST);
+        assert_eq!(bans[0].1.reason, "spam");
+        manager.clear_bans().unwrap();
+        assert!(PeerManager::open(&dir).unwrap().list_bans().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+// pow.rs
+
+use std::fmt;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use crypto::scrypt::{scrypt, ScryptParams};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::block::hash_header;
+use crate::errors::Result;
+
+// Size of the serialized header a proof of work hash is computed over
+pub use crate::block::HEADER_SIZE;
+
+// scrypt cost of the proof of work, as in Litecoin: N = 2^10 and r = p = 1,
+// so every hash walks 128 KiB of memory
+const SCRYPT_LOG_N: u8 = 10;
+const SCRYPT_R: u32 = 1;
+const SCRYPT_P: u32 = 1;
+
+// The function a proof of work header hash is computed with. Blocks are still
+// identified by the SHA-256 of their header, only the target check uses it.
+pub trait PowHasher: Debug + Send + Sync {
+    fn hash(&self, header: &[u8; HEADER_SIZE]) -> [u8; 32];
+}
+
+// The proof of work hash of a network. Chains record theirs, so it is part of
+// the chain format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PowAlgorithm {
+    #[default]
+    Sha256,
+    // Memory-hard, so GPUs and ASICs gain less over CPUs
+    Scrypt,
+}
+
+impl PowAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowAlgorithm::Sha256 => "sha256",
+            PowAlgorithm::Scrypt => "scrypt",
+        }
+    }
+
+    pub fn hasher(&self) -> Box<dyn PowHasher> {
+        match self {
+            PowAlgorithm::Sha256 => Box::new(Sha256Hasher),
+            PowAlgorithm::Scrypt => Box::new(ScryptHasher::default()),
+        }
+    }
+}
+
+impl fmt::Display for PowAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for PowAlgorithm {
+    type Err = failure::Error;
+
+    fn from_str(name: &str) -> Result<PowAlgorithm> {
+        match name {
+            "sha256" => Ok(PowAlgorithm::Sha256),
+            "scrypt" => Ok(PowAlgorithm::Scrypt),
+            _ => Err(format_err!(
+                "Unknown proof of work '{}', expected sha256 or scrypt",
+                name
+            )),
+        }
+    }
+}
+
+// SHA-256 of the header, the same hash that identifies the block
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256Hasher;
+
+impl PowHasher for Sha256Hasher {
+    fn hash(&self, header: &[u8; HEADER_SIZE]) -> [u8; 32] {
+        hash_header(header)
+    }
+}
+
+// scrypt of the header, with the header as both password and salt
+#[derive(Debug, Clone, Copy)]
+pub struct ScryptHasher {
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+impl Default for ScryptHasher {
+    fn default() -> ScryptHasher {
+        ScryptHasher::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)
+    }
+}
+
+impl ScryptHasher {
+    // A hasher with other costs than the consensus ones, e.g. to measure them
+    pub fn new(log_n: u8, r: u32, p: u32) -> ScryptHasher {
+        ScryptHasher { log_n, r, p }
+    }
+
+    // Bytes of memory one hash needs
+    pub fn memory(&self) -> usize {
+        128 * self.r as usize * (1 << self.log_n)
+    }
+}
+
+impl PowHasher for ScryptHasher {
+    fn hash(&self, header: &[u8; HEADER_SIZE]) -> [u8; 32] {
+        let params = ScryptParams::new(self.log_n, self.r, self.p);
+        let mut hash = [0; 32];
+        scrypt(header, header, &params, &mut hash);
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The vectors were computed with Python's hashlib (OpenSSL), over a
+    // header of zeros and one counting 0, 1, 2, ... 99
+    fn zeros() -> [u8; HEADER_SIZE] {
+        [0; HEADER_SIZE]
+    }
+
+    fn counting() -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        for (i, byte) in header.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        header
+    }
+
+    #[test]
+    fn sha256_matches_its_test_vectors() {
+        let hasher = PowAlgorithm::Sha256.hasher();
+        assert_eq!(
+            hex::encode(hasher.hash(&zeros())),
+            "cd00e292c5970d3c5e2f0ffa5171e555bc46bfc4faddfb4a418b6840b86e79a3"
+        );
+        assert_eq!(
+            hex::encode(hasher.hash(&counting())),
+            "bce0aff19cf5aa6a7469a30d61d04e4376e4bbf6381052ee9e7f33925c954d52"
+        );
+    }
+
+    #[test]
+    fn scrypt_matches_its_test_vectors() {
+        // N = 1024 and r = p = 1
+        let hasher = PowAlgorithm::Scrypt.hasher();
+        assert_eq!(
+            hex::encode(hasher.hash(&zeros())),
+            "f43d8da192a35ece5cbb907fdbad36fc22ed521610c89d2db7759b8b6eda8818"
+        );
+        assert_eq!(
+            hex::encode(hasher.hash(&counting())),
+            "3066fcb1d3a11e43139ac4340782e994e6fa7b4e02e5cfe6e9a2a308c59112df"
+        );
+    }
+
+    #[test]
+    fn algorithms_round_trip_through_their_names() {
+        for algorithm in [PowAlgorithm::Sha256, PowAlgorithm::Scrypt] {
+            assert_eq!(algorithm.name().parse::<PowAlgorithm>().unwrap(), algorithm);
+        }
+        assert!("md5".parse::<PowAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn scrypt_memory_follows_its_cost() {
+        assert_eq!(ScryptHasher::default().memory(), 128 * 1024);
+        assert_eq!(ScryptHasher::new(14, 1, 1).memory(), 2 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,1229 @@
+// rpc.rs
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::Duration;
+
+use bitcoincash_addr::Address;
+use failure::format_err;
+use log::{error, info};
+use rand::{rngs::OsRng, RngCore};
+use serde_json::{json, Value};
+
+use crate::addrbook::address_group;
+use crate::assembler::BlockAssembler;
+use crate::block::{hash_header, Block, NONCE_OFFSET};
+use crate::blockchain::Blockchain;
+use crate::consensus::TARGET_HEXT;
+use crate::errors::Result;
+use crate::mempool::{Acceptance, Mempool};
+use crate::miner::CancelToken;
+use crate::node::Node;
+use crate::params::Consensus;
+use crate::peers::{PeerManager, DEFAULT_BAN_TIME};
+use crate::store::DATA_DIR;
+use crate::transaction::{Transaction, TransactionBuilder};
+use crate::utxoset::UTXOSet;
+use crate::wallet::{address_of, hash_pub_key, Wallets};
+
+pub const DEFAULT_RPC_PORT: u16 = 9332;
+// File in DATA_DIR the generated token is written to
+const RPC_COOKIE_FILE: &str = "rpc.cookie";
+
+// Largest request line and headers, and request body, the server will read
+const MAX_HEADER_SIZE: u64 = 16 * 1024;
+const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+// How long a client may take to send any part of its request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Connections served at once, more wait to be accepted until one closes
+const MAX_CONNECTIONS: usize = 16;
+
+// Templates handed out since the last block, older ones are dropped past this
+const MAX_TEMPLATES: usize = 64;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<failure::Error> for RpcError {
+    fn from(e: failure::Error) -> RpcError {
+        RpcError::new(INTERNAL_ERROR, e.to_string())
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+// Long-running JSON-RPC 2.0 server over HTTP, bound to localhost.
+// Clones share the node, each connection is served on its own thread.
+#[derive(Clone)]
+pub struct RpcServer {
+    port: u16,
+    token: Arc<String>,
+    // Connections being served, at most MAX_CONNECTIONS, notified when one closes
+    connections: Arc<(Mutex<usize>, Condvar)>,
+    utxo_set: Arc<RwLock<UTXOSet>>,
+    // Searches in progress, cancelled when a block is connected under them
+    mining: Arc<Mutex<Vec<CancelToken>>>,
+    // Templates handed to external miners by template id, until a block is connected
+    templates: Arc<Mutex<HashMap<String, Block>>>,
+    // Connections and bans reported by getpeerinfo and changed by setban
+    peers: PeerManager,
+    // Unconfirmed transactions put in the templates, locked after the UTXO set
+    mempool: Arc<Mutex<Mempool>>,
+    // The P2P node sharing the chain, which relays new transactions
+    node: Option<Node>,
+}
+
+impl RpcServer {
+    pub fn new(port: u16, token: String) -> Result<RpcServer> {
+        let bc = Blockchain::open_blockchain()?;
+        let mut server = RpcServer::with_utxo_set(port, token, UTXOSet { blockchain: bc });
+        server.peers = PeerManager::open(DATA_DIR)?;
+        Ok(server)
+    }
+
+    // Serves a node that is already open in this process
+    pub fn with_utxo_set(port: u16, token: String, utxo_set: UTXOSet) -> RpcServer {
+        let utxo_set = Arc::new(RwLock::new(utxo_set));
+        RpcServer::with_shared(port, token, utxo_set, PeerManager::default())
+    }
+
+    // Serves the chain, the mempool and the peers of a P2P node running in
+    // this process, new transactions are relayed by the node
+    pub fn for_node(port: u16, token: String, node: &Node) -> RpcServer {
+        let mut server =
+            RpcServer::with_shared(port, token, node.utxo_set(), node.peer_manager().clone());
+        server.mempool = node.mempool();
+        server.node = Some(node.clone());
+        server
+    }
+
+    fn with_shared(
+        port: u16,
+        token: String,
+        utxo_set: Arc<RwLock<UTXOSet>>,
+        peers: PeerManager,
+    ) -> RpcServer {
+        RpcServer {
+            port,
+            token: Arc::new(token),
+            connections: Arc::new((Mutex::new(0), Condvar::new())),
+            utxo_set,
+            mining: Arc::new(Mutex::new(Vec::new())),
+            templates: Arc::new(Mutex::new(HashMap::new())),
+            peers,
+            mempool: Arc::new(Mutex::new(Mempool::default())),
+            node: None,
+        }
+    }
+
+    // Accepts connections and answers one HTTP request per connection
+    pub fn run(&self) -> Result<()> {
+        self.run_with(TcpListener::bind(("127.0.0.1", self.port))?)
+    }
+
+    // Like run, on a listener already bound, e.g. one whose port the OS picked
+    pub fn run_with(&self, listener: TcpListener) -> Result<()> {
+        info!("RPC server listening on {}", listener.local_addr()?);
+
+        loop {
+            self.acquire_connection();
+            let (stream, _) = listener.accept()?;
+            let server = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.handle_connection(stream) {
+                    error!("rpc connection error: {}", e);
+                }
+                server.release_connection();
+            });
+        }
+    }
+
+    // Waits until fewer than MAX_CONNECTIONS connections are served and takes a slot
+    fn acquire_connection(&self) {
+        let (count, released) = &*self.connections;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+        while *count >= MAX_CONNECTIONS {
+            count = released.wait(count).unwrap_or_else(|e| e.into_inner());
+        }
+        *count += 1;
+    }
+
+    fn release_connection(&self) {
+        let (count, released) = &*self.connections;
+        *count.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        released.notify_one();
+    }
+
+    // Readers run in parallel, calls that connect blocks or add wallets take the write lock
+    fn read(&self) -> RwLockReadGuard<'_, UTXOSet> {
+        self.utxo_set.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, UTXOSet> {
+        self.utxo_set.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_mempool(&self) -> MutexGuard<'_, Mempool> {
+        self.mempool.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_mining(&self) -> MutexGuard<'_, Vec<CancelToken>> {
+        self.mining.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_templates(&self) -> MutexGuard<'_, HashMap<String, Block>> {
+        self.templates.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Connects a block, cancels the searches for blocks at its height,
+    // forgets the templates built on the old tip and drops the block's
+    // transactions from the mempool
+    fn connect(&self, block: &Block) -> Result<()> {
+        let resolved = {
+            let mut utxo_set = self.write();
+            utxo_set.blockchain.submit_block(block)?;
+            self.lock_mempool()
+                .block_connected(&utxo_set.blockchain, block)
+        };
+        for cancel in self.lock_mining().drain(..) {
+            cancel.cancel();
+        }
+        self.lock_templates().clear();
+        if let Some(node) = &self.node {
+            node.relay(resolved, None);
+        }
+        Ok(())
+    }
+
+    // Adds the transaction to the mempool, relaying it if a node runs
+    fn submit_transaction(&self, tx: Transaction) -> Result<Acceptance> {
+        match &self.node {
+            Some(node) => node.submit_transaction(tx),
+            None => {
+                let utxo_set = self.read();
+                self.lock_mempool().accept(&utxo_set.blockchain, tx)
+            }
+        }
+    }
+
+    // Seals the template without holding the node lock, so blocks submitted
+    // meanwhile are connected and make a proof of work search stop
+    fn mine(&self, template: Block) -> Result<Option<Block>> {
+        let engine = self.read().blockchain.engine();
+        let cancel = CancelToken::new();
+        self.lock_mining().push(cancel.clone());
+        let mined = engine.seal(template, &cancel);
+        self.lock_mining().retain(|other| *other != cancel);
+        mined
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let answered = self.answer(&mut stream);
+        // Closing with unread input would reset the connection and lose
+        // the response, so what is left of a refused request is read first
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = io::copy(&mut (&stream).take(MAX_HEADER_SIZE), &mut io::sink());
+        answered
+    }
+
+    // Reads one HTTP request and writes its response
+    fn answer(&self, stream: &mut TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        // What is left of MAX_HEADER_SIZE, a line is cut off where it runs out
+        let mut header_budget = MAX_HEADER_SIZE;
+        let mut request_line = String::new();
+        header_budget -= (&mut reader).take(header_budget).read_line(&mut request_line)? as u64;
+        let method = request_line.split_whitespace().next().unwrap_or("");
+
+        let mut content_length = 0;
+        let mut authorization = String::new();
+        loop {
+            let mut line = String::new();
+            let read = (&mut reader).take(header_budget).read_line(&mut line)?;
+            if read == 0 {
+                if header_budget == 0 {
+                    return write_response(stream, "431 Request Header Fields Too Large", "");
+                }
+                break;
+            }
+            header_budget -= read as u64;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.trim().parse()?,
+                    "authorization" => authorization = value.trim().to_string(),
+                    _ => {}
+                }
+            }
+        }
+
+        if method != "POST" {
+            return write_response(stream, "405 Method Not Allowed", "");
+        }
+        if !self.is_authorized(&authorization) {
+            return write_response(stream, "401 Unauthorized", "");
+        }
+        if content_length > MAX_BODY_SIZE {
+            return write_response(stream, "413 Payload Too Large", "");
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        // Notifications are answered with nothing, a batch of only
+        // notifications with no content at all
+        let response = match serde_json::from_slice::<Value>(&body) {
+            Ok(Value::Array(batch)) if !batch.is_empty() => {
+                let responses: Vec<Value> =
+                    batch.iter().filter_map(|req| self.handle_request(req)).collect();
+                match responses.is_empty() {
+                    true => None,
+                    false => Some(Value::Array(responses)),
+                }
+            }
+            Ok(request) => self.handle_request(&request),
+            Err(e) => Some(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, e.to_string()),
+            )),
+        };
+        match response {
+            Some(response) => write_response(stream, "200 OK", &response.to_string()),
+            None => write_response(stream, "204 No Content", ""),
+        }
+    }
+
+    // Accepts "Authorization: Bearer <token>"
+    fn is_authorized(&self, authorization: &str) -> bool {
+        match authorization.strip_prefix("Bearer ") {
+            Some(token) => constant_time_eq(token.trim().as_bytes(), self.token.as_bytes()),
+            None => false,
+        }
+    }
+
+    // Runs the call and returns its response, or None for a notification:
+    // a valid request without an id, whose result or error is not sent back
+    fn handle_request(&self, request: &Value) -> Option<Value> {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+
+        if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+            return Some(error_response(
+                id,
+                RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""),
+            ));
+        }
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => {
+                return Some(error_response(
+                    id,
+                    RpcError::new(INVALID_REQUEST, "method is missing"),
+                ))
+            }
+        };
+        let notification = request.get("id").is_none();
+        let params = match request.get("params") {
+            Some(Value::Array(params)) => params.clone(),
+            Some(Value::Null) | None => Vec::new(),
+            Some(_) if notification => return None,
+            Some(_) => {
+                return Some(error_response(
+                    id,
+                    RpcError::new(INVALID_PARAMS, "params must be an array"),
+                ))
+            }
+        };
+
+        info!("rpc call: {}", method);
+        let result = self.dispatch(method, &params);
+        if notification {
+            return None;
+        }
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(e) => error_response(id, e),
+        })
+    }
+
+    fn dispatch(&self, method: &str, params: &[Value]) -> RpcResult {
+        match method {
+            "getblockcount" => self.get_block_count(),
+            "getbestblockhash" => Ok(json!(self.read().blockchain.get_tip_hash())),
+            "getblock" => self.get_block(params),
+            "gettransaction" => self.get_transaction(params),
+            "getbalance" => self.get_balance(params),
+            "sendtoaddress" => self.send_to_address(params),
+            "sendrawtransaction" => self.send_raw_transaction(params),
+            "bumpfee" => self.bump_fee(params),
+            "getrawmempool" => self.get_raw_mempool(params),
+            "getnewaddress" => self.get_new_address(),
+            "listunspent" => self.list_unspent(params),
+            "getaddressutxos" => self.get_address_utxos(params),
+            "getaddresshistory" => self.get_address_history(params),
+            "getblocktemplate" => self.get_block_template(params),
+            "submitblock" => self.submit_block(params),
+            "getpeerinfo" => self.get_peer_info(),
+            "setban" => self.set_ban(params),
+            "listbanned" => self.list_banned(),
+            "getnodeaddresses" => self.get_node_addresses(),
+            "clearbanned" => {
+                self.peers.clear_bans()?;
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Method '{}' not found", method),
+            )),
+        }
+    }
+
+    fn get_peer_info(&self) -> RpcResult {
+        let peers: Vec<Value> = self
+            .peers
+            .peer_info()
+            .iter()
+            .map(|peer| {
+                json!({
+                    "id": peer.id,
+                    "addr": peer.addr.to_string(),
+                    "inbound": peer.inbound,
+                    "conntime": peer.connected_at,
+                    "bestheight": peer.best_height,
+                    "banscore": peer.score,
+                })
+            })
+            .collect();
+        Ok(json!(peers))
+    }
+
+    // params: [ip, "add" | "remove", bantime in seconds = 24h]
+    fn set_ban(&self, params: &[Value]) -> RpcResult {
+        let ip: IpAddr = string_param(params, 0, "ip")?
+            .parse()
+            .map_err(|_| RpcError::new(INVALID_PARAMS, "invalid ip"))?;
+        match string_param(params, 1, "command")?.as_str() {
+            "add" => {
+                let duration = match params.get(2) {
+                    None | Some(Value::Null) => DEFAULT_BAN_TIME,
+                    Some(value) => match value.as_u64() {
+                        Some(secs) if secs > 0 => Duration::from_secs(secs),
+                        _ => {
+                            return Err(RpcError::new(
+                                INVALID_PARAMS,
+                                "bantime must be a positive number of seconds",
+                            ))
+                        }
+                    },
+                };
+                self.peers.ban(ip, duration, "manually banned")?;
+            }
+            "remove" => {
+                if !self.peers.unban(&ip)? {
+                    return Err(RpcError::new(INVALID_PARAMS, format!("{} is not banned", ip)));
+                }
+            }
+            _ => {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "command must be \"add\" or \"remove\"",
+                ))
+            }
+        }
+        Ok(Value::Null)
+    }
+
+    fn get_node_addresses(&self) -> RpcResult {
+        let addresses: Vec<Value> = self
+            .peers
+            .address_book()
+            .addresses()
+            .iter()
+            .map(|known| {
+                json!({
+                    "address": known.addr.to_string(),
+                    "group": address_group(&known.addr),
+                    "lastseen": known.last_seen,
+                    "lastsuccess": known.last_success,
+                    "failures": known.failures,
+                    "seed": known.seed,
+                })
+            })
+            .collect();
+        Ok(json!(addresses))
+    }
+
+    fn list_banned(&self) -> RpcResult {
+        let bans: Vec<Value> = self
+            .peers
+            .list_bans()
+            .iter()
+            .map(|(ip, ban)| {
+                json!({
+                    "address": ip.to_string(),
+                    "banned_until": ban.until,
+                    "reason": ban.reason,
+                })
+            })
+            .collect();
+        Ok(json!(bans))
+    }
+
+    fn get_block_count(&self) -> RpcResult {
+        Ok(json!(self.read().blockchain.get_best_height()?))
+    }
+
+    // params: [hash | height, verbose = true]
+    fn get_block(&self, params: &[Value]) -> RpcResult {
+        let utxo_set = self.read();
+        let bc = &utxo_set.blockchain;
+        let block = match params.first() {
+            Some(Value::String(hash)) => bc.get_block(hash)?,
+            Some(Value::Number(height)) => match height.as_u64() {
+                Some(height) => bc.get_block_by_height(height as usize)?,
+                None => return Err(RpcError::new(INVALID_PARAMS, "height must be positive")),
+            },
+            _ => {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "expected block hash or height",
+                ))
+            }
+        };
+        let block = block.ok_or_else(|| RpcError::new(INVALID_PARAMS, "Block is not found"))?;
+
+        if params.get(1).and_then(Value::as_bool) == Some(false) {
+            let data = bincode::serialize(&block).map_err(failure::Error::from)?;
+            return Ok(json!(hex::encode(data)));
+        }
+        block_to_json(&block)
+    }
+
+    // params: [txid]
+    fn get_transaction(&self, params: &[Value]) -> RpcResult {
+        let txid = string_param(params, 0, "txid")?;
+        let utxo_set = self.read();
+        let tx = utxo_set
+            .blockchain
+            .find_tranasaction(&txid)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+
+        let mut result = tx_to_json(&tx);
+        if let Some(location) = utxo_set.blockchain.get_tx_location(&txid)? {
+            result["blockhash"] = json!(location.block_hash);
+            result["position"] = json!(location.position);
+        }
+        Ok(result)
+    }
+
+    // params: [address]
+    fn get_balance(&self, params: &[Value]) -> RpcResult {
+        let pub_key_hash = address_param(params, 0)?;
+        let utxos = self.read().find_utxo_for_address(&pub_key_hash)?;
+        let balance: i32 = utxos.iter().map(|utxo| utxo.value).sum();
+        Ok(json!(balance))
+    }
+
+    // params: [from, to, amount, mine = true, fee = 0]
+    // The transaction is mined into a new block straight away, as `send` does,
+    // and fails if another block is connected while the search runs. With
+    // mine false it goes to the mempool and is relayed instead, may spend
+    // the outputs of unconfirmed transactions, pays the fee and can be
+    // replaced with bumpfee.
+    fn send_to_address(&self, params: &[Value]) -> RpcResult {
+        let sender = string_param(params, 0, "from address")?;
+        let receiver = string_param(params, 1, "to address")?;
+        let amount = match params.get(2).and_then(Value::as_i64) {
+            Some(amount) if amount > 0 && amount <= i32::MAX as i64 => amount as i32,
+            _ => {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "amount must be a positive integer",
+                ))
+            }
+        };
+
+        let mine = match params.get(3) {
+            None | Some(Value::Null) => true,
+            Some(value) => value
+                .as_bool()
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "mine must be a boolean"))?,
+        };
+        if !mine {
+            let fee = match params.get(4) {
+                None | Some(Value::Null) => 0,
+                Some(value) => match value.as_i64() {
+                    Some(fee) if fee >= 0 && fee <= i32::MAX as i64 => fee as i32,
+                    _ => {
+                        return Err(RpcError::new(
+                            INVALID_PARAMS,
+                            "fee must be a positive integer",
+                        ))
+                    }
+                },
+            };
+            let tx = {
+                let utxo_set = self.read();
+                let mempool = self.lock_mempool();
+                TransactionBuilder::new(&utxo_set)
+                    .mempool(&mempool)
+                    .sender(&sender)
+                    .pay_to(&receiver, amount)
+                    .fee(fee)
+                    .replaceable()
+                    .build()?
+            };
+            let txid = tx.id.clone();
+            return match self.submit_transaction(tx)? {
+                Acceptance::Accepted(_) | Acceptance::Known => Ok(json!(txid)),
+                Acceptance::Rejected(reason) => Err(RpcError::new(INVALID_PARAMS, reason)),
+                Acceptance::Invalid(reason) => Err(RpcError::new(INTERNAL_ERROR, reason)),
+                Acceptance::Orphan(_) | Acceptance::Conflict(_) => Err(format_err!(
+                    "The mempool changed while building {}, try again",
+                    txid
+                )
+                .into()),
+            };
+        }
+
+        let template = {
+            let utxo_set = self.read();
+            let tx = Transaction::new_transaction(&sender, &receiver, amount, &utxo_set)?;
+            // The height keeps coinbases paying the same address apart
+            let height = utxo_set.blockchain.get_best_height()? + 1;
+            let cbtx = Transaction::new_coinbase(
+                sender.clone(),
+                format!("Reward to '{}' at height {}", sender, height),
+            )?;
+            utxo_set.blockchain.new_block_template(vec![cbtx, tx])?
+        };
+        let txid = template.get_transactions()[1].id.clone();
+        match self.mine(template)? {
+            Some(block) => self.connect(&block)?,
+            None => return Err(format_err!("A competing block arrived while mining").into()),
+        }
+        Ok(json!(txid))
+    }
+
+    // params: [txid, fee = what it and its descendants pay + 1]
+    // Rebuilds a replaceable mempool transaction of a local wallet with the
+    // same selection as sendtoaddress: it pays the same receivers and a
+    // higher fee, spending the old inputs and more if the change does not
+    // cover the fee. The old transaction and its descendants are replaced.
+    fn bump_fee(&self, params: &[Value]) -> RpcResult {
+        let txid = string_param(params, 0, "txid")?;
+        let (tx, old_fee, fee) = {
+            let utxo_set = self.read();
+            let mempool = self.lock_mempool();
+            let entry = mempool.get(&txid).ok_or_else(|| {
+                RpcError::new(INVALID_PARAMS, "Transaction is not in the mempool")
+            })?;
+            if !entry.tx.is_replaceable() {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "Transaction does not signal replaceability",
+                ));
+            }
+            let replaced_fee: i64 = mempool
+                .descendants(&txid)
+                .iter()
+                .filter_map(|id| mempool.get(id))
+                .map(|entry| entry.fee)
+                .sum();
+            let fee = match params.get(1) {
+                None | Some(Value::Null) => replaced_fee + 1,
+                Some(value) => value.as_i64().ok_or_else(|| {
+                    RpcError::new(INVALID_PARAMS, "fee must be a positive integer")
+                })?,
+            };
+            if fee <= replaced_fee || fee > i32::MAX as i64 {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!(
+                        "fee must exceed the {} the transaction and its descendants pay",
+                        replaced_fee
+                    ),
+                ));
+            }
+
+            // The wallet owning the inputs pays, the outputs to others are kept
+            let pub_key = &entry.tx.v_inputs[0].pub_key;
+            let wallets = Wallets::open(utxo_set.blockchain.store().clone())?;
+            let sender = wallets
+                .get_all_address()
+                .into_iter()
+                .find(|address| {
+                    wallets
+                        .get_wallet(address)
+                        .is_some_and(|wallet| &wallet.public_key == pub_key)
+                })
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Sender wallet not found"))?;
+            let mut sender_hash = pub_key.clone();
+            hash_pub_key(&mut sender_hash);
+            let mut builder = TransactionBuilder::new(&utxo_set)
+                .mempool(&mempool)
+                .replacing(&entry.tx)
+                .sender(&sender)
+                .fee(fee as i32)
+                .replaceable()
+                .lock_time(entry.tx.lock_time);
+            if let Some(lock) = entry.tx.v_inputs.iter().find_map(|input| input.relative_lock()) {
+                builder = builder.relative_lock(lock);
+            }
+            for output in &entry.tx.v_outputs {
+                if let Some(htlc) = &output.htlc {
+                    builder = builder.pay_to_htlc(
+                        &address_of(&output.pub_key_hash),
+                        output.value,
+                        &htlc.hash,
+                        htlc.lock_time,
+                    );
+                } else if !output.is_locked_with_key(&sender_hash) {
+                    builder = builder.pay_to(&address_of(&output.pub_key_hash), output.value);
+                }
+            }
+            (builder.build()?, entry.fee, fee)
+        };
+        let new_txid = tx.id.clone();
+        match self.submit_transaction(tx)? {
+            Acceptance::Accepted(_) => Ok(json!({
+                "txid": new_txid,
+                "origfee": old_fee,
+                "fee": fee,
+            })),
+            Acceptance::Rejected(reason) | Acceptance::Conflict(reason) => {
+                Err(RpcError::new(INVALID_PARAMS, reason))
+            }
+            Acceptance::Invalid(reason) => Err(RpcError::new(INTERNAL_ERROR, reason)),
+            Acceptance::Orphan(_) | Acceptance::Known => Err(format_err!(
+                "The mempool changed while building {}, try again",
+                new_txid
+            )
+            .into()),
+        }
+    }
+
+    // params: [hex encoded bincode transaction]
+    // Adds the transaction to the mempool and relays it. A transaction whose
+    // parents are unknown is kept in the orphan pool and reported as an error.
+    fn send_raw_transaction(&self, params: &[Value]) -> RpcResult {
+        let data = hex::decode(string_param(params, 0, "transaction hex")?)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+        let tx: Transaction =
+            bincode::deserialize(&data).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+        let txid = tx.id.clone();
+        match self.submit_transaction(tx)? {
+            Acceptance::Accepted(_) | Acceptance::Known => Ok(json!(txid)),
+            Acceptance::Orphan(parents) => Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("Missing inputs from {}, kept as an orphan", parents.join(", ")),
+            )),
+            Acceptance::Conflict(reason)
+            | Acceptance::Rejected(reason)
+            | Acceptance::Invalid(reason) => Err(RpcError::new(INVALID_PARAMS, reason)),
+        }
+    }
+
+    // params: [verbose = false]
+    // Mempool txids in the order they were accepted, or entries with their fee and size
+    fn get_raw_mempool(&self, params: &[Value]) -> RpcResult {
+        let verbose = params.first().and_then(Value::as_bool).unwrap_or(false);
+        let mempool = self.lock_mempool();
+        Ok(Value::Array(
+            mempool
+                .entries()
+                .iter()
+                .map(|entry| {
+                    if verbose {
+                        json!({ "txid": entry.tx.id, "fee": entry.fee, "size": entry.size })
+                    } else {
+                        json!(entry.tx.id)
+                    }
+                })
+                .collect(),
+        ))
+    }
+
+    fn get_new_address(&self) -> RpcResult {
+        let utxo_set = self.write();
+        let mut ws = Wallets::open(utxo_set.blockchain.store().clone())?;
+        let address = ws.create_wallet();
+        ws.save_all()?;
+        Ok(json!(address))
+    }
+
+    // params: [address]
+    fn list_unspent(&self, params: &[Value]) -> RpcResult {
+        let pub_key_hash = address_param(params, 0)?;
+        let unspent = self.read().list_unspent(&pub_key_hash)?;
+        Ok(Value::Array(
+            unspent
+                .iter()
+                .map(|(txid, index, output)| {
+                    json!({ "txid": txid, "vout": index, "amount": output.value })
+                })
+                .collect(),
+        ))
+    }
+
+    // params: [address]
+    fn get_address_utxos(&self, params: &[Value]) -> RpcResult {
+        let pub_key_hash = address_param(params, 0)?;
+        let utxos = self.read().blockchain.get_address_utxos(&pub_key_hash)?;
+        Ok(Value::Array(
+            utxos
+                .iter()
+                .map(|(txid, index, output)| {
+                    json!({ "txid": txid, "vout": index, "amount": output.value })
+                })
+                .collect(),
+        ))
+    }
+
+    // params: [address]
+    fn get_address_history(&self, params: &[Value]) -> RpcResult {
+        let pub_key_hash = address_param(params, 0)?;
+        let history = self.read().blockchain.get_address_history(&pub_key_hash)?;
+        Ok(Value::Array(
+            history
+                .iter()
+                .map(|(height, txid)| json!({ "height": height, "txid": txid }))
+                .collect(),
+        ))
+    }
+
+    // params: [reward address, extra nonce (optional)]
+    // The miner sets the big endian nonce at `nonceoffset` of `header` until the
+    // sha256 of the header has `targethexdigits` leading zero hex digits, and sends
+    // it back with submitblock [templateid, nonce]. Asking again with another extra
+    // nonce gives a fresh nonce space.
+    fn get_block_template(&self, params: &[Value]) -> RpcResult {
+        let address = string_param(params, 0, "address")?;
+        address_param(params, 0)?;
+        let extra_nonce = match params.get(1) {
+            None => 0,
+            Some(value) => value.as_u64().ok_or_else(|| {
+                RpcError::new(INVALID_PARAMS, "extra nonce must be a positive integer")
+            })?,
+        };
+
+        let (mut template, fees, min_time, params) = {
+            let utxo_set = self.read();
+            let height = utxo_set.blockchain.get_best_height()? + 1;
+            // The height keeps coinbases paying the same address apart
+            let (template, fees) = BlockAssembler::default().assemble(
+                &utxo_set.blockchain,
+                &self.lock_mempool(),
+                &address,
+                format!("Reward to '{}' at height {}", address, height),
+            )?;
+            (
+                template,
+                fees,
+                utxo_set.blockchain.median_time_past()? + 1,
+                utxo_set.blockchain.params().clone(),
+            )
+        };
+        if extra_nonce > 0 {
+            template.set_extra_nonce(extra_nonce)?;
+        }
+
+        let header = template.header()?;
+        let template_id = hex::encode(hash_header(&header));
+        let pow = match &params.consensus {
+            Consensus::ProofOfWork(algorithm) => Some(algorithm.name()),
+            Consensus::ProofOfAuthority(_) => None,
+        };
+        let coinbase = &template.get_transactions()[0];
+        let result = json!({
+            "templateid": template_id,
+            "height": template.get_height(),
+            "previousblockhash": template.get_prev_hash(),
+            "curtime": template.get_timestamp() as u64,
+            "mintime": min_time as u64,
+            "header": hex::encode(header),
+            "nonceoffset": NONCE_OFFSET,
+            "pow": pow,
+            "targethexdigits": TARGET_HEXT,
+            "target": format!("{}{}", "0".repeat(TARGET_HEXT), "f".repeat(64 - TARGET_HEXT)),
+            "sizelimit": params.max_block_size,
+            "sigoplimit": params.max_block_sigops,
+            "coinbase": {
+                "address": address,
+                "value": coinbase.v_outputs[0].value,
+                "data": String::from_utf8_lossy(&coinbase.v_inputs[0].pub_key),
+                "extranonce": extra_nonce,
+                "fees": fees,
+            },
+            "transactions": template.get_transactions().iter().map(tx_to_json).collect::<Vec<_>>(),
+        });
+
+        let mut templates = self.lock_templates();
+        if templates.len() >= MAX_TEMPLATES {
+            templates.clear();
+        }
+        templates.insert(template_id, template);
+        Ok(result)
+    }
+
+    // params: [hex encoded bincode block] or [templateid, nonce]
+    fn submit_block(&self, params: &[Value]) -> RpcResult {
+        let block = if params.len() >= 2 {
+            self.solved_template(params)?
+        } else {
+            let data = hex::decode(string_param(params, 0, "block hex")?)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+            bincode::deserialize(&data).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?
+        };
+
+        self.connect(&block)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+        Ok(json!(block.get_hash()))
+    }
+
+    // Fills the nonce of a template handed out by getblocktemplate
+    fn solved_template(&self, params: &[Value]) -> std::result::Result<Block, RpcError> {
+        let template_id = string_param(params, 0, "template id")?;
+        let nonce = match params.get(1).and_then(Value::as_u64) {
+            Some(nonce) => nonce,
+            None => return Err(RpcError::new(INVALID_PARAMS, "nonce must be a positive integer")),
+        };
+        let mut block = match self.lock_templates().get(&template_id) {
+            Some(template) => template.clone(),
+            None => return Err(RpcError::new(INVALID_PARAMS, "unknown or stale template")),
+        };
+        block.set_nonce(nonce)?;
+        Ok(block)
+    }
+}
+
+// Returns the token given on the command line, or generates one and stores it in the cookie file
+pub fn load_or_generate_token(token: Option<&String>) -> Result<String> {
+    if let Some(token) = token {
+        return Ok(token.clone());
+    }
+    let mut key: [u8; 32] = [0; 32];
+    OsRng.fill_bytes(&mut key);
+    let token = hex::encode(key);
+    fs::create_dir_all(DATA_DIR)?;
+    let path = Path::new(DATA_DIR).join(RPC_COOKIE_FILE);
+    write_private(&path, &token)?;
+    info!("RPC token written to {}", path.display());
+    Ok(token)
+}
+
+// Writes a file only its owner can read, as the token gives full control
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // The mode only applies to new files, a cookie left by an older version
+    // may still be readable by others
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+fn block_to_json(block: &Block) -> RpcResult {
+    Ok(json!({
+        "hash": block.get_hash(),
+        "height": block.get_height(),
+        "previousblockhash": block.get_prev_hash(),
+        "merkleroot": hex::encode(block.hash_transactions()?),
+        "time": block.get_timestamp() as u64,
+        "nonce": block.get_nonce(),
+        "tx": block.get_transactions().iter().map(|tx| tx.id.clone()).collect::<Vec<_>>(),
+    }))
+}
+
+fn tx_to_json(tx: &Transaction) -> Value {
+    json!({
+        "txid": tx.id,
+        "coinbase": tx.is_coinbase(),
+        "vin": tx.v_inputs.iter().map(|input| json!({
+            "txid": input.txid,
+            "vout": input.output_index,
+            "signature": hex::encode(&input.signature),
+            "pubkey": hex::encode(&input.pub_key),
+            "sequence": input.sequence,
+            "preimage": hex::encode(&input.preimage),
+        })).collect::<Vec<_>>(),
+        "vout": tx.v_outputs.iter().map(|output| json!({
+            "value": output.value,
+            "pubkeyhash": hex::encode(&output.pub_key_hash),
+            "htlc": output.htlc.as_ref().map(|htlc| json!({
+                "hash": hex::encode(&htlc.hash),
+                "refundpubkeyhash": hex::encode(&htlc.refund_pub_key_hash),
+                "locktime": htlc.lock_time,
+            })),
+            "data": output.data.as_ref().map(hex::encode),
+        })).collect::<Vec<_>>(),
+        "locktime": tx.lock_time,
+    })
+}
+
+fn error_response(id: Value, e: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": e.code, "message": e.message },
+        "id": id,
+    })
+}
+
+fn string_param(
+    params: &[Value],
+    index: usize,
+    name: &str,
+) -> std::result::Result<String, RpcError> {
+    match params.get(index).and_then(Value::as_str) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(RpcError::new(INVALID_PARAMS, format!("missing {}", name))),
+    }
+}
+
+fn address_param(params: &[Value], index: usize) -> std::result::Result<Vec<u8>, RpcError> {
+    let address = string_param(params, index, "address")?;
+    match Address::decode(&address) {
+        Ok(address) => Ok(address.body),
+        Err(_) => Err(RpcError::new(INVALID_PARAMS, "invalid address")),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream
+        .write_all(response.as_bytes())
+        .map_err(|e| format_err!("failed to write rpc response: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::store::SledStore;
+
+    const TOKEN: &str = "secret";
+
+    // Serves a new chain in a temporary directory on a port the OS picks
+    fn start(name: &str) -> SocketAddr {
+        let root =
+            std::env::temp_dir().join(format!("rust-chain-rpc-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let store = SledStore::open(&root).unwrap();
+        let mut wallets = Wallets::open(store.clone()).unwrap();
+        let address = wallets.create_wallet();
+        wallets.save_all().unwrap();
+        let blockchain =
+            Blockchain::create_with_params(store, address, ChainParams::regtest()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            RpcServer::with_utxo_set(addr.port(), TOKEN.to_string(), UTXOSet { blockchain });
+        thread::spawn(move || server.run_with(listener));
+        addr
+    }
+
+    // Sends the request and returns the status line and the body
+    fn send(addr: SocketAddr, request: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    fn post(addr: SocketAddr, body: &str) -> (String, String) {
+        send(
+            addr,
+            &format!(
+                "POST / HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+                TOKEN,
+                body.len(),
+                body
+            ),
+        )
+    }
+
+    fn call(addr: SocketAddr, body: &str) -> Value {
+        let (status, body) = post(addr, body);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[test]
+    fn answers_calls_and_reports_error_codes() {
+        let addr = start("calls");
+        let response = call(addr, r#"{"jsonrpc":"2.0","method":"getblockcount","id":7}"#);
+        assert_eq!(response, json!({ "jsonrpc": "2.0", "result": 0, "id": 7 }));
+
+        let code = |body: &str| call(addr, body)["error"]["code"].as_i64().unwrap();
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","method":"nope","id":1}"#),
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","method":"getblock","id":1}"#),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","method":"getbalance","params":["nope"],"id":1}"#),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc":"1.0","method":"getblockcount","id":1}"#),
+            INVALID_REQUEST
+        );
+        assert_eq!(code(r#"{"jsonrpc":"2.0","id":1}"#), INVALID_REQUEST);
+        assert_eq!(code("{"), PARSE_ERROR);
+        assert_eq!(call(addr, "{")["id"], Value::Null);
+    }
+
+    #[test]
+    fn answers_batches_but_not_notifications() {
+        let addr = start("batch");
+        let response = call(
+            addr,
+            r#"[{"jsonrpc":"2.0","method":"getblockcount","id":1},
+                {"jsonrpc":"2.0","method":"getblockcount"},
+                {"jsonrpc":"2.0","method":"nope","id":2},
+                5]"#,
+        );
+        let ids: Vec<&Value> = response
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|response| &response["id"])
+            .collect();
+        assert_eq!(ids, vec![&json!(1), &json!(2), &Value::Null]);
+
+        let notification = r#"{"jsonrpc":"2.0","method":"getblockcount"}"#;
+        assert_eq!(
+            post(addr, notification),
+            ("HTTP/1.1 204 No Content".to_string(), String::new())
+        );
+        let batch = format!("[{},{}]", notification, notification);
+        assert_eq!(
+            post(addr, &batch),
+            ("HTTP/1.1 204 No Content".to_string(), String::new())
+        );
+    }
+
+    #[test]
+    fn refuses_requests_without_the_token() {
+        let addr = start("auth");
+        let body = r#"{"jsonrpc":"2.0","method":"getblockcount","id":1}"#;
+        let request = |authorization: &str| {
+            format!(
+                "POST / HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+                authorization,
+                body.len(),
+                body
+            )
+        };
+        assert_eq!(send(addr, &request("")).0, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(
+            send(addr, &request("Authorization: Bearer wrong\r\n")).0,
+            "HTTP/1.1 401 Unauthorized"
+        );
+        assert_eq!(
+            send(addr, &request("Authorization: Basic secret\r\n")).0,
+            "HTTP/1.1 401 Unauthorized"
+        );
+        let get = format!("GET / HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", TOKEN);
+        assert_eq!(send(addr, &get).0, "HTTP/1.1 405 Method Not Allowed");
+        let huge = format!(
+            "POST / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_HEADER_SIZE as usize)
+        );
+        assert_eq!(
+            send(addr, &huge).0,
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+        assert_eq!(
+            send(addr, &request("Authorization: Bearer secret\r\n")).0,
+            "HTTP/1.1 200 OK"
+        );
+    }
+
+    #[test]
+    fn waits_for_a_free_connection_over_the_limit() {
+        let addr = start("limit");
+        // Connections that never send their request keep their slot
+        let idle: Vec<TcpStream> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        let body = r#"{"jsonrpc":"2.0","method":"getblockcount","id":1}"#;
+        let request = format!(
+            "POST / HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+            TOKEN,
+            body.len(),
+            body
+        );
+        let mut waiting = TcpStream::connect(addr).unwrap();
+        waiting.write_all(request.as_bytes()).unwrap();
+        waiting
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let mut response = String::new();
+        assert!(waiting.read_to_string(&mut response).is_err());
+        assert!(response.is_empty());
+
+        drop(idle);
+        waiting.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        waiting.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+}
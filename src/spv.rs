@@ -0,0 +1,648 @@
+// spv.rs
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use failure::format_err;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::block::{Block, BlockHeader};
+use crate::consensus::{Clock, SystemClock};
+use crate::engine::{ConsensusEngine, ProofOfWork};
+use crate::errors::Result;
+use crate::filter::{filter_header, outpoint_element, output_element, BlockFilter};
+use crate::net::{Peer, TxProof};
+use crate::params::Checkpoints;
+use crate::peers::{PeerManager, INVALID_PROOF_SCORE};
+use crate::store::{ChainStore, SledStore, StoreBatch, DATA_DIR};
+use crate::sync::{best_candidate, connect_peers, Candidate, LocalChain, SyncProgress};
+use crate::transaction::Transaction;
+use crate::tx::TXOutput;
+use crate::wallet::{hash_pub_key, Wallets};
+
+// Keys of the light client's entries in the store:
+//   'h' height(u64 BE) -> bincode BlockHeader, the most-work header chain
+//   't' txid -> bincode ProvenTx, a wallet transaction with a checked merkle branch
+//   's' -> height(u64 BE) the wallet transactions are searched up to
+//   'f' height(u64 BE) -> filter header of a block searched with its compact filter
+const HEADER_KIND: u8 = b'h';
+const TX_KIND: u8 = b't';
+const FILTER_HEADER_KIND: u8 = b'f';
+const SCANNED_KEY: &[u8] = b"s";
+
+fn header_key(height: usize) -> Vec<u8> {
+    let mut key = vec![HEADER_KIND];
+    key.extend_from_slice(&(height as u64).to_be_bytes());
+    key
+}
+
+fn filter_header_key(height: usize) -> Vec<u8> {
+    let mut key = vec![FILTER_HEADER_KIND];
+    key.extend_from_slice(&(height as u64).to_be_bytes());
+    key
+}
+
+fn tx_key(txid: &str) -> Vec<u8> {
+    let mut key = vec![TX_KIND];
+    key.extend_from_slice(txid.as_bytes());
+    key
+}
+
+// True if the transaction pays or spends one of the pub key hashes
+fn concerns(tx: &Transaction, wanted: &HashSet<&[u8]>) -> bool {
+    let pays = tx
+        .v_outputs
+        .iter()
+        .any(|output| wanted.contains(output.pub_key_hash.as_slice()));
+    let spends = !tx.is_coinbase()
+        && tx.v_inputs.iter().any(|input| {
+            let mut pub_key_hash = input.pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            wanted.contains(pub_key_hash.as_slice())
+        });
+    pays || spends
+}
+
+// The filter header before a range of blocks and the filter hashes of the range
+type FilterHashes = (Vec<u8>, Vec<Vec<u8>>);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ProvenTx {
+    height: usize,
+    tx: Transaction,
+}
+
+// Simplified payment verification: follows the chain by its headers only and
+// keeps the wallet transactions full nodes prove to be in those blocks.
+// A node can leave transactions out, but can not make up any.
+pub struct LightClient<S: ChainStore = SledStore> {
+    store: S,
+    clock: Arc<dyn Clock>,
+    // Checks the seals of the headers, proof of work unless replaced
+    engine: Arc<dyn ConsensusEngine>,
+    checkpoints: Checkpoints,
+    peers: PeerManager,
+}
+
+impl LightClient {
+    // Opens the light client data in the CLI's data directory
+    pub fn open_light_client() -> Result<LightClient> {
+        Ok(LightClient::open(SledStore::open(DATA_DIR)?))
+    }
+}
+
+impl<S: ChainStore> LightClient<S> {
+    pub fn open(store: S) -> LightClient<S> {
+        LightClient {
+            store,
+            clock: Arc::new(SystemClock),
+            engine: Arc::new(ProofOfWork::default()),
+            checkpoints: Checkpoints::new(),
+            peers: PeerManager::default(),
+        }
+    }
+
+    // Replaces the clock header timestamps are checked against
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> LightClient<S> {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // Follows a chain sealed by another engine, e.g. the proof of authority
+    // engine of a network's params
+    pub fn with_engine(mut self, engine: Box<dyn ConsensusEngine>) -> LightClient<S> {
+        self.engine = Arc::from(engine);
+        self
+    }
+
+    // Rejects header chains that differ from the checkpoints
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> LightClient<S> {
+        self.checkpoints = checkpoints;
+        self
+    }
+
+    // Uses the manager's ban list and limits instead of an empty in-memory one
+    pub fn with_peer_manager(mut self, peers: PeerManager) -> LightClient<S> {
+        self.peers = peers;
+        self
+    }
+
+    // The header chain, indexed by height
+    pub fn get_headers(&self) -> Result<Vec<BlockHeader>> {
+        let mut headers = Vec::new();
+        for (_, data) in self.store.scan_spv(&[HEADER_KIND])? {
+            headers.push(bincode::deserialize(&data)?);
+        }
+        Ok(headers)
+    }
+
+    // Height of the last header, None before the first sync
+    pub fn get_best_height(&self) -> Result<Option<usize>> {
+        Ok(self.get_headers()?.len().checked_sub(1))
+    }
+
+    // Height below which the blocks were searched for wallet transactions
+    pub fn get_scanned_height(&self) -> Result<usize> {
+        match self.store.get_spv(SCANNED_KEY)? {
+            Some(data) => {
+                let bytes: [u8; 8] = data
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| format_err!("Corrupt scanned height"))?;
+                Ok(u64::from_be_bytes(bytes) as usize)
+            }
+            None => Ok(0),
+        }
+    }
+
+    // The proven wallet transactions with the height of their block
+    pub fn get_transactions(&self) -> Result<Vec<(usize, Transaction)>> {
+        let mut txs = Vec::new();
+        for (_, data) in self.store.scan_spv(&[TX_KIND])? {
+            let proven: ProvenTx = bincode::deserialize(&data)?;
+            txs.push((proven.height, proven.tx));
+        }
+        txs.sort_by_key(|(height, _)| *height);
+        Ok(txs)
+    }
+
+    // Outputs locked to the pub key hash that no proven transaction spends
+    pub fn get_address_utxos(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, i32, TXOutput)>> {
+        let txs = self.get_transactions()?;
+        let mut spent: HashSet<(&str, i32)> = HashSet::new();
+        for (_, tx) in &txs {
+            if !tx.is_coinbase() {
+                for input in &tx.v_inputs {
+                    spent.insert((&input.txid, input.output_index));
+                }
+            }
+        }
+
+        let mut utxos = Vec::new();
+        for (_, tx) in &txs {
+            for (index, output) in tx.v_outputs.iter().enumerate() {
+                if output.is_locked_with_key(pub_key_hash)
+                    && !spent.contains(&(tx.id.as_str(), index as i32))
+                {
+                    utxos.push((tx.id.clone(), index as i32, output.clone()));
+                }
+            }
+        }
+        Ok(utxos)
+    }
+
+    pub fn get_balance(&self, pub_key_hash: &[u8]) -> Result<i32> {
+        Ok(self
+            .get_address_utxos(pub_key_hash)?
+            .iter()
+            .map(|(_, _, output)| output.value)
+            .sum())
+    }
+
+    // Follows the most-work header chain among the peers, then asks them for
+    // proofs of the wallet transactions in the blocks not searched yet.
+    // Returns the number of new proven transactions.
+    pub fn sync(&self, peers: &[String], progress: &dyn Fn(&SyncProgress)) -> Result<usize> {
+        let peers = self.sync_headers(peers, progress)?;
+        self.scan(peers, progress)
+    }
+
+    // Like sync, but without telling the peers which addresses the wallets
+    // own: the blocks' compact filters are tested locally and only the
+    // matching blocks are downloaded
+    pub fn sync_filtered(
+        &self,
+        peers: &[String],
+        progress: &dyn Fn(&SyncProgress),
+    ) -> Result<usize> {
+        let peers = self.sync_headers(peers, progress)?;
+        self.scan_filters(peers, progress)
+    }
+
+    // Stores the most-work header chain among the peers and returns the peers on it
+    fn sync_headers(
+        &self,
+        peers: &[String],
+        progress: &dyn Fn(&SyncProgress),
+    ) -> Result<Vec<Peer>> {
+        let local = LocalChain::from_headers(&self.get_headers()?)?;
+        let peers = connect_peers(&self.peers, peers, &local)?;
+        let (candidate, peers) = best_candidate(
+            peers,
+            &local,
+            self.clock.now(),
+            &*self.engine,
+            &self.checkpoints,
+            progress,
+        )?;
+        match candidate {
+            Some(candidate) => self.apply_headers(&local, candidate)?,
+            None => info!("already on the most-work header chain"),
+        }
+        Ok(peers)
+    }
+
+    // Stores the candidate's headers, dropping what was proven in the blocks
+    // past the fork point so those are searched again
+    fn apply_headers(&self, local: &LocalChain, candidate: Candidate) -> Result<()> {
+        let mut batch = StoreBatch::default();
+        let start = candidate.fork_height.map_or(0, |height| height + 1);
+        for height in start..local.hashes.len() {
+            batch.remove_spv(header_key(height));
+            batch.remove_spv(filter_header_key(height));
+        }
+        if start < local.hashes.len() {
+            info!("reorganizing {} headers", local.hashes.len() - start);
+            for (txid, proven) in self.proven_txs()? {
+                if proven.height >= start {
+                    batch.remove_spv(tx_key(&txid));
+                }
+            }
+            if self.get_scanned_height()? > start {
+                batch.put_spv(SCANNED_KEY.to_vec(), (start as u64).to_be_bytes().to_vec());
+            }
+        }
+        for header in &candidate.headers {
+            batch.put_spv(header_key(header.height), bincode::serialize(header)?);
+        }
+        self.store.write_batch(batch)
+    }
+
+    fn proven_txs(&self) -> Result<Vec<(String, ProvenTx)>> {
+        let mut txs = Vec::new();
+        for (key, data) in self.store.scan_spv(&[TX_KIND])? {
+            txs.push((
+                String::from_utf8(key[1..].to_vec())?,
+                bincode::deserialize(&data)?,
+            ));
+        }
+        Ok(txs)
+    }
+
+    // Pub key hashes of every wallet in the store
+    fn wallet_pub_key_hashes(&self) -> Result<Vec<Vec<u8>>> {
+        let wallets = Wallets::open(self.store.clone())?;
+        let mut pub_key_hashes = Vec::new();
+        for address in wallets.get_all_address() {
+            if let Some(wallet) = wallets.get_wallet(&address) {
+                let mut pub_key_hash = wallet.public_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                pub_key_hashes.push(pub_key_hash);
+            }
+        }
+        Ok(pub_key_hashes)
+    }
+
+    // Searches the blocks past the scanned height peer by peer, each one
+    // continuing where the previous one stopped
+    fn scan(&self, peers: Vec<Peer>, progress: &dyn Fn(&SyncProgress)) -> Result<usize> {
+        let headers = self.get_headers()?;
+        let hashes = LocalChain::from_headers(&headers)?.hashes;
+        let pub_key_hashes = self.wallet_pub_key_hashes()?;
+        let start = self.get_scanned_height()?;
+        if pub_key_hashes.is_empty() {
+            return Err(format_err!("There is no wallet to search transactions for"));
+        }
+
+        let mut found = 0;
+        let mut next = start;
+        for mut peer in peers {
+            while next < hashes.len() {
+                let result = peer
+                    .get_proofs(pub_key_hashes.clone(), next, hashes.len())
+                    .and_then(|(proofs, next_height, last_hash)| {
+                        if next_height <= next {
+                            return Ok(None);
+                        }
+                        if hashes[next_height - 1] != last_hash {
+                            return Err(format_err!("Peer searched another chain"));
+                        }
+                        let proven = peer.blame(
+                            INVALID_PROOF_SCORE,
+                            self.check_proofs(&headers, &hashes, &pub_key_hashes, proofs),
+                        )?;
+                        Ok(Some((proven, next_height)))
+                    });
+                let (proven, next_height) = match result {
+                    Ok(Some(answer)) => answer,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("proofs from {} rejected: {}", peer.addr(), e);
+                        break;
+                    }
+                };
+
+                let mut batch = StoreBatch::default();
+                for tx in proven {
+                    if self.store.get_spv(&tx_key(&tx.tx.id))?.is_none() {
+                        found += 1;
+                    }
+                    batch.put_spv(tx_key(&tx.tx.id), bincode::serialize(&tx)?);
+                }
+                batch.put_spv(
+                    SCANNED_KEY.to_vec(),
+                    (next_height as u64).to_be_bytes().to_vec(),
+                );
+                self.store.write_batch(batch)?;
+                next = next_height;
+                progress(&SyncProgress {
+                    headers: hashes.len(),
+                    blocks: next - start,
+                    total_blocks: hashes.len() - start,
+                    height: next - 1,
+                });
+            }
+        }
+        if next < hashes.len() {
+            return Err(format_err!(
+                "No peer searched the blocks from height {}",
+                next
+            ));
+        }
+        info!("{} new wallet transactions proven", found);
+        Ok(found)
+    }
+
+    // Keeps the proofs whose transaction concerns the wallet and whose branch
+    // leads to the merkle root of our header at that height
+    fn check_proofs(
+        &self,
+        headers: &[BlockHeader],
+        hashes: &[String],
+        pub_key_hashes: &[Vec<u8>],
+        proofs: Vec<TxProof>,
+    ) -> Result<Vec<ProvenTx>> {
+        let wanted: HashSet<&[u8]> = pub_key_hashes.iter().map(|pkh| pkh.as_slice()).collect();
+        let mut proven = HashMap::new();
+        for proof in proofs {
+            if hashes.get(proof.height) != Some(&proof.block_hash) {
+                return Err(format_err!(
+                    "Proof for block {} is not on the header chain",
+                    proof.block_hash
+                ));
+            }
+            // The leaves hash the signed transaction, which leaves the id out
+            let leaf = proof.tx.clone().hash()?;
+            if !proof
+                .branch
+                .verify(&headers[proof.height].merkle_root, &leaf)?
+            {
+                return Err(format_err!("Merkle proof for {} is not valid", proof.tx.id));
+            }
+            if proof.tx.derived_id()? != proof.tx.id {
+                return Err(format_err!("Transaction {} has another id", leaf));
+            }
+
+            if concerns(&proof.tx, &wanted) {
+                proven.insert(
+                    proof.tx.id.clone(),
+                    ProvenTx {
+                        height: proof.height,
+                        tx: proof.tx,
+                    },
+                );
+            }
+        }
+        Ok(proven.into_values().collect())
+    }
+
+    // Searches the blocks past the scanned height with their compact filters.
+    // The filter headers are taken from the peers first, where peers disagree
+    // the block is downloaded to see whose filter is right.
+    fn scan_filters(&self, peers: Vec<Peer>, progress: &dyn Fn(&SyncProgress)) -> Result<usize> {
+        let headers = self.get_headers()?;
+        let hashes = LocalChain::from_headers(&headers)?.hashes;
+        let pub_key_hashes = self.wallet_pub_key_hashes()?;
+        let start = self.get_scanned_height()?;
+        if pub_key_hashes.is_empty() {
+            return Err(format_err!("There is no wallet to search transactions for"));
+        }
+        if start >= hashes.len() {
+            return Ok(0);
+        }
+        let ((mut prev_header, filter_hashes), mut peers) =
+            self.agreed_filter_hashes(peers, &hashes, start)?;
+
+        // Blocks paying the wallets match their pub key hashes, blocks
+        // spending from them match the outpoints they received
+        let wanted: HashSet<&[u8]> = pub_key_hashes.iter().map(|pkh| pkh.as_slice()).collect();
+        let mut elements: Vec<Vec<u8>> = pub_key_hashes
+            .iter()
+            .map(|pkh| output_element(pkh))
+            .collect();
+        for (_, tx) in self.get_transactions()? {
+            elements.extend(wallet_outpoints(&tx, &wanted));
+        }
+
+        let mut found = 0;
+        let mut next = start;
+        while next < hashes.len() {
+            let filters = fetch_filters(&mut peers, next, &hashes, &filter_hashes[next - start..])?;
+            let mut batch = StoreBatch::default();
+            for (hash, filter) in filters {
+                let height = next;
+                let header = filter_header(&filter_hashes[height - start], &prev_header);
+                if filter.matches_any(&hash, &elements)? {
+                    let block = fetch_block(&mut peers, &headers[height])?;
+                    for tx in block.get_transactions() {
+                        if !concerns(tx, &wanted) {
+                            continue;
+                        }
+                        elements.extend(wallet_outpoints(tx, &wanted));
+                        if self.store.get_spv(&tx_key(&tx.id))?.is_none() {
+                            found += 1;
+                        }
+                        let proven = ProvenTx {
+                            height,
+                            tx: tx.clone(),
+                        };
+                        batch.put_spv(tx_key(&tx.id), bincode::serialize(&proven)?);
+                    }
+                }
+                batch.put_spv(filter_header_key(height), header.clone());
+                prev_header = header;
+                next += 1;
+            }
+            batch.put_spv(SCANNED_KEY.to_vec(), (next as u64).to_be_bytes().to_vec());
+            self.store.write_batch(batch)?;
+            progress(&SyncProgress {
+                headers: hashes.len(),
+                blocks: next - start,
+                total_blocks: hashes.len() - start,
+                height: next - 1,
+            });
+        }
+        info!("{} new wallet transactions found", found);
+        Ok(found)
+    }
+
+    // Gets every peer's filter hashes from the start height to our tip and
+    // settles disagreements by building the disputed filter from its block.
+    // Returns the filter header before the start, the filter hashes and the
+    // peers that sent them.
+    fn agreed_filter_hashes(
+        &self,
+        peers: Vec<Peer>,
+        hashes: &[String],
+        start: usize,
+    ) -> Result<(FilterHashes, Vec<Peer>)> {
+        let known_prev = match start.checked_sub(1) {
+            Some(height) => self.store.get_spv(&filter_header_key(height))?,
+            None => Some(vec![0; 32]),
+        };
+        let mut serving = Vec::new();
+        let mut offers = Vec::new();
+        for mut peer in peers {
+            match fetch_filter_hashes(&mut peer, start, hashes) {
+                Ok((prev_header, _))
+                    if known_prev
+                        .as_ref()
+                        .is_some_and(|known| *known != prev_header) =>
+                {
+                    warn!("{} sent filter headers of another chain", peer.addr())
+                }
+                Ok(offer) => {
+                    serving.push(peer);
+                    offers.push(offer);
+                }
+                Err(e) => warn!("filter headers from {} rejected: {}", peer.addr(), e),
+            }
+        }
+        if offers.is_empty() {
+            return Err(format_err!("No peer sent filter headers"));
+        }
+        if offers
+            .iter()
+            .any(|(prev_header, _)| *prev_header != offers[0].0)
+        {
+            return Err(format_err!(
+                "Peers disagree on the filter header before height {}",
+                start
+            ));
+        }
+
+        let headers = self.get_headers()?;
+        loop {
+            let first = &offers[0].1;
+            let disputed = (0..first.len()).find(|i| {
+                offers
+                    .iter()
+                    .any(|(_, filter_hashes)| filter_hashes[*i] != first[*i])
+            });
+            let index = match disputed {
+                Some(index) => index,
+                None => break,
+            };
+            let height = start + index;
+            warn!("peers disagree on the filter of block {}", hashes[height]);
+            let block = fetch_block(&mut serving, &headers[height])?;
+            let filter_hash = BlockFilter::build(&block)?.hash()?;
+
+            let mut kept = (Vec::new(), Vec::new());
+            for (peer, offer) in serving.into_iter().zip(offers) {
+                if offer.1[index] == filter_hash {
+                    kept.0.push(peer);
+                    kept.1.push(offer);
+                } else {
+                    warn!("dropping {} for a wrong filter", peer.addr());
+                    peer.misbehaving(INVALID_PROOF_SCORE, "wrong filter hash");
+                }
+            }
+            (serving, offers) = kept;
+            if offers.is_empty() {
+                return Err(format_err!(
+                    "No peer sent the right filter of block {}",
+                    hashes[height]
+                ));
+            }
+        }
+
+        Ok((offers.swap_remove(0), serving))
+    }
+}
+
+// Outpoint elements of the transaction's outputs paying one of the pub key hashes
+fn wallet_outpoints(tx: &Transaction, wanted: &HashSet<&[u8]>) -> Vec<Vec<u8>> {
+    tx.v_outputs
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| wanted.contains(output.pub_key_hash.as_slice()))
+        .map(|(index, _)| outpoint_element(&tx.id, index as i32))
+        .collect()
+}
+
+// Gets the filter header before the start height and the filter hashes up to our tip
+fn fetch_filter_hashes(peer: &mut Peer, start: usize, hashes: &[String]) -> Result<FilterHashes> {
+    let stop_hash = &hashes[hashes.len() - 1];
+    let (prev_header, mut filter_hashes) = peer.get_cfheaders(start, stop_hash)?;
+    while start + filter_hashes.len() < hashes.len() {
+        let (_, more) = peer.get_cfheaders(start + filter_hashes.len(), stop_hash)?;
+        if more.is_empty() {
+            return Err(format_err!("Filter headers stop before our tip"));
+        }
+        filter_hashes.extend(more);
+    }
+    if start + filter_hashes.len() != hashes.len() {
+        return Err(format_err!("Too many filter headers"));
+    }
+    Ok((prev_header, filter_hashes))
+}
+
+// Gets the next filters from the first peer that answers with the agreed
+// ones, dropping the peers that do not
+fn fetch_filters(
+    peers: &mut Vec<Peer>,
+    start: usize,
+    hashes: &[String],
+    filter_hashes: &[Vec<u8>],
+) -> Result<Vec<(String, BlockFilter)>> {
+    let stop_hash = &hashes[hashes.len() - 1];
+    while !peers.is_empty() {
+        match peers[0].get_cfilters(start, stop_hash) {
+            Ok(filters) if filters.is_empty() || filters.len() > filter_hashes.len() => {
+                warn!("{} sent no usable filters", peers[0].addr())
+            }
+            Ok(filters) => {
+                let mut matching = true;
+                for (i, (hash, filter)) in filters.iter().enumerate() {
+                    matching &= *hash == hashes[start + i] && filter.hash()? == filter_hashes[i];
+                }
+                if matching {
+                    return Ok(filters);
+                }
+                warn!(
+                    "{} sent filters that do not match their headers",
+                    peers[0].addr()
+                );
+                peers[0].misbehaving(INVALID_PROOF_SCORE, "filters do not match their headers");
+            }
+            Err(e) => warn!("filters from {} rejected: {}", peers[0].addr(), e),
+        }
+        peers.remove(0);
+    }
+    Err(format_err!(
+        "No peer sent the filters from height {}",
+        start
+    ))
+}
+
+// Downloads a block from the first peer that has it. Its header must hash to
+// the one we have, which covers the transactions through the merkle root.
+fn fetch_block(peers: &mut [Peer], header: &BlockHeader) -> Result<Block> {
+    let hash = header.hash()?;
+    for peer in peers.iter_mut() {
+        match peer.get_blocks(vec![hash.clone()]) {
+            Ok(blocks) => {
+                for block in blocks {
+                    if block.get_header()?.hash()? == hash {
+                        return Ok(block);
+                    }
+                }
+                warn!("{} did not send block {}", peer.addr(), hash);
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+    Err(format_err!("No peer sent block {}", hash))
+}
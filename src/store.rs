@@ -0,0 +1,926 @@
+// store.rs
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use failure::format_err;
+use sled::transaction::TransactionError;
+use sled::Transactional;
+
+use crate::block::Block;
+use crate::errors::Result;
+use crate::filter::FilterEntry;
+use crate::params::Consensus;
+use crate::tx::TXOutputs;
+use crate::txindex::TxLocation;
+use crate::wallet::Wallet;
+
+// Directory the CLI keeps its databases in
+pub const DATA_DIR: &str = "data";
+
+const TIP_KEY: &str = "LAST";
+// Renamed whenever the encoding of UTXO entries changes, so a UTXO set
+// written by an older version has no tip and is rebuilt on open
+const UTXO_TIP_KEY: &str = "UTXO_LAST_V5";
+const TXINDEX_KEY: &str = "TXINDEX";
+const ADDRINDEX_KEY: &str = "ADDRINDEX";
+const NETWORK_KEY: &str = "NETWORK";
+const CONSENSUS_KEY: &str = "CONSENSUS";
+
+// Writes to blocks, tip, UTXOs and indexes that must land together.
+// A store applies a batch completely or not at all.
+#[derive(Debug, Default)]
+pub struct StoreBatch {
+    blocks: Vec<Block>,
+    tip: Option<String>,
+    clear_heights: bool,
+    // None removes the entry
+    heights: Vec<(usize, Option<String>)>,
+    network: Option<String>,
+    consensus: Option<Consensus>,
+    utxo_tip: Option<String>,
+    clear_utxos: bool,
+    // None removes the entry
+    utxos: Vec<(String, Option<TXOutputs>)>,
+    txindex_enabled: Option<bool>,
+    clear_txindex: bool,
+    txindex: Vec<(String, Option<TxLocation>)>,
+    addrindex_enabled: Option<bool>,
+    clear_addrindex: bool,
+    // Raw keys and values built by the addrindex module, applied in order
+    addrindex: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    // Raw keys and values built by the light client, applied in order
+    spv: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    cfilters: Vec<(String, FilterEntry)>,
+}
+
+impl StoreBatch {
+    pub fn put_block(&mut self, block: &Block) {
+        self.blocks.push(block.clone());
+    }
+
+    pub fn set_tip(&mut self, hash: &str) {
+        self.tip = Some(hash.to_string());
+    }
+
+    // Drops every height entry before the entries of this batch are applied
+    pub fn clear_heights(&mut self) {
+        self.clear_heights = true;
+        self.heights.clear();
+    }
+
+    // Records the block at a height of the active chain
+    pub fn index_height(&mut self, height: usize, hash: &str) {
+        self.heights.push((height, Some(hash.to_string())));
+    }
+
+    pub fn unindex_height(&mut self, height: usize) {
+        self.heights.push((height, None));
+    }
+
+    // Records the network the chain was created on
+    pub fn set_network(&mut self, network: &str) {
+        self.network = Some(network.to_string());
+    }
+
+    // Records how the blocks of the chain are sealed, its proof of work hash
+    // or validators
+    pub fn set_consensus(&mut self, consensus: &Consensus) {
+        self.consensus = Some(consensus.clone());
+    }
+
+    // Records which block the UTXO set reflects after this batch
+    pub fn set_utxo_tip(&mut self, hash: &str) {
+        self.utxo_tip = Some(hash.to_string());
+    }
+
+    // Drops every UTXO before the puts of this batch are applied
+    pub fn clear_utxos(&mut self) {
+        self.clear_utxos = true;
+        self.utxos.clear();
+    }
+
+    pub fn put_utxos(&mut self, txid: &str, outputs: TXOutputs) {
+        self.utxos.push((txid.to_string(), Some(outputs)));
+    }
+
+    pub fn remove_utxos(&mut self, txid: &str) {
+        self.utxos.push((txid.to_string(), None));
+    }
+
+    // Turns maintenance of the transaction index on or off
+    pub fn set_txindex_enabled(&mut self, enabled: bool) {
+        self.txindex_enabled = Some(enabled);
+    }
+
+    // Drops every index entry before the entries of this batch are applied
+    pub fn clear_txindex(&mut self) {
+        self.clear_txindex = true;
+        self.txindex.clear();
+    }
+
+    pub fn index_tx(&mut self, txid: &str, location: TxLocation) {
+        self.txindex.push((txid.to_string(), Some(location)));
+    }
+
+    pub fn unindex_tx(&mut self, txid: &str) {
+        self.txindex.push((txid.to_string(), None));
+    }
+
+    // Turns maintenance of the address index on or off
+    pub fn set_addrindex_enabled(&mut self, enabled: bool) {
+        self.addrindex_enabled = Some(enabled);
+    }
+
+    pub fn clear_addrindex(&mut self) {
+        self.clear_addrindex = true;
+        self.addrindex.clear();
+    }
+
+    pub fn index_address(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.addrindex.push((key, Some(value)));
+    }
+
+    pub fn unindex_address(&mut self, key: Vec<u8>) {
+        self.addrindex.push((key, None));
+    }
+
+    pub fn put_cfilter(&mut self, block_hash: &str, entry: &FilterEntry) {
+        self.cfilters.push((block_hash.to_string(), entry.clone()));
+    }
+
+    pub fn put_spv(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.spv.push((key, Some(value)));
+    }
+
+    pub fn remove_spv(&mut self, key: Vec<u8>) {
+        self.spv.push((key, None));
+    }
+}
+
+// Persistence used by Blockchain, UTXOSet and Wallets.
+// Implementations are cheap handles: clones share the same underlying data
+// and can be moved to other threads of the same process.
+pub trait ChainStore: Clone + Send + Sync {
+    // Blocks and tip
+    fn get_block(&self, hash: &str) -> Result<Option<Block>>;
+    fn get_tip(&self) -> Result<Option<String>>;
+    // Drops the chain and everything derived from it, the index settings stay
+    fn clear_blocks(&self) -> Result<()>;
+    // Hash of the active chain's block at a height
+    fn get_block_hash(&self, height: usize) -> Result<Option<String>>;
+    // Name of the network the chain was created on
+    fn get_network(&self) -> Result<Option<String>>;
+    // How the blocks of the chain are sealed, None for chains older than the record
+    fn get_consensus(&self) -> Result<Option<Consensus>>;
+
+    // UTXOs, keyed by the txid that created them
+    fn get_utxos(&self, txid: &str) -> Result<Option<TXOutputs>>;
+    fn all_utxos(&self) -> Result<Vec<(String, TXOutputs)>>;
+    // Hash of the block the UTXO set was last brought up to
+    fn get_utxo_tip(&self) -> Result<Option<String>>;
+
+    // Optional txid -> block index
+    fn txindex_enabled(&self) -> Result<bool>;
+    fn get_tx_location(&self, txid: &str) -> Result<Option<TxLocation>>;
+
+    // Optional address index, entries are returned in key order
+    fn addrindex_enabled(&self) -> Result<bool>;
+    fn scan_addrindex(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    // Compact filters with their headers, keyed by block hash
+    fn get_cfilter(&self, block_hash: &str) -> Result<Option<FilterEntry>>;
+
+    // Headers and proven wallet transactions of a light client, kept apart
+    // from the full chain, entries are returned in key order
+    fn get_spv(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn scan_spv(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    // Applies all writes of the batch atomically
+    fn write_batch(&self, batch: StoreBatch) -> Result<()>;
+
+    // Wallets, keyed by address
+    fn all_wallets(&self) -> Result<Vec<(String, Wallet)>>;
+    fn put_wallet(&self, address: &str, wallet: &Wallet) -> Result<()>;
+}
+
+// On disk store: blocks, tip and UTXOs live in one sled database so a block
+// connect is a single transaction, wallets are kept in their own database.
+// Both databases are opened once here; sled refuses a second open of the
+// same path, so a process should open the store once and clone the handle.
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    blocks: sled::Db,
+    heights: sled::Tree,
+    utxos: sled::Tree,
+    txindex: sled::Tree,
+    addrindex: sled::Tree,
+    spv: sled::Tree,
+    cfilters: sled::Tree,
+    wallets: sled::Db,
+}
+
+impl SledStore {
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<SledStore> {
+        let root = root.as_ref();
+        let blocks = sled::open(root.join("blocks"))?;
+        let heights = blocks.open_tree("heights")?;
+        let utxos = blocks.open_tree("utxos")?;
+        let txindex = blocks.open_tree("txindex")?;
+        let addrindex = blocks.open_tree("addrindex")?;
+        let spv = blocks.open_tree("spv")?;
+        let cfilters = blocks.open_tree("cfilters")?;
+        let wallets = sled::open(root.join("wallets"))?;
+        Ok(SledStore {
+            blocks,
+            heights,
+            utxos,
+            txindex,
+            addrindex,
+            spv,
+            cfilters,
+            wallets,
+        })
+    }
+}
+
+impl ChainStore for SledStore {
+    fn get_block(&self, hash: &str) -> Result<Option<Block>> {
+        match self.blocks.get(hash)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_tip(&self) -> Result<Option<String>> {
+        match self.blocks.get(TIP_KEY)? {
+            Some(hash) => Ok(Some(String::from_utf8(hash.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn clear_blocks(&self) -> Result<()> {
+        // One transaction like write_batch, so a crash can not leave half a
+        // chain behind. The index flags live among the blocks but are
+        // settings, not chain data, and are kept.
+        let flags = [TXINDEX_KEY.as_bytes(), ADDRINDEX_KEY.as_bytes()];
+        let mut stale_blocks = Vec::new();
+        for key in self.blocks.iter().keys() {
+            let key = key?;
+            if !flags.contains(&key.as_ref()) {
+                stale_blocks.push(key);
+            }
+        }
+        let mut stale = Vec::new();
+        for tree in [
+            &self.heights,
+            &self.utxos,
+            &self.txindex,
+            &self.addrindex,
+            &self.spv,
+            &self.cfilters,
+        ] {
+            stale.push(tree.iter().keys().collect::<sled::Result<Vec<_>>>()?);
+        }
+
+        let blocks: &sled::Tree = &self.blocks;
+        let trees = (
+            blocks,
+            &self.heights,
+            &self.utxos,
+            &self.txindex,
+            &self.addrindex,
+            &self.spv,
+            &self.cfilters,
+        );
+        trees
+            .transaction(|(blocks, heights, utxos, txindex, addrindex, spv, cfilters)| {
+                for key in &stale_blocks {
+                    blocks.remove(key)?;
+                }
+                let trees = [heights, utxos, txindex, addrindex, spv, cfilters];
+                for (tree, keys) in trees.iter().zip(&stale) {
+                    for key in keys {
+                        tree.remove(key)?;
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e: TransactionError<()>| format_err!("store transaction failed: {:?}", e))?;
+
+        self.blocks.flush()?;
+        Ok(())
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<Option<String>> {
+        match self.heights.get((height as u64).to_be_bytes())? {
+            Some(hash) => Ok(Some(String::from_utf8(hash.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_utxos(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        match self.utxos.get(txid)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn all_utxos(&self) -> Result<Vec<(String, TXOutputs)>> {
+        let mut utxos = Vec::new();
+        for kv in self.utxos.iter() {
+            let (k, v) = kv?;
+            utxos.push((String::from_utf8(k.to_vec())?, bincode::deserialize(&v)?));
+        }
+        Ok(utxos)
+    }
+
+    fn get_utxo_tip(&self) -> Result<Option<String>> {
+        match self.blocks.get(UTXO_TIP_KEY)? {
+            Some(hash) => Ok(Some(String::from_utf8(hash.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_network(&self) -> Result<Option<String>> {
+        match self.blocks.get(NETWORK_KEY)? {
+            Some(name) => Ok(Some(String::from_utf8(name.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_consensus(&self) -> Result<Option<Consensus>> {
+        match self.blocks.get(CONSENSUS_KEY)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn txindex_enabled(&self) -> Result<bool> {
+        Ok(self.blocks.get(TXINDEX_KEY)?.is_some())
+    }
+
+    fn get_tx_location(&self, txid: &str) -> Result<Option<TxLocation>> {
+        match self.txindex.get(txid)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn addrindex_enabled(&self) -> Result<bool> {
+        Ok(self.blocks.get(ADDRINDEX_KEY)?.is_some())
+    }
+
+    fn scan_addrindex(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        for kv in self.addrindex.scan_prefix(prefix) {
+            let (k, v) = kv?;
+            entries.push((k.to_vec(), v.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn get_cfilter(&self, block_hash: &str) -> Result<Option<FilterEntry>> {
+        match self.cfilters.get(block_hash)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_spv(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.spv.get(key)?.map(|data| data.to_vec()))
+    }
+
+    fn scan_spv(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        for kv in self.spv.scan_prefix(prefix) {
+            let (k, v) = kv?;
+            entries.push((k.to_vec(), v.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn write_batch(&self, batch: StoreBatch) -> Result<()> {
+        // Everything is encoded up front so the transaction closure cannot fail half way
+        let mut block_writes = Vec::new();
+        for block in &batch.blocks {
+            block_writes.push((block.get_hash(), bincode::serialize(block)?));
+        }
+        let mut utxo_writes = Vec::new();
+        for (txid, outputs) in &batch.utxos {
+            let data = match outputs {
+                Some(outputs) => Some(bincode::serialize(outputs)?),
+                None => None,
+            };
+            utxo_writes.push((txid.as_bytes(), data));
+        }
+        let consensus = match &batch.consensus {
+            Some(consensus) => Some(bincode::serialize(consensus)?),
+            None => None,
+        };
+        let mut stale_heights = Vec::new();
+        if batch.clear_heights {
+            for key in self.heights.iter().keys() {
+                stale_heights.push(key?);
+            }
+        }
+        let mut stale_utxos = Vec::new();
+        if batch.clear_utxos {
+            for key in self.utxos.iter().keys() {
+                stale_utxos.push(key?);
+            }
+        }
+        let mut index_writes = Vec::new();
+        for (txid, location) in &batch.txindex {
+            let data = match location {
+                Some(location) => Some(bincode::serialize(location)?),
+                None => None,
+            };
+            index_writes.push((txid.as_bytes(), data));
+        }
+        let mut stale_index = Vec::new();
+        if batch.clear_txindex {
+            for key in self.txindex.iter().keys() {
+                stale_index.push(key?);
+            }
+        }
+        let mut filter_writes = Vec::new();
+        for (hash, entry) in &batch.cfilters {
+            filter_writes.push((hash.as_bytes(), bincode::serialize(entry)?));
+        }
+        let mut stale_addresses = Vec::new();
+        if batch.clear_addrindex {
+            for key in self.addrindex.iter().keys() {
+                stale_addresses.push(key?);
+            }
+        }
+
+        let blocks: &sled::Tree = &self.blocks;
+        let trees = (
+            blocks,
+            &self.heights,
+            &self.utxos,
+            &self.txindex,
+            &self.addrindex,
+            &self.spv,
+            &self.cfilters,
+        );
+        trees
+            .transaction(|(blocks, heights, utxos, txindex, addrindex, spv, cfilters)| {
+                for (hash, data) in &filter_writes {
+                    cfilters.insert(*hash, data.as_slice())?;
+                }
+                for (key, value) in &batch.spv {
+                    match value {
+                        Some(value) => spv.insert(key.as_slice(), value.as_slice())?,
+                        None => spv.remove(key.as_slice())?,
+                    };
+                }
+                for key in &stale_utxos {
+                    utxos.remove(key)?;
+                }
+                for key in &stale_addresses {
+                    addrindex.remove(key)?;
+                }
+                for (key, value) in &batch.addrindex {
+                    match value {
+                        Some(value) => addrindex.insert(key.as_slice(), value.as_slice())?,
+                        None => addrindex.remove(key.as_slice())?,
+                    };
+                }
+                match batch.addrindex_enabled {
+                    Some(true) => blocks.insert(ADDRINDEX_KEY, &[1u8][..]).map(|_| ())?,
+                    Some(false) => blocks.remove(ADDRINDEX_KEY).map(|_| ())?,
+                    None => {}
+                }
+                for key in &stale_index {
+                    txindex.remove(key)?;
+                }
+                for (txid, data) in &index_writes {
+                    match data {
+                        Some(data) => txindex.insert(*txid, data.as_slice())?,
+                        None => txindex.remove(*txid)?,
+                    };
+                }
+                match batch.txindex_enabled {
+                    Some(true) => blocks.insert(TXINDEX_KEY, &[1u8][..]).map(|_| ())?,
+                    Some(false) => blocks.remove(TXINDEX_KEY).map(|_| ())?,
+                    None => {}
+                }
+                for (txid, data) in &utxo_writes {
+                    match data {
+                        Some(data) => utxos.insert(*txid, data.as_slice())?,
+                        None => utxos.remove(*txid)?,
+                    };
+                }
+                for (hash, data) in &block_writes {
+                    blocks.insert(hash.as_bytes(), data.as_slice())?;
+                }
+                for key in &stale_heights {
+                    heights.remove(key)?;
+                }
+                for (height, hash) in &batch.heights {
+                    let key = (*height as u64).to_be_bytes();
+                    match hash {
+                        Some(hash) => heights.insert(&key[..], hash.as_bytes())?,
+                        None => heights.remove(&key[..])?,
+                    };
+                }
+                if let Some(hash) = &batch.utxo_tip {
+                    blocks.insert(UTXO_TIP_KEY, hash.as_bytes())?;
+                }
+                if let Some(hash) = &batch.tip {
+                    blocks.insert(TIP_KEY, hash.as_bytes())?;
+                }
+                if let Some(network) = &batch.network {
+                    blocks.insert(NETWORK_KEY, network.as_bytes())?;
+                }
+                if let Some(consensus) = &consensus {
+                    blocks.insert(CONSENSUS_KEY, consensus.as_slice())?;
+                }
+                Ok(())
+            })
+            .map_err(|e: TransactionError<()>| format_err!("store transaction failed: {:?}", e))?;
+
+        self.blocks.flush()?;
+        Ok(())
+    }
+
+    fn all_wallets(&self) -> Result<Vec<(String, Wallet)>> {
+        let mut wallets = Vec::new();
+        for item in self.wallets.iter() {
+            // IVec is wrapper around a vector of bytes(Vec<u8>)
+            // for handing storing and sending binary data
+            let (k, v) = item?;
+            wallets.push((String::from_utf8(k.to_vec())?, bincode::deserialize(&v)?));
+        }
+        Ok(wallets)
+    }
+
+    fn put_wallet(&self, address: &str, wallet: &Wallet) -> Result<()> {
+        self.wallets
+            .insert(address, bincode::serialize(wallet)?)?;
+        self.wallets.flush()?;
+        Ok(())
+    }
+}
+
+// In memory store for tests and throwaway chains, nothing touches the disk
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    inner: Arc<Mutex<MemoryData>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryData {
+    blocks: HashMap<String, Block>,
+    tip: Option<String>,
+    heights: BTreeMap<usize, String>,
+    network: Option<String>,
+    consensus: Option<Consensus>,
+    utxos: BTreeMap<String, TXOutputs>,
+    utxo_tip: Option<String>,
+    txindex_enabled: bool,
+    txindex: HashMap<String, TxLocation>,
+    addrindex_enabled: bool,
+    addrindex: BTreeMap<Vec<u8>, Vec<u8>>,
+    spv: BTreeMap<Vec<u8>, Vec<u8>>,
+    cfilters: HashMap<String, FilterEntry>,
+    wallets: BTreeMap<String, Wallet>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn data(&self) -> std::sync::MutexGuard<'_, MemoryData> {
+        // A panic while holding the lock cannot leave the maps half written
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get_block(&self, hash: &str) -> Result<Option<Block>> {
+        Ok(self.data().blocks.get(hash).cloned())
+    }
+
+    fn get_tip(&self) -> Result<Option<String>> {
+        Ok(self.data().tip.clone())
+    }
+
+    fn clear_blocks(&self) -> Result<()> {
+        let mut data = self.data();
+        data.blocks.clear();
+        data.tip = None;
+        data.heights.clear();
+        data.network = None;
+        data.consensus = None;
+        data.utxos.clear();
+        data.utxo_tip = None;
+        data.txindex.clear();
+        data.addrindex.clear();
+        data.spv.clear();
+        data.cfilters.clear();
+        Ok(())
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<Option<String>> {
+        Ok(self.data().heights.get(&height).cloned())
+    }
+
+    fn get_utxos(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        Ok(self.data().utxos.get(txid).cloned())
+    }
+
+    fn all_utxos(&self) -> Result<Vec<(String, TXOutputs)>> {
+        Ok(self
+            .data()
+            .utxos
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn get_utxo_tip(&self) -> Result<Option<String>> {
+        Ok(self.data().utxo_tip.clone())
+    }
+
+    fn get_network(&self) -> Result<Option<String>> {
+        Ok(self.data().network.clone())
+    }
+
+    fn get_consensus(&self) -> Result<Option<Consensus>> {
+        Ok(self.data().consensus.clone())
+    }
+
+    fn txindex_enabled(&self) -> Result<bool> {
+        Ok(self.data().txindex_enabled)
+    }
+
+    fn get_tx_location(&self, txid: &str) -> Result<Option<TxLocation>> {
+        Ok(self.data().txindex.get(txid).cloned())
+    }
+
+    fn addrindex_enabled(&self) -> Result<bool> {
+        Ok(self.data().addrindex_enabled)
+    }
+
+    fn scan_addrindex(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .data()
+            .addrindex
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn get_cfilter(&self, block_hash: &str) -> Result<Option<FilterEntry>> {
+        Ok(self.data().cfilters.get(block_hash).cloned())
+    }
+
+    fn get_spv(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.data().spv.get(key).cloned())
+    }
+
+    fn scan_spv(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .data()
+            .spv
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn write_batch(&self, batch: StoreBatch) -> Result<()> {
+        // The lock is held for the whole batch so readers never see part of it
+        let mut data = self.data();
+        if batch.clear_heights {
+            data.heights.clear();
+        }
+        for (height, hash) in batch.heights {
+            match hash {
+                Some(hash) => data.heights.insert(height, hash),
+                None => data.heights.remove(&height),
+            };
+        }
+        if batch.clear_utxos {
+            data.utxos.clear();
+        }
+        for (txid, outputs) in batch.utxos {
+            match outputs {
+                Some(outputs) => data.utxos.insert(txid, outputs),
+                None => data.utxos.remove(&txid),
+            };
+        }
+        if batch.clear_txindex {
+            data.txindex.clear();
+        }
+        for (txid, location) in batch.txindex {
+            match location {
+                Some(location) => data.txindex.insert(txid, location),
+                None => data.txindex.remove(&txid),
+            };
+        }
+        if let Some(enabled) = batch.txindex_enabled {
+            data.txindex_enabled = enabled;
+        }
+        if batch.clear_addrindex {
+            data.addrindex.clear();
+        }
+        for (key, value) in batch.addrindex {
+            match value {
+                Some(value) => data.addrindex.insert(key, value),
+                None => data.addrindex.remove(&key),
+            };
+        }
+        if let Some(enabled) = batch.addrindex_enabled {
+            data.addrindex_enabled = enabled;
+        }
+        for (hash, entry) in batch.cfilters {
+            data.cfilters.insert(hash, entry);
+        }
+        for (key, value) in batch.spv {
+            match value {
+                Some(value) => data.spv.insert(key, value),
+                None => data.spv.remove(&key),
+            };
+        }
+        for block in batch.blocks {
+            data.blocks.insert(block.get_hash(), block);
+        }
+        if batch.utxo_tip.is_some() {
+            data.utxo_tip = batch.utxo_tip;
+        }
+        if batch.tip.is_some() {
+            data.tip = batch.tip;
+        }
+        if batch.network.is_some() {
+            data.network = batch.network;
+        }
+        if batch.consensus.is_some() {
+            data.consensus = batch.consensus;
+        }
+        Ok(())
+    }
+
+    fn all_wallets(&self) -> Result<Vec<(String, Wallet)>> {
+        Ok(self
+            .data()
+            .wallets
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn put_wallet(&self, address: &str, wallet: &Wallet) -> Result<()> {
+        self.data()
+            .wallets
+            .insert(address.to_string(), wallet.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::params::ChainParams;
+    use crate::testutil::{mine, new_chain, pay, pub_key_hash};
+
+    #[test]
+    fn memory_store_connects_and_disconnects_blocks() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        utxo.blockchain.set_txindex(true).unwrap();
+        utxo.blockchain.set_addrindex(true).unwrap();
+        let store = utxo.blockchain.store().clone();
+        let genesis = utxo.blockchain.get_tip_hash();
+        let genesis_block = utxo.blockchain.get_block(&genesis).unwrap().unwrap();
+        let genesis_coinbase = genesis_block.get_transactions()[0].id.clone();
+        let other_hash = pub_key_hash(&utxo, &other);
+
+        let tx = pay(&utxo, &miner, &other, 30).unwrap();
+        let block = mine(&mut utxo, &miner, vec![tx.clone()]).unwrap();
+        assert_eq!(store.get_tip().unwrap(), Some(block.get_hash()));
+        assert_eq!(store.get_block_hash(0).unwrap(), Some(genesis.clone()));
+        assert_eq!(store.get_block_hash(1).unwrap(), Some(block.get_hash()));
+        assert!(store.get_block(&block.get_hash()).unwrap().is_some());
+        assert!(store.get_utxos(&genesis_coinbase).unwrap().is_none());
+        assert_eq!(store.get_utxos(&tx.id).unwrap().unwrap().outputs.len(), 2);
+        assert_eq!(
+            store.get_tx_location(&tx.id).unwrap().unwrap().block_hash,
+            block.get_hash()
+        );
+        assert_eq!(utxo.blockchain.get_address_utxos(&other_hash).unwrap().len(), 1);
+        assert_eq!(utxo.blockchain.get_address_history(&other_hash).unwrap().len(), 1);
+        assert_eq!(utxo.find_utxo_for_address(&other_hash).unwrap()[0].value, 30);
+
+        let disconnected = utxo.blockchain.disconnect_tip().unwrap();
+        assert_eq!(disconnected.get_hash(), block.get_hash());
+        assert_eq!(store.get_tip().unwrap(), Some(genesis.clone()));
+        assert_eq!(utxo.blockchain.get_tip_hash(), genesis);
+        assert!(store.get_block_hash(1).unwrap().is_none());
+        // The block stays in the store, its changes do not
+        assert!(store.get_block(&block.get_hash()).unwrap().is_some());
+        assert!(store.get_utxos(&genesis_coinbase).unwrap().is_some());
+        assert!(store.get_utxos(&tx.id).unwrap().is_none());
+        assert!(store.get_tx_location(&tx.id).unwrap().is_none());
+        assert!(utxo.blockchain.get_address_utxos(&other_hash).unwrap().is_empty());
+        assert!(utxo.blockchain.get_address_history(&other_hash).unwrap().is_empty());
+        assert!(utxo.find_utxo_for_address(&other_hash).unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_store_writes_batches_and_scans_in_key_order() {
+        let store = MemoryStore::new();
+        let mut batch = StoreBatch::default();
+        batch.index_address(b"ub".to_vec(), vec![2]);
+        batch.index_address(b"ua".to_vec(), vec![1]);
+        batch.index_address(b"h".to_vec(), vec![3]);
+        batch.put_spv(b"x1".to_vec(), vec![4]);
+        store.write_batch(batch).unwrap();
+        assert_eq!(
+            store.scan_addrindex(b"u").unwrap(),
+            vec![(b"ua".to_vec(), vec![1]), (b"ub".to_vec(), vec![2])]
+        );
+        assert_eq!(store.get_spv(b"x1").unwrap(), Some(vec![4]));
+
+        let mut batch = StoreBatch::default();
+        batch.unindex_address(b"ua".to_vec());
+        batch.remove_spv(b"x1".to_vec());
+        store.write_batch(batch).unwrap();
+        assert_eq!(store.scan_addrindex(b"u").unwrap(), vec![(b"ub".to_vec(), vec![2])]);
+        assert!(store.get_spv(b"x1").unwrap().is_none());
+    }
+
+    // Fills every tree, clears the chain and checks what is left
+    fn check_clear_blocks<S: ChainStore>(store: S) {
+        let (utxo, miner, _) = new_chain(ChainParams::regtest());
+        let genesis = utxo.blockchain.get_block(&utxo.blockchain.get_tip_hash()).unwrap().unwrap();
+        let mut batch = StoreBatch::default();
+        batch.put_block(&genesis);
+        batch.set_tip(&genesis.get_hash());
+        batch.index_height(0, &genesis.get_hash());
+        batch.set_network("regtest");
+        batch.set_utxo_tip(&genesis.get_hash());
+        batch.put_utxos("txid", TXOutputs::default());
+        batch.set_txindex_enabled(true);
+        batch.index_tx(
+            "txid",
+            TxLocation {
+                block_hash: genesis.get_hash(),
+                position: 0,
+            },
+        );
+        batch.set_addrindex_enabled(true);
+        batch.index_address(b"key".to_vec(), Vec::new());
+        batch.put_spv(b"key".to_vec(), Vec::new());
+        let filter = utxo.blockchain.get_block_filter(&genesis.get_hash()).unwrap().unwrap();
+        batch.put_cfilter(&genesis.get_hash(), &filter);
+        store.write_batch(batch).unwrap();
+        let wallet = utxo.blockchain.store().all_wallets().unwrap().remove(0).1;
+        store.put_wallet(&miner, &wallet).unwrap();
+
+        store.clear_blocks().unwrap();
+        assert!(store.get_block(&genesis.get_hash()).unwrap().is_none());
+        assert!(store.get_tip().unwrap().is_none());
+        assert!(store.get_block_hash(0).unwrap().is_none());
+        assert!(store.get_network().unwrap().is_none());
+        assert!(store.get_utxo_tip().unwrap().is_none());
+        assert!(store.all_utxos().unwrap().is_empty());
+        assert!(store.get_tx_location("txid").unwrap().is_none());
+        assert!(store.scan_addrindex(b"").unwrap().is_empty());
+        assert!(store.scan_spv(b"").unwrap().is_empty());
+        assert!(store.get_cfilter(&genesis.get_hash()).unwrap().is_none());
+        assert!(store.txindex_enabled().unwrap());
+        assert!(store.addrindex_enabled().unwrap());
+        assert_eq!(store.all_wallets().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn both_stores_clear_the_same_data() {
+        check_clear_blocks(MemoryStore::new());
+        let root = std::env::temp_dir().join(format!("rust-chain-clear-{}", std::process::id()));
+        check_clear_blocks(SledStore::open(&root).unwrap());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn a_new_chain_is_indexed_when_the_indexes_are_enabled() {
+        let (utxo, miner, _) = new_chain(ChainParams::regtest());
+        utxo.blockchain.set_txindex(true).unwrap();
+        utxo.blockchain.set_addrindex(true).unwrap();
+        let store = utxo.blockchain.store().clone();
+        let hash = pub_key_hash(&utxo, &miner);
+        drop(utxo);
+
+        let bc = Blockchain::create_with_params(store, miner, ChainParams::regtest()).unwrap();
+        let genesis = bc.get_block(&bc.get_tip_hash()).unwrap().unwrap();
+        let coinbase = &genesis.get_transactions()[0].id;
+        assert!(bc.get_tx_location(coinbase).unwrap().is_some());
+        assert_eq!(bc.get_address_utxos(&hash).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,766 @@
+// sync.rs
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::mpsc;
+use std::thread;
+
+use failure::format_err;
+use log::{info, warn};
+
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::consensus::{
+    chain_work, check_checkpoint, check_genesis_header, check_header, check_timestamp,
+    median_time_past, Clock, SystemClock, MEDIAN_TIME_SPAN,
+};
+use crate::engine::ConsensusEngine;
+use crate::errors::Result;
+use crate::mempool::Mempool;
+use crate::net::{Peer, MAX_BLOCKS, MAX_HEADERS};
+use crate::params::{ChainParams, Checkpoints};
+use crate::peers::{PeerManager, INVALID_BLOCK_SCORE, INVALID_HEADERS_SCORE};
+use crate::store::ChainStore;
+
+// Blocks past the next one to connect that may be requested at once
+const BLOCK_WINDOW: usize = 128;
+
+// Where a sync is at, passed to the progress callback
+#[derive(Debug, Clone, Default)]
+pub struct SyncProgress {
+    pub headers: usize,
+    pub blocks: usize,
+    pub total_blocks: usize,
+    pub height: usize,
+}
+
+// The active chain as the sync sees it, indexed by height
+#[derive(Default)]
+pub(crate) struct LocalChain {
+    pub(crate) hashes: Vec<String>,
+    timestamps: Vec<u128>,
+}
+
+impl LocalChain {
+    // A chain known only by its headers, as a light client keeps it
+    pub(crate) fn from_headers(headers: &[BlockHeader]) -> Result<LocalChain> {
+        let mut chain = LocalChain::default();
+        for header in headers {
+            chain.hashes.push(header.hash()?);
+            chain.timestamps.push(header.timestamp);
+        }
+        Ok(chain)
+    }
+
+    fn of<S: ChainStore>(bc: &Blockchain<S>) -> LocalChain {
+        let mut chain = LocalChain::default();
+        for block in bc.iter() {
+            chain.hashes.push(block.get_hash());
+            chain.timestamps.push(block.get_timestamp());
+        }
+        chain.hashes.reverse();
+        chain.timestamps.reverse();
+        chain
+    }
+
+    fn tip(&self) -> (usize, String) {
+        match self.hashes.last() {
+            Some(hash) => (self.hashes.len() - 1, hash.clone()),
+            None => (0, String::new()),
+        }
+    }
+
+    // Hashes of the last ten blocks, then exponentially further apart, down to genesis
+    fn locator(&self) -> Vec<String> {
+        let mut locator = Vec::new();
+        let mut height = self.hashes.len();
+        let mut step = 1;
+        while height > 0 {
+            height = height.saturating_sub(step);
+            locator.push(self.hashes[height].clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+        }
+        locator
+    }
+}
+
+// A validated header chain a peer offered, following our block at `fork_height`
+// (or replacing our whole chain when there is no fork height)
+pub(crate) struct Candidate {
+    pub(crate) fork_height: Option<usize>,
+    pub(crate) headers: Vec<BlockHeader>,
+    pub(crate) hashes: Vec<String>,
+}
+
+impl Candidate {
+    fn tip_height(&self) -> Option<usize> {
+        match self.headers.last() {
+            Some(header) => Some(header.height),
+            None => self.fork_height,
+        }
+    }
+}
+
+// Brings the chain up to the most-work chain among the peers: headers are
+// fetched and checked first, then the bodies are downloaded from all peers in
+// parallel. Peers sending invalid headers or blocks are scored by the manager.
+// The mempool follows the chain: transactions of disconnected blocks go back
+// in and those of connected blocks are dropped.
+pub fn sync<S: ChainStore>(
+    bc: &mut Blockchain<S>,
+    mempool: &mut Mempool,
+    manager: &PeerManager,
+    peers: &[String],
+    progress: &dyn Fn(&SyncProgress),
+) -> Result<usize> {
+    let local = LocalChain::of(bc);
+    let peers = connect_peers(manager, peers, &local)?;
+    let engine = bc.params().engine();
+    let checkpoints = &bc.params().checkpoints;
+    let (candidate, peers) =
+        match best_candidate(peers, &local, bc.now(), &*engine, checkpoints, progress)? {
+            (Some(candidate), peers) => (candidate, peers),
+            (None, _) => {
+                info!("already on the most-work chain");
+                return Ok(0);
+            }
+        };
+    download(bc, mempool, peers, candidate, progress)
+}
+
+// Downloads the most-work chain among the peers into an empty store, as a
+// chain of the network of `params`
+pub fn sync_new<S: ChainStore>(
+    store: S,
+    params: ChainParams,
+    manager: &PeerManager,
+    peers: &[String],
+    progress: &dyn Fn(&SyncProgress),
+) -> Result<Blockchain<S>> {
+    let local = LocalChain::default();
+    let peers = connect_peers(manager, peers, &local)?;
+    let (mut candidate, mut peers) = match best_candidate(
+        peers,
+        &local,
+        SystemClock.now(),
+        &*params.engine(),
+        &params.checkpoints,
+        progress,
+    )? {
+        (Some(candidate), peers) => (candidate, peers),
+        (None, _) => return Err(format_err!("Peers have no chain to download")),
+    };
+
+    let genesis_hash = candidate.hashes.remove(0);
+    candidate.headers.remove(0);
+    candidate.fork_height = Some(0);
+    let mut genesis = None;
+    for peer in &mut peers {
+        match peer.get_blocks(vec![genesis_hash.clone()]) {
+            Ok(blocks) => {
+                genesis = blocks
+                    .into_iter()
+                    .find(|block| block.get_hash() == genesis_hash);
+                if genesis.is_some() {
+                    break;
+                }
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+    let genesis = match genesis {
+        Some(genesis) => genesis,
+        None => return Err(format_err!("No peer sent the genesis block")),
+    };
+
+    let mut bc = Blockchain::create_from_genesis(store, genesis, params)?;
+    download(&mut bc, &mut Mempool::default(), peers, candidate, progress)?;
+    Ok(bc)
+}
+
+pub(crate) fn connect_peers(
+    manager: &PeerManager,
+    addrs: &[String],
+    local: &LocalChain,
+) -> Result<Vec<Peer>> {
+    let (height, tip) = local.tip();
+    let mut peers = Vec::new();
+    for addr in addrs {
+        match Peer::connect_with(manager, addr, height, &tip) {
+            Ok(peer) => {
+                info!("connected to {} at height {}", addr, peer.best_height());
+                peers.push(peer);
+            }
+            Err(e) => warn!("can not connect to {}: {}", addr, e),
+        }
+    }
+    if peers.is_empty() {
+        return Err(format_err!("Could not connect to any peer"));
+    }
+    Ok(peers)
+}
+
+// Fetches every peer's header chain and returns the one with the most work,
+// if it has more work than the local chain, with the peers that are on it.
+// Without a better chain all peers whose headers were valid are returned.
+// Header seals are checked by `engine`, and headers must match `checkpoints`.
+pub(crate) fn best_candidate(
+    peers: Vec<Peer>,
+    local: &LocalChain,
+    now: u128,
+    engine: &dyn ConsensusEngine,
+    checkpoints: &Checkpoints,
+    progress: &dyn Fn(&SyncProgress),
+) -> Result<(Option<Candidate>, Vec<Peer>)> {
+    let local_work = match local.hashes.len() {
+        0 => 0,
+        n => chain_work(n - 1),
+    };
+    let mut offers = Vec::new();
+    for mut peer in peers {
+        match fetch_headers(&mut peer, local, now, engine, checkpoints, progress) {
+            Ok(candidate) => offers.push((peer, candidate)),
+            Err(e) => warn!("headers from {} rejected: {}", peer.addr(), e),
+        }
+    }
+
+    let best = offers
+        .iter()
+        .enumerate()
+        .map(|(i, (_, candidate))| (candidate.tip_height().map_or(0, chain_work), i))
+        .filter(|(work, _)| *work > local_work)
+        .max();
+    let best = match best {
+        Some((_, i)) => i,
+        None => return Ok((None, offers.into_iter().map(|(peer, _)| peer).collect())),
+    };
+
+    // Peers that forked off somewhere else do not have the blocks
+    let first_hash = offers[best].1.hashes[0].clone();
+    let mut candidate = None;
+    let mut serving = Vec::new();
+    for (i, (peer, offer)) in offers.into_iter().enumerate() {
+        if offer.hashes.first() == Some(&first_hash) {
+            serving.push(peer);
+        }
+        if i == best {
+            candidate = Some(offer);
+        }
+    }
+    Ok((candidate, serving))
+}
+
+// Fetches and checks the headers a peer has past the fork point with the local chain
+fn fetch_headers(
+    peer: &mut Peer,
+    local: &LocalChain,
+    now: u128,
+    engine: &dyn ConsensusEngine,
+    checkpoints: &Checkpoints,
+    progress: &dyn Fn(&SyncProgress),
+) -> Result<Candidate> {
+    let mut batch = peer.get_headers(local.locator())?;
+    let fork_height = match batch.first() {
+        None => {
+            return Ok(Candidate {
+                fork_height: local.hashes.len().checked_sub(1),
+                headers: Vec::new(),
+                hashes: Vec::new(),
+            })
+        }
+        // Our genesis is in every locator, so a peer sharing it never starts over
+        Some(first) if first.height == 0 => {
+            if !local.hashes.is_empty() {
+                return Err(format_err!("Peer is on a chain with another genesis block"));
+            }
+            None
+        }
+        Some(first) => {
+            if local.hashes.get(first.height - 1) != Some(&first.prev_block_hash) {
+                return peer.blame(
+                    INVALID_HEADERS_SCORE,
+                    Err(format_err!("Headers do not connect to the local chain")),
+                );
+            }
+            Some(first.height - 1)
+        }
+    };
+    // The local chain is final up to the last checkpoint it has
+    let last_checkpoint = checkpoints
+        .iter()
+        .rev()
+        .find(|(height, hash)| local.hashes.get(**height) == Some(*hash));
+    if let (Some(fork_height), Some((height, _))) = (fork_height, last_checkpoint) {
+        if fork_height < *height {
+            return peer.blame(
+                INVALID_HEADERS_SCORE,
+                Err(format_err!(
+                    "Headers fork off below the checkpoint at height {}",
+                    height
+                )),
+            );
+        }
+    }
+
+    // Timestamps of the chain the headers extend, for the median time past rule
+    let mut timestamps: Vec<u128> = match fork_height {
+        Some(height) => local.timestamps[..=height].to_vec(),
+        None => Vec::new(),
+    };
+    let mut prev = fork_height.map(|height| (height, local.hashes[height].clone()));
+    let mut candidate = Candidate {
+        fork_height,
+        headers: Vec::new(),
+        hashes: Vec::new(),
+    };
+
+    loop {
+        let full = batch.len() == MAX_HEADERS;
+        for header in batch {
+            let start = timestamps.len().saturating_sub(MEDIAN_TIME_SPAN);
+            let checked = match &prev {
+                Some((height, hash)) => check_header(&header, hash, *height, engine),
+                None => check_genesis_header(&header, engine),
+            }
+            .and_then(|_| {
+                check_timestamp(
+                    header.timestamp,
+                    median_time_past(&timestamps[start..]),
+                    now,
+                )
+            });
+            peer.blame(INVALID_HEADERS_SCORE, checked)?;
+
+            let hash = header.hash()?;
+            peer.blame(
+                INVALID_HEADERS_SCORE,
+                check_checkpoint(header.height, &hash, checkpoints),
+            )?;
+            prev = Some((header.height, hash.clone()));
+            timestamps.push(header.timestamp);
+            candidate.hashes.push(hash);
+            candidate.headers.push(header);
+        }
+        progress(&SyncProgress {
+            headers: candidate.headers.len(),
+            ..Default::default()
+        });
+        if !full {
+            break;
+        }
+        let last = candidate.hashes.last().cloned().unwrap_or_default();
+        batch = peer.get_headers(vec![last])?;
+    }
+    info!(
+        "{} offers {} headers past height {:?}",
+        peer.addr(),
+        candidate.headers.len(),
+        fork_height
+    );
+    Ok(candidate)
+}
+
+type Job = Vec<usize>;
+
+fn fork_height(candidate: &Candidate) -> usize {
+    candidate.fork_height.unwrap_or(0)
+}
+
+// Downloads the candidate's blocks from all peers within a moving window and
+// connects them in order, after rolling the local chain back to the fork point.
+// If the new chain can not be completed the old one is restored.
+fn download<S: ChainStore>(
+    bc: &mut Blockchain<S>,
+    mempool: &mut Mempool,
+    peers: Vec<Peer>,
+    candidate: Candidate,
+    progress: &dyn Fn(&SyncProgress),
+) -> Result<usize> {
+    let fork_height = fork_height(&candidate);
+    let old_height = bc.get_best_height()?;
+    let mut disconnected = Vec::new();
+    while bc.get_best_height()? > fork_height {
+        let block = bc.disconnect_tip()?;
+        mempool.block_disconnected(bc, &block);
+        disconnected.push(block);
+    }
+    if !disconnected.is_empty() {
+        info!(
+            "reorganizing {} blocks past height {}",
+            disconnected.len(),
+            fork_height
+        );
+    }
+
+    let result = fetch_and_connect(bc, mempool, peers, &candidate, progress);
+    if let Err(e) = &result {
+        warn!("sync failed: {}", e);
+        if bc.get_best_height()? < old_height {
+            restore(bc, mempool, fork_height, disconnected)?;
+        }
+    }
+    result
+}
+
+// Puts the blocks a failed reorganization disconnected back on the chain
+fn restore<S: ChainStore>(
+    bc: &mut Blockchain<S>,
+    mempool: &mut Mempool,
+    fork_height: usize,
+    mut disconnected: Vec<Block>,
+) -> Result<()> {
+    warn!("restoring the previous chain");
+    while bc.get_best_height()? > fork_height {
+        let block = bc.disconnect_tip()?;
+        mempool.block_disconnected(bc, &block);
+    }
+    while let Some(block) = disconnected.pop() {
+        bc.submit_block(&block)?;
+        mempool.block_connected(bc, &block);
+    }
+    Ok(())
+}
+
+fn fetch_and_connect<S: ChainStore>(
+    bc: &mut Blockchain<S>,
+    mempool: &mut Mempool,
+    peers: Vec<Peer>,
+    candidate: &Candidate,
+    progress: &dyn Fn(&SyncProgress),
+) -> Result<usize> {
+    let hashes = &candidate.hashes;
+    let total = hashes.len();
+    // Blocks up to the assumed-valid one are connected without signature checks
+    let assumed_valid = bc
+        .params()
+        .assume_valid
+        .as_ref()
+        .and_then(|assumed| hashes.iter().position(|hash| hash == assumed));
+    if let Some(index) = assumed_valid {
+        info!(
+            "not checking signatures up to the assumed-valid block {}",
+            hashes[index]
+        );
+    }
+    let (result_tx, result_rx) = mpsc::channel();
+    let mut workers = Vec::new();
+    let mut handles = Vec::new();
+    // Where to report each worker's peer for sending an invalid block
+    let mut managers = Vec::new();
+    for (id, mut peer) in peers.into_iter().enumerate() {
+        managers.push(peer.manager().cloned());
+        let (job_tx, job_rx) = mpsc::channel::<(Job, Vec<String>)>();
+        let result_tx = result_tx.clone();
+        handles.push(thread::spawn(move || {
+            for (job, job_hashes) in job_rx {
+                let blocks = peer.get_blocks(job_hashes);
+                let failed = blocks.is_err();
+                if result_tx.send((id, job, blocks)).is_err() || failed {
+                    break;
+                }
+            }
+        }));
+        workers.push(job_tx);
+    }
+    drop(result_tx);
+
+    let mut idle: Vec<usize> = (0..workers.len()).collect();
+    let mut dead: HashSet<usize> = HashSet::new();
+    let mut in_flight = 0;
+    let mut next_request = 0;
+    let mut next_connect = 0;
+    let mut retry: VecDeque<usize> = VecDeque::new();
+    // Peers that answered without a block, it is not asked from them again
+    let mut lacking: HashMap<usize, HashSet<usize>> = HashMap::new();
+    // Blocks waiting for their parent, with the worker that sent them
+    let mut received: BTreeMap<usize, (Block, usize)> = BTreeMap::new();
+
+    let outcome = loop {
+        // Hand out retries and the blocks inside the window to idle peers
+        for worker in std::mem::take(&mut idle) {
+            let mut job = Vec::new();
+            retry.retain(|index| {
+                let lacks = lacking
+                    .get(index)
+                    .is_some_and(|peers| peers.contains(&worker));
+                if job.len() < MAX_BLOCKS && !lacks {
+                    job.push(*index);
+                    return false;
+                }
+                true
+            });
+            while job.len() < MAX_BLOCKS
+                && next_request < total
+                && next_request < next_connect + BLOCK_WINDOW
+            {
+                job.push(next_request);
+                next_request += 1;
+            }
+            if job.is_empty() {
+                idle.push(worker);
+                continue;
+            }
+            let job_hashes = job.iter().map(|index| hashes[*index].clone()).collect();
+            if workers[worker].send((job.clone(), job_hashes)).is_err() {
+                retry.extend(job);
+                dead.insert(worker);
+                continue;
+            }
+            in_flight += 1;
+        }
+
+        if next_connect == total {
+            break Ok(total);
+        }
+        if in_flight == 0 {
+            break Err(format_err!("No peer left to download blocks from"));
+        }
+
+        let (worker, job, blocks) = match result_rx.recv() {
+            Ok(result) => result,
+            Err(_) => break Err(format_err!("All peers disconnected")),
+        };
+        in_flight -= 1;
+        match blocks {
+            Ok(blocks) => {
+                let mut by_hash: HashMap<String, Block> = blocks
+                    .into_iter()
+                    .map(|block| (block.get_hash(), block))
+                    .collect();
+                for index in job {
+                    // The body must be the one the checked header commits to
+                    match by_hash.remove(&hashes[index]) {
+                        Some(block) if block.verify_hash().unwrap_or(false) => {
+                            received.insert(index, (block, worker));
+                        }
+                        _ => {
+                            lacking.entry(index).or_default().insert(worker);
+                            retry.push_back(index);
+                        }
+                    }
+                }
+                idle.push(worker);
+            }
+            Err(e) => {
+                warn!("dropping peer: {}", e);
+                retry.extend(job);
+                dead.insert(worker);
+                if dead.len() == workers.len() {
+                    break Err(format_err!("All peers disconnected"));
+                }
+            }
+        }
+
+        let unavailable = retry.iter().find(|index| {
+            let lacks = lacking.get(index);
+            (0..workers.len())
+                .filter(|worker| !dead.contains(worker))
+                .all(|worker| lacks.is_some_and(|peers| peers.contains(&worker)))
+        });
+        if let Some(index) = unavailable {
+            break Err(format_err!("No peer sent block {}", hashes[*index]));
+        }
+
+        // Connect whatever continues the chain
+        let mut connected = Ok(false);
+        while let Some((block, sender)) = received.remove(&next_connect) {
+            let submitted = if assumed_valid.is_some_and(|index| next_connect <= index) {
+                bc.submit_assumed_valid_block(&block)
+            } else {
+                bc.submit_block(&block)
+            };
+            if let Err(e) = submitted {
+                let e = format_err!("Block {} is not valid: {}", block.get_hash(), e);
+                if let Some((manager, id)) = &managers[sender] {
+                    manager.misbehaving(*id, INVALID_BLOCK_SCORE, &e.to_string());
+                }
+                connected = Err(e);
+                break;
+            }
+            mempool.block_connected(bc, &block);
+            next_connect += 1;
+            connected = Ok(true);
+        }
+        match connected {
+            Ok(true) => progress(&SyncProgress {
+                headers: total,
+                blocks: next_connect,
+                total_blocks: total,
+                height: fork_height(candidate) + next_connect,
+            }),
+            Ok(false) => {}
+            Err(e) => break Err(e),
+        }
+    };
+
+    drop(workers);
+    for handle in handles {
+        let _ = handle.join();
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::node::Node;
+    use crate::spv::LightClient;
+    use crate::store::MemoryStore;
+    use crate::testutil::{mine, new_chain, pay, pub_key_hash};
+    use crate::utxoset::UTXOSet;
+
+    // Serves the chain on a loopback port the OS picks until the test ends,
+    // returns the node and its address
+    fn serve(utxo: UTXOSet<MemoryStore>) -> (Node<MemoryStore>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let node = Node::new(addr.port(), utxo);
+        let server = node.clone();
+        thread::spawn(move || server.run_with(listener));
+        (node, addr.to_string())
+    }
+
+    // The same blocks in another store, to grow another branch on
+    fn copy_chain(bc: &Blockchain<MemoryStore>) -> UTXOSet<MemoryStore> {
+        let hashes = hashes(bc);
+        let block = |hash: &String| bc.get_block(hash).unwrap().unwrap();
+        let mut blockchain = Blockchain::create_from_genesis(
+            MemoryStore::new(),
+            block(&hashes[0]),
+            bc.params().clone(),
+        )
+        .unwrap();
+        for hash in &hashes[1..] {
+            blockchain.submit_block(&block(hash)).unwrap();
+        }
+        UTXOSet { blockchain }
+    }
+
+    fn hashes(bc: &Blockchain<MemoryStore>) -> Vec<String> {
+        let mut hashes: Vec<String> = bc.iter().map(|block| block.get_hash()).collect();
+        hashes.reverse();
+        hashes
+    }
+
+    #[test]
+    fn syncs_from_a_loopback_node_and_follows_its_reorg() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let payment = pay(&utxo, &miner, &other, 30).unwrap();
+        mine(&mut utxo, &miner, vec![payment.clone()]).unwrap();
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let (node, addr) = serve(utxo);
+        let peers = vec![addr];
+
+        let mut bc = sync_new(
+            MemoryStore::new(),
+            ChainParams::regtest(),
+            &PeerManager::default(),
+            &peers,
+            &|_| {},
+        )
+        .unwrap();
+        let served = node.utxo_set();
+        assert_eq!(hashes(&bc), hashes(&served.read().unwrap().blockchain));
+        assert!(bc.store().get_utxos(&payment.id).unwrap().is_some());
+
+        // The node drops its last two blocks, with the payment, for a
+        // longer fork
+        {
+            let mut utxo = served.write().unwrap();
+            utxo.blockchain.disconnect_tip().unwrap();
+            utxo.blockchain.disconnect_tip().unwrap();
+            for _ in 0..3 {
+                mine(&mut utxo, &other, Vec::new()).unwrap();
+            }
+        }
+        let mut mempool = Mempool::default();
+        let connected = sync(&mut bc, &mut mempool, &PeerManager::default(), &peers, &|_| {});
+        assert_eq!(connected.unwrap(), 3);
+        let fork = hashes(&served.read().unwrap().blockchain);
+        assert_eq!(hashes(&bc), fork);
+        assert_eq!(bc.get_best_height().unwrap(), 4);
+        for (height, hash) in fork.iter().enumerate() {
+            assert_eq!(bc.get_block_hash(height).unwrap().as_ref(), Some(hash));
+        }
+        assert!(bc.store().get_utxos(&payment.id).unwrap().is_none());
+        // The payment the fork does not have is waiting to be mined again
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&payment.id).is_some());
+        assert_eq!(
+            bc.store().all_utxos().unwrap().len(),
+            served.read().unwrap().blockchain.store().all_utxos().unwrap().len()
+        );
+
+        // Nothing left to fetch
+        let connected = sync(&mut bc, &mut mempool, &PeerManager::default(), &peers, &|_| {});
+        assert_eq!(connected.unwrap(), 0);
+    }
+
+    #[test]
+    fn downloads_the_most_work_chain_from_several_loopback_nodes() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let mut longer = copy_chain(&utxo.blockchain);
+        for _ in 0..3 {
+            mine(&mut utxo, &miner, Vec::new()).unwrap();
+        }
+        // More blocks than one request holds, the shorter chain lacks most
+        for _ in 0..MAX_BLOCKS + 2 {
+            mine(&mut longer, &other, Vec::new()).unwrap();
+        }
+        let expected = hashes(&longer.blockchain);
+        // The node on the shorter chain comes first, so it is asked for
+        // the first blocks and the ones it lacks are asked from the other
+        let (_, shorter_addr) = serve(utxo);
+        let (_, longer_addr) = serve(longer);
+
+        let bc = sync_new(
+            MemoryStore::new(),
+            ChainParams::regtest(),
+            &PeerManager::default(),
+            &[shorter_addr, longer_addr],
+            &|_| {},
+        )
+        .unwrap();
+        assert_eq!(hashes(&bc), expected);
+        assert_eq!(bc.get_best_height().unwrap(), MAX_BLOCKS + 4);
+    }
+
+    #[test]
+    fn light_clients_sync_proofs_and_filters_from_a_loopback_node() {
+        let (mut utxo, miner, other) = new_chain(ChainParams::regtest());
+        let payment = pay(&utxo, &miner, &other, 30).unwrap();
+        mine(&mut utxo, &miner, vec![payment]).unwrap();
+        mine(&mut utxo, &miner, Vec::new()).unwrap();
+        let receiver = pub_key_hash(&utxo, &other);
+        let wallet = utxo
+            .blockchain
+            .store()
+            .all_wallets()
+            .unwrap()
+            .into_iter()
+            .find(|(address, _)| *address == other)
+            .unwrap()
+            .1;
+        let (_, addr) = serve(utxo);
+        let peers = vec![addr];
+
+        for filtered in [false, true] {
+            let store = MemoryStore::new();
+            store.put_wallet(&other, &wallet).unwrap();
+            let client = LightClient::open(store);
+            let found = match filtered {
+                false => client.sync(&peers, &|_| {}).unwrap(),
+                true => client.sync_filtered(&peers, &|_| {}).unwrap(),
+            };
+            assert_eq!(found, 1);
+            assert_eq!(client.get_best_height().unwrap(), Some(2));
+            assert_eq!(client.get_balance(&receiver).unwrap(), 30);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+// testutil.rs
+
+use failure::format_err;
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::miner::CancelToken;
+use crate::params::ChainParams;
+use crate::store::MemoryStore;
+use crate::transaction::{Transaction, TransactionBuilder};
+use crate::utxoset::UTXOSet;
+use crate::wallet::{hash_pub_key, Wallets};
+
+// A chain in a MemoryStore with two wallets, the genesis block paying the first
+pub(crate) fn new_chain(params: ChainParams) -> (UTXOSet<MemoryStore>, String, String) {
+    let store = MemoryStore::new();
+    let mut wallets = Wallets::open(store.clone()).unwrap();
+    let miner = wallets.create_wallet();
+    let other = wallets.create_wallet();
+    wallets.save_all().unwrap();
+    let blockchain = Blockchain::create_with_params(store, miner.clone(), params).unwrap();
+    (UTXOSet { blockchain }, miner, other)
+}
+
+// Seals a block of the transactions on top of the tip, after a coinbase
+// paying the reward to `address`, without connecting it
+pub(crate) fn seal_block(
+    bc: &Blockchain<MemoryStore>,
+    address: &str,
+    transactions: Vec<Transaction>,
+) -> Result<Block> {
+    let height = bc.get_best_height()? + 1;
+    let coinbase = Transaction::new_coinbase(
+        address.to_string(),
+        format!("Reward to '{}' at height {}", address, height),
+    )?;
+    seal_block_with_coinbase(bc, coinbase, transactions)
+}
+
+// Seals a block of the coinbase and transactions on top of the tip
+pub(crate) fn seal_block_with_coinbase(
+    bc: &Blockchain<MemoryStore>,
+    coinbase: Transaction,
+    transactions: Vec<Transaction>,
+) -> Result<Block> {
+    let mut block_transactions = vec![coinbase];
+    block_transactions.extend(transactions);
+    let template = bc.new_block_template(block_transactions)?;
+    match bc.engine().seal(template, &CancelToken::new())? {
+        Some(block) => Ok(block),
+        None => Err(format_err!("Sealing was cancelled")),
+    }
+}
+
+// Seals a block with only a coinbase on top of the tip, stamped with
+// `timestamp` whatever the rules say
+pub(crate) fn seal_block_at(
+    bc: &Blockchain<MemoryStore>,
+    address: &str,
+    timestamp: u128,
+) -> Result<Block> {
+    let height = bc.get_best_height()? + 1;
+    let coinbase = Transaction::new_coinbase(
+        address.to_string(),
+        format!("Reward to '{}' at height {}", address, height),
+    )?;
+    let template = Block::new_template(vec![coinbase], bc.get_tip_hash(), height, timestamp);
+    match bc.engine().seal(template, &CancelToken::new())? {
+        Some(block) => Ok(block),
+        None => Err(format_err!("Sealing was cancelled")),
+    }
+}
+
+// Seals a block of the transactions and connects it
+pub(crate) fn mine(
+    utxo: &mut UTXOSet<MemoryStore>,
+    address: &str,
+    transactions: Vec<Transaction>,
+) -> Result<Block> {
+    let block = seal_block(&utxo.blockchain, address, transactions)?;
+    utxo.blockchain.submit_block(&block)?;
+    Ok(block)
+}
+
+// Pays `amount` plus a fee of `fee` from one wallet to another
+pub(crate) fn pay_with_fee(
+    utxo: &UTXOSet<MemoryStore>,
+    from: &str,
+    to: &str,
+    amount: i32,
+    fee: i32,
+) -> Result<Transaction> {
+    TransactionBuilder::new(utxo)
+        .sender(from)
+        .pay_to(to, amount)
+        .fee(fee)
+        .build()
+}
+
+pub(crate) fn pay(
+    utxo: &UTXOSet<MemoryStore>,
+    from: &str,
+    to: &str,
+    amount: i32,
+) -> Result<Transaction> {
+    TransactionBuilder::new(utxo)
+        .sender(from)
+        .pay_to(to, amount)
+        .build()
+}
+
+// Gives a transaction changed after it was built its id and signatures back,
+// signed by the wallet of `address`
+pub(crate) fn resign(
+    utxo: &UTXOSet<MemoryStore>,
+    tx: &mut Transaction,
+    address: &str,
+) -> Result<()> {
+    tx.id = tx.derived_id()?;
+    let wallets = Wallets::open(utxo.blockchain.store().clone())?;
+    let private_key = match wallets.get_wallet(address) {
+        Some(wallet) => wallet.private_key.clone(),
+        None => return Err(format_err!("Wallet {} not found", address)),
+    };
+    utxo.blockchain.sign_transaction(tx, &private_key)
+}
+
+// The hash outputs paying a local wallet are locked to
+pub(crate) fn pub_key_hash(utxo: &UTXOSet<MemoryStore>, address: &str) -> Vec<u8> {
+    let wallets = Wallets::open(utxo.blockchain.store().clone()).unwrap();
+    let mut pub_key_hash = wallets.get_wallet(address).unwrap().public_key.clone();
+    hash_pub_key(&mut pub_key_hash);
+    pub_key_hash
+}
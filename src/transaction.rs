@@ -1,53 +1,184 @@
 // transaction.rs
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use bincode::serialize;
 use crypto::ed25519;
 use crypto::{digest::Digest, sha2::Sha256};
 use failure::format_err;
 use log::error;
 use serde::{Deserialize, Serialize};
 
-use crate::blockchain::Blockchain;
 use crate::errors::Result;
-use crate::tx::{TXInput, TXOutput};
+use crate::htlc::Htlc;
+use crate::mempool::Mempool;
+use crate::store::{ChainStore, SledStore};
+use crate::tx::{RelativeLock, TXInput, TXOutput, MAX_RBF_SEQUENCE, SEQUENCE_FINAL};
 use crate::utxoset::UTXOSet;
 use crate::wallet::{hash_pub_key, Wallets};
 
+// New coins a block's coinbase may pay on top of the fees of the block
+pub const SUBSIDY: i32 = 100;
+
+// The bincode encoding of this type is part of the chain format,
+// see "Semver policy" in the README before changing it
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: String, // Transaction ID of the transaction
     pub v_inputs: Vec<TXInput>,
     pub v_outputs: Vec<TXOutput>,
+    // 0, a block height below LOCKTIME_THRESHOLD or a unix time in seconds:
+    // the transaction is only valid in blocks past it
+    pub lock_time: u32,
 }
 
-impl Transaction {
-    // Creates a new transaction
-    pub fn new_transaction(
-        sender_address: &str,
-        receiver_address: &str,
-        amount: i32,
-        utxo: &UTXOSet,
-    ) -> Result<Transaction> {
-        let mut v_inputs = Vec::new();
+// Secret hash and refund lock time of an HTLC output to build
+type HtlcTerms = (Vec<u8>, u32);
+
+// Builds and signs a transaction that pays one or more receivers
+// from the UTXOs of a wallet stored in the local wallet DB
+pub struct TransactionBuilder<'a, S: ChainStore = SledStore> {
+    utxo: &'a UTXOSet<S>,
+    mempool: Option<&'a Mempool>,
+    sender_address: Option<String>,
+    // Receiver, amount and, for HTLCs, their terms
+    outputs: Vec<(String, i32, Option<HtlcTerms>)>,
+    data: Vec<Vec<u8>>,
+    fee: i32,
+    replaceable: bool,
+    replacing: Option<&'a Transaction>,
+    lock_time: u32,
+    relative_lock: Option<RelativeLock>,
+}
 
-        let wallets = Wallets::new()?;
-        let wallet = match wallets.get_wallet(sender_address) {
-            Some(wallet) => wallet,
-            None => return Err(format_err!("Sender wallet not found")),
+impl<'a, S: ChainStore> TransactionBuilder<'a, S> {
+    pub fn new(utxo: &'a UTXOSet<S>) -> TransactionBuilder<'a, S> {
+        TransactionBuilder {
+            utxo,
+            mempool: None,
+            sender_address: None,
+            outputs: Vec::new(),
+            data: Vec::new(),
+            fee: 0,
+            replaceable: false,
+            replacing: None,
+            lock_time: 0,
+            relative_lock: None,
+        }
+    }
+
+    // Sets the wallet whose UTXOs are spent, change goes back to it
+    pub fn sender(mut self, address: &str) -> Self {
+        self.sender_address = Some(address.to_string());
+        self
+    }
+
+    // Also spends the unconfirmed outputs of the mempool, and leaves out the
+    // confirmed ones its transactions already spend
+    pub fn mempool(mut self, mempool: &'a Mempool) -> Self {
+        self.mempool = Some(mempool);
+        self
+    }
+
+    // Adds an output paying `amount` to `address`
+    pub fn pay_to(mut self, address: &str, amount: i32) -> Self {
+        self.outputs.push((address.to_string(), amount, None));
+        self
+    }
+
+    // Adds an HTLC output that `address` spends with the secret hashing to
+    // `hash`, and the sender takes back in blocks past `lock_time`
+    pub fn pay_to_htlc(mut self, address: &str, amount: i32, hash: &[u8], lock_time: u32) -> Self {
+        self.outputs
+            .push((address.to_string(), amount, Some((hash.to_vec(), lock_time))));
+        self
+    }
+
+    // Adds an unspendable output anchoring `data` in the chain
+    pub fn data(mut self, data: &[u8]) -> Self {
+        self.data.push(data.to_vec());
+        self
+    }
+
+    // Leaves `fee` of the inputs to the miner
+    pub fn fee(mut self, fee: i32) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    // Signals that a transaction paying a higher fee may replace this one
+    // while it is unconfirmed
+    pub fn replaceable(mut self) -> Self {
+        self.replaceable = true;
+        self
+    }
+
+    // Spends the outputs the mempool transaction spends before any other, so
+    // the new transaction replaces it and its descendants. Needs `mempool`.
+    pub fn replacing(mut self, tx: &'a Transaction) -> Self {
+        self.replacing = Some(tx);
+        self
+    }
+
+    // Keeps the transaction out of blocks up to the height, or the unix time
+    // in seconds if it is at least LOCKTIME_THRESHOLD
+    pub fn lock_time(mut self, lock_time: u32) -> Self {
+        self.lock_time = lock_time;
+        self
+    }
+
+    // Keeps the transaction out of blocks until every output it spends is
+    // confirmed for that long. Relative locks also signal replaceability.
+    pub fn relative_lock(mut self, lock: RelativeLock) -> Self {
+        self.relative_lock = Some(lock);
+        self
+    }
+
+    // Selects the inputs, adds the change output and signs the transaction
+    pub fn build(self) -> Result<Transaction> {
+        let sender_address = match self.sender_address {
+            Some(address) => address,
+            None => return Err(format_err!("Sender address is not set")),
         };
+        if self.outputs.is_empty() && self.data.is_empty() {
+            return Err(format_err!("Transaction has no receivers"));
+        }
+        if self.outputs.iter().any(|(_, amount, _)| *amount <= 0) {
+            return Err(format_err!("Amounts must be positive"));
+        }
+        let amount = self
+            .outputs
+            .iter()
+            .try_fold(0i32, |total, (_, amount, _)| total.checked_add(*amount))
+            .ok_or_else(|| format_err!("Total amount overflows"))?;
+        if self.fee < 0 {
+            return Err(format_err!("Fee must not be negative"));
+        }
+        let amount = amount
+            .checked_add(self.fee)
+            .ok_or_else(|| format_err!("Total amount overflows"))?;
 
-        if let None = wallets.get_wallet(&receiver_address) {
-            return Err(format_err!("Receiver wallet not found"));
+        let wallets = Wallets::open(self.utxo.blockchain.store().clone())?;
+        let wallet = match wallets.get_wallet(&sender_address) {
+            Some(wallet) => wallet,
+            None => return Err(format_err!("Sender wallet not found")),
         };
 
         let mut pub_key_hash = wallet.public_key.clone();
         hash_pub_key(&mut pub_key_hash);
 
-        let balance_utxos = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
+        // A transaction only anchoring data still spends an output
+        let needed = amount.max(1);
+        let balance_utxos = match (self.mempool, self.replacing) {
+            (Some(mempool), replacing) => {
+                spendable_with_mempool(self.utxo, mempool, replacing, &pub_key_hash, needed)?
+            }
+            (None, Some(_)) => {
+                return Err(format_err!("Replacing a transaction needs the mempool"))
+            }
+            (None, None) => self.utxo.find_spendable_outputs(&pub_key_hash, needed)?,
+        };
         // Check if there is enough money to spend
-        if balance_utxos.0 < amount {
+        if balance_utxos.0 < needed {
             error!("Not Enough Balance");
             return Err(format_err!(
                 "NOT ENOUGH BALANCE: CURRENT BALANCE {}",
@@ -56,42 +187,145 @@ impl Transaction {
         }
 
         // creates the inputs list of the transaction
+        let sequence = match self.relative_lock {
+            Some(lock) => lock.to_sequence(),
+            None if self.replaceable => MAX_RBF_SEQUENCE,
+            None => SEQUENCE_FINAL,
+        };
+        let mut v_inputs = Vec::new();
         for txid_output_index in balance_utxos.1 {
             for output_index in txid_output_index.1 {
                 let input = TXInput {
                     txid: txid_output_index.0.clone(),
-                    output_index: output_index,
+                    output_index,
                     signature: Vec::new(),
                     pub_key: wallet.public_key.clone(),
+                    sequence,
+                    preimage: Vec::new(),
                 };
                 v_inputs.push(input);
             }
         }
 
-        let mut v_outputs = vec![TXOutput::new(amount, receiver_address.to_string())?];
+        let mut v_outputs = Vec::new();
+        for (receiver_address, value, htlc) in self.outputs {
+            let mut output = TXOutput::new(value, receiver_address)?;
+            if let Some((hash, lock_time)) = htlc {
+                output.htlc = Some(Htlc {
+                    hash,
+                    refund_pub_key_hash: pub_key_hash.clone(),
+                    lock_time,
+                });
+            }
+            v_outputs.push(output);
+        }
+        for data in self.data {
+            v_outputs.push(TXOutput::new_data(data));
+        }
 
         if balance_utxos.0 > amount {
-            v_outputs.push(TXOutput::new(
-                balance_utxos.0 - amount,
-                sender_address.to_string(),
-            )?)
+            v_outputs.push(TXOutput::new(balance_utxos.0 - amount, sender_address)?)
         }
 
         let mut tx = Transaction {
             id: String::new(),
-            v_inputs: v_inputs,
-            v_outputs: v_outputs,
+            v_inputs,
+            v_outputs,
+            lock_time: self.lock_time,
         };
 
         tx.id = tx.hash()?;
-        utxo.blockchain.sign_transaction(&mut tx, &wallet.private_key)?;
+        match self.mempool {
+            Some(mempool) => {
+                let prev_txs = mempool.prev_txs(&self.utxo.blockchain, &tx)?;
+                tx.sign(&wallet.private_key, prev_txs)?;
+            }
+            None => self
+                .utxo
+                .blockchain
+                .sign_transaction(&mut tx, &wallet.private_key)?,
+        }
 
         Ok(tx)
     }
+}
+
+// Like find_spendable_outputs, over the outputs the replaced transaction
+// spends, then the confirmed outputs the mempool does not spend yet, then
+// the unconfirmed ones that are not replaced
+fn spendable_with_mempool<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    mempool: &Mempool,
+    replacing: Option<&Transaction>,
+    address: &[u8],
+    amount: i32,
+) -> Result<(i32, HashMap<String, Vec<i32>>)> {
+    let mut candidates = Vec::new();
+    let mut replaced = HashSet::new();
+    if let Some(old) = replacing {
+        replaced = mempool.descendants(&old.id);
+        for input in &old.v_inputs {
+            if let Some(output) = mempool.output(&utxo.blockchain, &input.txid, input.output_index)? {
+                if output.is_locked_with_key(address) {
+                    candidates.push((input.txid.clone(), input.output_index, output));
+                }
+            }
+        }
+    }
+    candidates.extend(
+        utxo.list_unspent(address)?
+            .into_iter()
+            .filter(|(txid, output_index, _)| mempool.spender(txid, *output_index).is_none()),
+    );
+    candidates.extend(
+        mempool
+            .unspent_outputs(address)
+            .into_iter()
+            .filter(|(txid, _, _)| !replaced.contains(txid)),
+    );
+    let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
+    let mut accumulated = 0;
+    for (txid, output_index, output) in candidates {
+        if accumulated >= amount {
+            break;
+        }
+        accumulated += output.value;
+        unspent_outputs.entry(txid).or_default().push(output_index);
+    }
+    Ok((accumulated, unspent_outputs))
+}
+
+impl Transaction {
+    // Creates a new transaction
+    pub fn new_transaction<S: ChainStore>(
+        sender_address: &str,
+        receiver_address: &str,
+        amount: i32,
+        utxo: &UTXOSet<S>,
+    ) -> Result<Transaction> {
+        let wallets = Wallets::open(utxo.blockchain.store().clone())?;
+        if wallets.get_wallet(receiver_address).is_none() {
+            return Err(format_err!("Receiver wallet not found"));
+        };
+
+        TransactionBuilder::new(utxo)
+            .sender(sender_address)
+            .pay_to(receiver_address, amount)
+            .build()
+    }
 
     // Creates a new COINBASE TRANSACTION with the miner's address
-    pub fn new_coinbase(receiver: String, mut data: String) -> Result<Transaction> {
-        if data == String::from("") {
+    pub fn new_coinbase(receiver: String, data: String) -> Result<Transaction> {
+        Transaction::new_coinbase_with_fees(receiver, data, 0)
+    }
+
+    // A coinbase paying the block reward plus the fees of the block's transactions
+    pub fn new_coinbase_with_fees(
+        receiver: String,
+        mut data: String,
+        fees: i32,
+    ) -> Result<Transaction> {
+        if data.is_empty() {
             data += &format!("Reward to '{}'", receiver);
         }
 
@@ -102,13 +336,21 @@ impl Transaction {
                 output_index: -1,
                 signature: Vec::new(),
                 pub_key: Vec::from(data.as_bytes()),
+                sequence: SEQUENCE_FINAL,
+                preimage: Vec::new(),
             }],
-            v_outputs: vec![TXOutput::new(100, receiver)?],
+            v_outputs: vec![TXOutput::new(SUBSIDY.saturating_add(fees), receiver)?],
+            lock_time: 0,
         };
         tx.id = tx.hash()?;
         Ok(tx)
     }
 
+    // True if one of the inputs opts in to replace-by-fee
+    pub fn is_replaceable(&self) -> bool {
+        !self.is_coinbase() && self.v_inputs.iter().any(TXInput::signals_rbf)
+    }
+
     // Check whether the transaction is coinbase
     pub fn is_coinbase(&self) -> bool {
         self.v_inputs.len() == 1
@@ -136,28 +378,18 @@ impl Transaction {
             return Ok(());
         }
 
-        // Checks that all the inputs are valid
-        for tx_input in &self.v_inputs {
-            if prev_txs.get(&tx_input.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
-        }
-
         // Creates a copy of the transaction with empty signature in v_inputs
         let mut tx_copy = self.trim_copy();
 
         for input_index in 0..tx_copy.v_inputs.len() {
-            // Get the prev trx which contained this input
-            let prev_tx = prev_txs.get(&tx_copy.v_inputs[input_index].txid).unwrap();
+            // Get the output of the prev trx this input spends
+            let prev_output = spent_output(&prev_txs, &self.v_inputs[input_index])?;
 
             // Clear the signature of each input UTXO
             tx_copy.v_inputs[input_index].signature.clear();
 
             // Puts the sender's PKH in the TXInput.pub_key
-            tx_copy.v_inputs[input_index].pub_key = prev_tx.v_outputs
-                [tx_copy.v_inputs[input_index].output_index as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.v_inputs[input_index].pub_key = prev_output.pub_key_hash.clone();
 
             // SHA-256 hash the tx_copy{input UTXOs, output UTXOs} and sets it as its id
             tx_copy.id = tx_copy.hash()?;
@@ -182,26 +414,26 @@ impl Transaction {
             return Ok(true);
         }
 
-        for tx_input in &self.v_inputs {
-            if prev_txs.get(&tx_input.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: PREVIOUS TRANSACTION IS NOT CORRECT"));
-            }
-        }
-
         let mut tx_copy = self.trim_copy();
 
         for input_index in 0..self.v_inputs.len() {
-            let prev_tx = prev_txs.get(&self.v_inputs[input_index].txid).unwrap();
+            let prev_output = spent_output(&prev_txs, &self.v_inputs[input_index])?;
             tx_copy.v_inputs[input_index].signature.clear();
-            tx_copy.v_inputs[input_index].pub_key = prev_tx.v_outputs
-                [self.v_inputs[input_index].output_index as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.v_inputs[input_index].pub_key = prev_output.pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.v_inputs[input_index].pub_key = Vec::new();
 
+            // ed25519::verify panics on keys and signatures of the wrong size,
+            // which anyone can put in a transaction
+            let input = &self.v_inputs[input_index];
+            if input.pub_key.len() != 32 || input.signature.len() != 64 {
+                return Ok(false);
+            }
+            if !prev_output.can_be_spent_by(input, self.lock_time) {
+                return Ok(false);
+            }
             if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
+                tx_copy.id.as_bytes(),
                 &self.v_inputs[input_index].pub_key,
                 &self.v_inputs[input_index].signature,
             ) {
@@ -221,6 +453,18 @@ impl Transaction {
         Ok(hasher.result_str())   
     }
 
+    // The id the transaction should have: ids are hashed before the inputs
+    // are signed, coinbases have no signatures
+    pub fn derived_id(&self) -> Result<String> {
+        let mut copy = self.clone();
+        if !copy.is_coinbase() {
+            for input in &mut copy.v_inputs {
+                input.signature.clear();
+            }
+        }
+        copy.hash()
+    }
+
     // Creates a copy of the transaction with any signature in any of the inputs
     fn trim_copy(&self) -> Transaction {
         let mut v_inputs = Vec::new();
@@ -229,15 +473,19 @@ impl Transaction {
         for tx_input in &self.v_inputs {
             v_inputs.push(TXInput {
                 txid: tx_input.txid.clone(),
-                output_index: tx_input.output_index.clone(),
+                output_index: tx_input.output_index,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
+                sequence: tx_input.sequence,
+                preimage: tx_input.preimage.clone(),
             })
         }
         for tx_output in &self.v_outputs {
             v_outputs.push(TXOutput {
                 value: tx_output.value,
                 pub_key_hash: tx_output.pub_key_hash.clone(),
+                htlc: tx_output.htlc.clone(),
+                data: tx_output.data.clone(),
             })
         }
 
@@ -245,6 +493,29 @@ impl Transaction {
             id: self.id.clone(),
             v_inputs,
             v_outputs,
+            lock_time: self.lock_time,
         }
     }
 }
+
+// The output of one of the previous transactions the input spends
+fn spent_output<'a>(
+    prev_txs: &'a HashMap<String, Transaction>,
+    input: &TXInput,
+) -> Result<&'a TXOutput> {
+    let prev_tx = match prev_txs.get(&input.txid) {
+        Some(prev_tx) if !prev_tx.id.is_empty() => prev_tx,
+        _ => return Err(format_err!("Previous transaction {} is not found", input.txid)),
+    };
+    let output = usize::try_from(input.output_index)
+        .ok()
+        .and_then(|index| prev_tx.v_outputs.get(index));
+    match output {
+        Some(output) => Ok(output),
+        None => Err(format_err!(
+            "Transaction {} has no output {}",
+            input.txid,
+            input.output_index
+        )),
+    }
+}
@@ -1,64 +1,162 @@
+use std::collections::BTreeMap;
+
 use bitcoincash_addr::Address;
+use failure::format_err;
 use log::debug;
 use serde::{Deserialize, Serialize};
 
 use crate::errors::Result;
+use crate::htlc::Htlc;
 use crate::wallet::hash_pub_key;
 
+// Sequence of inputs that do not let their transaction be replaced
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+
+// Highest sequence that opts the transaction in to replace-by-fee while it
+// is in the mempool
+pub const MAX_RBF_SEQUENCE: u32 = 0xffff_fffd;
+
+// A sequence without this bit is a relative lock: the low 16 bits count
+// blocks, or units of SEQUENCE_LOCK_GRANULARITY seconds with the type bit
+pub const SEQUENCE_LOCK_DISABLE_FLAG: u32 = 1 << 31;
+pub const SEQUENCE_LOCK_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCK_MASK: u32 = 0xffff;
+pub const SEQUENCE_LOCK_GRANULARITY: u64 = 512;
+
+// How long an input must wait after the output it spends was confirmed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativeLock {
+    Blocks(u16),
+    // In units of SEQUENCE_LOCK_GRANULARITY seconds
+    Time(u16),
+}
+
+impl RelativeLock {
+    pub fn to_sequence(self) -> u32 {
+        match self {
+            RelativeLock::Blocks(blocks) => blocks as u32,
+            RelativeLock::Time(units) => SEQUENCE_LOCK_TYPE_FLAG | units as u32,
+        }
+    }
+}
+
+// TXInput, TXOutput and TXOutputs are encoded with bincode inside blocks and
+// the UTXO DB: reordering or adding fields is a breaking change
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     pub txid: String, // Transaction ID of the prev transaction from where the input came from.
     pub output_index: i32, // Index of the output in the previous transaction
     pub signature: Vec<u8>,
     pub pub_key: Vec<u8>,
+    // SEQUENCE_FINAL, or MAX_RBF_SEQUENCE or below to signal replaceability.
+    // Relative locks are below it too.
+    pub sequence: u32,
+    // The secret redeeming an HTLC output, empty otherwise
+    pub preimage: Vec<u8>,
+}
+
+impl TXInput {
+    pub fn signals_rbf(&self) -> bool {
+        self.sequence <= MAX_RBF_SEQUENCE
+    }
+
+    pub fn relative_lock(&self) -> Option<RelativeLock> {
+        if self.sequence & SEQUENCE_LOCK_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = (self.sequence & SEQUENCE_LOCK_MASK) as u16;
+        if self.sequence & SEQUENCE_LOCK_TYPE_FLAG != 0 {
+            Some(RelativeLock::Time(value))
+        } else {
+            Some(RelativeLock::Blocks(value))
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
     pub value: i32,            // The amount of crypto the that the output holds
     pub pub_key_hash: Vec<u8>, // Receiver address PKH
+    // Extra conditions making the output a hash time-locked contract
+    pub htlc: Option<Htlc>,
+    // Data anchored in the chain. Such an output pays nobody and can never
+    // be spent, so it is kept out of the UTXO set.
+    pub data: Option<Vec<u8>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    // Unspent outputs of one transaction, keyed by their index in it
+    pub outputs: BTreeMap<i32, TXOutput>,
+    pub confirmation: Confirmation,
 }
 
-impl TXInput {
-    // CanUnlockOutputWith checks whether the address initiated the transaction
-    pub fn can_unlock_output_with(&self, sender_address: &[u8]) -> bool {
-        let mut pubkeyhash = self.pub_key.clone();
-        hash_pub_key(&mut pubkeyhash);
-        pubkeyhash == sender_address
-    }
+// The block a transaction was confirmed in, relative locks count from it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Confirmation {
+    pub height: usize,
+    // Block timestamp in milliseconds
+    pub timestamp: u128,
 }
 
 impl TXOutput {
-
+    // True if the key hash alone spends the output, HTLCs need more
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash == pub_key_hash
+        self.htlc.is_none() && !self.is_data() && self.pub_key_hash == pub_key_hash
+    }
+
+    pub fn is_data(&self) -> bool {
+        self.data.is_some()
     }
 
+    // Checks that the input meets the output's conditions: the key of the
+    // address it pays, or for an HTLC that key and the secret, or the refund
+    // key with a lock time at least the HTLC's
+    pub fn can_be_spent_by(&self, input: &TXInput, lock_time: u32) -> bool {
+        let mut pub_key_hash = input.pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        if self.is_data() {
+            return false;
+        }
+        match &self.htlc {
+            None => pub_key_hash == self.pub_key_hash,
+            Some(htlc) => {
+                (pub_key_hash == self.pub_key_hash && htlc.is_preimage(&input.preimage))
+                    || (pub_key_hash == htlc.refund_pub_key_hash
+                        && htlc.is_refundable_at(lock_time))
+            }
+        }
+    }
 
     pub fn new(value: i32, receiver_address: String) -> Result<Self> {
         let mut txo = TXOutput {
             value,
             pub_key_hash: Vec::new(),
+            htlc: None,
+            data: None,
         };
 
         txo.lock(&receiver_address)?;
         Ok(txo)
     }
 
+    // An unspendable output carrying `data`, with no value
+    pub fn new_data(data: Vec<u8>) -> Self {
+        TXOutput {
+            value: 0,
+            pub_key_hash: Vec::new(),
+            htlc: None,
+            data: Some(data),
+        }
+    }
+
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
+        let pub_key_hash = match Address::decode(address) {
+            Ok(address) => address.body,
+            Err(_) => return Err(format_err!("Invalid address {}", address)),
+        };
         debug!("lock: {}", address);
         self.pub_key_hash = pub_key_hash;
         Ok(())
     }
-
-    // CanBeUnlockedWith checks if the output can be unlocked with the provided data
-    pub fn can_be_unlocked_with(&self, receiver_address: &[u8]) -> bool {
-        self.pub_key_hash == receiver_address
-    }
 }
@@ -0,0 +1,42 @@
+// txindex.rs
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::store::{ChainStore, StoreBatch};
+
+// Where a transaction of the active chain is stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxLocation {
+    pub block_hash: String,
+    pub position: u32, // Index of the transaction in the block's transactions
+}
+
+// Adds the transactions of a block that is being connected to the index
+pub(crate) fn stage_connect(block: &Block, batch: &mut StoreBatch) {
+    for (position, tx) in block.get_transactions().iter().enumerate() {
+        batch.index_tx(
+            &tx.id,
+            TxLocation {
+                block_hash: block.get_hash(),
+                position: position as u32,
+            },
+        );
+    }
+}
+
+// Removes the transactions of a block that is being disconnected from the index
+pub(crate) fn stage_disconnect(block: &Block, batch: &mut StoreBatch) {
+    for tx in block.get_transactions() {
+        batch.unindex_tx(&tx.id);
+    }
+}
+
+// Replaces the whole index with one built from the active chain
+pub(crate) fn stage_rebuild<S: ChainStore>(bc: &Blockchain<S>, batch: &mut StoreBatch) {
+    batch.clear_txindex();
+    for block in bc.iter() {
+        stage_connect(&block, batch);
+    }
+}
@@ -1,94 +1,163 @@
-use log::info;
+use failure::format_err;
+
 use crate::block::Block;
 use crate::blockchain::Blockchain;
 use crate::errors::Result;
-use crate::tx::TXOutputs;
-use std::collections::HashMap;
+use crate::store::{ChainStore, SledStore, StoreBatch};
+use crate::tx::{TXOutput, TXOutputs};
+use crate::{addrindex, txindex};
+use std::collections::{BTreeMap, HashMap};
+
+// Adds the UTXO changes made by the block to the batch.
+// The block must extend the block the UTXO set currently reflects.
+pub(crate) fn stage_block<S: ChainStore>(
+    store: &S,
+    block: &Block,
+    batch: &mut StoreBatch,
+) -> Result<()> {
+    // Entries touched by this block, so later transactions of the block
+    // see the outputs created and spent by earlier ones
+    let mut view: HashMap<String, Option<TXOutputs>> = HashMap::new();
+
+    for tx in block.get_transactions() {
+        if !tx.is_coinbase() {
+            for tx_input in &tx.v_inputs {
+                let v_tx_outputs = match view.get(&tx_input.txid) {
+                    Some(outputs) => outputs.clone(),
+                    None => store.get_utxos(&tx_input.txid)?,
+                };
+                // Whatever is left after removing the spent output stays unspent
+                let mut update_outputs = match v_tx_outputs {
+                    Some(outputs) => outputs,
+                    None => return Err(format_err!("UTXO of {} is not found", tx_input.txid)),
+                };
+                if update_outputs
+                    .outputs
+                    .remove(&tx_input.output_index)
+                    .is_none()
+                {
+                    return Err(format_err!(
+                        "Output {} of {} is already spent",
+                        tx_input.output_index,
+                        tx_input.txid
+                    ));
+                }
 
-// Allows to access the DB connected to our blockchain
-pub struct UTXOSet {
-    pub blockchain: Blockchain,
-}
+                if update_outputs.outputs.is_empty() {
+                    view.insert(tx_input.txid.clone(), None);
+                } else {
+                    view.insert(tx_input.txid.clone(), Some(update_outputs));
+                }
+            }
+        }
 
-impl UTXOSet {
-    // Reindex rebuils the UTXO set
-    pub fn reindex(&self) -> Result<()> {
-        if let Err(e) = std::fs::remove_dir_all("data/utxos") {
-            info!("not exist any utxos to delete")
+        // Data outputs can not be spent and never enter the set
+        let new_outputs = TXOutputs {
+            outputs: (0..)
+                .zip(tx.v_outputs.iter().cloned())
+                .filter(|(_, output)| !output.is_data())
+                .collect(),
+            confirmation: block.confirmation(),
+        };
+        if !new_outputs.outputs.is_empty() {
+            view.insert(tx.id.clone(), Some(new_outputs));
         }
+    }
 
-        let db = sled::open("data/utxos")?;
+    for (txid, outputs) in view {
+        match outputs {
+            Some(outputs) => batch.put_utxos(&txid, outputs),
+            None => batch.remove_utxos(&txid),
+        }
+    }
+    batch.set_utxo_tip(&block.get_hash());
+    Ok(())
+}
 
-        let utxos = self.blockchain.find_utxo();
+// Adds the UTXO changes undoing the block to the batch.
+// The block must be the block the UTXO set currently reflects.
+pub(crate) fn stage_disconnect<S: ChainStore>(
+    bc: &Blockchain<S>,
+    block: &Block,
+    batch: &mut StoreBatch,
+) -> Result<()> {
+    let mut view: HashMap<String, Option<TXOutputs>> = HashMap::new();
+    let block_txids: Vec<&String> = block.get_transactions().iter().map(|tx| &tx.id).collect();
+
+    for tx in block.get_transactions().iter().rev() {
+        // Outputs created by the block disappear, spent or not
+        view.insert(tx.id.clone(), None);
+        if tx.is_coinbase() {
+            continue;
+        }
 
-        for (txid, v_tx_outputs) in utxos {
-            db.insert(txid.as_bytes(), bincode::serialize(&v_tx_outputs)?)?;
+        // Outputs spent by the block come back, unless the block created them too
+        for tx_input in &tx.v_inputs {
+            if block_txids.contains(&&tx_input.txid) {
+                continue;
+            }
+            let (prev_tx, confirmation) = bc.find_confirmed_transaction(&tx_input.txid)?;
+            let mut outputs = match view.get(&tx_input.txid) {
+                Some(outputs) => outputs.clone(),
+                None => bc.store().get_utxos(&tx_input.txid)?,
+            }
+            .unwrap_or(TXOutputs {
+                outputs: BTreeMap::new(),
+                confirmation,
+            });
+            let output = match prev_tx.v_outputs.get(tx_input.output_index as usize) {
+                Some(output) => output.clone(),
+                None => return Err(format_err!("Spent output of {} is not found", tx.id)),
+            };
+            outputs.outputs.insert(tx_input.output_index, output);
+            view.insert(tx_input.txid.clone(), Some(outputs));
         }
+    }
 
-        Ok(())
+    for (txid, outputs) in view {
+        match outputs {
+            Some(outputs) => batch.put_utxos(&txid, outputs),
+            None => batch.remove_utxos(&txid),
+        }
     }
+    batch.set_utxo_tip(&block.get_prev_hash());
+    Ok(())
+}
 
-    // Updates the UTXO set with transactions from the BLOCk
-    // The block is tip of the blockchain
-    pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
-
-        for tx in block.get_transactions() {
-            if !tx.is_coinbase() {
-                for tx_input in &tx.v_inputs {
-                    let mut update_outputs = TXOutputs {
-                        // This vector will be used to store the remaining unspent outputs
-                        // after removing the spent ones
-                        outputs: Vec::new(),
-                    };
-                    let v_tx_outputs: TXOutputs =
-                        bincode::deserialize(&db.get(&tx_input.txid)?.unwrap())?;
-
-                    // Think of this line of code from when the first block is added
-                    // For the first time the outputs will be put in the v_tx_outputs
-                    // in the index sequence only for the first time
-                    // BOOYAAH...!!
-
-                    for output_index in 0..v_tx_outputs.outputs.len() {
-                        if output_index != tx_input.output_index as usize {
-                            update_outputs
-                                .outputs
-                                .push(v_tx_outputs.outputs[output_index].clone());
-                        }
-                    }
-
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&tx_input.txid)?;
-                    } else {
-                        db.insert(
-                            tx_input.txid.as_bytes(),
-                            bincode::serialize(&update_outputs)?,
-                        )?;
-                    }
-                }
-            }
+// Replaces the whole UTXO set with the one computed from the chain
+pub(crate) fn stage_reindex<S: ChainStore>(bc: &Blockchain<S>, batch: &mut StoreBatch) {
+    batch.clear_utxos();
+    for (txid, v_tx_outputs) in bc.find_utxo() {
+        batch.put_utxos(&txid, v_tx_outputs);
+    }
+    batch.set_utxo_tip(&bc.get_tip_hash());
+}
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
-            };
-            for tx_output in &tx.v_outputs {
-                new_outputs.outputs.push(tx_output.clone());
-            }
+// Allows to access the DB connected to our blockchain
+pub struct UTXOSet<S: ChainStore = SledStore> {
+    pub blockchain: Blockchain<S>,
+}
 
-            db.insert(tx.id.as_bytes(), bincode::serialize(&new_outputs)?)?;
-        }
+impl<S: ChainStore> UTXOSet<S> {
+    fn store(&self) -> &S {
+        self.blockchain.store()
+    }
 
-        Ok(())
+    // Reindex rebuils the UTXO set, and the indexes that are enabled
+    pub fn reindex(&self) -> Result<()> {
+        let mut batch = StoreBatch::default();
+        stage_reindex(&self.blockchain, &mut batch);
+        if self.store().txindex_enabled()? {
+            txindex::stage_rebuild(&self.blockchain, &mut batch);
+        }
+        if self.store().addrindex_enabled()? {
+            addrindex::stage_rebuild(&self.blockchain, &mut batch)?;
+        }
+        self.store().write_batch(batch)
     }
 
     pub fn count_transactions(&self) -> Result<i32> {
-        let mut counter = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            kv?;
-            counter += 1;
-        }
-        Ok(counter)
+        Ok(self.store().all_utxos()?.len() as i32)
     }
 
     // Finds the sufficient UTXOs for the transacation to take place
@@ -99,47 +168,44 @@ impl UTXOSet {
     ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
         let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
         let mut accumulated = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            let (k, v) = kv?;
-            let txid = String::from_utf8(k.to_vec())?;
-            let v_tx_outputs: TXOutputs = bincode::deserialize(&v.to_vec())?;
-
-            for output_index in 0..v_tx_outputs.outputs.len() {
-                if v_tx_outputs.outputs[output_index].is_locked_with_key(address)
-                    && accumulated < amount
-                {
-                    accumulated += v_tx_outputs.outputs[output_index].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(output_index as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![output_index as i32]);
-                        }
-                    }
-                }
+        for (txid, output_index, utxo) in self.list_unspent(address)? {
+            if accumulated >= amount {
+                break;
             }
+            accumulated += utxo.value;
+            unspent_outputs.entry(txid).or_default().push(output_index);
         }
         Ok((accumulated, unspent_outputs))
     }
 
-    // Returns a set of UTXOs for a sender's address
-    pub fn find_utxo_for_address(&self, sender_address: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let db = sled::open("data/utxos")?;
-
-        for kv in db.iter() {
-            let (_, v) = kv?;
-            let v_tx_outputs: TXOutputs = bincode::deserialize(&v.to_vec())?;
+    // Returns (txid, output index, output) of every UTXO locked to the address,
+    // HTLCs paying it are left out.
+    // Uses the address index when it is enabled instead of scanning the whole set.
+    pub fn list_unspent(&self, address: &[u8]) -> Result<Vec<(String, i32, TXOutput)>> {
+        if self.store().addrindex_enabled()? {
+            let mut unspent = addrindex::address_utxos(self.store(), address)?;
+            unspent.retain(|(_, _, utxo)| utxo.is_locked_with_key(address));
+            return Ok(unspent);
+        }
 
-            for utxo in v_tx_outputs.outputs {
-                if utxo.can_be_unlocked_with(sender_address) {
-                    utxos.outputs.push(utxo.clone())
+        let mut unspent = Vec::new();
+        for (txid, v_tx_outputs) in self.store().all_utxos()? {
+            for (output_index, utxo) in v_tx_outputs.outputs {
+                if utxo.is_locked_with_key(address) {
+                    unspent.push((txid.clone(), output_index, utxo));
                 }
             }
         }
 
-        Ok(utxos)
+        Ok(unspent)
+    }
+
+    // Returns a set of UTXOs for a sender's address
+    pub fn find_utxo_for_address(&self, sender_address: &[u8]) -> Result<Vec<TXOutput>> {
+        Ok(self
+            .list_unspent(sender_address)?
+            .into_iter()
+            .map(|(_, _, utxo)| utxo)
+            .collect())
     }
 }
@@ -6,16 +6,19 @@ use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 
 use crate::errors::Result;
+use crate::store::{ChainStore, SledStore, DATA_DIR};
 
+// Persisted as bincode in the wallets DB
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
     pub private_key: Vec<u8>,
     pub public_key: Vec<u8>,
 }
 
-pub struct Wallets {
+pub struct Wallets<S: ChainStore = SledStore> {
     // Key: Base58 encoding of the public key hash of the wallet in the value
-    wallets: HashMap<String, Wallet>
+    wallets: HashMap<String, Wallet>,
+    store: S,
 }
 
 impl Wallet {
@@ -43,22 +46,27 @@ impl Wallet {
     }
 
     // Returns Base58 encoding of the public key hash
-    fn get_address(&self) -> String {
+    pub fn get_address(&self) -> String {
         let mut pub_hash = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
-        // 0 O 1 I
-        // This encoding converts the binary data in address.body into Base58 string
-        address.encode().unwrap()
+        address_of(&pub_hash)
     }
 
 }
 
+// Returns the address of a public key hash, as outputs are locked to
+pub fn address_of(pub_key_hash: &[u8]) -> String {
+    let address = Address {
+        body: pub_key_hash.to_vec(),
+        scheme: Scheme::Base58,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+    // 0 O 1 I
+    // This encoding converts the binary data in address.body into Base58 string
+    address.encode().unwrap()
+}
+
 // Returns the SHA-256 RIPEMD-160 hash of the public key(public key hash)
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
@@ -74,25 +82,23 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
 }
 
 impl Wallets {
-
-    // Gets hash map of all wallets and their Base58 encoding of the public key hash 
+    // Gets hash map of all wallets and their Base58 encoding of the public key hash
     pub fn new() -> Result<Wallets> {
+        Wallets::open(SledStore::open(DATA_DIR)?)
+    }
+}
+
+impl<S: ChainStore> Wallets<S> {
+    // Loads all wallets kept in the given store
+    pub fn open(store: S) -> Result<Wallets<S>> {
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            store,
         };
 
-        let db = sled::open("data/wallets")?;
-
-        for item in db.into_iter() {
-            // IVec is wrapper around a vector of bytes(Vec<u8>) 
-            // for handing storing and sending binary data
-            let i = item?;
-            let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
+        for (address, wallet) in wlt.store.all_wallets()? {
             wlt.wallets.insert(address, wallet);
-
         }
-        drop(db);
         Ok(wlt)
     }
 
@@ -110,7 +116,7 @@ impl Wallets {
     // Get all the Base58 PKH stored in the DB
     pub fn get_all_address(&self) -> Vec<String> {
         let mut addresses = Vec::new();
-        for (address, _) in &self.wallets {
+        for address in self.wallets.keys() {
             addresses.push(address.clone());
         }
         addresses
@@ -123,16 +129,9 @@ impl Wallets {
 
     // Saves all the (Base58 PKH, wallets) in wallets in DB
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
-
         for (address, wallet) in &self.wallets {
-            let data = bincode::serialize(wallet)?;
-            db.insert(address, data)?;
+            self.store.put_wallet(address, wallet)?;
         }
-
-        db.flush()?;
-        drop(db);
         Ok(())
     }
-
 }
\ No newline at end of file